@@ -0,0 +1,134 @@
+//! Machine-readable output for `--json`
+//!
+//! Every event is written as one JSON object per line on stdout. Human-readable output and logs go to
+//! stderr instead, so that stdout can be parsed by scripts.
+
+use magic_wormhole::transit::{ConnectionType, TransitInfo};
+use serde_derive::Serialize;
+use std::{
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// The code was allocated or entered. Forwarding codes have no URI.
+    Code { code: &'a str, uri: Option<String> },
+    /// The welcome message of the rendezvous server
+    Welcome { message: &'a str },
+    /// The verifier of the established wormhole, which can be compared with the peer's
+    Verifier { verifier: String },
+    /// What the peer offered us
+    Offer {
+        name: String,
+        /// The total size, if known in advance
+        size: Option<u64>,
+        num_files: u64,
+        /// The individual files, if known in advance
+        files: Vec<OfferFile>,
+    },
+    /// A text message we received
+    Text { text: &'a str },
+    /// The transit connection has been established
+    Transit {
+        conn_type: &'static str,
+        relay: Option<&'a str>,
+        peer_addr: String,
+    },
+    /// Bytes have been sent or received
+    Progress { bytes: u64, total: Option<u64> },
+    /// Forwarded ports were offered to us, mapping our local ports and unix sockets to the remote targets
+    Forward {
+        mapping: Vec<(u16, &'a str)>,
+        sockets: Vec<(String, &'a str)>,
+    },
+    /// The command succeeded
+    Success,
+    /// The command failed, and the process will exit with `exit_code`
+    Error {
+        /// One of "wormhole", "transfer", "forwarding" or "other"
+        class: &'static str,
+        message: String,
+        exit_code: i32,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct OfferFile {
+    pub path: Vec<String>,
+    pub size: u64,
+}
+
+impl<'a> Event<'a> {
+    pub fn transit(info: &'a TransitInfo) -> Self {
+        let (conn_type, relay) = match &info.conn_type {
+            ConnectionType::Direct => ("direct", None),
+            ConnectionType::Relay { name } => ("relay", name.as_deref()),
+            _ => ("unknown", None),
+        };
+        Event::Transit {
+            conn_type,
+            relay,
+            peer_addr: info.peer_addr.to_string(),
+        }
+    }
+}
+
+/** Write an event as a line on stdout */
+pub fn emit(event: &Event) {
+    let mut stdout = std::io::stdout().lock();
+    let result = serde_json::to_writer(&mut stdout, event)
+        .map_err(std::io::Error::from)
+        .and_then(|()| writeln!(stdout))
+        .and_then(|()| stdout.flush());
+    if let Err(err) = result {
+        tracing::warn!("Failed to write JSON output: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_event_format() {
+        let event = Event::Code {
+            code: "4-purple-sausages",
+            uri: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({"event": "code", "code": "4-purple-sausages", "uri": null})
+        );
+
+        let event = Event::Error {
+            class: "transfer",
+            message: "Transfer was not acknowledged by peer".into(),
+            exit_code: 4,
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "error",
+                "class": "transfer",
+                "message": "Transfer was not acknowledged by peer",
+                "exit_code": 4,
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(Event::Success).unwrap(),
+            serde_json::json!({"event": "success"})
+        );
+    }
+}
@@ -1,5 +1,6 @@
 #![expect(clippy::too_many_arguments)]
 mod completer;
+mod json;
 mod util;
 
 use std::{
@@ -19,11 +20,11 @@ use console::{Term, style};
 use futures::{Future, future::Either};
 use indicatif::{MultiProgress, ProgressBar};
 use magic_wormhole::{
-    MailboxConnection, ParseCodeError, ParsePasswordError, Wormhole, forwarding, transfer,
+    MailboxConnection, Mood, ParseCodeError, ParsePasswordError, Wormhole, forwarding, transfer,
     transit::{self, ConnectionType, TransitInfo},
 };
 use std::{io::Write, path::PathBuf};
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{EnvFilter, fmt::writer::BoxMakeWriter};
 
 #[cfg(feature = "clipboard")]
 use arboard::Clipboard;
@@ -113,6 +114,37 @@ struct CommonFollowerArgs {
     code: Option<String>,
 }
 
+// send, receive, serve, connect
+#[derive(Debug, Args)]
+struct CommonVerifyArgs {
+    /// Compare the verifier with the peer before transferring anything. Use hex with the Python implementation
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "words"
+    )]
+    verify: Option<VerifyFormat>,
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum VerifyFormat {
+    Words,
+    Emoji,
+    Hex,
+}
+
+impl From<VerifyFormat> for magic_wormhole::VerifierFormat {
+    fn from(format: VerifyFormat) -> Self {
+        match format {
+            VerifyFormat::Words => Self::Words,
+            VerifyFormat::Emoji => Self::Emoji,
+            VerifyFormat::Hex => Self::Hex,
+        }
+    }
+}
+
 // send, send-mane, receive, serve, connect
 #[derive(Debug, Clone, Args)]
 struct CommonArgs {
@@ -135,6 +167,9 @@ struct CommonArgs {
     /// Always route traffic over a relay server. This hides your IP address from the peer (but not from the server operators. Use Tor for that).
     #[arg(long, conflicts_with = "force_direct")]
     force_relay: bool,
+    /// Limit the bandwidth in each direction to this many bytes per second. Accepts the suffixes K, M and G (e.g. 5M).
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<u64>,
 }
 
 #[derive(Debug, Subcommand)]
@@ -146,13 +181,30 @@ enum ForwardCommand {
         alias = "server", /* Muscle memory <3 */
     )]
     Serve {
-        /// List of ports to open up. You can optionally specify a domain/address to forward remote ports
-        #[arg(value_name = "[DOMAIN:]PORT", required = true, action = clap::ArgAction::Append, value_hint = clap::ValueHint::Hostname)]
+        /// List of ports to open up. You can optionally specify a domain/address to forward remote ports, and prefix them with `udp:` to forward UDP. Unix sockets are given as `unix:PATH`
+        #[arg(value_name = "[udp:][DOMAIN:]PORT", required_unless_present = "socks", action = clap::ArgAction::Append, value_hint = clap::ValueHint::Hostname)]
         targets: Vec<String>,
+        /// Act as the exit of a SOCKS5 proxy, which the peer runs on its side. Its clients may connect to any host that you can reach, unless restricted with `--socks-allow` or `--socks-deny`. Loopback, unspecified and link-local addresses are only reachable if a `--socks-allow` rule names them explicitly
+        #[arg(long)]
+        socks: bool,
+        /// Only allow SOCKS connections to these destinations. HOST is `*`, a domain, `*.DOMAIN` for its subdomains, an IP address or a network like 10.0.0.0/8. Can be provided multiple times.
+        #[arg(long, value_name = "HOST[:PORT]", requires = "socks", action = clap::ArgAction::Append, value_hint = clap::ValueHint::Hostname)]
+        socks_allow: Vec<forwarding::SocksRule>,
+        /// Never allow SOCKS connections to these destinations, even if `--socks-allow` does. Takes the same rules. Can be provided multiple times.
+        #[arg(long, value_name = "HOST[:PORT]", requires = "socks", action = clap::ArgAction::Append, value_hint = clap::ValueHint::Hostname)]
+        socks_deny: Vec<forwarding::SocksRule>,
+        /// Let the peer offer its own ports in return, with `forward connect --reverse`, and make them available here
+        #[arg(long)]
+        accept_reverse: bool,
+        /// Bind to a specific address to accept the ports the peer offers in return
+        #[arg(long = "reverse-bind", value_name = "ADDRESS", default_value = "::", requires = "accept_reverse", value_hint = clap::ValueHint::Other)]
+        reverse_bind_address: std::net::IpAddr,
         #[command(flatten)]
         common: CommonArgs,
         #[command(flatten)]
         common_leader: CommonLeaderArgs,
+        #[command(flatten)]
+        common_verify: CommonVerifyArgs,
     },
     /// Connect to some ports forwarded to you
     #[command()]
@@ -168,6 +220,12 @@ enum ForwardCommand {
         /// Bind to a specific address to accept the forwarding. Depending on your system and firewall, this may make the forwarded ports accessible from the outside.
         #[arg(long = "bind", value_name = "ADDRESS", default_value = "::", value_hint = clap::ValueHint::Other)]
         bind_address: std::net::IpAddr,
+        /// Make forwarded unix sockets available as unix sockets in this directory instead of on TCP ports (unix only)
+        #[arg(long = "unix-sockets", value_name = "DIR", value_hint = clap::ValueHint::DirPath)]
+        unix_socket_dir: Option<PathBuf>,
+        /// Offer a port of your system to the peer in return, if it accepts that. Takes the same targets as `forward serve`. Can be provided multiple times.
+        #[arg(long, value_name = "[udp:][DOMAIN:]PORT", action = clap::ArgAction::Append, value_hint = clap::ValueHint::Hostname)]
+        reverse: Vec<String>,
         /// Accept the forwarding without asking for confirmation
         #[arg(long, visible_alias = "yes")]
         noconfirm: bool,
@@ -175,20 +233,38 @@ enum ForwardCommand {
         common: CommonArgs,
         #[command(flatten)]
         common_follower: CommonFollowerArgs,
+        #[command(flatten)]
+        common_verify: CommonVerifyArgs,
     },
 }
 
 #[derive(Debug, Subcommand)]
 enum WormholeCommand {
     /// Send a file or a folder
-    #[command(visible_alias = "tx")]
+    #[command(
+        visible_alias = "tx",
+        mut_arg("files", |arg| arg.required(false).required_unless_present("text"))
+    )]
     Send {
+        /// Send a text message instead of a file. Reads it from stdin if no TEXT (or "-") is given
+        #[arg(
+            long,
+            value_name = "TEXT",
+            num_args = 0..=1,
+            conflicts_with_all = ["files", "file_name"],
+        )]
+        text: Option<Option<String>>,
+        /// The size in bytes of the data when sending from stdin ("-"). If it is unknown, the receiver needs to support transfer v2
+        #[arg(long, value_name = "BYTES", conflicts_with = "text")]
+        size: Option<u64>,
         #[clap(flatten)]
         common: CommonArgs,
         #[clap(flatten)]
         common_leader: CommonLeaderArgs,
         #[clap(flatten)]
         common_send: CommonSenderArgs,
+        #[clap(flatten)]
+        common_verify: CommonVerifyArgs,
     },
     /// Receive a file or a folder
     #[command(visible_alias = "rx")]
@@ -196,12 +272,21 @@ enum WormholeCommand {
         /// Accept file transfer without asking for confirmation
         #[arg(long, visible_alias = "yes")]
         noconfirm: bool,
+        /// Write the received file to stdout instead of saving it. Only works for single files
+        #[arg(long, conflicts_with = "file_path")]
+        stdout: bool,
+        /// Apply the sender's file permissions and modification times to the received files
+        #[cfg(feature = "experimental-transfer-v2")]
+        #[arg(long)]
+        preserve_metadata: bool,
         #[command(flatten)]
         common: CommonArgs,
         #[command(flatten)]
         common_follower: CommonFollowerArgs,
         #[command(flatten)]
         common_receiver: CommonReceiverArgs,
+        #[command(flatten)]
+        common_verify: CommonVerifyArgs,
     },
     /// Send a file to many recipients
     #[command(
@@ -274,10 +359,60 @@ struct WormholeCli {
         display_order = 101
     )]
     no_color: bool,
+
+    /// Print newline-delimited JSON events to stdout instead of human-readable output
+    #[arg(long, global = true, display_order = 102)]
+    json: bool,
 }
 
 fn main() -> eyre::Result<()> {
-    smol::block_on(async_main())
+    color_eyre::install()?;
+
+    let app = WormholeCli::parse();
+    /* These print something else than events to stdout */
+    if app.json
+        && !matches!(
+            app.command,
+            WormholeCommand::Completion { .. } | WormholeCommand::Help
+        )
+    {
+        json::enable();
+    }
+
+    match smol::block_on(async_main(app)) {
+        Ok(()) => {
+            if json::is_enabled() {
+                json::emit(&json::Event::Success);
+            }
+            Ok(())
+        },
+        Err(err) => {
+            let (class, exit_code) = error_class(&err);
+            if json::is_enabled() {
+                json::emit(&json::Event::Error {
+                    class,
+                    message: format!("{err:#}"),
+                    exit_code,
+                });
+            }
+            eprintln!("Error: {err:?}");
+            std::process::exit(exit_code);
+        },
+    }
+}
+
+/* Stable exit codes per class of error, so that scripts can tell failures apart */
+fn error_class(err: &eyre::Report) -> (&'static str, i32) {
+    for cause in err.chain() {
+        if cause.is::<magic_wormhole::WormholeError>() {
+            return ("wormhole", 3);
+        } else if cause.is::<transfer::TransferError>() {
+            return ("transfer", 4);
+        } else if cause.is::<forwarding::ForwardingError>() {
+            return ("forwarding", 5);
+        }
+    }
+    ("other", 1)
 }
 
 #[cfg_attr(
@@ -286,12 +421,20 @@ fn main() -> eyre::Result<()> {
         note = "The 'tls' feature depends on the async-tls crate which in turn depends on an old unmaintained version of rustls. If you need websocket TLS support use one of the futures-rustls features."
     )
 )]
-async fn async_main() -> eyre::Result<()> {
-    color_eyre::install()?;
-
-    let app = WormholeCli::parse();
-
-    let mut term = Term::stdout();
+async fn async_main(app: WormholeCli) -> eyre::Result<()> {
+    /* Keep stdout clean for the JSON events */
+    let mut term = if app.json {
+        Term::stderr()
+    } else {
+        Term::stdout()
+    };
+    /* Keep stdout clean for the JSON events or the received data */
+    let receive_to_stdout = matches!(app.command, WormholeCommand::Receive { stdout: true, .. });
+    let log_writer = if app.json || receive_to_stdout {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
 
     // Set NO_COLOR environment variable if --no-color flag is used
     if app.no_color {
@@ -305,6 +448,7 @@ async fn async_main() -> eyre::Result<()> {
                 "wormhole_rs=debug,magic_wormhole::core=trace,mio=debug,ws=error",
             ))
             .with_target(false)
+            .with_writer(log_writer)
             .init();
         tracing::trace!("Logging enabled.");
     } else {
@@ -312,10 +456,53 @@ async fn async_main() -> eyre::Result<()> {
             .with_max_level(tracing::Level::INFO)
             .with_env_filter(EnvFilter::new("mio=debug"))
             .with_target(false)
+            .with_writer(log_writer)
             .init();
     };
 
     match app.command {
+        WormholeCommand::Send {
+            text: Some(text),
+            common,
+            common_leader:
+                CommonLeaderArgs {
+                    code,
+                    code_length,
+                    no_qr,
+                },
+            common_verify: CommonVerifyArgs { verify },
+            ..
+        } => {
+            let text = match text.filter(|text| text != "-") {
+                Some(text) => text,
+                None => read_text_from_stdin(&mut term).await?,
+            };
+
+            let (wormhole, _code, _relay_hints) = match util::cancellable(
+                Box::pin(parse_and_connect(
+                    &mut term,
+                    common,
+                    code,
+                    Some(code_length),
+                    no_qr,
+                    true,
+                    transfer::APP_CONFIG,
+                    Some(&sender_print_code),
+                )),
+                ctrlc_handler(),
+            )
+            .await
+            {
+                Ok(result) => result?,
+                Err(_) => return Ok(()),
+            };
+            let wormhole = verify_peer(&mut term, wormhole, verify).await?;
+
+            transfer::send_text(wormhole, text, ctrlc_handler())
+                .await
+                .context("Send process failed")?;
+            writeln!(&mut term, "Text message sent")?;
+        },
         WormholeCommand::Send {
             common,
             common_leader:
@@ -325,11 +512,17 @@ async fn async_main() -> eyre::Result<()> {
                     no_qr,
                 },
             common_send: CommonSenderArgs { file_name, files },
+            common_verify: CommonVerifyArgs { verify },
+            size,
             ..
         } => {
-            let offer = make_send_offer(files, file_name).await?;
+            let offer = match &files[..] {
+                [path] if path.as_os_str() == "-" => make_stdin_offer(file_name, size),
+                _ => make_send_offer(files, file_name).await?,
+            };
 
             let transit_abilities = parse_transit_args(&common);
+            let rate_limit = common.limit_rate;
             let (wormhole, _code, relay_hints) = match util::cancellable(
                 Box::pin(parse_and_connect(
                     &mut term,
@@ -348,8 +541,16 @@ async fn async_main() -> eyre::Result<()> {
                 Ok(result) => result?,
                 Err(_) => return Ok(()),
             };
+            let wormhole = verify_peer(&mut term, wormhole, verify).await?;
 
-            Box::pin(send(wormhole, relay_hints, offer, transit_abilities)).await?;
+            Box::pin(send(
+                wormhole,
+                relay_hints,
+                offer,
+                transit_abilities,
+                rate_limit,
+            ))
+            .await?;
         },
         WormholeCommand::SendMany {
             tries,
@@ -365,6 +566,7 @@ async fn async_main() -> eyre::Result<()> {
             ..
         } => {
             let transit_abilities = parse_transit_args(&common);
+            let rate_limit = common.limit_rate;
             let (wormhole, code, relay_hints) = {
                 let connect_fut = Box::pin(parse_and_connect(
                     &mut term,
@@ -393,17 +595,29 @@ async fn async_main() -> eyre::Result<()> {
                 wormhole,
                 &mut term,
                 transit_abilities,
+                rate_limit,
             ))
             .await?;
         },
         WormholeCommand::Receive {
             noconfirm,
+            stdout,
+            #[cfg(feature = "experimental-transfer-v2")]
+            preserve_metadata,
             common,
             common_follower: CommonFollowerArgs { code },
             common_receiver: CommonReceiverArgs { file_path },
+            common_verify: CommonVerifyArgs { verify },
             ..
         } => {
+            eyre::ensure!(
+                !(stdout && json::is_enabled()),
+                "--stdout can't be combined with --json, as both write to stdout"
+            );
+            /* Keep stdout clean for the received data */
+            let mut term = if stdout { Term::stderr() } else { term };
             let transit_abilities = parse_transit_args(&common);
+            let rate_limit = common.limit_rate;
             let (wormhole, _code, relay_hints) = {
                 let connect_fut = Box::pin(parse_and_connect(
                     &mut term,
@@ -420,18 +634,28 @@ async fn async_main() -> eyre::Result<()> {
                     Either::Right(((), _)) => return Ok(()),
                 }
             };
+            let wormhole = verify_peer(&mut term, wormhole, verify).await?;
 
             Box::pin(receive(
                 wormhole,
                 relay_hints,
                 &file_path,
+                stdout,
                 noconfirm,
+                #[cfg(feature = "experimental-transfer-v2")]
+                preserve_metadata,
                 transit_abilities,
+                rate_limit,
             ))
             .await?;
         },
         WormholeCommand::Forward(ForwardCommand::Serve {
             targets,
+            socks,
+            socks_allow,
+            socks_deny,
+            accept_reverse,
+            reverse_bind_address,
             common,
             common_leader:
                 CommonLeaderArgs {
@@ -439,50 +663,30 @@ async fn async_main() -> eyre::Result<()> {
                     code_length,
                     no_qr,
                 },
+            common_verify: CommonVerifyArgs { verify },
             ..
         }) => {
             // TODO make fancy
             tracing::warn!(
                 "This is an unstable feature. Make sure that your peer is running the exact same version of the program as you. Also, please report all bugs and crashes."
             );
-            /* Map the CLI argument to Strings. Use the occasion to inspect them and fail early on malformed input. */
-            let targets = targets
-                .into_iter()
-                .enumerate()
-                .map(|(index, target)| {
-                    let result = (|| {
-                        /* Either HOST:PORT or PORT */
-                        match target.rsplit_once(':') {
-                            Some((host, port)) => {
-                                let host = url::Host::parse(host)
-                                    .map_err(eyre::Error::from)
-                                    .context("Invalid host")?;
-                                let port: u16 = port.parse().context("Invalid port")?;
-                                Ok((Some(host), port))
-                            },
-                            None => {
-                                /* It's just a port */
-                                target
-                                    .parse::<u16>()
-                                    .map(|port| (None, port))
-                                    .map_err(eyre::Error::from)
-                                    .context("Invalid port")
-                            },
-                        }
-                    })();
-                    result.context(format!(
-                        "Invalid {}{} target argument ('{}') ",
-                        index + 1,
-                        match (index + 1) % 10 {
-                            1 => "st",
-                            2 => "nd",
-                            3 => "rd",
-                            _ => "th",
-                        },
-                        target
-                    ))
-                })
-                .collect::<Result<Vec<_>, _>>()?;
+            let mut targets = parse_forward_targets(targets)?;
+            if socks {
+                let policy = socks_allow
+                    .into_iter()
+                    .fold(forwarding::SocksPolicy::default(), |policy, rule| {
+                        policy.allow(rule)
+                    });
+                let policy = socks_deny
+                    .into_iter()
+                    .fold(policy, |policy, rule| policy.deny(rule));
+                if policy.allows_any() {
+                    tracing::warn!(
+                        "The peer may reach every host that you can reach through the SOCKS proxy, except for loopback, unspecified and link-local addresses. Use --socks-allow to restrict this."
+                    );
+                }
+                targets.push(forwarding::Target::Socks(policy));
+            }
             loop {
                 let mut app_config = forwarding::APP_CONFIG;
                 app_config.app_version.transit_abilities = parse_transit_args(&common);
@@ -501,50 +705,101 @@ async fn async_main() -> eyre::Result<()> {
                         Either::Left((result, _)) => result?,
                         Either::Right(((), _)) => break,
                     };
-                smol::spawn(forwarding::serve(
-                    wormhole,
-                    &transit_handler,
-                    relay_hints,
-                    targets.clone(),
-                    ctrlc_handler(),
-                ))
-                .detach();
+                /* A rejected peer only ends this session, keep serving the next one */
+                let wormhole = match verify_peer(&mut term, wormhole, verify).await {
+                    Ok(wormhole) => wormhole,
+                    Err(error) => {
+                        tracing::error!("{error:#}");
+                        continue;
+                    },
+                };
+                if accept_reverse {
+                    smol::spawn(forwarding::serve_with_reverse(
+                        wormhole,
+                        &transit_handler,
+                        relay_hints,
+                        common.limit_rate,
+                        targets.clone(),
+                        Some(reverse_bind_address),
+                        |mapping: &[(u16, String)]| {
+                            tracing::info!("The peer offered the following ports in return:");
+                            print_forward_mapping(
+                                mapping
+                                    .iter()
+                                    .map(|(port, target)| (*port, target.as_str()))
+                                    .collect(),
+                                Vec::new(),
+                            );
+                        },
+                        ctrlc_handler(),
+                    ))
+                    .detach();
+                } else {
+                    smol::spawn(forwarding::serve(
+                        wormhole,
+                        &transit_handler,
+                        relay_hints,
+                        common.limit_rate,
+                        targets.clone(),
+                        ctrlc_handler(),
+                    ))
+                    .detach();
+                }
             }
         },
         WormholeCommand::Forward(ForwardCommand::Connect {
             ports,
             noconfirm,
             bind_address,
+            unix_socket_dir,
+            reverse,
             common,
             common_follower: CommonFollowerArgs { code },
+            common_verify: CommonVerifyArgs { verify },
             ..
         }) => {
             // TODO make fancy
             tracing::warn!(
                 "This is an unstable feature. Make sure that your peer is running the exact same version of the program as you. Also, please report all bugs and crashes."
             );
+            let reverse = parse_forward_targets(reverse)?;
             let mut app_config = forwarding::APP_CONFIG;
             app_config.app_version.transit_abilities = parse_transit_args(&common);
+            let rate_limit = common.limit_rate;
             let (wormhole, _code, relay_hints) = parse_and_connect(
                 &mut term, common, code, None, false, false, app_config, None,
             )
             .await?;
+            let wormhole = verify_peer(&mut term, wormhole, verify).await?;
 
             let offer = forwarding::connect(
                 wormhole,
                 &transit_handler,
                 relay_hints,
+                rate_limit,
                 Some(bind_address),
                 &ports,
+                unix_socket_dir.as_deref(),
             )
             .await?;
             tracing::info!("Mapping the following open ports to targets:");
-            tracing::info!("  local port -> remote target (no address = localhost on remote)");
-            for (port, target) in &offer.mapping {
-                tracing::info!("  {} -> {}", port, target);
-            }
+            print_forward_mapping(
+                offer
+                    .mapping
+                    .iter()
+                    .map(|(port, target)| (*port, target.as_str()))
+                    .collect(),
+                offer
+                    .unix_mapping
+                    .iter()
+                    .map(|(path, target)| (path.display().to_string(), target.as_str()))
+                    .collect(),
+            );
             if noconfirm || util::ask_user("Accept forwarded ports?", true).await {
-                offer.accept(ctrlc_handler()).await?;
+                offer
+                    .with_reverse_targets(reverse)
+                    .accept(ctrlc_handler())
+                    .await?;
             } else {
                 offer.reject().await?;
             }
@@ -584,6 +839,76 @@ async fn async_main() -> eyre::Result<()> {
     Ok(())
 }
 
+/* Parse forwarding targets. Use the occasion to inspect them and fail early on malformed input. */
+fn parse_forward_targets(targets: Vec<String>) -> eyre::Result<Vec<forwarding::Target>> {
+    targets
+        .into_iter()
+        .enumerate()
+        .map(|(index, target)| {
+            let result = (|| {
+                if let Some(path) = target.strip_prefix("unix:") {
+                    #[cfg(unix)]
+                    return Ok(forwarding::Target::Unix(path.into()));
+                    #[cfg(not(unix))]
+                    eyre::bail!(
+                        "Unix sockets are not supported on this platform ('{}')",
+                        path
+                    );
+                }
+                let (udp, address) = match target.strip_prefix("udp:") {
+                    Some(address) => (true, address),
+                    None => (false, target.as_str()),
+                };
+                /* Either HOST:PORT or PORT */
+                let (host, port) = match address.rsplit_once(':') {
+                    Some((host, port)) => {
+                        let host = url::Host::parse(host)
+                            .map_err(eyre::Error::from)
+                            .context("Invalid host")?;
+                        let port: u16 = port.parse().context("Invalid port")?;
+                        (Some(host), port)
+                    },
+                    None => {
+                        /* It's just a port */
+                        let port: u16 = address.parse().context("Invalid port")?;
+                        (None, port)
+                    },
+                };
+                eyre::Result::<_>::Ok(if udp {
+                    forwarding::Target::Udp(host, port)
+                } else {
+                    forwarding::Target::Tcp(host, port)
+                })
+            })();
+            result.context(format!(
+                "Invalid {}{} target argument ('{}') ",
+                index + 1,
+                match (index + 1) % 10 {
+                    1 => "st",
+                    2 => "nd",
+                    3 => "rd",
+                    _ => "th",
+                },
+                target
+            ))
+        })
+        .collect()
+}
+
+/* Tell the user (or script) where the ports of the peer are available */
+fn print_forward_mapping(mapping: Vec<(u16, &str)>, sockets: Vec<(String, &str)>) {
+    tracing::info!("  local port -> remote target (no address = localhost on remote)");
+    for (port, target) in &mapping {
+        tracing::info!("  {} -> {}", port, target);
+    }
+    for (path, target) in &sockets {
+        tracing::info!("  {} -> {}", path, target);
+    }
+    if json::is_enabled() {
+        json::emit(&json::Event::Forward { mapping, sockets });
+    }
+}
+
 fn parse_transit_args(args: &CommonArgs) -> transit::Abilities {
     match (args.force_direct, args.force_relay) {
         (false, false) => transit::Abilities::ALL,
@@ -593,6 +918,22 @@ fn parse_transit_args(args: &CommonArgs) -> transit::Abilities {
     }
 }
 
+/* Parse a number of bytes per second like curl's --limit-rate, with binary suffixes */
+fn parse_rate(rate: &str) -> Result<u64, String> {
+    let (number, multiplier) = match rate.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&rate[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&rate[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&rate[..i], 1 << 30),
+        _ => (rate, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .filter(|rate| *rate > 0)
+        .ok_or_else(|| format!("'{rate}' is not a positive number of bytes, like 500K or 5M"))
+}
+
 type PrintCodeFn =
     dyn Fn(&mut Term, &magic_wormhole::Code, &Option<url::Url>, bool) -> eyre::Result<()>;
 
@@ -723,14 +1064,88 @@ async fn parse_and_connect(
     print_welcome(term, mailbox_connection.welcome())?;
     let code = mailbox_connection.code().clone();
     let wormhole = Wormhole::connect(mailbox_connection).await?;
+    if json::is_enabled() {
+        json::emit(&json::Event::Verifier {
+            verifier: wormhole
+                .verifier()
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect(),
+        });
+    }
     eyre::Result::<_>::Ok((wormhole, code, relay_hints))
 }
 
+/* Like `wormhole --verify`, let the users compare the verifier before any data flows */
+async fn verify_peer(
+    term: &mut Term,
+    mut wormhole: Wormhole,
+    format: Option<VerifyFormat>,
+) -> eyre::Result<Wormhole> {
+    let Some(format) = format else {
+        return Ok(wormhole);
+    };
+
+    let verifier = wormhole.format_verifier(format.into());
+    writeln!(term, "Verifier: {}", style(&verifier).bold())?;
+    if util::ask_user("Does it match the verifier of your peer?", false).await {
+        return Ok(wormhole);
+    }
+
+    /* Tell the peer why we are leaving, like the Python implementation does */
+    wormhole
+        .send_json(&serde_json::json!({
+            "error": "verification rejected, abandoning transfer",
+        }))
+        .await?;
+    wormhole.shutdown(Mood::Errory).await?;
+    eyre::bail!("Verification rejected, abandoning the transfer")
+}
+
+async fn read_text_from_stdin(term: &mut Term) -> eyre::Result<String> {
+    if std::io::stdin().is_terminal() {
+        writeln!(term, "Enter the text to send, finish with Ctrl+D:")?;
+    }
+    let text = smol::unblock(|| std::io::read_to_string(std::io::stdin()))
+        .await
+        .context("Failed to read text from stdin")?;
+    eyre::ensure!(!text.is_empty(), "Refusing to send an empty text message");
+    Ok(text)
+}
+
+/* Stream stdin without buffering it. Without knowing its size in advance, it only gets buffered for peers that need the size */
+fn make_stdin_offer(file_name: Option<String>, size: Option<u64>) -> transfer::offer::OfferSend {
+    let file_name = file_name.unwrap_or_else(|| "stdin".into());
+    let content = transfer::offer::new_offer_content_stream(smol::Unblock::new(std::io::stdin()));
+    match size.or_else(stdin_file_size) {
+        Some(size) => transfer::offer::OfferSend::new_file_custom(file_name, size, content),
+        None => transfer::offer::OfferSend::new_file_stream(file_name, content),
+    }
+}
+
+/* When stdin is redirected from a file, we know its size */
+fn stdin_file_size() -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsFd;
+
+        let stdin = std::fs::File::from(std::io::stdin().as_fd().try_clone_to_owned().ok()?);
+        let metadata = stdin.metadata().ok()?;
+        metadata.is_file().then_some(metadata.len())
+    }
+    #[cfg(not(unix))]
+    None
+}
+
 async fn make_send_offer(
     mut files: Vec<PathBuf>,
     file_name: Option<String>,
 ) -> eyre::Result<transfer::offer::OfferSend> {
     for file in &files {
+        eyre::ensure!(
+            file.as_os_str() != "-",
+            "Sending from stdin is only supported for single files with `send -`"
+        );
         let path = std::path::PathBuf::from(file);
         eyre::ensure!(
             smol::unblock(move || path.exists()).await,
@@ -791,7 +1206,12 @@ fn create_progress_bar(file_size: u64) -> ProgressBar {
         "[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} | {decimal_bytes_per_sec} | ETA: {eta}"
     };
 
-    let pb = ProgressBar::new(file_size);
+    /* JSON output reports the progress as events instead */
+    let pb = if json::is_enabled() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(file_size)
+    };
     pb.set_style(
         ProgressStyle::default_bar()
             .template(template)
@@ -801,21 +1221,45 @@ fn create_progress_bar(file_size: u64) -> ProgressBar {
     pb
 }
 
-fn create_progress_handler(pb: ProgressBar) -> impl FnMut(u64, u64) {
+fn create_progress_handler(pb: ProgressBar) -> impl FnMut(u64, Option<u64>) {
+    let mut last_event: Option<Instant> = None;
     move |sent, total| {
         if sent == 0 {
             pb.reset_elapsed();
-            pb.set_length(total);
+            match total {
+                Some(total) => pb.set_length(total),
+                None => pb.unset_length(),
+            }
             pb.enable_steady_tick(std::time::Duration::from_millis(250));
         }
 
         pb.set_position(sent);
+
+        /* Don't flood the output, but always report the start and the end */
+        if json::is_enabled()
+            && (sent == 0
+                || Some(sent) == total
+                || last_event.is_none_or(|last| last.elapsed() >= Duration::from_millis(250)))
+        {
+            last_event = Some(Instant::now());
+            json::emit(&json::Event::Progress { bytes: sent, total });
+        }
     }
 }
 
+/* The transfer v1 API always knows the total size */
+fn create_v1_progress_handler(pb: ProgressBar) -> impl FnMut(u64, u64) {
+    let mut handler = create_progress_handler(pb);
+    move |sent, total| handler(sent, Some(total))
+}
+
 fn print_welcome(term: &mut Term, welcome: Option<&str>) -> eyre::Result<()> {
     if let Some(welcome) = &welcome {
-        writeln!(term, "Got welcome from server: {welcome}")?;
+        if json::is_enabled() {
+            json::emit(&json::Event::Welcome { message: welcome });
+        } else {
+            writeln!(term, "Got welcome from server: {welcome}")?;
+        }
     }
     Ok(())
 }
@@ -834,6 +1278,14 @@ fn sender_print_code(
     }
     .to_string();
 
+    if json::is_enabled() {
+        json::emit(&json::Event::Code {
+            code: &code.to_string(),
+            uri: Some(uri),
+        });
+        return Ok(());
+    }
+
     if cfg!(feature = "clipboard") {
         writeln!(
             term,
@@ -877,6 +1329,14 @@ fn server_print_code(
     _: &Option<url::Url>,
     _qr: bool,
 ) -> eyre::Result<()> {
+    if json::is_enabled() {
+        json::emit(&json::Event::Code {
+            code: &code.to_string(),
+            uri: None,
+        });
+        return Ok(());
+    }
+
     if cfg!(feature = "clipboard") {
         writeln!(
             term,
@@ -905,6 +1365,7 @@ async fn send(
     relay_hints: Vec<transit::RelayHint>,
     offer: transfer::offer::OfferSend,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
 ) -> eyre::Result<()> {
     let pb = create_progress_bar(0);
     let pb2 = pb.clone();
@@ -912,6 +1373,7 @@ async fn send(
         wormhole,
         relay_hints,
         transit_abilities,
+        rate_limit,
         offer,
         &transit_handler,
         create_progress_handler(pb),
@@ -933,6 +1395,7 @@ async fn send_many(
     wormhole: Wormhole,
     term: &mut Term,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
 ) -> eyre::Result<()> {
     tracing::warn!(
         "Reminder that you are sending the file to multiple people, and this may reduce the overall security. See the help page for more information."
@@ -953,6 +1416,7 @@ async fn send_many(
         term.clone(),
         &mp,
         transit_abilities,
+        rate_limit,
         ctrlc_handler(),
     )
     .await?;
@@ -982,6 +1446,7 @@ async fn send_many(
             term.clone(),
             &mp,
             transit_abilities,
+            rate_limit,
             ctrlc_handler(),
         )
         .await?;
@@ -994,6 +1459,7 @@ async fn send_many(
         mut term: Term,
         mp: &MultiProgress,
         transit_abilities: transit::Abilities,
+        rate_limit: Option<u64>,
         cancel: impl Future<Output = ()> + Send + 'static,
     ) -> eyre::Result<()> {
         writeln!(&mut term, "Sending file to peer").unwrap();
@@ -1006,6 +1472,7 @@ async fn send_many(
                     wormhole,
                     relay_hints,
                     transit_abilities,
+                    rate_limit,
                     offer,
                     &transit_handler,
                     create_progress_handler(pb2),
@@ -1036,38 +1503,133 @@ async fn receive(
     wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     target_dir: &std::path::Path,
+    to_stdout: bool,
     noconfirm: bool,
+    #[cfg(feature = "experimental-transfer-v2")] preserve_metadata: bool,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
 ) -> eyre::Result<()> {
-    #[cfg(not(feature = "experimental-transfer-v2"))]
+    let req = transfer::request(
+        wormhole,
+        relay_hints,
+        transit_abilities,
+        rate_limit,
+        ctrlc_handler(),
+    )
+    .await
+    .context("Could not get an offer")?;
+
+    if json::is_enabled()
+        && let Some(req) = &req
     {
-        let req = transfer::request_file(wormhole, relay_hints, transit_abilities, ctrlc_handler())
-            .await
-            .context("Could not get an offer")?;
-        /* If None, the task got cancelled */
-        if let Some(req) = req {
+        emit_offer(req);
+    }
+
+    match req {
+        Some(req) if to_stdout && !matches!(req, transfer::ReceiveRequest::Text(_)) => {
+            receive_inner_stdout(req, noconfirm).await
+        },
+        Some(transfer::ReceiveRequest::V1(req)) => {
             receive_inner_v1(req, target_dir, noconfirm).await
-        } else {
+        },
+        Some(transfer::ReceiveRequest::Directory(req)) => {
+            receive_inner_directory(req, target_dir, noconfirm).await
+        },
+        Some(transfer::ReceiveRequest::Text(text)) if json::is_enabled() => {
+            json::emit(&json::Event::Text { text: &text });
             Ok(())
-        }
+        },
+        Some(transfer::ReceiveRequest::Text(text)) => {
+            /* Print it as is, so that it can be piped somewhere else */
+            print!("{text}");
+            if !text.ends_with('\n') {
+                println!();
+            }
+            Ok(())
+        },
+        #[cfg(feature = "experimental-transfer-v2")]
+        Some(transfer::ReceiveRequest::V2(req)) => {
+            receive_inner_v2(req, target_dir, noconfirm, preserve_metadata).await
+        },
+        Some(req) => {
+            req.reject().await.context("Could not reject offer")?;
+            eyre::bail!("Received an offer that is not supported by this version")
+        },
+        /* If None, the task got cancelled */
+        None => Ok(()),
     }
-    #[cfg(feature = "experimental-transfer-v2")]
-    {
-        let req = transfer::request(wormhole, relay_hints, transit_abilities, ctrlc_handler())
-            .await
-            .context("Could not get an offer")?;
+}
 
-        match req {
-            Some(transfer::ReceiveRequest::V1(req)) => {
-                receive_inner_v1(req, target_dir, noconfirm).await
-            },
-            #[cfg(feature = "experimental-transfer-v2")]
-            Some(transfer::ReceiveRequest::V2(req)) => {
-                receive_inner_v2(req, target_dir, noconfirm).await
+/* Tell what we are about to receive, before asking for confirmation */
+fn emit_offer(req: &transfer::ReceiveRequest) {
+    let event = match (req, req.offer()) {
+        (_, Some(offer)) => {
+            let files = offer
+                .iter_files()
+                .map(|(path, _, size)| json::OfferFile { path, size })
+                .collect::<Vec<_>>();
+            json::Event::Offer {
+                name: offer.offer_name(),
+                size: offer.is_size_known().then(|| offer.total_size()),
+                num_files: files.len() as u64,
+                files,
+            }
+        },
+        (transfer::ReceiveRequest::Directory(req), None) => json::Event::Offer {
+            name: req.dir_name(),
+            size: Some(req.num_bytes()),
+            num_files: req.num_files(),
+            files: Vec::new(),
+        },
+        _ => return,
+    };
+    json::emit(&event);
+}
+
+async fn receive_inner_stdout(req: transfer::ReceiveRequest, noconfirm: bool) -> eyre::Result<()> {
+    use unit_prefix::NumberPrefix;
+
+    let Some(offer) = req
+        .offer()
+        .filter(|offer| !offer.is_multiple() && !offer.is_directory())
+    else {
+        req.reject().await.context("Could not reject offer")?;
+        eyre::bail!("Only single files can be written to stdout");
+    };
+
+    let file_name = offer.offer_name();
+    let file_size = offer.total_size();
+    let size = match NumberPrefix::binary(file_size as f64) {
+        _ if !offer.is_size_known() => "unknown size".to_string(),
+        NumberPrefix::Standalone(bytes) => format!("{bytes} bytes"),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.1} {}B", n, prefix.symbol()),
+    };
+    if !(noconfirm
+        || util::ask_user(
+            match should_use_color() {
+                true => format!(
+                    "Receive file '{}' ({}) to stdout?",
+                    file_name.green().bold(),
+                    size.blue().bold()
+                ),
+                false => format!("Receive file '{file_name}' ({size}) to stdout?"),
             },
-            None => Ok(()),
-        }
+            true,
+        )
+        .await)
+    {
+        return req.reject().await.context("Could not reject offer");
     }
+
+    let pb = create_progress_bar(file_size);
+    req.accept_into(
+        &transit_handler,
+        create_progress_handler(pb),
+        smol::Unblock::new(std::io::stdout()),
+        ctrlc_handler(),
+    )
+    .await
+    .context("Receive process failed")
 }
 
 async fn receive_inner_v1(
@@ -1133,7 +1695,7 @@ async fn receive_inner_v1(
         return req
             .accept(
                 &transit_handler,
-                create_progress_handler(pb),
+                create_v1_progress_handler(pb),
                 &mut file,
                 ctrlc_handler(),
             )
@@ -1166,7 +1728,7 @@ async fn receive_inner_v1(
         .await?;
     req.accept(
         &transit_handler,
-        create_progress_handler(pb),
+        create_v1_progress_handler(pb),
         &mut file,
         ctrlc_handler(),
     )
@@ -1174,11 +1736,97 @@ async fn receive_inner_v1(
     .context("Receive process failed")
 }
 
+async fn receive_inner_directory(
+    req: transfer::ReceiveDirectoryRequest,
+    target_dir: &std::path::Path,
+    noconfirm: bool,
+) -> eyre::Result<()> {
+    use unit_prefix::NumberPrefix;
+
+    let dir_name = req.dir_name();
+    let size = match NumberPrefix::binary(req.num_bytes() as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{bytes} bytes"),
+        NumberPrefix::Prefixed(prefix, n) => format!("{:.1} {}B", n, prefix.symbol()),
+    };
+    if !(noconfirm
+        || util::ask_user(
+            match should_use_color() {
+                true => format!(
+                    "Receive directory '{}' ({} files, {})?",
+                    dir_name.green().bold(),
+                    req.num_files(),
+                    size.blue().bold(),
+                ),
+                false => format!(
+                    "Receive directory '{}' ({} files, {})?",
+                    dir_name,
+                    req.num_files(),
+                    size,
+                ),
+            },
+            true,
+        )
+        .await)
+    {
+        return req.reject().await.context("Could not reject offer");
+    }
+
+    /* The name is untrusted input, it must not take us out of the target directory */
+    if matches!(dir_name.as_str(), "" | "." | "..") || dir_name.contains(['/', '\\']) {
+        req.reject().await.context("Could not reject offer")?;
+        eyre::bail!("The sender offered an invalid directory name: '{dir_name}'");
+    }
+    let dir_path = target_dir.join(&dir_name);
+
+    /* If there is a collision, ask whether to overwrite */
+    if !noconfirm
+        && dir_path.exists()
+        && !util::ask_user(
+            if should_use_color() {
+                format!(
+                    "Override existing files in {}?",
+                    dir_path.display().red().bold()
+                )
+            } else {
+                format!("Override existing files in {}?", dir_path.display())
+            },
+            false,
+        )
+        .await
+    {
+        return req.reject().await.context("Could not reject offer");
+    }
+
+    let pb = create_progress_bar(req.transfer_size());
+    req.accept(
+        &transit_handler,
+        create_v1_progress_handler(pb),
+        &dir_path,
+        ctrlc_handler(),
+    )
+    .await
+    .context("Receive process failed")
+}
+
+/* Derived from the offered paths and sizes, with a hash that is stable across runs and versions */
+#[cfg(feature = "experimental-transfer-v2")]
+fn partial_dir_name(offer: &transfer::offer::Offer) -> String {
+    use sha2::{Digest, Sha256};
+    let mut hasher = Sha256::new();
+    for (path, _, size) in offer.iter_files() {
+        hasher.update(
+            serde_json::to_vec(&(path, size)).expect("Serializing a list of strings can't fail"),
+        );
+    }
+    format!("wormhole-partial-{}", hex::encode(&hasher.finalize()[..8]))
+}
+
 #[cfg(feature = "experimental-transfer-v2")]
 async fn receive_inner_v2(
     req: transfer::ReceiveRequestV2,
     target_dir: &std::path::Path,
     noconfirm: bool,
+    preserve_metadata: bool,
 ) -> eyre::Result<()> {
     let offer = req.offer();
     let file_size = offer.total_size();
@@ -1191,6 +1839,7 @@ async fn receive_inner_v2(
                 "Receive {} ({})?",
                 offer_name,
                 match NumberPrefix::binary(file_size as f64) {
+                    _ if !offer.is_size_known() => "unknown size".to_string(),
                     NumberPrefix::Standalone(bytes) => format!("{bytes} bytes"),
                     NumberPrefix::Prefixed(prefix, n) =>
                         format!("{:.1} {}B in size", n, prefix.symbol()),
@@ -1205,16 +1854,25 @@ async fn receive_inner_v2(
 
     let pb = create_progress_bar(file_size);
 
-    let on_progress = move |received, _total| {
-        pb.set_position(received);
-    };
+    /* Receive into a temporary directory which is unique for this offer, so that an interrupted
+     * transfer can be resumed by receiving the same offer again */
+    let tmp_dir = target_dir.join(partial_dir_name(&offer));
 
-    /* Create a temporary directory for receiving */
-    use rand::Rng;
-    let tmp_dir = target_dir.join(format!(
-        "wormhole-tmp-{:06}",
-        rand::thread_rng().gen_range(0..1_000_000)
-    ));
+    let resume = smol::fs::metadata(&tmp_dir).await.is_ok()
+        && (noconfirm
+            || util::ask_user(
+                format!(
+                    "Found a partial transfer in {}, resume it?",
+                    tmp_dir.display()
+                ),
+                true,
+            )
+            .await);
+    if !resume && smol::fs::metadata(&tmp_dir).await.is_ok() {
+        smol::fs::remove_dir_all(&tmp_dir)
+            .await
+            .context("Failed to delete the partial transfer")?;
+    }
     smol::fs::create_dir_all(&tmp_dir)
         .await
         .context("Failed to create temporary directory for receiving")?;
@@ -1223,13 +1881,37 @@ async fn receive_inner_v2(
     offer.create_directories(&tmp_dir).await?;
 
     /* Accept the offer and receive it */
-    let answer = offer.accept_all(&tmp_dir);
-    req.accept(&transit_handler, answer, on_progress, ctrlc_handler())
+    let answer = if resume {
+        offer
+            .accept_all_resume(&tmp_dir)
+            .await
+            .context("Failed to read the partial transfer")?
+    } else {
+        offer.accept_all(&tmp_dir)
+    };
+    let accepted = transfer::offer::Offer::from(&answer);
+    req.accept(
+        &transit_handler,
+        answer,
+        create_progress_handler(pb),
+        ctrlc_handler(),
+    )
+    .await
+    .context("Receive process failed")?;
+
+    /* Put in all the symlinks last, this greatly reduces the attack surface */
+    offer
+        .create_symlinks(&tmp_dir, transfer::offer::SymlinkPolicy::default())
         .await
-        .context("Receive process failed")?;
+        .context("Failed to create symlinks")?;
 
-    // /* Put in all the symlinks last, this greatly reduces the attack surface */
-    // offer.create_symlinks(&tmp_dir).await?;
+    /* Only now, as this might make some of the files read-only */
+    if preserve_metadata {
+        accepted
+            .apply_metadata(&tmp_dir)
+            .await
+            .context("Failed to apply the file metadata")?;
+    }
 
     /* TODO walk the output directory and delete things we did not accept; this will be important for resumption */
 
@@ -1273,6 +1955,10 @@ async fn receive_inner_v2(
 
 fn transit_handler(info: TransitInfo) {
     tracing::info!("{info}");
+    if json::is_enabled() {
+        json::emit(&json::Event::transit(&info));
+        return;
+    }
     let mut term = Term::stderr();
     let use_color = should_use_color();
 
@@ -1333,6 +2019,37 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1000"), Ok(1000));
+        assert_eq!(parse_rate("500k"), Ok(500 * 1024));
+        assert_eq!(parse_rate("5M"), Ok(5 * 1024 * 1024));
+        assert_eq!(parse_rate("1G"), Ok(1024 * 1024 * 1024));
+        assert!(parse_rate("0").is_err());
+        assert!(parse_rate("M").is_err());
+        assert!(parse_rate("5MB").is_err());
+        assert!(parse_rate("-5M").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "experimental-transfer-v2")]
+    fn test_partial_dir_name() {
+        let offer = |size| -> transfer::offer::Offer {
+            (&transfer::offer::OfferSend::new_file_custom(
+                "example.txt".into(),
+                size,
+                transfer::offer::new_offer_content_stream(futures::io::empty()),
+            ))
+                .into()
+        };
+        /* Resuming relies on this being the same across runs and versions */
+        assert_eq!(
+            partial_dir_name(&offer(42)),
+            "wormhole-partial-37bc16229acd1fe7"
+        );
+        assert_ne!(partial_dir_name(&offer(42)), partial_dir_name(&offer(43)));
+    }
+
     #[test]
     fn verify_cli() {
         WormholeCli::command().debug_assert();
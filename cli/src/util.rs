@@ -11,12 +11,13 @@ pub async fn ask_user(message: impl std::fmt::Display, default_answer: bool) ->
         if default_answer { "n" } else { "N" }
     );
 
-    let mut stdout = Unblock::new(std::io::stdout());
+    /* Prompt on stderr, so that stdout can be piped somewhere else */
+    let mut stderr = Unblock::new(std::io::stderr());
     let mut stdin = BufReader::new(Unblock::new(std::io::stdin()));
 
     loop {
-        stdout.write(message.as_bytes()).await.unwrap();
-        stdout.flush().await.unwrap();
+        stderr.write(message.as_bytes()).await.unwrap();
+        stderr.flush().await.unwrap();
 
         let mut answer = String::new();
         stdin.read_line(&mut answer).await.unwrap();
@@ -26,11 +27,11 @@ pub async fn ask_user(message: impl std::fmt::Display, default_answer: bool) ->
             "n" | "no" => break false,
             "" => break default_answer,
             _ => {
-                stdout
+                stderr
                     .write("Please type y or n!\n".as_bytes())
                     .await
                     .unwrap();
-                stdout.flush().await.unwrap();
+                stderr.flush().await.unwrap();
                 continue;
             },
         };
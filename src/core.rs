@@ -1,8 +1,12 @@
 pub(super) mod key;
+#[cfg(not(target_family = "wasm"))]
+pub mod mailbox_server;
 pub mod rendezvous;
 mod server_messages;
+mod split;
 #[cfg(test)]
 mod test;
+mod verifier;
 
 /// Module for wormhole code generation and completion.
 pub mod wordlist;
@@ -14,6 +18,10 @@ use thiserror::Error;
 use crate::Wordlist;
 
 use self::{rendezvous::*, server_messages::EncryptedMessage};
+pub use self::{
+    split::{WormholeDriver, WormholeReceiver, WormholeSender},
+    verifier::VerifierFormat,
+};
 
 use crypto_secretbox as secretbox;
 
@@ -43,6 +51,17 @@ pub enum WormholeError {
     /// Cannot decrypt a received message
     #[error("Cannot decrypt a received message")]
     Crypto,
+    /// The peer skipped messages. We buffer messages that arrive out of order, but only up to a limit.
+    #[error("Message {received} from peer arrived, but message {expected} is still missing")]
+    PhaseGap {
+        /// The message we are waiting for
+        expected: u64,
+        /// The message that arrived
+        received: u64,
+    },
+    /// The wormhole has been closed, e.g. because the driver of a [split](Wormhole::split) wormhole stopped
+    #[error("The wormhole has been closed")]
+    Closed,
     /// Nameplate is unclaimed
     #[error("Nameplate is unclaimed: {}", _0)]
     UnclaimedNameplate(Nameplate),
@@ -93,6 +112,9 @@ pub struct MailboxConnection<V: serde::Serialize + Send + Sync + 'static> {
     mailbox: Mailbox,
     /// The Code which is required to connect to the mailbox.
     code: Code,
+    /// Whether to announce support for dilation to the peer
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    dilation: bool,
 }
 
 impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
@@ -170,6 +192,8 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
             mailbox,
             code,
             welcome,
+            #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+            dilation: false,
         })
     }
 
@@ -219,6 +243,8 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
             mailbox,
             code,
             welcome,
+            #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+            dilation: false,
         })
     }
 
@@ -260,6 +286,16 @@ impl<V: serde::Serialize + Send + Sync + 'static> MailboxConnection<V> {
     pub fn code(&self) -> &Code {
         &self.code
     }
+
+    /// Tell the peer that we support [dilation](crate::dilation)
+    ///
+    /// This must be set on both sides before connecting in order to call
+    /// [`dilate`](crate::dilation::dilate) on the resulting [`Wormhole`].
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub fn enable_dilation(mut self) -> Self {
+        self.dilation = true;
+        self
+    }
 }
 
 /// A wormhole is an open connection to a peer via the rendezvous server.
@@ -277,6 +313,27 @@ pub struct Wormhole {
     our_version: Box<dyn std::any::Any + Send + Sync>,
     /// The app version of the peer
     peer_version: serde_json::Value,
+    /// The peer's dilation abilities, if both sides can dilate
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    peer_dilation_abilities: Option<crate::transit::Abilities>,
+    /// The phase of the next message from the peer
+    peer_phase: u64,
+    /// Messages from the peer that arrived before their predecessors
+    pending_peer_messages: std::collections::BTreeMap<u64, Vec<u8>>,
+    unknown_phase_handler: Option<UnknownPhaseHandler>,
+}
+
+/* How many messages may arrive ahead of a missing one, before we give up on it */
+const MAX_PENDING_PEER_MESSAGES: u64 = 64;
+
+type UnknownPhaseFn = dyn FnMut(&str, Vec<u8>) + Send;
+
+struct UnknownPhaseHandler(Box<UnknownPhaseFn>);
+
+impl std::fmt::Debug for UnknownPhaseHandler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("UnknownPhaseHandler")
+    }
 }
 
 impl Wormhole {
@@ -298,6 +355,8 @@ impl Wormhole {
             mailbox: _mailbox,
             code,
             welcome: _welcome,
+            #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+            dilation,
         } = mailbox_connection;
 
         /* Send PAKE */
@@ -314,6 +373,10 @@ impl Wormhole {
         /* Send versions message */
         let mut versions = key::VersionsMessage::new();
         versions.set_app_versions(serde_json::to_value(&config.app_version).unwrap());
+        #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+        if dilation {
+            versions.enable_dilation();
+        }
         let (version_phase, version_msg) = key::build_version_msg(server.side(), &key, &versions);
         server.send_peer_message(version_phase, version_msg).await?;
         let peer_version = server.next_peer_message_some().await?;
@@ -326,6 +389,17 @@ impl Wormhole {
                 serde_json::from_slice(&plaintext).map_err(WormholeError::ProtocolJson)
             })?;
 
+        #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+        let peer_dilation_abilities = if dilation
+            && versions
+                .can_dilate
+                .iter()
+                .any(|version| crate::dilation::DILATION_VERSIONS.contains(&version.as_str()))
+        {
+            Some(versions.dilation_abilities.unwrap_or_default())
+        } else {
+            None
+        };
         let peer_version = versions.app_versions;
 
         if server.needs_nameplate_release() {
@@ -343,6 +417,11 @@ impl Wormhole {
             verifier: Box::new(key::derive_verifier(&key)),
             our_version: Box::new(config.app_version),
             peer_version,
+            #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+            peer_dilation_abilities,
+            peer_phase: 0,
+            pending_peer_messages: Default::default(),
+            unknown_phase_handler: None,
         })
     }
 
@@ -350,14 +429,43 @@ impl Wormhole {
     pub async fn send(&mut self, plaintext: Vec<u8>) -> Result<(), WormholeError> {
         let phase_string = Phase::numeric(self.phase);
         self.phase += 1;
-        let data_key = key::derive_phase_key(self.server.side(), self.key.as_ref(), &phase_string);
-        let (_nonce, encrypted) = key::encrypt_data(&data_key, &plaintext);
-        self.server
-            .send_peer_message(phase_string, encrypted)
-            .await?;
+        self.send_with_phase(phase_string, &plaintext).await
+    }
+
+    /** Send an encrypted message to peer, with an explicit phase */
+    pub(crate) async fn send_with_phase(
+        &mut self,
+        phase: Phase,
+        plaintext: &[u8],
+    ) -> Result<(), WormholeError> {
+        let data_key = key::derive_phase_key(self.server.side(), self.key.as_ref(), &phase);
+        let (_nonce, encrypted) = key::encrypt_data(&data_key, plaintext);
+        self.server.send_peer_message(phase, encrypted).await?;
         Ok(())
     }
 
+    /** Receive and decrypt the next message from peer, whatever its phase */
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub(crate) async fn receive_with_phase(&mut self) -> Result<(Phase, Vec<u8>), WormholeError> {
+        let peer_message = self.server.next_peer_message_some().await?;
+        let decrypted_message = peer_message
+            .decrypt(self.key.as_ref())
+            .ok_or(WormholeError::Crypto)?;
+        Ok((peer_message.phase, decrypted_message))
+    }
+
+    /** Our side in the mailbox, which is unique among both peers */
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub(crate) fn side(&self) -> &str {
+        self.server.side()
+    }
+
+    /** The peer's dilation abilities, if both sides support dilation */
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub(crate) fn peer_dilation_abilities(&self) -> Option<&crate::transit::Abilities> {
+        self.peer_dilation_abilities.as_ref()
+    }
+
     /**
      * Serialize and send an encrypted message to peer
      *
@@ -375,28 +483,73 @@ impl Wormhole {
         self.send(serde_json::to_vec(message).unwrap()).await
     }
 
-    /** Receive an encrypted message from peer */
+    /**
+     * Receive an encrypted message from peer
+     *
+     * Messages are returned in the order in which the peer sent them, even if they arrive in a different one.
+     * Messages with a phase that we don't know get passed to the
+     * [unknown phase handler](Self::set_unknown_phase_handler) instead, or ignored.
+     */
     pub async fn receive(&mut self) -> Result<Vec<u8>, WormholeError> {
         loop {
+            if let Some(message) = self.pending_peer_messages.remove(&self.peer_phase) {
+                self.peer_phase += 1;
+                return Ok(message);
+            }
+
             let peer_message = match self.server.next_peer_message().await? {
                 Some(peer_message) => peer_message,
                 None => continue,
             };
-            if peer_message.phase.to_num().is_none() {
-                // TODO: log and ignore, for future expansion
-                todo!("log and ignore, for future expansion");
-            }
-
-            // TODO maybe reorder incoming messages by phase numeral?
             let decrypted_message = peer_message
                 .decrypt(self.key.as_ref())
                 .ok_or(WormholeError::Crypto)?;
 
-            // Send to client
-            return Ok(decrypted_message);
+            match peer_message.phase.to_num() {
+                None => {
+                    tracing::info!(
+                        "Received message with unknown phase '{}'",
+                        peer_message.phase
+                    );
+                    if let Some(UnknownPhaseHandler(handler)) = &mut self.unknown_phase_handler {
+                        handler(peer_message.phase.as_ref(), decrypted_message);
+                    }
+                },
+                Some(phase) if phase < self.peer_phase => {
+                    tracing::warn!("Ignoring message {phase} from peer, we already received it");
+                },
+                Some(phase) if phase - self.peer_phase >= MAX_PENDING_PEER_MESSAGES => {
+                    return Err(WormholeError::PhaseGap {
+                        expected: self.peer_phase,
+                        received: phase,
+                    });
+                },
+                Some(phase) => {
+                    if phase != self.peer_phase {
+                        tracing::debug!(
+                            "Message {phase} from peer arrived before message {}, buffering it",
+                            self.peer_phase
+                        );
+                    }
+                    self.pending_peer_messages.insert(phase, decrypted_message);
+                },
+            }
         }
     }
 
+    /**
+     * Handle messages with a phase that we don't know, which newer versions of the protocol may send
+     *
+     * The handler gets the phase and the decrypted message. Without a handler, these messages get
+     * ignored by [`receive`](Self::receive).
+     */
+    pub fn set_unknown_phase_handler(
+        &mut self,
+        handler: impl FnMut(&str, Vec<u8>) + Send + 'static,
+    ) {
+        self.unknown_phase_handler = Some(UnknownPhaseHandler(Box::new(handler)));
+    }
+
     /**
      * Receive an encrypted message from peer
      *
@@ -418,10 +571,40 @@ impl Wormhole {
         })
     }
 
+    /**
+     * Split the wormhole into halves, to send and receive messages concurrently
+     *
+     * This is useful for protocols where both sides may talk at any time. The halves only exchange
+     * messages while the [`WormholeDriver`] is [running](WormholeDriver::run):
+     *
+     * ```no_run
+     * # fn main() -> eyre::Result<()> { async_io::block_on(async {
+     * # let wormhole: magic_wormhole::Wormhole = unimplemented!();
+     * let (mut sender, mut receiver, driver) = wormhole.split();
+     * let app = async move {
+     *     sender.send(b"Hello".to_vec()).await?;
+     *     let reply = receiver.receive().await;
+     *     /* Dropping both halves stops the driver */
+     *     drop((sender, receiver));
+     *     eyre::Result::<_>::Ok(reply)
+     * };
+     * let (reply, ()) = futures::try_join!(app, async { Ok(driver.run().await?) })?;
+     * # Ok(()) })}
+     * ```
+     */
+    pub fn split(self) -> (WormholeSender, WormholeReceiver, WormholeDriver) {
+        split::split(self)
+    }
+
     /// Close the wormhole
     pub async fn close(self) -> Result<(), WormholeError> {
+        self.shutdown(Mood::Happy).await
+    }
+
+    /// Close the wormhole with the given mood, e.g. [`Mood::Errory`] when giving up after an error
+    pub async fn shutdown(self, mood: Mood) -> Result<(), WormholeError> {
         tracing::debug!("Closing Wormhole…");
-        self.server.shutdown(Mood::Happy).await.map_err(Into::into)
+        self.server.shutdown(mood).await.map_err(Into::into)
     }
 
     /**
@@ -456,6 +639,15 @@ impl Wormhole {
         &self.verifier
     }
 
+    /**
+     * The [`verifier`](Self::verifier) in a form that users can compare, e.g. by reading it to each other.
+     *
+     * Both sides need to use the same format. Use [`VerifierFormat::Hex`] to compare with the Python implementation.
+     */
+    pub fn format_verifier(&self, format: VerifierFormat) -> String {
+        verifier::format_verifier(&self.verifier, format)
+    }
+
     /**
      * Our "app version" information that we sent. See the [`peer_version`](Self::peer_version()) for more information.
      */
@@ -637,6 +829,16 @@ impl Phase {
         Phase(phase.to_string().into())
     }
 
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub fn dilation(phase: u64) -> Self {
+        Phase(format!("dilate-{phase}").into())
+    }
+
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub fn to_dilation_num(&self) -> Option<u64> {
+        self.0.strip_prefix("dilate-")?.parse().ok()
+    }
+
     #[allow(dead_code)]
     pub fn is_version(&self) -> bool {
         self == &Self::VERSION
@@ -116,6 +116,18 @@ pub struct VersionsMessage {
     pub abilities: Vec<String>,
     #[serde(default)]
     pub app_versions: serde_json::Value,
+    /// The dilation protocol versions we support, see [`crate::dilation`]
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    #[serde(default, rename = "can-dilate", skip_serializing_if = "Vec::is_empty")]
+    pub can_dilate: Vec<String>,
+    /// How the peer may connect to us once dilated
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    #[serde(
+        default,
+        rename = "dilation-abilities",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dilation_abilities: Option<crate::transit::Abilities>,
     // resume: Option<WormholeResume>,
 }
 
@@ -128,6 +140,15 @@ impl VersionsMessage {
         self.app_versions = versions;
     }
 
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub fn enable_dilation(&mut self) {
+        self.can_dilate = crate::dilation::DILATION_VERSIONS
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        self.dilation_abilities = Some(crate::transit::Abilities::ALL);
+    }
+
     // pub fn add_resume_ability(&mut self, _resume: ()) {
     //     self.abilities.push("resume-v1".into())
     // }
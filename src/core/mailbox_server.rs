@@ -0,0 +1,649 @@
+//! An embeddable mailbox server
+//!
+//! This is the server side of the [`rendezvous`](crate::rendezvous) protocol. It speaks the same JSON
+//! protocol as the public mailbox server, but keeps all its state in memory. It is mostly useful for
+//! tests and for small self-hosted deployments.
+//!
+//! Clients find the server via [`AppConfig::rendezvous_url`](crate::AppConfig::rendezvous_url):
+//!
+//! ```no_run
+//! # fn main() -> eyre::Result<()> { async_io::block_on(async {
+//! use magic_wormhole::{MailboxConnection, mailbox_server::MailboxServer, transfer::APP_CONFIG};
+//!
+//! let server = MailboxServer::bind("127.0.0.1:0").await?;
+//! let config = APP_CONFIG.rendezvous_url(server.url().into());
+//! std::thread::spawn(|| async_io::block_on(server.run()));
+//!
+//! let mailbox_connection = MailboxConnection::create(config, 2).await?;
+//! # Ok(()) })}
+//! ```
+
+use super::{
+    EncryptedMessage, Mailbox, Mood, Nameplate, TheirSide,
+    server_messages::{InboundMessage, OutboundMessage, WelcomeMessage},
+};
+use async_tungstenite::tungstenite as ws2;
+use futures::{channel::mpsc, prelude::*};
+use futures_concurrency::prelude::*;
+use rand::{RngCore, seq::SliceRandom};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long nameplates and mailboxes nobody listens on are kept by default
+const DEFAULT_EXPIRY: Duration = Duration::from_secs(2 * 60 * 60);
+/// How often to look for expired nameplates and mailboxes, at most
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// An error the server reports back to a client
+///
+/// The `Display` text is sent verbatim as `error` field and matches the strings used by the Python server.
+#[derive(Debug, thiserror::Error)]
+enum ClientError {
+    #[error("invalid message: {}", _0)]
+    Invalid(#[from] serde_json::Error),
+    #[error("already bound")]
+    AlreadyBound,
+    #[error("must bind first")]
+    MustBindFirst,
+    #[error("you already allocated one, don't be greedy")]
+    AlreadyAllocated,
+    #[error("nameplate must be digits")]
+    InvalidNameplate,
+    #[error("only one claim per connection")]
+    OnlyOneClaim,
+    #[error("reclaimed")]
+    Reclaimed,
+    #[error("crowded")]
+    Crowded,
+    #[error("must claim a nameplate before releasing it")]
+    ReleaseBeforeClaim,
+    #[error("release and claim must use same nameplate")]
+    ReleaseMismatch,
+    #[error("only one release per connection")]
+    OnlyOneRelease,
+    #[error("only one open per connection")]
+    OnlyOneOpen,
+    #[error("must open mailbox before adding")]
+    AddBeforeOpen,
+    #[error("open and close must use same mailbox")]
+    CloseMismatch,
+    #[error("only one close per connection")]
+    OnlyOneClose,
+}
+
+/**
+ * A mailbox server
+ *
+ * Create one with [`MailboxServer::bind`], point your clients at [`MailboxServer::url`] and then
+ * drive [`MailboxServer::run`] on an executor of your choice.
+ */
+#[derive(Debug)]
+pub struct MailboxServer {
+    listener: async_net::TcpListener,
+    motd: Option<String>,
+    expiry: Duration,
+}
+
+impl MailboxServer {
+    /**
+     * Listen for clients on the given address
+     *
+     * Use port `0` to let the operating system pick a free port, e.g. `"127.0.0.1:0"`.
+     */
+    pub async fn bind(addr: impl async_net::AsyncToSocketAddrs) -> std::io::Result<Self> {
+        Ok(Self {
+            listener: async_net::TcpListener::bind(addr).await?,
+            motd: None,
+            expiry: DEFAULT_EXPIRY,
+        })
+    }
+
+    /// Set a message of the day that will be sent to all clients in the `welcome` message
+    pub fn motd(mut self, motd: impl Into<String>) -> Self {
+        self.motd = Some(motd.into());
+        self
+    }
+
+    /**
+     * Remove nameplates and mailboxes after nobody listened on them for this long
+     *
+     * This cleans up after clients that went away without releasing and closing everything,
+     * and after clients that never got a peer. The default is two hours.
+     */
+    pub fn expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = expiry;
+        self
+    }
+
+    /// The address the server is listening on
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /**
+     * The URL clients should use to connect to this server
+     *
+     * ## Panics
+     *
+     * If the listening socket has no local address
+     */
+    pub fn url(&self) -> String {
+        format!("ws://{}/v1", self.local_addr().unwrap())
+    }
+
+    /**
+     * Serve clients
+     *
+     * This never returns. Connections that can't be accepted are logged and skipped.
+     * All state lives inside of this future, dropping it will disconnect all clients.
+     */
+    pub async fn run(self) -> std::io::Result<()> {
+        let state = Arc::new(Mutex::new(ServerState::default()));
+        let executor = async_executor::Executor::new();
+
+        let accept = async {
+            loop {
+                match self.listener.accept().await {
+                    Ok((stream, peer)) => {
+                        tracing::debug!("Accepted mailbox connection from {}", peer);
+                        executor
+                            .spawn(handle_connection(state.clone(), self.motd.clone(), stream))
+                            .detach();
+                    },
+                    Err(error) => {
+                        tracing::warn!("Failed to accept a mailbox connection: {}", error);
+                        /* Don't spin if the error persists, e.g. when running out of file descriptors */
+                        crate::util::sleep(Duration::from_millis(100)).await;
+                    },
+                }
+            }
+        };
+        let prune = async {
+            loop {
+                crate::util::sleep(PRUNE_INTERVAL.min(self.expiry)).await;
+                state.lock().unwrap().prune(Instant::now(), self.expiry);
+            }
+        };
+
+        executor.run((accept, prune).race()).await
+    }
+}
+
+async fn handle_connection(
+    state: Arc<Mutex<ServerState>>,
+    motd: Option<String>,
+    stream: async_net::TcpStream,
+) {
+    let connection = match async_tungstenite::accept_async(stream).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            tracing::debug!("WebSocket handshake failed: {}", error);
+            return;
+        },
+    };
+    let (mut sink, mut stream) = connection.split();
+    let (tx, mut rx) = mpsc::unbounded();
+
+    let mut client = Client::new(state, tx);
+    client.send(InboundMessage::Welcome {
+        welcome: WelcomeMessage {
+            motd,
+            ..Default::default()
+        },
+    });
+
+    let reader = async move {
+        while let Some(message) = stream.next().await {
+            match message {
+                Ok(ws2::Message::Text(message)) => client.receive(message.as_str()),
+                Ok(ws2::Message::Binary(_)) => {
+                    tracing::debug!("Got a binary message from a client, closing connection");
+                    break;
+                },
+                Ok(ws2::Message::Close(_)) => break,
+                Ok(_) => (),
+                Err(error) => {
+                    tracing::debug!("Mailbox connection failed: {}", error);
+                    break;
+                },
+            }
+        }
+        client.disconnect();
+    };
+
+    let writer = async move {
+        while let Some(message) = rx.next().await {
+            tracing::debug!("Sending {}", message);
+            let message = ws2::Message::text(serde_json::to_string(&message).unwrap());
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+        sink.close(None).await.ok();
+    };
+
+    (reader, writer).join().await;
+}
+
+#[derive(Default)]
+struct ServerState {
+    apps: HashMap<String, AppState>,
+    next_client_id: u64,
+}
+
+impl ServerState {
+    /// Remove everything nobody listened on for longer than `expiry`
+    fn prune(&mut self, now: Instant, expiry: Duration) {
+        for app in self.apps.values_mut() {
+            app.prune(now, expiry);
+        }
+        self.apps
+            .retain(|_, app| !app.nameplates.is_empty() || !app.mailboxes.is_empty());
+    }
+}
+
+#[derive(Default)]
+struct AppState {
+    nameplates: HashMap<String, NameplateState>,
+    mailboxes: HashMap<String, MailboxState>,
+}
+
+struct NameplateState {
+    mailbox: String,
+    /// Sides which have claimed this nameplate, and whether they haven't released it yet
+    sides: HashMap<String, bool>,
+}
+
+struct MailboxState {
+    /// Sides which have opened this mailbox, and whether they haven't closed it yet
+    sides: HashMap<String, bool>,
+    messages: Vec<EncryptedMessage>,
+    listeners: HashMap<u64, mpsc::UnboundedSender<InboundMessage>>,
+    /// The last time a side opened the mailbox or added a message to it
+    updated: Instant,
+}
+
+impl Default for MailboxState {
+    fn default() -> Self {
+        Self {
+            sides: HashMap::new(),
+            messages: Vec::new(),
+            listeners: HashMap::new(),
+            updated: Instant::now(),
+        }
+    }
+}
+
+impl AppState {
+    /* Mailboxes that have a listener are still in use. A nameplate goes away together with its mailbox */
+    fn prune(&mut self, now: Instant, expiry: Duration) {
+        self.mailboxes.retain(|mailbox, state| {
+            let keep = !state.listeners.is_empty()
+                || now.saturating_duration_since(state.updated) < expiry;
+            if !keep {
+                tracing::debug!("Mailbox {} expired", mailbox);
+            }
+            keep
+        });
+        let mailboxes = &self.mailboxes;
+        self.nameplates.retain(|nameplate, state| {
+            let keep = mailboxes.contains_key(&state.mailbox);
+            if !keep {
+                tracing::debug!("Nameplate {} expired", nameplate);
+            }
+            keep
+        });
+    }
+
+    fn find_available_nameplate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        (1..)
+            .find_map(|digits: u32| {
+                let available: Vec<u64> = (10u64.pow(digits - 1)..10u64.pow(digits))
+                    .filter(|id| !self.nameplates.contains_key(&id.to_string()))
+                    .collect();
+                available.choose(&mut rng).copied()
+            })
+            .unwrap()
+            .to_string()
+    }
+
+    fn allocate_nameplate(&mut self, side: &str) -> Result<String, ClientError> {
+        let nameplate = self.find_available_nameplate();
+        self.claim_nameplate(&nameplate, side)?;
+        Ok(nameplate)
+    }
+
+    fn claim_nameplate(&mut self, nameplate: &str, side: &str) -> Result<String, ClientError> {
+        let nameplate = self
+            .nameplates
+            .entry(nameplate.to_owned())
+            .or_insert_with(|| NameplateState {
+                mailbox: generate_mailbox_id(),
+                sides: HashMap::new(),
+            });
+        match nameplate.sides.get(side) {
+            Some(true) => (),
+            Some(false) => return Err(ClientError::Reclaimed),
+            None if nameplate.sides.len() >= 2 => return Err(ClientError::Crowded),
+            None => (),
+        }
+        open_mailbox(&mut self.mailboxes, &nameplate.mailbox, side)?;
+        nameplate.sides.insert(side.to_owned(), true);
+        Ok(nameplate.mailbox.clone())
+    }
+
+    fn release_nameplate(&mut self, nameplate: &str, side: &str) {
+        let Some(state) = self.nameplates.get_mut(nameplate) else {
+            return;
+        };
+        if let Some(claimed) = state.sides.get_mut(side) {
+            *claimed = false;
+        }
+        if !state.sides.values().any(|claimed| *claimed) {
+            tracing::debug!("Removing nameplate {}", nameplate);
+            self.nameplates.remove(nameplate);
+        }
+    }
+
+    fn close_mailbox(&mut self, mailbox: &str, side: &str, mood: Mood) {
+        let Some(state) = self.mailboxes.get_mut(mailbox) else {
+            return;
+        };
+        tracing::debug!("Side {} closed mailbox {} ({})", side, mailbox, mood);
+        if let Some(opened) = state.sides.get_mut(side) {
+            *opened = false;
+        }
+        if !state.sides.values().any(|opened| *opened) {
+            tracing::debug!("Removing mailbox {}", mailbox);
+            self.mailboxes.remove(mailbox);
+        }
+    }
+}
+
+fn open_mailbox<'a>(
+    mailboxes: &'a mut HashMap<String, MailboxState>,
+    mailbox: &str,
+    side: &str,
+) -> Result<&'a mut MailboxState, ClientError> {
+    let state = mailboxes.entry(mailbox.to_owned()).or_default();
+    if !state.sides.contains_key(side) && state.sides.len() >= 2 {
+        return Err(ClientError::Crowded);
+    }
+    state.sides.insert(side.to_owned(), true);
+    state.updated = Instant::now();
+    Ok(state)
+}
+
+fn generate_mailbox_id() -> String {
+    let mut bytes = [0; 8];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+struct Binding {
+    appid: String,
+    side: String,
+}
+
+/// The state of a single client connection
+struct Client {
+    state: Arc<Mutex<ServerState>>,
+    id: u64,
+    tx: mpsc::UnboundedSender<InboundMessage>,
+    binding: Option<Binding>,
+    did_allocate: bool,
+    nameplate: Option<String>,
+    did_release: bool,
+    mailbox: Option<String>,
+    did_close: bool,
+}
+
+impl Client {
+    fn new(state: Arc<Mutex<ServerState>>, tx: mpsc::UnboundedSender<InboundMessage>) -> Self {
+        let id = {
+            let mut state = state.lock().unwrap();
+            state.next_client_id += 1;
+            state.next_client_id
+        };
+        Self {
+            state,
+            id,
+            tx,
+            binding: None,
+            did_allocate: false,
+            nameplate: None,
+            did_release: false,
+            mailbox: None,
+            did_close: false,
+        }
+    }
+
+    fn send(&self, message: InboundMessage) {
+        /* The receiving end only goes away together with the connection */
+        self.tx.unbounded_send(message).ok();
+    }
+
+    fn receive(&mut self, message_plain: &str) {
+        let message: serde_json::Value = match serde_json::from_str(message_plain) {
+            Ok(message) => message,
+            Err(error) => {
+                self.send(InboundMessage::Error {
+                    error: ClientError::from(error).to_string(),
+                    orig: Box::new(message_plain.into()),
+                });
+                return;
+            },
+        };
+        self.send(InboundMessage::Ack);
+
+        let result = serde_json::from_value(message.clone())
+            .map_err(ClientError::from)
+            .and_then(|message: OutboundMessage| {
+                tracing::debug!("Received {}", message);
+                self.handle(message)
+            });
+        if let Err(error) = result {
+            tracing::debug!("Client error: {}", error);
+            self.send(InboundMessage::Error {
+                error: error.to_string(),
+                orig: Box::new(message),
+            });
+        }
+    }
+
+    /// Run some operation on the app the client is bound to
+    fn with_app<T>(
+        &self,
+        f: impl FnOnce(&mut AppState, &str) -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let binding = self.binding.as_ref().ok_or(ClientError::MustBindFirst)?;
+        let mut state = self.state.lock().unwrap();
+        let app = state.apps.entry(binding.appid.clone()).or_default();
+        f(app, &binding.side)
+    }
+
+    fn handle(&mut self, message: OutboundMessage) -> Result<(), ClientError> {
+        match message {
+            /* We never ask for permissions, so there is nothing to check */
+            OutboundMessage::SubmitPermission(_) => (),
+            OutboundMessage::Bind { appid, side } => {
+                if self.binding.is_some() {
+                    return Err(ClientError::AlreadyBound);
+                }
+                self.binding = Some(Binding {
+                    appid: appid.to_string(),
+                    side: side.0.0,
+                });
+            },
+            OutboundMessage::List => {
+                let nameplates = self.with_app(|app, _side| {
+                    Ok(app
+                        .nameplates
+                        .keys()
+                        .map(|nameplate| Nameplate(nameplate.clone()))
+                        .collect())
+                })?;
+                self.send(InboundMessage::Nameplates { nameplates });
+            },
+            OutboundMessage::Allocate => {
+                if self.did_allocate {
+                    return Err(ClientError::AlreadyAllocated);
+                }
+                let nameplate = self.with_app(|app, side| app.allocate_nameplate(side))?;
+                self.did_allocate = true;
+                self.send(InboundMessage::Allocated {
+                    nameplate: Nameplate(nameplate),
+                });
+            },
+            OutboundMessage::Claim { nameplate } => {
+                if nameplate.parse::<Nameplate>().is_err() {
+                    return Err(ClientError::InvalidNameplate);
+                }
+                if self.nameplate.is_some() {
+                    return Err(ClientError::OnlyOneClaim);
+                }
+                let mailbox = self.with_app(|app, side| app.claim_nameplate(&nameplate, side))?;
+                self.nameplate = Some(nameplate);
+                self.send(InboundMessage::Claimed {
+                    mailbox: Mailbox(mailbox),
+                });
+            },
+            OutboundMessage::Release { nameplate } => {
+                if self.did_release {
+                    return Err(ClientError::OnlyOneRelease);
+                }
+                match &self.nameplate {
+                    None => return Err(ClientError::ReleaseBeforeClaim),
+                    Some(claimed) if *claimed != nameplate => {
+                        return Err(ClientError::ReleaseMismatch);
+                    },
+                    Some(_) => (),
+                }
+                self.with_app(|app, side| {
+                    app.release_nameplate(&nameplate, side);
+                    Ok(())
+                })?;
+                self.did_release = true;
+                self.send(InboundMessage::Released);
+            },
+            OutboundMessage::Open { mailbox } => {
+                if self.mailbox.is_some() {
+                    return Err(ClientError::OnlyOneOpen);
+                }
+                let (id, tx) = (self.id, self.tx.clone());
+                self.with_app(|app, side| {
+                    let state = open_mailbox(&mut app.mailboxes, &mailbox.0, side)?;
+                    /* Catch up on everything that has been sent before we joined */
+                    for message in &state.messages {
+                        tx.unbounded_send(InboundMessage::Message(message.clone()))
+                            .ok();
+                    }
+                    state.listeners.insert(id, tx);
+                    Ok(())
+                })?;
+                self.mailbox = Some(mailbox.0);
+            },
+            OutboundMessage::Add { phase, body } => {
+                let mailbox = self.mailbox.as_ref().ok_or(ClientError::AddBeforeOpen)?;
+                self.with_app(|app, side| {
+                    let Some(state) = app.mailboxes.get_mut(mailbox) else {
+                        return Ok(());
+                    };
+                    let message = EncryptedMessage {
+                        side: TheirSide::from(side),
+                        phase,
+                        body,
+                    };
+                    for listener in state.listeners.values() {
+                        listener
+                            .unbounded_send(InboundMessage::Message(message.clone()))
+                            .ok();
+                    }
+                    state.messages.push(message);
+                    state.updated = Instant::now();
+                    Ok(())
+                })?;
+            },
+            OutboundMessage::Close { mailbox, mood } => {
+                if self.did_close {
+                    return Err(ClientError::OnlyOneClose);
+                }
+                if self
+                    .mailbox
+                    .as_ref()
+                    .is_some_and(|opened| *opened != mailbox.0)
+                {
+                    return Err(ClientError::CloseMismatch);
+                }
+                let id = self.id;
+                self.with_app(|app, side| {
+                    if let Some(state) = app.mailboxes.get_mut(&mailbox.0) {
+                        state.listeners.remove(&id);
+                    }
+                    app.close_mailbox(&mailbox.0, side, mood);
+                    Ok(())
+                })?;
+                self.did_close = true;
+                self.send(InboundMessage::Closed);
+            },
+            OutboundMessage::Ping { ping } => self.send(InboundMessage::Pong { pong: ping }),
+        }
+        Ok(())
+    }
+
+    /// Stop listening on the mailbox after the connection went away
+    fn disconnect(self) {
+        if let (Some(mailbox), Some(binding)) = (&self.mailbox, &self.binding) {
+            let mut state = self.state.lock().unwrap();
+            if let Some(mailbox) = state
+                .apps
+                .get_mut(&binding.appid)
+                .and_then(|app| app.mailboxes.get_mut(mailbox))
+            {
+                mailbox.listeners.remove(&self.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let expiry = Duration::from_secs(60);
+        let mut state = ServerState::default();
+        let app = state.apps.entry("appid".into()).or_default();
+        let abandoned = app.allocate_nameplate("side1").unwrap();
+        let waiting = app.allocate_nameplate("side2").unwrap();
+        let (tx, _rx) = mpsc::unbounded();
+        let mailbox = app.nameplates[&waiting].mailbox.clone();
+        app.mailboxes
+            .get_mut(&mailbox)
+            .unwrap()
+            .listeners
+            .insert(1, tx);
+
+        /* Nothing expires early */
+        state.prune(Instant::now(), expiry);
+        assert_eq!(state.apps["appid"].nameplates.len(), 2);
+
+        /* Only the nameplate and mailbox somebody is still waiting on are kept */
+        state.prune(Instant::now() + expiry, expiry);
+        let app = &state.apps["appid"];
+        assert!(!app.nameplates.contains_key(&abandoned));
+        assert!(app.nameplates.contains_key(&waiting));
+        assert_eq!(app.mailboxes.len(), 1);
+
+        /* Apps without anything left go away as well */
+        state.apps.get_mut("appid").unwrap().mailboxes.clear();
+        state.prune(Instant::now(), expiry);
+        assert!(state.apps.is_empty());
+    }
+}
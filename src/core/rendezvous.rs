@@ -17,6 +17,21 @@ use crate::core::{
 /// Two applications that want to communicate with each other *must* use the same rendezvous server.
 pub const DEFAULT_RENDEZVOUS_SERVER: &str = "ws://relay.magic-wormhole.io:4000/v1";
 
+/// How long to wait before each attempt to reconnect to the rendezvous server
+const RECONNECT_BACKOFF: [std::time::Duration; 8] = {
+    use std::time::Duration;
+    [
+        Duration::from_millis(500),
+        Duration::from_secs(1),
+        Duration::from_secs(2),
+        Duration::from_secs(4),
+        Duration::from_secs(8),
+        Duration::from_secs(16),
+        Duration::from_secs(30),
+        Duration::from_secs(30),
+    ]
+};
+
 /// An error occurred when connecting to the rendezvous server
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
@@ -65,6 +80,11 @@ impl RendezvousError {
     pub(self) fn server(error: impl Into<Box<str>>) -> Self {
         Self::Server(error.into())
     }
+
+    /// Did we lose the connection to the server? We can recover from this by reconnecting.
+    pub(self) fn is_connection_lost(&self) -> bool {
+        matches!(self, Self::IO(_))
+    }
 }
 
 #[cfg(not(target_family = "wasm"))]
@@ -213,7 +233,7 @@ impl WsConnection {
             .connection
             .next()
             .await
-            .expect("TODO this should always be Some")?;
+            .unwrap_or(Err(ws2::Error::ConnectionClosed))?;
         match message {
             ws2::Message::Text(message_plain) => {
                 let message = serde_json::from_str(&message_plain)?;
@@ -303,9 +323,21 @@ struct MailboxMachine {
     mailbox: Mailbox,
     queue: MessageQueue,
     processed: std::collections::HashSet<Phase>,
+    /// Messages we sent, but the server did not echo back to us yet. They are sent again after reconnecting.
+    pending_outbound: Vec<(Phase, Vec<u8>)>,
 }
 
 impl MailboxMachine {
+    fn new(nameplate: Option<Nameplate>, mailbox: Mailbox) -> Self {
+        Self {
+            nameplate,
+            mailbox,
+            queue: Default::default(),
+            processed: Default::default(),
+            pending_outbound: Default::default(),
+        }
+    }
+
     fn receive_message(&mut self, message: &EncryptedMessage, side: &MySide) -> bool {
         if *message.side != **side {
             // Got a message from them. Check if duplicate
@@ -316,17 +348,28 @@ impl MailboxMachine {
                 false
             }
         } else {
-            // Echo of ours. Ignore
+            // Echo of ours. The server has it now, so we won't need to send it again
+            self.pending_outbound
+                .retain(|(phase, _body)| *phase != message.phase);
             false
         }
     }
 }
 
-/// The rendezvous server is a central server used for connection establishment
+/**
+ * The rendezvous server is a central server used for connection establishment
+ *
+ * Once a mailbox is open, a lost connection to the server is transparently re-established: we bind again
+ * with the same side, re-open the mailbox and send all messages again that did not make it to the server.
+ */
 pub(crate) struct RendezvousServer {
     connection: Box<WsConnection>,
     state: Option<MailboxMachine>,
     side: MySide,
+    appid: AppID,
+    relay_url: String,
+    /* Set while reconnecting, so that it can be resumed if the future doing it gets dropped */
+    reconnecting: bool,
 }
 
 impl std::fmt::Debug for RendezvousServer {
@@ -350,6 +393,29 @@ impl RendezvousServer {
         relay_url: &str,
     ) -> Result<(Self, Option<String>), RendezvousError> {
         let side = MySide::generate();
+        let (connection, motd) = Self::connect_bind(appid, relay_url, &side).await?;
+
+        tracing::info!("Connected to rendezvous server.");
+
+        Ok((
+            Self {
+                connection: Box::new(connection),
+                state: None,
+                side,
+                appid: appid.clone(),
+                relay_url: relay_url.to_owned(),
+                reconnecting: false,
+            },
+            motd,
+        ))
+    }
+
+    /** Open the WebSocket connection, do the permission negotiation and bind to our `appid` and `side` */
+    async fn connect_bind(
+        appid: &AppID,
+        relay_url: &str,
+        side: &MySide,
+    ) -> Result<(WsConnection, Option<String>), RendezvousError> {
         let mut connection;
 
         #[cfg(not(target_arch = "wasm32"))]
@@ -406,16 +472,100 @@ impl RendezvousServer {
             .send_message(&OutboundMessage::bind(appid.clone(), side.clone()), None)
             .await?;
 
-        tracing::info!("Connected to rendezvous server.");
+        Ok((connection, welcome.motd))
+    }
 
-        Ok((
-            Self {
-                connection: Box::new(connection),
-                state: None,
-                side,
-            },
-            welcome.motd,
-        ))
+    /**
+     * Re-establish a lost connection to the server
+     *
+     * Only call this once the mailbox is open. Retries with an increasing delay, and gives up with the
+     * last error if the server stays unreachable.
+     */
+    async fn reconnect(&mut self, mut error: RendezvousError) -> Result<(), RendezvousError> {
+        self.reconnecting = true;
+        for delay in RECONNECT_BACKOFF {
+            tracing::warn!(
+                "Lost connection to the rendezvous server: {}. Reconnecting in {:?}",
+                error,
+                delay
+            );
+            crate::util::sleep(delay).await;
+            match self.reopen().await {
+                Ok(()) => {
+                    tracing::info!("Reconnected to rendezvous server.");
+                    return Ok(());
+                },
+                Err(new_error) if new_error.is_connection_lost() => error = new_error,
+                Err(new_error) => return Err(new_error),
+            }
+        }
+        Err(error)
+    }
+
+    /** Connect and get back into the state we were in before losing the connection */
+    async fn reopen(&mut self) -> Result<(), RendezvousError> {
+        let (connection, _motd) =
+            Self::connect_bind(&self.appid, &self.relay_url, &self.side).await?;
+        *self.connection = connection;
+
+        let state = self
+            .state
+            .as_mut()
+            .expect("Can only reconnect when having a claimed+open mailbox");
+
+        if let Some(nameplate) = &state.nameplate {
+            self.connection
+                .send_message(
+                    &OutboundMessage::claim(nameplate.to_string()),
+                    Some(&mut state.queue),
+                )
+                .await?;
+            match self
+                .connection
+                .receive_reply(Some(&mut state.queue))
+                .await?
+            {
+                RendezvousReply::Claimed(mailbox) if mailbox == state.mailbox => (),
+                other => return Err(RendezvousError::invalid_message("claimed", other)),
+            }
+        }
+
+        self.connection
+            .send_message(
+                &OutboundMessage::open(state.mailbox.clone()),
+                Some(&mut state.queue),
+            )
+            .await?;
+
+        /* Duplicates will be discarded by our peer, so it's fine to send more than needed */
+        for (phase, body) in state.pending_outbound.clone() {
+            self.connection
+                .send_message(
+                    &OutboundMessage::Add { phase, body },
+                    Some(&mut state.queue),
+                )
+                .await?;
+        }
+
+        self.reconnecting = false;
+        Ok(())
+    }
+
+    /**
+     * Finish a reconnection that was interrupted
+     *
+     * Reconnecting happens while sending or receiving, and those may be cancelled at any time.
+     * The connection might then be bound, but the mailbox not open yet, so we need to start over.
+     */
+    async fn resume_reconnect(&mut self) -> Result<(), RendezvousError> {
+        if !self.reconnecting {
+            return Ok(());
+        }
+        tracing::debug!("Resuming the interrupted reconnection to the rendezvous server");
+        match self.reopen().await {
+            Err(error) if error.is_connection_lost() => self.reconnect(error).await,
+            result => result,
+        }
     }
 
     /** A random unique string for this session */
@@ -440,8 +590,20 @@ impl RendezvousServer {
         phase: Phase,
         body: Vec<u8>,
     ) -> Result<(), RendezvousError> {
-        self.send_message(&OutboundMessage::Add { body, phase })
+        self.resume_reconnect().await?;
+        let state = self
+            .state
+            .as_mut()
+            .expect("Can only send messages when having a claimed+open mailbox");
+        state.pending_outbound.push((phase.clone(), body.clone()));
+        match self
+            .send_message(&OutboundMessage::Add { body, phase })
             .await
+        {
+            /* Reconnecting sends all pending messages again */
+            Err(error) if error.is_connection_lost() => self.reconnect(error).await,
+            result => result,
+        }
     }
 
     pub(crate) async fn next_peer_message_some(
@@ -457,6 +619,7 @@ impl RendezvousServer {
     pub(crate) async fn next_peer_message(
         &mut self,
     ) -> Result<Option<EncryptedMessage>, RendezvousError> {
+        self.resume_reconnect().await?;
         let machine = &mut self
             .state
             .as_mut()
@@ -468,18 +631,23 @@ impl RendezvousServer {
                 return Ok(None);
             }
         }
-        match self.connection.receive_message().await? {
-            Some(InboundMessage::Message(message)) => {
+        match self.connection.receive_message().await {
+            Ok(Some(InboundMessage::Message(message))) => {
                 if machine.receive_message(&message, &self.side) {
                     Ok(Some(message))
                 } else {
                     Ok(None)
                 }
             },
-            Some(other) => Err(RendezvousError::protocol(format!(
+            Ok(Some(other)) => Err(RendezvousError::protocol(format!(
                 "Expected message from peer, got '{other}' instead"
             ))),
-            None => Ok(None),
+            Ok(None) => Ok(None),
+            Err(error) if error.is_connection_lost() => {
+                self.reconnect(error).await?;
+                Ok(None)
+            },
+            Err(error) => Err(error),
         }
     }
 
@@ -506,12 +674,10 @@ impl RendezvousServer {
         self.send_message(&OutboundMessage::open(mailbox.clone()))
             .await?;
 
-        self.state = Some(MailboxMachine {
-            nameplate: Some(nameplate.clone()),
-            mailbox: mailbox.clone(),
-            queue: Default::default(),
-            processed: Default::default(),
-        });
+        self.state = Some(MailboxMachine::new(
+            Some(nameplate.clone()),
+            mailbox.clone(),
+        ));
         Ok((nameplate, mailbox))
     }
 
@@ -532,12 +698,10 @@ impl RendezvousServer {
         self.send_message(&OutboundMessage::open(mailbox.clone()))
             .await?;
 
-        self.state = Some(MailboxMachine {
-            nameplate: Some(nameplate.clone()),
-            mailbox: mailbox.clone(),
-            queue: Default::default(),
-            processed: Default::default(),
-        });
+        self.state = Some(MailboxMachine::new(
+            Some(nameplate.clone()),
+            mailbox.clone(),
+        ));
         Ok(mailbox)
     }
 
@@ -562,6 +726,16 @@ impl RendezvousServer {
     }
 
     pub async fn release_nameplate(&mut self) -> Result<(), RendezvousError> {
+        self.resume_reconnect().await?;
+        loop {
+            match self.release_nameplate_inner().await {
+                Err(error) if error.is_connection_lost() => self.reconnect(error).await?,
+                result => return result,
+            }
+        }
+    }
+
+    async fn release_nameplate_inner(&mut self) -> Result<(), RendezvousError> {
         let nameplate = &mut self
             .state
             .as_mut()
@@ -591,46 +765,55 @@ impl RendezvousServer {
         );
         self.send_message(&OutboundMessage::open(mailbox.clone()))
             .await?;
-        self.state = Some(MailboxMachine {
-            nameplate: None,
-            mailbox,
-            queue: Default::default(),
-            processed: Default::default(),
-        });
+        self.state = Some(MailboxMachine::new(None, mailbox));
         Ok(())
     }
 
     pub async fn shutdown(mut self, mood: Mood) -> Result<(), RendezvousError> {
-        if let Some(MailboxMachine {
+        if self.state.is_some() {
+            self.resume_reconnect().await?;
+            loop {
+                match self.close_mailbox(mood).await {
+                    Err(error) if error.is_connection_lost() => self.reconnect(error).await?,
+                    result => break result?,
+                }
+            }
+        }
+
+        self.connection.close().await?;
+        Ok(())
+    }
+
+    /** Release the nameplate if necessary, and close the mailbox */
+    async fn close_mailbox(&mut self, mood: Mood) -> Result<(), RendezvousError> {
+        let MailboxMachine {
             nameplate,
             mailbox,
-            mut queue,
+            queue,
             ..
-        }) = self.state
-        {
-            if let Some(nameplate) = nameplate {
-                self.connection
-                    .send_message(
-                        &OutboundMessage::release(nameplate.to_string()),
-                        Some(&mut queue),
-                    )
-                    .await?;
-                match self.connection.receive_reply(Some(&mut queue)).await? {
-                    RendezvousReply::Released => (),
-                    other => return Err(RendezvousError::invalid_message("released", other)),
-                };
-            }
+        } = self
+            .state
+            .as_mut()
+            .expect("Can only close when having a claimed+open mailbox");
 
+        if let Some(name) = nameplate {
             self.connection
-                .send_message(&OutboundMessage::close(mailbox, mood), Some(&mut queue))
+                .send_message(&OutboundMessage::release(name.to_string()), Some(queue))
                 .await?;
-            match self.connection.receive_reply(Some(&mut queue)).await? {
-                RendezvousReply::Closed => (),
-                other => return Err(RendezvousError::invalid_message("closed", other)),
+            match self.connection.receive_reply(Some(queue)).await? {
+                RendezvousReply::Released => (),
+                other => return Err(RendezvousError::invalid_message("released", other)),
             };
+            *nameplate = None;
         }
 
-        self.connection.close().await?;
+        self.connection
+            .send_message(&OutboundMessage::close(mailbox.clone(), mood), Some(queue))
+            .await?;
+        match self.connection.receive_reply(Some(queue)).await? {
+            RendezvousReply::Closed => (),
+            other => return Err(RendezvousError::invalid_message("closed", other)),
+        };
         Ok(())
     }
 }
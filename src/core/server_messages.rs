@@ -17,7 +17,7 @@ impl Nameplate_ {
         Ok(value.into_iter().map(|value| Nameplate(value.id)).collect())
     }
 
-    #[expect(clippy::all, dead_code)]
+    #[expect(clippy::ptr_arg)]
     fn serialize<S>(value: &Vec<Nameplate>, ser: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
@@ -28,7 +28,7 @@ impl Nameplate_ {
     }
 }
 
-#[derive(Serialize, Debug, PartialEq, Eq, derive_more::Display)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, derive_more::Display)]
 #[serde(rename_all = "kebab-case")]
 #[serde(tag = "method")]
 pub enum SubmitPermission {
@@ -36,14 +36,20 @@ pub enum SubmitPermission {
     Hashcash { stamp: String },
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Default)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
 pub struct WelcomeMessage {
     #[deprecated(note = "This is for the Python client")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub current_cli_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub motd: Option<String>,
     #[deprecated(note = "Servers should send a proper error message instead")]
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
-    #[serde(rename = "permission-required")]
+    #[serde(
+        rename = "permission-required",
+        skip_serializing_if = "Option::is_none"
+    )]
     pub permission_required: Option<PermissionRequired>,
 }
 
@@ -61,10 +67,15 @@ impl std::fmt::Display for WelcomeMessage {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct PermissionRequired {
-    #[serde(deserialize_with = "PermissionRequired::deserialize_none")]
+    #[serde(
+        deserialize_with = "PermissionRequired::deserialize_none",
+        serialize_with = "PermissionRequired::serialize_none",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
     pub none: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub hashcash: Option<HashcashPermission>,
     #[serde(flatten)]
     pub other: HashMap<String, serde_json::Value>,
@@ -79,6 +90,13 @@ impl PermissionRequired {
             serde::Deserialize::deserialize(de)?;
         Ok(value.is_some())
     }
+
+    fn serialize_none<S>(_value: &bool, ser: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serde::Serialize::serialize(&serde_json::Map::new(), ser)
+    }
 }
 
 impl std::fmt::Display for PermissionRequired {
@@ -94,7 +112,7 @@ impl std::fmt::Display for PermissionRequired {
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, derive_more::Display)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, derive_more::Display)]
 #[display("HashcashPermission {{ bits: {}, resource: '{}' }}", bits, resource)]
 #[serde(deny_unknown_fields)]
 pub struct HashcashPermission {
@@ -102,7 +120,7 @@ pub struct HashcashPermission {
     pub resource: String,
 }
 
-#[derive(Debug, PartialEq, Clone, Deserialize, derive_more::Display)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, derive_more::Display)]
 #[display(
     "EncryptedMessage {{ side: {}, phase: {}, body: {}",
     side,
@@ -112,7 +130,7 @@ pub struct HashcashPermission {
 pub(crate) struct EncryptedMessage {
     pub side: TheirSide,
     pub phase: Phase,
-    #[serde(deserialize_with = "hex::serde::deserialize")]
+    #[serde(with = "hex::serde")]
     pub body: Vec<u8>,
 }
 
@@ -125,10 +143,9 @@ impl EncryptedMessage {
 }
 
 // Client sends only these
-#[derive(Serialize, Debug, PartialEq, derive_more::Display)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, derive_more::Display)]
 #[serde(rename_all = "kebab-case")]
 #[serde(tag = "type")]
-#[expect(dead_code)]
 pub enum OutboundMessage {
     #[display("SubmitPermission({})", _0)]
     SubmitPermission(SubmitPermission),
@@ -158,7 +175,7 @@ pub enum OutboundMessage {
     )]
     Add {
         phase: Phase,
-        #[serde(serialize_with = "hex::serde::serialize")]
+        #[serde(with = "hex::serde")]
         body: Vec<u8>,
     },
     #[display("Close {{ mailbox: {}, mood: {} }}", mailbox, mood)]
@@ -204,7 +221,7 @@ impl OutboundMessage {
 }
 
 // Server sends only these
-#[derive(Deserialize, Debug, PartialEq, derive_more::Display)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, derive_more::Display)]
 #[serde(rename_all = "kebab-case")]
 #[serde(tag = "type")]
 pub enum InboundMessage {
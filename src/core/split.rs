@@ -0,0 +1,230 @@
+//! Sending and receiving concurrently, see [`Wormhole::split`]
+
+use super::{Wormhole, WormholeError};
+use futures::{
+    StreamExt,
+    channel::{mpsc, oneshot},
+    future::Either,
+    sink::Sink,
+    stream::Stream,
+};
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/**
+ * The sending half of a [split](Wormhole::split) wormhole
+ *
+ * Messages are queued, and sent in order by the [`WormholeDriver`]. Drop this once you are done sending.
+ */
+#[derive(Clone, Debug)]
+pub struct WormholeSender {
+    outbound: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl WormholeSender {
+    /** Queue an encrypted message to the peer */
+    pub async fn send(&mut self, plaintext: Vec<u8>) -> Result<(), WormholeError> {
+        self.outbound
+            .unbounded_send(plaintext)
+            .map_err(|_| WormholeError::Closed)
+    }
+
+    /**
+     * Serialize and queue an encrypted message to the peer
+     *
+     * ## Panics
+     *
+     * If the serialization fails
+     */
+    pub async fn send_json<T: serde::Serialize>(
+        &mut self,
+        message: &T,
+    ) -> Result<(), WormholeError> {
+        self.send(serde_json::to_vec(message).unwrap()).await
+    }
+}
+
+impl Sink<Vec<u8>> for WormholeSender {
+    type Error = WormholeError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.outbound)
+            .poll_ready(cx)
+            .map_err(|_| WormholeError::Closed)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, plaintext: Vec<u8>) -> Result<(), Self::Error> {
+        Pin::new(&mut self.outbound)
+            .start_send(plaintext)
+            .map_err(|_| WormholeError::Closed)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.outbound)
+            .poll_flush(cx)
+            .map_err(|_| WormholeError::Closed)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.outbound)
+            .poll_close(cx)
+            .map_err(|_| WormholeError::Closed)
+    }
+}
+
+/**
+ * The receiving half of a [split](Wormhole::split) wormhole
+ *
+ * As a [`Stream`], it ends once the [`WormholeDriver`] stopped. Drop this once you don't expect any more messages.
+ */
+#[derive(Debug)]
+pub struct WormholeReceiver {
+    inbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    _alive: oneshot::Sender<std::convert::Infallible>,
+}
+
+impl WormholeReceiver {
+    /** Receive the next decrypted message from the peer, or `None` once the driver stopped */
+    pub async fn receive(&mut self) -> Option<Vec<u8>> {
+        self.inbound.next().await
+    }
+
+    /**
+     * Receive the next message from the peer and deserialize it, see [`Wormhole::receive_json`]
+     */
+    pub async fn receive_json<T>(&mut self) -> Option<Result<T, serde_json::Error>>
+    where
+        T: for<'a> serde::Deserialize<'a>,
+    {
+        self.receive()
+            .await
+            .map(|data| serde_json::from_slice(&data))
+    }
+}
+
+impl Stream for WormholeReceiver {
+    type Item = Vec<u8>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inbound.poll_next_unpin(cx)
+    }
+}
+
+/**
+ * The driver of a [split](Wormhole::split) wormhole
+ *
+ * It does nothing on its own, you need to [`run`](Self::run) it.
+ */
+#[derive(Debug)]
+pub struct WormholeDriver {
+    wormhole: Wormhole,
+    outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+    inbound: mpsc::UnboundedSender<Vec<u8>>,
+    receiver_alive: oneshot::Receiver<std::convert::Infallible>,
+}
+
+enum Event {
+    Outbound(Option<Vec<u8>>),
+    Inbound(Result<Vec<u8>, WormholeError>),
+    ReceiverDropped,
+}
+
+impl WormholeDriver {
+    /**
+     * Exchange the messages of both halves with the mailbox server
+     *
+     * This returns once both the [`WormholeSender`] and the [`WormholeReceiver`] have been dropped and
+     * all queued messages have been sent. The wormhole is closed afterwards.
+     * Errors stop the driver, which ends the receiver's stream.
+     */
+    pub async fn run(self) -> Result<(), WormholeError> {
+        let Self {
+            mut wormhole,
+            outbound,
+            inbound,
+            receiver_alive,
+        } = self;
+        let mut outbound = Some(outbound);
+        let mut receiver_alive = Some(receiver_alive);
+
+        while outbound.is_some() || receiver_alive.is_some() {
+            /* Keep receiving across the rounds, until we need the wormhole for sending. Dropping the receive
+             * then is fine, the rendezvous server resumes anything that got interrupted.
+             */
+            let event = {
+                let receiving = receiver_alive.is_some();
+                let receive = async {
+                    match &mut receiver_alive {
+                        /* Only receive while someone is interested */
+                        Some(alive) => {
+                            futures_lite::future::or(
+                                async {
+                                    let _ = alive.await;
+                                    Event::ReceiverDropped
+                                },
+                                async { Event::Inbound(wormhole.receive().await) },
+                            )
+                            .await
+                        },
+                        None => futures::future::pending().await,
+                    }
+                };
+                let mut receive = std::pin::pin!(receive);
+                loop {
+                    let send = async {
+                        match &mut outbound {
+                            Some(outbound) => outbound.next().await,
+                            None => futures::future::pending().await,
+                        }
+                    };
+                    let event =
+                        match futures::future::select(std::pin::pin!(send), receive.as_mut()).await
+                        {
+                            Either::Left((plaintext, _)) => Event::Outbound(plaintext),
+                            Either::Right((event, _)) => event,
+                        };
+                    match event {
+                        /* Nothing to send anymore, but maybe still something to receive */
+                        Event::Outbound(None) if receiving => outbound = None,
+                        event => break event,
+                    }
+                }
+            };
+
+            match event {
+                Event::Outbound(Some(plaintext)) => wormhole.send(plaintext).await?,
+                Event::Outbound(None) => outbound = None,
+                /* The receiver might be gone by now, which we will notice in the next round */
+                Event::Inbound(message) => {
+                    let _ = inbound.unbounded_send(message?);
+                },
+                Event::ReceiverDropped => receiver_alive = None,
+            }
+        }
+
+        wormhole.close().await
+    }
+}
+
+pub(super) fn split(wormhole: Wormhole) -> (WormholeSender, WormholeReceiver, WormholeDriver) {
+    let (outbound_tx, outbound_rx) = mpsc::unbounded();
+    let (inbound_tx, inbound_rx) = mpsc::unbounded();
+    let (alive_tx, alive_rx) = oneshot::channel();
+    (
+        WormholeSender {
+            outbound: outbound_tx,
+        },
+        WormholeReceiver {
+            inbound: inbound_rx,
+            _alive: alive_tx,
+        },
+        WormholeDriver {
+            wormhole,
+            outbound: outbound_rx,
+            inbound: inbound_tx,
+            receiver_alive: alive_rx,
+        },
+    )
+}
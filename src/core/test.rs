@@ -5,6 +5,8 @@ use futures_concurrency::prelude::*;
 use rand::Rng;
 use std::{borrow::Cow, str::FromStr, time::Duration};
 
+#[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+use crate::dilation;
 #[cfg(feature = "transfer")]
 use crate::transfer;
 use crate::{
@@ -39,6 +41,39 @@ pub const APP_CONFIG: AppConfig<()> = AppConfig::<()> {
     app_version: (),
 };
 
+/// The URL of a mailbox server for the tests
+///
+/// Outside of WASM, this starts a local [`MailboxServer`](crate::mailbox_server::MailboxServer)
+/// on first use, which is then shared by all tests.
+fn rendezvous_url() -> Cow<'static, str> {
+    #[cfg(target_family = "wasm")]
+    return Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER);
+
+    #[cfg(not(target_family = "wasm"))]
+    {
+        static URL: std::sync::LazyLock<String> = std::sync::LazyLock::new(|| {
+            let server =
+                async_io::block_on(crate::mailbox_server::MailboxServer::bind("127.0.0.1:0"))
+                    .unwrap();
+            let url = server.url();
+            crate::util::spawn(server.run()).detach();
+            url
+        });
+        Cow::Borrowed(&URL)
+    }
+}
+
+fn app_config() -> AppConfig<()> {
+    APP_CONFIG.rendezvous_url(rendezvous_url())
+}
+
+#[cfg(feature = "transfer")]
+fn transfer_app_config() -> AppConfig<transfer::AppVersion> {
+    transfer::APP_CONFIG
+        .id(TEST_APPID)
+        .rendezvous_url(rendezvous_url())
+}
+
 const TIMEOUT: Duration = Duration::from_secs(60);
 
 /// Utility method that logs information of the transit result
@@ -63,7 +98,25 @@ pub(crate) fn log_transit_connection(info: crate::transit::TransitInfo) {
     tracing::info!("{info}")
 }
 
+/// Relay hints for the tests
+///
+/// With the `relay-server` feature, this starts a local
+/// [`RelayServer`](crate::transit::relay_server::RelayServer) on first use.
 fn default_relay_hints() -> Vec<transit::RelayHint> {
+    #[cfg(all(feature = "relay-server", not(target_family = "wasm")))]
+    {
+        static HINT: std::sync::LazyLock<transit::RelayHint> = std::sync::LazyLock::new(|| {
+            let server =
+                async_io::block_on(transit::relay_server::RelayServer::bind("127.0.0.1:0"))
+                    .unwrap();
+            let hint = server.hint().unwrap();
+            crate::util::spawn(server.run()).detach();
+            hint
+        });
+        vec![HINT.clone()]
+    }
+
+    #[cfg(not(all(feature = "relay-server", not(target_family = "wasm"))))]
     vec![
         transit::RelayHint::from_urls(None, [transit::DEFAULT_RELAY_SERVER.parse().unwrap()])
             .unwrap(),
@@ -75,8 +128,7 @@ fn default_relay_hints() -> Vec<transit::RelayHint> {
 async fn test_connect_with_unknown_code_and_allocate_passes() {
     let code = generate_random_code();
 
-    let mailbox_connection =
-        MailboxConnection::connect(transfer::APP_CONFIG.id(TEST_APPID).clone(), code, true).await;
+    let mailbox_connection = MailboxConnection::connect(transfer_app_config(), code, true).await;
 
     assert!(mailbox_connection.is_ok());
 
@@ -93,12 +145,8 @@ async fn test_connect_with_unknown_code_and_no_allocate_fails() {
     tracing::info!("hola!");
     let code = generate_random_code();
 
-    let mailbox_connection = MailboxConnection::connect(
-        transfer::APP_CONFIG.id(TEST_APPID).clone(),
-        code.clone(),
-        false,
-    )
-    .await;
+    let mailbox_connection =
+        MailboxConnection::connect(transfer_app_config(), code.clone(), false).await;
 
     assert!(mailbox_connection.is_err());
     let error = mailbox_connection.err().unwrap();
@@ -252,8 +300,7 @@ async fn test_file_rust2rust() {
         let (code_tx, code_rx) = futures::channel::oneshot::channel();
 
         let sender_task = async {
-            let mailbox_connection =
-                MailboxConnection::create(transfer::APP_CONFIG.id(TEST_APPID).clone(), 2).await?;
+            let mailbox_connection = MailboxConnection::create(transfer_app_config(), 2).await?;
             if let Some(welcome) = &mailbox_connection.welcome {
                 tracing::info!("Got welcome: {}", welcome);
             }
@@ -265,6 +312,7 @@ async fn test_file_rust2rust() {
                     wormhole,
                     default_relay_hints(),
                     magic_wormhole::transit::Abilities::ALL,
+                    None,
                     offer,
                     &log_transit_connection,
                     |_sent, _total| {},
@@ -276,7 +324,7 @@ async fn test_file_rust2rust() {
 
         let receiver_task = async {
             let code = code_rx.await?;
-            let config = transfer::APP_CONFIG.id(TEST_APPID);
+            let config = transfer_app_config();
             let mailbox = MailboxConnection::connect(config, code.clone(), false).await?;
             if let Some(welcome) = mailbox.welcome.clone() {
                 tracing::info!("Got welcome: {}", welcome);
@@ -323,6 +371,280 @@ async fn test_file_rust2rust() {
     }
 }
 
+/** Send a text message using the Rust implementation, the same way `wormhole send --text` does */
+#[cfg(feature = "transfer")]
+#[apply(test)]
+async fn test_text_rust2rust() -> eyre::Result<()> {
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender_task = async {
+        let mailbox_connection = MailboxConnection::create(transfer_app_config(), 2).await?;
+        code_tx.send(mailbox_connection.code.clone()).unwrap();
+        let wormhole = crate::Wormhole::connect(mailbox_connection).await?;
+        transfer::send_text(wormhole, "hello from rust", futures::future::pending()).await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let receiver_task = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(transfer_app_config(), code, false).await?;
+        let wormhole = crate::Wormhole::connect(mailbox).await?;
+        let req = transfer::request(
+            wormhole,
+            default_relay_hints(),
+            magic_wormhole::transit::Abilities::ALL,
+            None,
+            futures::future::pending(),
+        )
+        .await?
+        .unwrap();
+        match req {
+            transfer::ReceiveRequest::Text(text) => Ok(text),
+            _ => eyre::bail!("Expected a text message"),
+        }
+    };
+
+    let ((), received) = timeout(TIMEOUT, (sender_task, receiver_task).try_join()).await??;
+    assert_eq!(received, "hello from rust");
+    Ok(())
+}
+
+/** Send a stream of known size and receive it into a writer, like `wormhole-rs send -` and `receive --stdout` */
+#[cfg(all(feature = "transfer", not(target_family = "wasm")))]
+#[apply(test)]
+async fn test_stream_rust2rust() -> eyre::Result<()> {
+    use futures::AsyncReadExt;
+
+    let data = b"streamed without ever touching the disk".repeat(1000);
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender_task = async {
+        let mailbox_connection = MailboxConnection::create(transfer_app_config(), 2).await?;
+        code_tx.send(mailbox_connection.code.clone()).unwrap();
+        let wormhole = crate::Wormhole::connect(mailbox_connection).await?;
+        let offer = transfer::offer::OfferSend::new_file_custom(
+            "stdin".into(),
+            data.len() as u64,
+            transfer::offer::new_offer_content_stream(futures::io::Cursor::new(data.clone())),
+        );
+        transfer::send(
+            wormhole,
+            default_relay_hints(),
+            magic_wormhole::transit::Abilities::ALL,
+            None,
+            offer,
+            &log_transit_connection,
+            |_sent, _total| {},
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let received =
+        std::env::temp_dir().join(format!("wormhole-test-stream-{}", rand::random::<u32>()));
+    let receiver_task = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(transfer_app_config(), code, false).await?;
+        let wormhole = crate::Wormhole::connect(mailbox).await?;
+        let req = transfer::request(
+            wormhole,
+            default_relay_hints(),
+            magic_wormhole::transit::Abilities::ALL,
+            None,
+            futures::future::pending(),
+        )
+        .await?
+        .unwrap();
+        req.accept_into(
+            &log_transit_connection,
+            |_received, _total| {},
+            async_fs::File::create(&received).await?,
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (sender_task, receiver_task).try_join()).await??;
+    let mut content = Vec::new();
+    async_fs::File::open(&received)
+        .await?
+        .read_to_end(&mut content)
+        .await?;
+    async_fs::remove_file(&received).await?;
+    assert_eq!(content, data);
+    Ok(())
+}
+
+/** Streams of unknown size are buffered for transfer v1 peers, which need the size upfront */
+#[cfg(all(feature = "transfer", not(target_family = "wasm")))]
+#[apply(test)]
+async fn test_stream_unknown_size_v1() -> eyre::Result<()> {
+    use futures::AsyncReadExt;
+
+    let data = b"buffered before it is sent".repeat(1000);
+    let len = data.len() as u64;
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender_task = async {
+        let mailbox_connection = MailboxConnection::create(transfer_app_config(), 2).await?;
+        code_tx.send(mailbox_connection.code.clone()).unwrap();
+        let wormhole = crate::Wormhole::connect(mailbox_connection).await?;
+        let offer = transfer::offer::OfferSend::new_file_stream(
+            "stdin".into(),
+            transfer::offer::new_offer_content_stream(futures::io::Cursor::new(data.clone())),
+        );
+        transfer::send(
+            wormhole,
+            default_relay_hints(),
+            magic_wormhole::transit::Abilities::ALL,
+            None,
+            offer,
+            &log_transit_connection,
+            move |_sent, total| assert_eq!(total, Some(len)),
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let received =
+        std::env::temp_dir().join(format!("wormhole-test-stream-{}", rand::random::<u32>()));
+    let receiver_task = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(transfer_app_config(), code, false).await?;
+        let wormhole = crate::Wormhole::connect(mailbox).await?;
+        let req = transfer::request(
+            wormhole,
+            default_relay_hints(),
+            magic_wormhole::transit::Abilities::ALL,
+            None,
+            futures::future::pending(),
+        )
+        .await?
+        .unwrap();
+        assert!(matches!(req, transfer::ReceiveRequest::V1(_)));
+        assert_eq!(req.offer().unwrap().total_size(), len);
+        req.accept_into(
+            &log_transit_connection,
+            |_received, _total| {},
+            async_fs::File::create(&received).await?,
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (sender_task, receiver_task).try_join()).await??;
+    let mut content = Vec::new();
+    async_fs::File::open(&received)
+        .await?
+        .read_to_end(&mut content)
+        .await?;
+    async_fs::remove_file(&received).await?;
+    assert_eq!(content, data);
+    Ok(())
+}
+
+/** The events of a transfer arrive in order, from connecting to finishing */
+#[cfg(all(feature = "transfer", not(target_family = "wasm")))]
+#[apply(test)]
+async fn test_events_rust2rust() -> eyre::Result<()> {
+    use transfer::TransferEvent;
+
+    let data = b"reported in detail".repeat(1000);
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender_task = async {
+        let mailbox_connection = MailboxConnection::create(transfer_app_config(), 2).await?;
+        code_tx.send(mailbox_connection.code.clone()).unwrap();
+        let wormhole = crate::Wormhole::connect(mailbox_connection).await?;
+        let offer = transfer::offer::OfferSend::new_file_custom(
+            "events.bin".into(),
+            data.len() as u64,
+            transfer::offer::new_offer_content_stream(futures::io::Cursor::new(data.clone())),
+        );
+        let mut events = Vec::new();
+        transfer::send_with_events(
+            wormhole,
+            default_relay_hints(),
+            magic_wormhole::transit::Abilities::ALL,
+            None,
+            offer,
+            |event| events.push(event),
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(events)
+    };
+
+    let receiver_task = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(transfer_app_config(), code, false).await?;
+        let wormhole = crate::Wormhole::connect(mailbox).await?;
+        let mut events = Vec::new();
+        let req = transfer::request_with_events(
+            wormhole,
+            default_relay_hints(),
+            magic_wormhole::transit::Abilities::ALL,
+            None,
+            |event| events.push(event),
+            futures::future::pending(),
+        )
+        .await?
+        .unwrap();
+        let answer = req
+            .offer()
+            .unwrap()
+            .set_content(|_path| transfer::offer::AcceptInner {
+                offset: 0,
+                sha256: None,
+                content: Box::new(|_append| {
+                    Box::pin(futures::future::ready(Ok(Box::new(futures::io::sink())
+                        as Box<dyn futures::AsyncWrite + Unpin + Send>)))
+                }),
+            });
+        req.accept_with_events(
+            answer,
+            |event| events.push(event),
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(events)
+    };
+
+    let (sent, received) = timeout(TIMEOUT, (sender_task, receiver_task).try_join()).await??;
+    assert!(matches!(received[0], TransferEvent::OfferReceived(_)));
+    for events in [&sent, &received] {
+        let position = |predicate: &dyn Fn(&TransferEvent) -> bool| {
+            events
+                .iter()
+                .position(predicate)
+                .unwrap_or_else(|| panic!("Missing event in {events:?}"))
+        };
+        let hints = position(&|event| {
+            matches!(
+                event,
+                TransferEvent::Transit(transit::TransitEvent::Hints { .. })
+            )
+        });
+        let connected = position(&|event| matches!(event, TransferEvent::Connected(_)));
+        let started = position(&|event| {
+            matches!(event, TransferEvent::FileStarted { path, size: Some(size) }
+                if path == &["events.bin"] && *size == data.len() as u64)
+        });
+        let finished = position(&|event| matches!(event, TransferEvent::FileFinished { .. }));
+        assert!(hints < connected && connected < started && started < finished);
+        assert!(matches!(
+            &events[finished - 1],
+            TransferEvent::Progress(progress) if progress.bytes == data.len() as u64
+        ));
+        assert!(matches!(events.last(), Some(TransferEvent::Finished)));
+    }
+    Ok(())
+}
+
 /** Test the functionality used by the `send-many` subcommand.
  */
 #[cfg(feature = "transfer")]
@@ -330,7 +652,7 @@ async fn test_file_rust2rust() {
 // TODO Wasm test disabled, it crashes
 // #[cfg_attr(target_arch = "wasm32", test(wasm_bindgen_test::wasm_bindgen_test))]
 async fn test_send_many() {
-    let mailbox = MailboxConnection::create(transfer::APP_CONFIG.id(TEST_APPID), 2)
+    let mailbox = MailboxConnection::create(transfer_app_config(), 2)
         .await
         .unwrap();
     let code = mailbox.code.clone();
@@ -360,6 +682,7 @@ async fn test_send_many() {
                         wormhole,
                         default_relay_hints(),
                         magic_wormhole::transit::Abilities::ALL,
+                        None,
                         gen_offer().await?,
                         &log_transit_connection,
                         |_, _| {},
@@ -373,12 +696,8 @@ async fn test_send_many() {
         for i in 1..5usize {
             tracing::info!("Sending file #{}", i);
             let wormhole = crate::Wormhole::connect(
-                MailboxConnection::connect(
-                    transfer::APP_CONFIG.id(TEST_APPID),
-                    sender_code.clone(),
-                    true,
-                )
-                .await?,
+                MailboxConnection::connect(transfer_app_config(), sender_code.clone(), true)
+                    .await?,
             )
             .await?;
             senders.push(crate::util::spawn(async move {
@@ -387,6 +706,7 @@ async fn test_send_many() {
                         wormhole,
                         default_relay_hints(),
                         magic_wormhole::transit::Abilities::ALL,
+                        None,
                         gen_offer().await?,
                         &log_transit_connection,
                         |_, _| {},
@@ -406,7 +726,7 @@ async fn test_send_many() {
     for i in 0..5usize {
         tracing::info!("Receiving file #{}", i);
         let wormhole = crate::Wormhole::connect(
-            MailboxConnection::connect(transfer::APP_CONFIG.id(TEST_APPID), code.clone(), true)
+            MailboxConnection::connect(transfer_app_config(), code.clone(), true)
                 .await
                 .unwrap(),
         )
@@ -458,7 +778,7 @@ async fn test_wrong_code() {
     let (code_tx, code_rx) = futures::channel::oneshot::channel();
 
     let sender_task = crate::util::spawn(async {
-        let mailbox = MailboxConnection::create(APP_CONFIG, 2).await.unwrap();
+        let mailbox = MailboxConnection::create(app_config(), 2).await.unwrap();
         if let Some(welcome) = &mailbox.welcome {
             tracing::info!("Got welcome: {}", welcome);
         }
@@ -476,7 +796,7 @@ async fn test_wrong_code() {
         tracing::info!("Got nameplate over local: {}", &nameplate);
         let result = crate::Wormhole::connect(
             MailboxConnection::connect(
-                APP_CONFIG,
+                app_config(),
                 /* Making a wrong code here by appending nonsense */
                 Code::from_components(nameplate, "foo-bar".parse().unwrap()),
                 true,
@@ -499,12 +819,12 @@ async fn test_wrong_code() {
 #[apply(test)]
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test::wasm_bindgen_test)]
 async fn test_crowded() {
-    let initial_mailbox_connection = MailboxConnection::create(APP_CONFIG, 2).await.unwrap();
+    let initial_mailbox_connection = MailboxConnection::create(app_config(), 2).await.unwrap();
     tracing::info!("This test's code is: {}", &initial_mailbox_connection.code);
     let code = initial_mailbox_connection.code.clone();
 
-    let mailbox_connection_1 = MailboxConnection::connect(APP_CONFIG.clone(), code.clone(), false);
-    let mailbox_connection_2 = MailboxConnection::connect(APP_CONFIG.clone(), code.clone(), false);
+    let mailbox_connection_1 = MailboxConnection::connect(app_config(), code.clone(), false);
+    let mailbox_connection_2 = MailboxConnection::connect(app_config(), code.clone(), false);
 
     match futures::try_join!(mailbox_connection_1, mailbox_connection_2)
         .err()
@@ -523,7 +843,7 @@ async fn test_crowded() {
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test::wasm_bindgen_test)]
 async fn test_connect_with_code_expecting_nameplate() {
     let code = generate_random_code();
-    let result = MailboxConnection::connect(APP_CONFIG, code.clone(), false).await;
+    let result = MailboxConnection::connect(app_config(), code.clone(), false).await;
     let error = result.err().unwrap();
     match error {
         magic_wormhole::WormholeError::UnclaimedNameplate(x) => {
@@ -533,6 +853,1027 @@ async fn test_connect_with_code_expecting_nameplate() {
     }
 }
 
+/// A TCP proxy that can cut all its connections on demand
+#[cfg(not(target_family = "wasm"))]
+struct FlakyProxy {
+    addr: std::net::SocketAddr,
+    connections: std::sync::Arc<std::sync::Mutex<Vec<async_task::Task<()>>>>,
+    _accept: async_task::Task<()>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl FlakyProxy {
+    /// Proxy the local mailbox server
+    async fn mailbox() -> Self {
+        Self::mailbox_with_latency(Duration::ZERO).await
+    }
+
+    /// Proxy the local mailbox server, and delay everything it sends
+    async fn mailbox_with_latency(latency: Duration) -> Self {
+        let upstream = url::Url::parse(&rendezvous_url()).unwrap();
+        Self::start_with_latency(
+            format!(
+                "{}:{}",
+                upstream.host_str().unwrap(),
+                upstream.port().unwrap()
+            ),
+            latency,
+        )
+        .await
+    }
+
+    #[cfg(all(feature = "dilation", feature = "relay-server"))]
+    async fn start(upstream: String) -> Self {
+        Self::start_with_latency(upstream, Duration::ZERO).await
+    }
+
+    async fn start_with_latency(upstream: String, latency: Duration) -> Self {
+        let listener = async_net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connections = std::sync::Arc::<std::sync::Mutex<Vec<_>>>::default();
+
+        let accept = crate::util::spawn({
+            let connections = connections.clone();
+            async move {
+                while let Ok((client, _)) = listener.accept().await {
+                    let server = async_net::TcpStream::connect(&upstream).await.unwrap();
+                    let forward = crate::util::spawn(async move {
+                        let downstream = async {
+                            use futures::{AsyncReadExt, AsyncWriteExt};
+                            let (mut server, mut client) = (server.clone(), client.clone());
+                            let mut buffer = [0; 4096];
+                            loop {
+                                let read = server.read(&mut buffer).await?;
+                                if read == 0 {
+                                    break std::io::Result::Ok(0);
+                                }
+                                crate::util::sleep(latency).await;
+                                client.write_all(&buffer[..read]).await?;
+                            }
+                        };
+                        let _ = (
+                            futures::io::copy(client.clone(), &mut server.clone()),
+                            downstream,
+                        )
+                            .race()
+                            .await;
+                    });
+                    connections.lock().unwrap().push(forward);
+                }
+            }
+        });
+
+        Self {
+            addr,
+            connections,
+            _accept: accept,
+        }
+    }
+
+    /// The URL of the proxied mailbox server
+    fn url(&self) -> String {
+        format!("ws://{}/v1", self.addr)
+    }
+
+    /// Drop all currently open connections, while still accepting new ones
+    fn disconnect(&self) {
+        self.connections.lock().unwrap().clear();
+    }
+}
+
+/// Both sides keep talking after one of them loses its connection to the mailbox server
+#[apply(test)]
+#[cfg(not(target_family = "wasm"))]
+async fn test_reconnect() -> eyre::Result<()> {
+    let proxy = FlakyProxy::mailbox().await;
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender = async {
+        let mailbox =
+            MailboxConnection::create(app_config().rendezvous_url(proxy.url().into()), 2).await?;
+        code_tx.send(mailbox.code.clone()).unwrap();
+        let mut wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+
+        proxy.disconnect();
+        wormhole.send(b"hello".to_vec()).await?;
+        assert_eq!(wormhole.receive().await?, b"world");
+
+        proxy.disconnect();
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let receiver = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(app_config(), code, false).await?;
+        let mut wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+
+        assert_eq!(wormhole.receive().await?, b"hello");
+        wormhole.send(b"world".to_vec()).await?;
+
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (sender, receiver).try_join()).await??;
+    Ok(())
+}
+
+/// Messages are received in the order they were sent, unknown phases are skipped and gaps are detected
+#[apply(test)]
+async fn test_phase_order() -> eyre::Result<()> {
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender = async {
+        let mailbox = MailboxConnection::create(app_config(), 2).await?;
+        code_tx.send(mailbox.code.clone()).unwrap();
+        let mut wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+
+        wormhole
+            .send_with_phase(Phase::numeric(1), b"second")
+            .await?;
+        wormhole
+            .send_with_phase(Phase("from-the-future".into()), b"unknown")
+            .await?;
+        wormhole
+            .send_with_phase(Phase::numeric(0), b"first")
+            .await?;
+        assert_eq!(wormhole.receive().await?, b"received");
+        wormhole
+            .send_with_phase(Phase::numeric(1000), b"too far")
+            .await?;
+        assert_eq!(wormhole.receive().await?, b"gap");
+
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let receiver = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(app_config(), code, false).await?;
+        let mut wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+        let (unknown_tx, unknown_rx) = std::sync::mpsc::channel();
+        wormhole.set_unknown_phase_handler(move |phase, message| {
+            unknown_tx.send((phase.to_owned(), message)).unwrap();
+        });
+
+        assert_eq!(wormhole.receive().await?, b"first");
+        assert_eq!(wormhole.receive().await?, b"second");
+        assert_eq!(
+            unknown_rx.try_recv()?,
+            ("from-the-future".to_owned(), b"unknown".to_vec())
+        );
+        wormhole.send(b"received".to_vec()).await?;
+
+        assert!(matches!(
+            wormhole.receive().await,
+            Err(WormholeError::PhaseGap {
+                expected: 2,
+                received: 1000
+            })
+        ));
+        wormhole.send(b"gap".to_vec()).await?;
+
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (sender, receiver).try_join()).await??;
+    Ok(())
+}
+
+/// Both sides of split wormholes send and receive at the same time
+#[apply(test)]
+async fn test_split() -> eyre::Result<()> {
+    use futures::StreamExt;
+
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let first = async {
+        let mailbox = MailboxConnection::create(app_config(), 2).await?;
+        code_tx.send(mailbox.code.clone()).unwrap();
+        let (sender, receiver, driver) = magic_wormhole::Wormhole::connect(mailbox).await?.split();
+
+        let app = async move {
+            let send = async move {
+                futures::stream::iter((0..3u8).map(|i| Ok(vec![i])))
+                    .forward(sender)
+                    .await?;
+                eyre::Result::<_>::Ok(())
+            };
+            let receive = receiver.take(3).collect::<Vec<_>>();
+            let ((), received) = (send, async { Ok(receive.await) }).try_join().await?;
+            assert_eq!(received, [b"a", b"b", b"c"]);
+            eyre::Result::<_>::Ok(())
+        };
+        (app, async { Ok(driver.run().await?) }).try_join().await
+    };
+
+    let second = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(app_config(), code, false).await?;
+        let (mut sender, mut receiver, driver) =
+            magic_wormhole::Wormhole::connect(mailbox).await?.split();
+
+        let app = async move {
+            for message in [b"a", b"b", b"c"] {
+                sender.send(message.to_vec()).await?;
+            }
+            drop(sender);
+            for i in 0..3u8 {
+                assert_eq!(receiver.receive().await, Some(vec![i]));
+            }
+            eyre::Result::<_>::Ok(())
+        };
+        (app, async { Ok(driver.run().await?) }).try_join().await
+    };
+
+    timeout(TIMEOUT, (first, second).try_join()).await??;
+    Ok(())
+}
+
+/// Cancelling a receive while it reconnects to the mailbox server does not break the wormhole
+#[apply(test)]
+#[cfg(not(target_family = "wasm"))]
+async fn test_reconnect_cancelled() -> eyre::Result<()> {
+    use std::{future::Future, task::Poll};
+
+    const ROUNDS: u8 = 12;
+
+    let proxy = &FlakyProxy::mailbox_with_latency(Duration::from_millis(20)).await;
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let sender = async {
+        let mailbox =
+            MailboxConnection::create(app_config().rendezvous_url(proxy.url().into()), 2).await?;
+        code_tx.send(mailbox.code.clone()).unwrap();
+        let mut wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+
+        /* Drop the receive after a growing number of polls, to hit every step of reconnecting */
+        for round in 0..ROUNDS {
+            proxy.disconnect();
+            {
+                let mut receive = std::pin::pin!(wormhole.receive());
+                let mut polls = 0;
+                let _ = timeout(
+                    Duration::from_secs(1),
+                    futures::future::poll_fn(|cx| {
+                        polls += 1;
+                        if polls > round {
+                            return Poll::Ready(());
+                        }
+                        match receive.as_mut().poll(cx) {
+                            Poll::Ready(message) => panic!("Unexpected message {message:?}"),
+                            Poll::Pending => Poll::Pending,
+                        }
+                    }),
+                )
+                .await;
+            }
+            wormhole.send(vec![round]).await?;
+            assert_eq!(wormhole.receive().await?, [round]);
+        }
+
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let receiver = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(app_config(), code, false).await?;
+        let mut wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+
+        for round in 0..ROUNDS {
+            assert_eq!(wormhole.receive().await?, [round]);
+            wormhole.send(vec![round]).await?;
+        }
+
+        wormhole.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (sender, receiver).try_join()).await??;
+    Ok(())
+}
+
+/// Connect two wormholes for forwarding to each other
+#[cfg(feature = "forwarding")]
+async fn forwarding_pair() -> eyre::Result<(crate::Wormhole, crate::Wormhole)> {
+    let forwarding_app_config = || {
+        crate::forwarding::APP_CONFIG
+            .id(TEST_APPID)
+            .rendezvous_url(rendezvous_url())
+    };
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let serve = async {
+        let mailbox = MailboxConnection::create(forwarding_app_config(), 2).await?;
+        code_tx.send(mailbox.code.clone()).unwrap();
+        eyre::Result::<_>::Ok(crate::Wormhole::connect(mailbox).await?)
+    };
+    let connect = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(forwarding_app_config(), code, false).await?;
+        eyre::Result::<_>::Ok(crate::Wormhole::connect(mailbox).await?)
+    };
+
+    (serve, connect).try_join().await
+}
+
+/// Start a TCP echo service on localhost, which answers every connection with `greeting` first
+///
+/// The service stops when the returned task is dropped.
+#[cfg(feature = "forwarding")]
+async fn tcp_echo(greeting: &'static [u8]) -> std::io::Result<(u16, async_task::Task<()>)> {
+    use futures::AsyncWriteExt;
+
+    let listener = async_net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?;
+    let port = listener.local_addr()?.port();
+    let task = crate::util::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let _ = stream.write_all(greeting).await;
+            let _ = futures::io::copy(stream.clone(), &mut stream).await;
+        }
+    });
+    Ok((port, task))
+}
+
+/** Forward a UDP echo service, with two clients that get their own pseudo-connections */
+#[cfg(feature = "forwarding")]
+#[apply(test)]
+async fn test_forward_udp() -> eyre::Result<()> {
+    use crate::forwarding;
+    use std::net::Ipv4Addr;
+
+    let echo = async_net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let echo_port = echo.local_addr()?.port();
+    let _echo = crate::util::spawn(async move {
+        let mut buffer = [0; 1024];
+        while let Ok((read, source)) = echo.recv_from(&mut buffer).await {
+            let _ = echo.send_to(&buffer[..read], source).await;
+        }
+    });
+
+    let (serve_wormhole, connect_wormhole) = timeout(TIMEOUT, forwarding_pair()).await??;
+
+    let serve_task = async {
+        forwarding::serve(
+            serve_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            [forwarding::Target::Udp(
+                Some(url::Host::Ipv4(Ipv4Addr::LOCALHOST)),
+                echo_port,
+            )],
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let connect_task = async {
+        let offer = forwarding::connect(
+            connect_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            Some(Ipv4Addr::LOCALHOST.into()),
+            &[],
+            None,
+        )
+        .await?;
+        let (port, target) = offer.mapping[0].clone();
+        assert_eq!(*target, format!("udp:127.0.0.1:{echo_port}"));
+
+        let (done_tx, done_rx) = futures::channel::oneshot::channel();
+        let clients = async move {
+            let first = async_net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            let second = async_net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+            let mut buffer = [0; 1024];
+            for (socket, message) in [(&first, b"ping"), (&second, b"pong"), (&first, b"pang")] {
+                socket.send_to(message, (Ipv4Addr::LOCALHOST, port)).await?;
+                let read = socket.recv(&mut buffer).await?;
+                assert_eq!(&buffer[..read], message);
+            }
+            done_tx.send(()).unwrap();
+            eyre::Result::<_>::Ok(())
+        };
+        let accept = async {
+            offer
+                .accept(async {
+                    let _ = done_rx.await;
+                })
+                .await?;
+            eyre::Result::<_>::Ok(())
+        };
+        (accept, clients).try_join().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (serve_task, connect_task).try_join()).await??;
+    Ok(())
+}
+
+/** Forward a unix socket echo service, and make it available as unix socket on the other side */
+#[cfg(all(feature = "forwarding", unix))]
+#[apply(test)]
+async fn test_forward_unix() -> eyre::Result<()> {
+    use crate::forwarding;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let id = rand::random::<u32>();
+    let echo_path = std::env::temp_dir().join(format!("wormhole-test-echo-{id}.sock"));
+    let socket_dir = std::env::temp_dir().join(format!("wormhole-test-sockets-{id}"));
+    async_fs::create_dir(&socket_dir).await?;
+
+    let echo = async_net::unix::UnixListener::bind(&echo_path)?;
+    let _echo = crate::util::spawn(async move {
+        while let Ok((stream, _)) = echo.accept().await {
+            let (reader, mut writer) = stream.split();
+            let _ = futures::io::copy(reader, &mut writer).await;
+        }
+    });
+
+    let (serve_wormhole, connect_wormhole) = timeout(TIMEOUT, forwarding_pair()).await??;
+
+    let serve_task = async {
+        forwarding::serve(
+            serve_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            [forwarding::Target::Unix(echo_path.clone())],
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let exposed_path = socket_dir.join(echo_path.file_name().unwrap());
+    let connect_task = async {
+        let offer = forwarding::connect(
+            connect_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            None,
+            &[],
+            Some(&socket_dir),
+        )
+        .await?;
+        assert!(offer.mapping.is_empty());
+        assert_eq!(offer.unix_mapping[0].0, exposed_path);
+        assert_eq!(
+            *offer.unix_mapping[0].1,
+            format!("unix:{}", echo_path.display())
+        );
+
+        let (done_tx, done_rx) = futures::channel::oneshot::channel();
+        let client = async {
+            let mut stream = async_net::unix::UnixStream::connect(&exposed_path).await?;
+            stream.write_all(b"hello").await?;
+            let mut buffer = [0; 5];
+            stream.read_exact(&mut buffer).await?;
+            assert_eq!(&buffer, b"hello");
+            done_tx.send(()).unwrap();
+            eyre::Result::<_>::Ok(())
+        };
+        let accept = async {
+            offer
+                .accept(async {
+                    let _ = done_rx.await;
+                })
+                .await?;
+            eyre::Result::<_>::Ok(())
+        };
+        (accept, client).try_join().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (serve_task, connect_task).try_join()).await??;
+    /* The socket is cleaned up afterwards */
+    assert!(!exposed_path.exists());
+    async_fs::remove_dir(&socket_dir).await?;
+    async_fs::remove_file(&echo_path).await?;
+    Ok(())
+}
+
+/** Forward in both directions at once, with `serve_with_reverse` and `with_reverse_targets` */
+#[cfg(feature = "forwarding")]
+#[apply(test)]
+async fn test_forward_reverse() -> eyre::Result<()> {
+    use crate::forwarding;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use std::net::Ipv4Addr;
+
+    async fn request(port: u16) -> std::io::Result<Vec<u8>> {
+        let mut stream = async_net::TcpStream::connect((Ipv4Addr::LOCALHOST, port)).await?;
+        stream.write_all(b"hello").await?;
+        let mut buffer = vec![0; 10];
+        stream.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    }
+    /* Each echo service answers with its name first */
+    let (serve_port, _serve_echo) = tcp_echo(b"serve").await?;
+    let (connect_port, _connect_echo) = tcp_echo(b"cnect").await?;
+
+    let (serve_wormhole, connect_wormhole) = timeout(TIMEOUT, forwarding_pair()).await??;
+    let (mapping_tx, mapping_rx) = futures::channel::oneshot::channel();
+    let (reverse_done_tx, reverse_done_rx) = futures::channel::oneshot::channel();
+
+    let serve_task = async {
+        forwarding::serve_with_reverse(
+            serve_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            [(Some(url::Host::Ipv4(Ipv4Addr::LOCALHOST)), serve_port)],
+            Some(Ipv4Addr::LOCALHOST.into()),
+            move |mapping: &[(u16, String)]| mapping_tx.send(mapping.to_vec()).unwrap(),
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let reverse_client = async {
+        let mapping = mapping_rx.await?;
+        assert_eq!(mapping.len(), 1);
+        assert_eq!(mapping[0].1, format!("127.0.0.1:{connect_port}"));
+        assert_eq!(request(mapping[0].0).await?, b"cnecthello");
+        reverse_done_tx.send(()).unwrap();
+        eyre::Result::<_>::Ok(())
+    };
+
+    let connect_task = async {
+        let offer = forwarding::connect(
+            connect_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            Some(Ipv4Addr::LOCALHOST.into()),
+            &[],
+            None,
+        )
+        .await?;
+        let port = offer.mapping[0].0;
+
+        let (done_tx, done_rx) = futures::channel::oneshot::channel();
+        let client = async {
+            assert_eq!(request(port).await?, b"servehello");
+            done_tx.send(()).unwrap();
+            eyre::Result::<_>::Ok(())
+        };
+        let accept = async {
+            offer
+                .with_reverse_targets([forwarding::Target::Tcp(
+                    Some(url::Host::Ipv4(Ipv4Addr::LOCALHOST)),
+                    connect_port,
+                )])
+                .accept(async {
+                    let _ = done_rx.await;
+                    let _ = reverse_done_rx.await;
+                })
+                .await?;
+            eyre::Result::<_>::Ok(())
+        };
+        (accept, client).try_join().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(
+        TIMEOUT,
+        (serve_task, reverse_client, connect_task).try_join(),
+    )
+    .await??;
+    Ok(())
+}
+
+/** A connection whose service doesn't read doesn't hold up the other connections */
+#[cfg(feature = "forwarding")]
+#[apply(test)]
+async fn test_forward_flow_control() -> eyre::Result<()> {
+    use crate::forwarding;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use std::net::Ipv4Addr;
+
+    /* Accepts connections, but never reads from them */
+    let stalled = async_net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let stalled_port = stalled.local_addr()?.port();
+    let _stalled = crate::util::spawn(async move {
+        let mut streams = Vec::new();
+        while let Ok((stream, _)) = stalled.accept().await {
+            streams.push(stream);
+        }
+    });
+    let (echo_port, _echo) = tcp_echo(b"").await?;
+
+    let (serve_wormhole, connect_wormhole) = timeout(TIMEOUT, forwarding_pair()).await??;
+
+    let serve_task = async {
+        forwarding::serve(
+            serve_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            [
+                (Some(url::Host::Ipv4(Ipv4Addr::LOCALHOST)), stalled_port),
+                (Some(url::Host::Ipv4(Ipv4Addr::LOCALHOST)), echo_port),
+            ],
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let connect_task = async {
+        let offer = forwarding::connect(
+            connect_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            Some(Ipv4Addr::LOCALHOST.into()),
+            &[],
+            None,
+        )
+        .await?;
+        let port = |target_port: u16| {
+            let target = format!("127.0.0.1:{target_port}");
+            offer
+                .mapping
+                .iter()
+                .find(|(_, address)| **address == target)
+                .map(|(port, _)| *port)
+                .unwrap()
+        };
+        let (stalled_port, echo_port) = (port(stalled_port), port(echo_port));
+
+        let (done_tx, done_rx) = futures::channel::oneshot::channel();
+        let clients = async move {
+            /* Write way more than the transit and the sockets could buffer */
+            let (written_tx, written_rx) = futures::channel::oneshot::channel();
+            let _flood = crate::util::spawn(async move {
+                let mut stream =
+                    async_net::TcpStream::connect((Ipv4Addr::LOCALHOST, stalled_port)).await?;
+                let chunk = vec![0; 1024 * 1024];
+                stream.write_all(&chunk).await?;
+                written_tx.send(()).unwrap();
+                for _ in 0..64 {
+                    stream.write_all(&chunk).await?;
+                }
+                std::io::Result::Ok(())
+            });
+            written_rx.await?;
+
+            /* Larger than the flow control window, so that it takes a few window updates */
+            let payload: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+            let stream = async_net::TcpStream::connect((Ipv4Addr::LOCALHOST, echo_port)).await?;
+            let mut received = vec![0; payload.len()];
+            (
+                stream.clone().write_all(&payload),
+                stream.clone().read_exact(&mut received),
+            )
+                .try_join()
+                .await?;
+            assert!(received == payload);
+            done_tx.send(()).unwrap();
+            eyre::Result::<_>::Ok(())
+        };
+        let accept = async {
+            offer
+                .accept(async {
+                    let _ = done_rx.await;
+                })
+                .await?;
+            eyre::Result::<_>::Ok(())
+        };
+        (accept, clients).try_join().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (serve_task, connect_task).try_join()).await??;
+    Ok(())
+}
+
+/** Connect through a SOCKS proxy, whose policy only allows some destinations */
+#[cfg(feature = "forwarding")]
+#[apply(test)]
+async fn test_forward_socks() -> eyre::Result<()> {
+    use crate::forwarding;
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use std::net::Ipv4Addr;
+
+    let (echo_port, _echo) = tcp_echo(b"").await?;
+    let (denied_port, _denied) = tcp_echo(b"").await?;
+    let policy = forwarding::SocksPolicy::default()
+        .allow("127.0.0.0/8".parse()?)
+        .deny(format!("127.0.0.1:{denied_port}").parse()?);
+
+    let (serve_wormhole, connect_wormhole) = timeout(TIMEOUT, forwarding_pair()).await??;
+
+    let serve_task = async {
+        forwarding::serve(
+            serve_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            [forwarding::Target::Socks(policy)],
+            futures::future::pending(),
+        )
+        .await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let connect_task = async {
+        let offer = forwarding::connect(
+            connect_wormhole,
+            log_transit_connection,
+            default_relay_hints(),
+            None,
+            Some(Ipv4Addr::LOCALHOST.into()),
+            &[],
+            None,
+        )
+        .await?;
+        let (proxy_port, target) = offer.mapping[0].clone();
+        assert_eq!(*target, "socks");
+
+        /* Do a SOCKS5 handshake without authentication, and connect to a domain */
+        async fn socks_connect(
+            proxy_port: u16,
+            domain: &str,
+            port: u16,
+        ) -> std::io::Result<async_net::TcpStream> {
+            let mut stream =
+                async_net::TcpStream::connect((Ipv4Addr::LOCALHOST, proxy_port)).await?;
+            stream.write_all(&[5, 1, 0]).await?;
+            let mut method = [0; 2];
+            stream.read_exact(&mut method).await?;
+            assert_eq!(method, [5, 0]);
+            let mut request = vec![5, 1, 0, 3, domain.len() as u8];
+            request.extend_from_slice(domain.as_bytes());
+            request.extend_from_slice(&port.to_be_bytes());
+            stream.write_all(&request).await?;
+            let mut reply = [0; 10];
+            stream.read_exact(&mut reply).await?;
+            assert_eq!(reply[..2], [5, 0]);
+            Ok(stream)
+        }
+
+        let (done_tx, done_rx) = futures::channel::oneshot::channel();
+        let clients = async move {
+            let mut stream = socks_connect(proxy_port, "localhost", echo_port).await?;
+            stream.write_all(b"hello").await?;
+            let mut buffer = [0; 5];
+            stream.read_exact(&mut buffer).await?;
+            assert_eq!(&buffer, b"hello");
+
+            /* The peer closes connections that the policy doesn't allow */
+            let mut stream = socks_connect(proxy_port, "127.0.0.1", denied_port).await?;
+            let mut buffer = Vec::new();
+            stream.read_to_end(&mut buffer).await?;
+            assert!(buffer.is_empty());
+
+            done_tx.send(()).unwrap();
+            eyre::Result::<_>::Ok(())
+        };
+        let accept = async {
+            offer
+                .accept(async {
+                    let _ = done_rx.await;
+                })
+                .await?;
+            eyre::Result::<_>::Ok(())
+        };
+        (accept, clients).try_join().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(TIMEOUT, (serve_task, connect_task).try_join()).await??;
+    Ok(())
+}
+
+/// Connect two dilated wormholes to each other
+#[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+async fn dilate_pair(
+    abilities: transit::Abilities,
+    relay_hints: Vec<transit::RelayHint>,
+) -> eyre::Result<(
+    (dilation::DilatedWormhole, dilation::Dilation),
+    (dilation::DilatedWormhole, dilation::Dilation),
+)> {
+    let (code_tx, code_rx) = futures::channel::oneshot::channel();
+
+    let first = async {
+        let mailbox = MailboxConnection::create(app_config(), 2)
+            .await?
+            .enable_dilation();
+        code_tx.send(mailbox.code.clone()).unwrap();
+        let wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+        eyre::Result::<_>::Ok(dilation::dilate(wormhole, abilities, relay_hints.clone()).await?)
+    };
+    let second = async {
+        let code = code_rx.await?;
+        let mailbox = MailboxConnection::connect(app_config(), code, false)
+            .await?
+            .enable_dilation();
+        let wormhole = magic_wormhole::Wormhole::connect(mailbox).await?;
+        eyre::Result::<_>::Ok(dilation::dilate(wormhole, abilities, relay_hints.clone()).await?)
+    };
+
+    (first, second).try_join().await
+}
+
+/// Subchannels of a dilated wormhole carry data in both directions, and everything shuts down cleanly
+#[apply(test)]
+#[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+async fn test_dilation() -> eyre::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let ((mut first, first_dilation), (mut second, second_dilation)) = timeout(
+        TIMEOUT,
+        dilate_pair(transit::Abilities::ALL, default_relay_hints()),
+    )
+    .await??;
+    let payload: Vec<u8> = (0..200_000).map(|_| rand::random()).collect();
+
+    let payload = &payload;
+
+    /* The dilation only stops once the handle and all subchannels are dropped, so move them */
+    let first = async move {
+        let mut control = first.control_channel().unwrap();
+        control.write_all(b"ping").await?;
+        let mut pong = [0; 4];
+        control.read_exact(&mut pong).await?;
+        assert_eq!(&pong, b"pong");
+
+        let mut subchannel = first.open().await?;
+        subchannel.write_all(payload).await?;
+        let mut thanks = [0; 6];
+        subchannel.read_exact(&mut thanks).await?;
+        assert_eq!(&thanks, b"thanks");
+        subchannel.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let second = async move {
+        let mut control = second.control_channel().unwrap();
+        let mut ping = [0; 4];
+        control.read_exact(&mut ping).await?;
+        assert_eq!(&ping, b"ping");
+        control.write_all(b"pong").await?;
+
+        let mut subchannel = second.accept().await.unwrap();
+        let mut received = vec![0; payload.len()];
+        subchannel.read_exact(&mut received).await?;
+        assert!(&received == payload);
+        subchannel.write_all(b"thanks").await?;
+
+        /* Closing on one side closes both */
+        let mut rest = Vec::new();
+        subchannel.read_to_end(&mut rest).await?;
+        assert!(rest.is_empty());
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(
+        TIMEOUT,
+        (
+            first,
+            second,
+            async { eyre::Result::<_>::Ok(first_dilation.run().await?) },
+            async { eyre::Result::<_>::Ok(second_dilation.run().await?) },
+        )
+            .try_join(),
+    )
+    .await??;
+    Ok(())
+}
+
+/// A subchannel that isn't read from holds up the writer, instead of queueing up everything it sent
+#[apply(test)]
+#[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+async fn test_dilation_backpressure() -> eyre::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let ((first, first_dilation), (second, second_dilation)) = timeout(
+        TIMEOUT,
+        dilate_pair(transit::Abilities::ALL, default_relay_hints()),
+    )
+    .await??;
+    /* Way more than the queues and the unacknowledged records may hold */
+    let payload: Vec<u8> = (0..16 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let payload = &payload;
+    let written = &AtomicUsize::new(0);
+
+    let first = async move {
+        let mut first = first;
+        let mut control = first.control_channel().unwrap();
+        for chunk in payload.chunks(64 * 1024) {
+            control.write_all(chunk).await?;
+            written.fetch_add(chunk.len(), Ordering::SeqCst);
+        }
+        control.close().await?;
+        eyre::Result::<_>::Ok(())
+    };
+
+    let second = async move {
+        let mut second = second;
+        let mut control = second.control_channel().unwrap();
+        /* Nobody reads, so the writer gets stuck */
+        crate::util::sleep(Duration::from_secs(2)).await;
+        let stuck_at = written.load(Ordering::SeqCst);
+        crate::util::sleep(Duration::from_secs(1)).await;
+        assert_eq!(written.load(Ordering::SeqCst), stuck_at);
+        assert!(stuck_at < payload.len());
+
+        let mut received = Vec::new();
+        control.read_to_end(&mut received).await?;
+        assert!(&received == payload);
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(
+        TIMEOUT,
+        (
+            first,
+            second,
+            async { eyre::Result::<_>::Ok(first_dilation.run().await?) },
+            async { eyre::Result::<_>::Ok(second_dilation.run().await?) },
+        )
+            .try_join(),
+    )
+    .await??;
+    Ok(())
+}
+
+/// Data keeps flowing in order after the dilated connection broke down
+#[apply(test)]
+#[cfg(all(
+    feature = "dilation",
+    feature = "relay-server",
+    not(target_family = "wasm")
+))]
+async fn test_dilation_reconnect() -> eyre::Result<()> {
+    use futures::{AsyncReadExt, AsyncWriteExt};
+
+    let relay = default_relay_hints().remove(0);
+    let relay = relay.tcp.iter().next().unwrap();
+    let proxy = FlakyProxy::start(format!("{}:{}", relay.hostname, relay.port)).await;
+    let relay_hints = vec![transit::RelayHint::from_urls(
+        None,
+        [format!("tcp://{}", proxy.addr).parse().unwrap()],
+    )?];
+
+    let ((mut first, first_dilation), (mut second, second_dilation)) = timeout(
+        TIMEOUT,
+        dilate_pair(transit::Abilities::FORCE_RELAY, relay_hints),
+    )
+    .await??;
+
+    /* Keep the proxy around for the final acknowledgements */
+    let proxy = &proxy;
+    let first = async move {
+        let mut control = first.control_channel().unwrap();
+        for i in 0..10u8 {
+            if i % 3 == 1 {
+                proxy.disconnect();
+            }
+            control.write_all(&[i; 1000]).await?;
+            let mut echo = [0; 1000];
+            control.read_exact(&mut echo).await?;
+            assert_eq!(echo, [i; 1000]);
+        }
+        eyre::Result::<_>::Ok(())
+    };
+
+    let second = async move {
+        let mut control = second.control_channel().unwrap();
+        let mut buffer = [0; 1000];
+        for i in 0..10u8 {
+            control.read_exact(&mut buffer).await?;
+            assert_eq!(buffer, [i; 1000]);
+            control.write_all(&buffer).await?;
+        }
+        eyre::Result::<_>::Ok(())
+    };
+
+    timeout(
+        TIMEOUT,
+        (
+            first,
+            second,
+            async { eyre::Result::<_>::Ok(first_dilation.run().await?) },
+            async { eyre::Result::<_>::Ok(second_dilation.run().await?) },
+        )
+            .try_join(),
+    )
+    .await??;
+    Ok(())
+}
+
 fn generate_random_code() -> Code {
     let mut rng = rand::thread_rng();
     let nameplate_string = format!("{}-guitarist-revenge", rng.gen_range(1000..10000));
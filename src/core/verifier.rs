@@ -0,0 +1,78 @@
+//! Renderings of the [`verifier`](super::Wormhole::verifier) that humans can compare
+
+use super::wordlist::Wordlist;
+
+/// How to show the verifier, so that users can compare it with their peer's
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum VerifierFormat {
+    /// Eight words from the PGP word list, like `topmost istanbul pluto vagabond …`
+    #[default]
+    Words,
+    /// Eight emoji, like `🔒 🐙 🚲 …`
+    Emoji,
+    /// The whole verifier in groups of hexadecimal digits. The Python implementation shows it as one string.
+    Hex,
+}
+
+/* Words and emoji cover only the start of the verifier, which is plenty to make a man in the middle stand out */
+const NUM_WORDS: usize = 8;
+const NUM_EMOJI: usize = 8;
+
+/* Six bits per emoji */
+const EMOJI: [&str; 64] = [
+    "🐶", "🐱", "🦁", "🐎", "🦄", "🐷", "🐘", "🐰", "🐼", "🐓", "🐧", "🐢", "🐟", "🐙", "🦋", "🌷",
+    "🌳", "🌵", "🍄", "🌏", "🌙", "☁️", "🔥", "🍌", "🍎", "🍓", "🌽", "🍕", "🎂", "❤️", "😀", "🤖",
+    "🎩", "👓", "🔧", "🎅", "👍", "☂️", "⌛", "⏰", "🎁", "💡", "📕", "✏️", "📎", "✂️", "🔒", "🔑",
+    "🔨", "☎️", "🏁", "🚂", "🚲", "✈️", "🚀", "🏆", "⚽", "🎸", "🎺", "🔔", "⚓", "🎧", "📁", "📌",
+];
+
+pub(super) fn format_verifier(verifier: &[u8], format: VerifierFormat) -> String {
+    match format {
+        VerifierFormat::Words => Wordlist::default_wordlist(NUM_WORDS)
+            .encode_bytes(&verifier[..NUM_WORDS])
+            .collect::<Vec<_>>()
+            .join(" "),
+        VerifierFormat::Emoji => {
+            let bits = verifier[..NUM_EMOJI * 6 / 8]
+                .iter()
+                .fold(0u64, |bits, byte| (bits << 8) | u64::from(*byte));
+            (0..NUM_EMOJI)
+                .map(|i| EMOJI[(bits >> ((NUM_EMOJI - 1 - i) * 6)) as usize & 0x3f])
+                .collect::<Vec<_>>()
+                .join(" ")
+        },
+        VerifierFormat::Hex => verifier
+            .chunks(2)
+            .map(hex::encode)
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /* The example from the PGP word list */
+    const VERIFIER: [u8; 10] = [0xe5, 0x82, 0x94, 0xf2, 0xe9, 0xa2, 0x27, 0x48, 0x6e, 0x8b];
+
+    #[test]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test::wasm_bindgen_test)]
+    fn test_format_verifier() {
+        assert_eq!(
+            format_verifier(&VERIFIER, VerifierFormat::Words),
+            "topmost istanbul pluto vagabond treadmill pacific brackish dictator"
+        );
+        assert_eq!(
+            format_verifier(&VERIFIER, VerifierFormat::Hex),
+            "e582 94f2 e9a2 2748 6e8b"
+        );
+
+        /* 111001 011000 001010 010100 111100 101110 100110 100010 */
+        assert_eq!(
+            format_verifier(&VERIFIER, VerifierFormat::Emoji),
+            "🎸 🍎 🐧 🌙 ⚓ 🔒 ⌛ 🔧"
+        );
+    }
+}
@@ -96,6 +96,14 @@ impl Wordlist {
         }
     }
 
+    /* Alternate between the two lists like the PGP word list does, starting with the two-syllable words */
+    pub(crate) fn encode_bytes<'a>(&'a self, bytes: &'a [u8]) -> impl Iterator<Item = &'a str> {
+        bytes
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| self.words[1 - i % 2][usize::from(*byte)].as_str())
+    }
+
     pub(crate) fn into_words(self) -> impl Iterator<Item = String> {
         self.words.into_iter().flatten()
     }
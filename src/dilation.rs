@@ -0,0 +1,1009 @@
+//! Long-lived, reconnecting and multiplexed connections between two peers
+//!
+//! A [`Wormhole`] can be "dilated" into a durable connection. Once dilated, both peers
+//! exchange connection hints over the mailbox and connect to each other like with [`transit`].
+//! If that connection breaks, for example because one side switched networks, a new one is
+//! established transparently. No data is lost in the process: every record is acknowledged by the
+//! peer and sent again on the new connection if necessary.
+//!
+//! On top of that connection, any number of [`Subchannel`]s can be opened by either side. Each one
+//! is an independent, reliable byte stream. Additionally, there is always one pre-opened "control
+//! channel".
+//!
+//! This is compatible with the dilation protocol of the Python implementation. The layers are:
+//!
+//! - L2: The encrypted connection, using a Noise handshake with a key derived from the wormhole key.
+//! - L3: The selection of one connection, and reconnecting through messages over the mailbox.
+//! - L4: Records with sequence numbers and acknowledgements.
+//! - L5: Subchannels multiplexed over those records.
+//!
+//! Both sides must [enable dilation](crate::MailboxConnection::enable_dilation) before connecting
+//! the wormhole:
+//!
+//! ```no_run
+//! # fn main() -> eyre::Result<()> { async_io::block_on(async {
+//! use futures::{AsyncReadExt, AsyncWriteExt};
+//! use magic_wormhole::{MailboxConnection, Wormhole, dilation, transit};
+//! # let config: magic_wormhole::AppConfig<()> = unimplemented!();
+//! # let relay_hints = unimplemented!();
+//! let mailbox_connection = MailboxConnection::create(config, 2)
+//!     .await?
+//!     .enable_dilation();
+//! let wormhole = Wormhole::connect(mailbox_connection).await?;
+//! let (mut dilated, dilation) =
+//!     dilation::dilate(wormhole, transit::Abilities::ALL, relay_hints).await?;
+//!
+//! let app = async move {
+//!     let mut subchannel = dilated.open().await?;
+//!     subchannel.write_all(b"Hello").await?;
+//!     subchannel.close().await?;
+//!     eyre::Result::<_>::Ok(())
+//! };
+//! /* The connection only makes progress while `run` is being polled */
+//! futures::try_join!(app, async { Ok(dilation.run().await?) })?;
+//! # Ok(()) })}
+//! ```
+
+use crate::{
+    Wormhole, WormholeError,
+    core::Phase,
+    transit::{
+        self, Abilities, Hints, RelayHint, Transit, TransitConnectError, TransitConnector,
+        TransitError, TransitInfo, TransitKey, TransitRole,
+    },
+};
+use futures::{
+    FutureExt, SinkExt, StreamExt,
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    sink::Sink,
+    stream::BoxStream,
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+mod record;
+mod subchannel;
+
+use record::Record;
+pub use subchannel::Subchannel;
+
+/// The dilation protocol versions we support, in order of preference
+pub(crate) const DILATION_VERSIONS: &[&str] = &["1"];
+
+/// The payload of the last handshake message, which selects the connection. It is the encoding of a
+/// [`Record::KeyConfirmation`].
+pub(crate) const KEY_CONFIRMATION: &[u8] = b"\x00";
+
+/// How often we check if the connection is still alive
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Give up on a connection if we didn't hear anything from the peer for that long
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(75);
+/// How long the leader waits before trying again after connecting failed
+const RECONNECT_BACKOFF: [Duration; 6] = [
+    Duration::ZERO,
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(5),
+    Duration::from_secs(10),
+    Duration::from_secs(30),
+];
+/// Stop accepting data from subchannels while that much of it still needs to be acknowledged
+const MAX_UNACKED_BYTES: usize = 4 * 1024 * 1024;
+/// How many received data records a subchannel queues up, before we stop reading from the connection
+const SUBCHANNEL_QUEUE_SIZE: usize = 16;
+
+/// The subchannel ID of the control channel
+const CONTROL_CHANNEL: u32 = 0;
+
+/// An error occurred on a dilated wormhole
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum DilationError {
+    /// Both sides need to enable dilation when connecting the wormhole
+    #[error("Dilation is not supported by both sides")]
+    Unsupported,
+    /// The dilated connection has been shut down
+    #[error("The dilated connection has been shut down")]
+    Closed,
+    /// A generic string message for "something went wrong", i.e.
+    /// the peer sent some bullshit message order
+    #[error("Protocol error: {}", _0)]
+    Protocol(Box<str>),
+    /// Some deserialization went wrong, we probably got some garbage
+    #[error("Corrupt JSON message received")]
+    ProtocolJson(
+        #[from]
+        #[source]
+        serde_json::Error,
+    ),
+    /// Wormhole connection error
+    #[error("Wormhole connection error")]
+    Wormhole(
+        #[from]
+        #[source]
+        WormholeError,
+    ),
+    /// I/O error
+    #[error("I/O error")]
+    IO(
+        #[from]
+        #[source]
+        std::io::Error,
+    ),
+}
+
+impl DilationError {
+    fn protocol(message: impl Into<Box<str>>) -> Self {
+        Self::Protocol(message.into())
+    }
+}
+
+/* The messages exchanged over the mailbox, each one in its own "dilate-N" phase */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case", tag = "type")]
+enum ManagerMessage {
+    Please {
+        side: String,
+    },
+    ConnectionHints {
+        hints: Hints,
+    },
+    Reconnect,
+    Reconnecting,
+    #[serde(other)]
+    Unknown,
+}
+
+/* What the subchannels and the handle tell the manager, in the order they happened */
+pub(crate) enum Command {
+    Open {
+        scid: u32,
+        inbound: mpsc::Sender<Vec<u8>>,
+    },
+    Data {
+        scid: u32,
+        data: Vec<u8>,
+    },
+    Close {
+        scid: u32,
+    },
+}
+
+/**
+ * Upgrade a wormhole into a dilated one
+ *
+ * Both sides need to call this, after having [enabled](crate::MailboxConnection::enable_dilation)
+ * dilation when connecting. The wormhole is taken over, all further messages over the mailbox
+ * belong to the dilation protocol.
+ *
+ * This returns a handle to open and accept subchannels, and the [`Dilation`] that drives the connection.
+ * The latter must be [run](Dilation::run) concurrently to the usage of the former.
+ */
+pub async fn dilate(
+    mut wormhole: Wormhole,
+    abilities: Abilities,
+    relay_hints: Vec<RelayHint>,
+) -> Result<(DilatedWormhole, Dilation), DilationError> {
+    let their_abilities = *wormhole
+        .peer_dilation_abilities()
+        .ok_or(DilationError::Unsupported)?;
+    let side = wormhole.side().to_owned();
+
+    wormhole
+        .send_with_phase(
+            Phase::dilation(0),
+            &serde_json::to_vec(&ManagerMessage::Please { side: side.clone() })?,
+        )
+        .await?;
+
+    let their_side = loop {
+        let (phase, plaintext) = wormhole.receive_with_phase().await?;
+        match phase.to_dilation_num() {
+            Some(0) => match serde_json::from_slice(&plaintext)? {
+                ManagerMessage::Please { side } => break side,
+                other => bail!(DilationError::protocol(format!(
+                    "Expected 'please', got {other:?}"
+                ))),
+            },
+            Some(_) => bail!(DilationError::protocol(
+                "Received dilation messages out of order"
+            )),
+            None => tracing::warn!(
+                "Ignoring message with phase '{}' while dilating",
+                phase.as_ref()
+            ),
+        }
+    };
+    ensure!(
+        side != their_side,
+        DilationError::protocol("Both sides use the same side ID")
+    );
+
+    let (role, first_scid) = choose_role(&side, &their_side);
+    tracing::debug!("Dilating the wormhole as {:?}", role);
+
+    let (commands_tx, commands_rx) = mpsc::channel(16);
+    let (incoming_tx, incoming_rx) = mpsc::unbounded();
+    let (alive_tx, alive_rx) = oneshot::channel();
+    let (outbound_tx, outbound_rx) = mpsc::unbounded();
+    let (inbound_tx, inbound_rx) = mpsc::unbounded();
+
+    let (control_tx, control_rx) = mpsc::channel(SUBCHANNEL_QUEUE_SIZE);
+    let control = Subchannel::new(CONTROL_CHANNEL, commands_tx.clone(), control_rx);
+
+    let manager = Manager {
+        role,
+        side: Arc::new(side),
+        dilation_key: Arc::new(
+            wormhole
+                .key()
+                .derive_subkey_from_purpose::<TransitKey>("dilation-v1"),
+        ),
+        our_abilities: abilities,
+        their_abilities,
+        relay_hints,
+        mailbox_tx: outbound_tx,
+        mailbox_rx: inbound_rx,
+        commands_tx: commands_tx.clone(),
+        commands_rx,
+        incoming: Some(incoming_tx),
+        alive: alive_rx.fuse(),
+        subchannels: HashMap::from([(
+            CONTROL_CHANNEL,
+            SubchannelState {
+                inbound: Some(control_tx),
+                sent_close: false,
+            },
+        )]),
+        outbound: VecDeque::new(),
+        outbound_bytes: 0,
+        next_seqnum: 0,
+        last_inbound_seqnum: None,
+        stalled: None,
+        connection: Connection::Idle { retry_at: None },
+        connector: None,
+        their_hints: None,
+        awaiting_reconnecting: false,
+        reconnect_attempts: 0,
+        last_received: Instant::now(),
+        next_ping: Instant::now(),
+    };
+
+    Ok((
+        DilatedWormhole {
+            commands: commands_tx,
+            next_scid: first_scid,
+            incoming: incoming_rx,
+            control: Some(control),
+            _alive: alive_tx,
+        },
+        Dilation {
+            wormhole,
+            manager,
+            outbound: outbound_rx,
+            inbound: inbound_tx,
+        },
+    ))
+}
+
+/* The side with the higher ID leads, and the subchannel IDs are split among both.
+ * Like in the Python implementation, the leader uses the odd ones and the follower the even ones.
+ */
+fn choose_role(side: &str, their_side: &str) -> (TransitRole, u32) {
+    if side > their_side {
+        (TransitRole::Leader, 1)
+    } else {
+        (TransitRole::Follower, 2)
+    }
+}
+
+/**
+ * A dilated wormhole, to open and accept [`Subchannel`]s
+ *
+ * Once this and all subchannels are dropped, the dilation will shut down.
+ */
+pub struct DilatedWormhole {
+    commands: mpsc::Sender<Command>,
+    next_scid: u32,
+    incoming: mpsc::UnboundedReceiver<Subchannel>,
+    control: Option<Subchannel>,
+    _alive: oneshot::Sender<std::convert::Infallible>,
+}
+
+impl DilatedWormhole {
+    /// Take the control channel, which is opened implicitly on both sides
+    ///
+    /// Returns `None` if it has already been taken.
+    pub fn control_channel(&mut self) -> Option<Subchannel> {
+        self.control.take()
+    }
+
+    /// Open a new subchannel
+    ///
+    /// This does not wait for the peer, it will learn about the subchannel
+    /// through [`accept`](Self::accept) once it is connected.
+    pub async fn open(&mut self) -> Result<Subchannel, DilationError> {
+        let scid = self.next_scid;
+        self.next_scid += 2;
+        let (inbound_tx, inbound_rx) = mpsc::channel(SUBCHANNEL_QUEUE_SIZE);
+        self.commands
+            .send(Command::Open {
+                scid,
+                inbound: inbound_tx,
+            })
+            .await
+            .map_err(|_| DilationError::Closed)?;
+        Ok(Subchannel::new(scid, self.commands.clone(), inbound_rx))
+    }
+
+    /// Wait for the peer to open a subchannel
+    ///
+    /// Returns `None` once the dilation has shut down.
+    pub async fn accept(&mut self) -> Option<Subchannel> {
+        self.incoming.next().await
+    }
+}
+
+impl std::fmt::Debug for DilatedWormhole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DilatedWormhole")
+            .field("next_scid", &self.next_scid)
+            .finish_non_exhaustive()
+    }
+}
+
+/**
+ * The driver of a dilated wormhole
+ *
+ * It does nothing on its own, you need to [`run`](Self::run) it.
+ */
+pub struct Dilation {
+    wormhole: Wormhole,
+    manager: Manager,
+    /* manager → mailbox */
+    outbound: mpsc::UnboundedReceiver<ManagerMessage>,
+    /* mailbox → manager */
+    inbound: mpsc::UnboundedSender<ManagerMessage>,
+}
+
+impl Dilation {
+    /**
+     * Keep the dilated connection going
+     *
+     * This connects to the peer, and reconnects whenever the connection is lost. It returns
+     * once the [`DilatedWormhole`] and all its subchannels have been dropped and the peer received
+     * all our data. The wormhole is closed afterwards.
+     */
+    pub async fn run(self) -> Result<(), DilationError> {
+        let Self {
+            wormhole,
+            manager,
+            outbound,
+            inbound,
+        } = self;
+        futures::try_join!(mailbox(wormhole, outbound, inbound), manager.run())?;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Dilation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Dilation")
+            .field("role", &self.manager.role)
+            .finish_non_exhaustive()
+    }
+}
+
+/* Exchange the manager's messages over the mailbox, in order */
+async fn mailbox(
+    mut wormhole: Wormhole,
+    mut outbound: mpsc::UnboundedReceiver<ManagerMessage>,
+    inbound: mpsc::UnboundedSender<ManagerMessage>,
+) -> Result<(), DilationError> {
+    use futures::future::Either;
+
+    /* Phase 0 has already been used for the "please" */
+    let mut next_outbound = 1;
+    let mut next_inbound = 1;
+    let mut pending = BTreeMap::new();
+
+    loop {
+        let event =
+            match futures::future::select(outbound.next(), Box::pin(wormhole.receive_with_phase()))
+                .await
+            {
+                Either::Left((message, _)) => Either::Left(message),
+                Either::Right((message, _)) => Either::Right(message?),
+            };
+
+        match event {
+            Either::Left(Some(message)) => {
+                tracing::trace!("Sending dilation message {:?}", message);
+                wormhole
+                    .send_with_phase(
+                        Phase::dilation(next_outbound),
+                        &serde_json::to_vec(&message)?,
+                    )
+                    .await?;
+                next_outbound += 1;
+            },
+            /* The manager is done */
+            Either::Left(None) => break,
+            Either::Right((phase, plaintext)) => {
+                let Some(num) = phase.to_dilation_num() else {
+                    tracing::warn!(
+                        "Ignoring message with phase '{}' on a dilated wormhole",
+                        phase.as_ref()
+                    );
+                    continue;
+                };
+                pending.insert(num, plaintext);
+                while let Some(plaintext) = pending.remove(&next_inbound) {
+                    let message: ManagerMessage = serde_json::from_slice(&plaintext)?;
+                    tracing::trace!("Received dilation message {:?}", message);
+                    /* If the manager is gone, we are about to stop anyways */
+                    let _ = inbound.unbounded_send(message);
+                    next_inbound += 1;
+                }
+            },
+        }
+    }
+
+    wormhole.close().await?;
+    Ok(())
+}
+
+struct SubchannelState {
+    /** `None` once the peer closed the subchannel */
+    inbound: Option<mpsc::Sender<Vec<u8>>>,
+    sent_close: bool,
+}
+
+type RecordSink = Pin<Box<dyn Sink<Box<[u8]>, Error = TransitError> + Send>>;
+
+enum Connection {
+    /** Not connected. The leader tries again once `retry_at` has passed. */
+    Idle {
+        retry_at: Option<Instant>,
+    },
+    Connecting(BoxFuture<'static, Result<(Transit, TransitInfo), TransitConnectError>>),
+    Connected {
+        tx: RecordSink,
+        rx: BoxStream<'static, Result<Box<[u8]>, TransitError>>,
+    },
+}
+
+enum Event {
+    Mailbox(ManagerMessage),
+    Command(Command),
+    HandleDropped,
+    Connected(Result<(Transit, TransitInfo), TransitConnectError>),
+    Record(Option<Result<Box<[u8]>, TransitError>>),
+    Unstalled,
+    Timer,
+}
+
+/* The state machine of the dilated connection */
+struct Manager {
+    role: TransitRole,
+    side: Arc<String>,
+    dilation_key: Arc<crate::Key<TransitKey>>,
+    our_abilities: Abilities,
+    their_abilities: Abilities,
+    relay_hints: Vec<RelayHint>,
+
+    mailbox_tx: mpsc::UnboundedSender<ManagerMessage>,
+    mailbox_rx: mpsc::UnboundedReceiver<ManagerMessage>,
+    /* Handed out to subchannels of the peer */
+    commands_tx: mpsc::Sender<Command>,
+    commands_rx: mpsc::Receiver<Command>,
+    /* `None` once the handle is dropped */
+    incoming: Option<mpsc::UnboundedSender<Subchannel>>,
+    alive: futures::future::Fuse<oneshot::Receiver<std::convert::Infallible>>,
+
+    subchannels: HashMap<u32, SubchannelState>,
+    /* Records the peer did not acknowledge yet */
+    outbound: VecDeque<Record>,
+    outbound_bytes: usize,
+    next_seqnum: u32,
+    last_inbound_seqnum: Option<u32>,
+    /* Data that its subchannel had no room for. No records are read until it has been delivered. */
+    stalled: Option<(u32, Vec<u8>)>,
+
+    connection: Connection,
+    connector: Option<TransitConnector>,
+    their_hints: Option<Hints>,
+    /* The leader asked the follower to reconnect, and ignores its stale hints until it confirms */
+    awaiting_reconnecting: bool,
+    reconnect_attempts: usize,
+    last_received: Instant,
+    next_ping: Instant,
+}
+
+impl Manager {
+    async fn run(mut self) -> Result<(), DilationError> {
+        self.start_generation().await?;
+
+        while !(self.incoming.is_none() && self.subchannels.is_empty() && self.outbound.is_empty())
+        {
+            let event = self.next_event().await?;
+            self.handle_event(event).await?;
+        }
+
+        tracing::debug!("All subchannels are closed, shutting down the dilated connection");
+        if let Connection::Connected { tx, .. } = &mut self.connection {
+            let _ = tx.close().await;
+        }
+        Ok(())
+    }
+
+    async fn next_event(&mut self) -> Result<Event, DilationError> {
+        let deadline = self.next_deadline();
+        let accept_commands = self.outbound_bytes < MAX_UNACKED_BYTES;
+        let Self {
+            mailbox_rx,
+            commands_rx,
+            alive,
+            connection,
+            subchannels,
+            stalled,
+            ..
+        } = self;
+        let stalled = stalled.as_ref().map(|(scid, _)| *scid);
+        let mut mailbox = mailbox_rx.next();
+        let mut alive = alive;
+
+        let timer = async {
+            match deadline {
+                Some(deadline) => {
+                    crate::util::sleep(deadline.saturating_duration_since(Instant::now())).await
+                },
+                None => futures::future::pending().await,
+            }
+        }
+        .fuse();
+        let connection = async {
+            match connection {
+                Connection::Idle { .. } => futures::future::pending().await,
+                Connection::Connecting(connecting) => Event::Connected(connecting.await),
+                Connection::Connected { .. } if stalled.is_some() => {
+                    futures::future::pending().await
+                },
+                Connection::Connected { rx, .. } => Event::Record(rx.next().await),
+            }
+        }
+        .fuse();
+        /* Wait until the stalled subchannel has room again, or is gone */
+        let unstall = async {
+            match stalled {
+                Some(scid) => {
+                    if let Some(inbound) = subchannels
+                        .get_mut(&scid)
+                        .and_then(|state| state.inbound.as_mut())
+                    {
+                        let _ = futures::future::poll_fn(|cx| inbound.poll_ready(cx)).await;
+                    }
+                },
+                None => futures::future::pending().await,
+            }
+        }
+        .fuse();
+        let command = async {
+            match accept_commands {
+                true => commands_rx.next().await,
+                false => futures::future::pending().await,
+            }
+        }
+        .fuse();
+        futures::pin_mut!(timer, connection, unstall, command);
+
+        Ok(futures::select! {
+            message = mailbox => {
+                Event::Mailbox(message.ok_or(DilationError::Closed)?)
+            },
+            /* We always hold a sender ourselves */
+            command = command => Event::Command(command.expect("The command channel cannot close")),
+            _ = alive => Event::HandleDropped,
+            event = connection => event,
+            () = unstall => Event::Unstalled,
+            () = timer => Event::Timer,
+        })
+    }
+
+    async fn handle_event(&mut self, event: Event) -> Result<(), DilationError> {
+        match event {
+            Event::Mailbox(message) => self.handle_message(message).await?,
+            Event::Command(command) => self.handle_command(command).await,
+            Event::HandleDropped => {
+                tracing::debug!("The dilated wormhole has been dropped");
+                self.incoming = None;
+            },
+            Event::Connected(Ok((transit, info))) => {
+                tracing::info!("Dilated connection: {info}");
+                let (tx, rx) = transit.split();
+                self.connection = Connection::Connected {
+                    tx: Box::pin(tx),
+                    rx: rx.boxed(),
+                };
+                self.reconnect_attempts = 0;
+                self.last_received = Instant::now();
+                self.next_ping = Instant::now() + PING_INTERVAL;
+
+                /* Everything that was not acknowledged may have been lost with the old connection */
+                for record in self.outbound.clone() {
+                    if !self.send_record(record).await {
+                        break;
+                    }
+                }
+            },
+            Event::Connected(Err(error)) => {
+                tracing::debug!("Failed to establish the dilated connection: {error}");
+                self.connection_lost();
+            },
+            Event::Record(Some(Ok(plaintext))) => {
+                self.last_received = Instant::now();
+                match Record::decode(&plaintext) {
+                    Ok(record) => self.handle_record(record).await,
+                    Err(error) => {
+                        tracing::warn!("Received an invalid record: {error}");
+                        self.connection_lost();
+                    },
+                }
+            },
+            Event::Record(Some(Err(error))) => {
+                tracing::debug!("Dilated connection failed: {error}");
+                self.connection_lost();
+            },
+            Event::Record(None) => {
+                tracing::debug!("Dilated connection closed by the peer");
+                self.connection_lost();
+            },
+            Event::Unstalled => {
+                /* We didn't read anything in the meantime, so the peer couldn't have been heard */
+                self.last_received = Instant::now();
+                if let Some((scid, data)) = self.stalled.take() {
+                    self.deliver(scid, data);
+                }
+            },
+            Event::Timer => self.handle_timer().await,
+        }
+        Ok(())
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        match &self.connection {
+            Connection::Idle { retry_at } => *retry_at,
+            Connection::Connecting(_) => None,
+            Connection::Connected { .. } if self.stalled.is_some() => Some(self.next_ping),
+            Connection::Connected { .. } => {
+                Some(self.next_ping.min(self.last_received + CONNECTION_TIMEOUT))
+            },
+        }
+    }
+
+    fn send_message(&self, message: ManagerMessage) {
+        /* If the mailbox is gone, we'll notice on the receiving side */
+        let _ = self.mailbox_tx.unbounded_send(message);
+    }
+
+    /* Start a new round of connection attempts, with fresh hints */
+    async fn start_generation(&mut self) -> Result<(), DilationError> {
+        self.connection = Connection::Idle { retry_at: None };
+        let connector = transit::init(
+            self.our_abilities,
+            Some(self.their_abilities),
+            self.relay_hints.clone(),
+        )
+        .await?;
+        self.send_message(ManagerMessage::ConnectionHints {
+            hints: (**connector.our_hints()).clone(),
+        });
+        self.connector = Some(connector);
+        self.maybe_connect();
+        Ok(())
+    }
+
+    /* Start connecting once we have both our and their hints */
+    fn maybe_connect(&mut self) {
+        if !matches!(self.connection, Connection::Idle { .. })
+            || self.connector.is_none()
+            || self.their_hints.is_none()
+        {
+            return;
+        }
+        let connector = self.connector.take().unwrap();
+        let their_hints = self.their_hints.take().unwrap();
+        tracing::debug!("Connecting to the peer");
+        self.connection = Connection::Connecting(
+            connector
+                .dilation_connect(
+                    self.role.clone(),
+                    self.dilation_key.clone(),
+                    self.side.clone(),
+                    self.their_abilities,
+                    Arc::new(their_hints),
+                )
+                .boxed(),
+        );
+    }
+
+    fn connection_lost(&mut self) {
+        tracing::info!("Lost the dilated connection, reconnecting");
+        self.connector = None;
+        self.connection = match self.role {
+            TransitRole::Leader => {
+                let backoff =
+                    RECONNECT_BACKOFF[self.reconnect_attempts.min(RECONNECT_BACKOFF.len() - 1)];
+                self.reconnect_attempts += 1;
+                Connection::Idle {
+                    retry_at: Some(Instant::now() + backoff),
+                }
+            },
+            /* The leader will tell us when to reconnect */
+            TransitRole::Follower => Connection::Idle { retry_at: None },
+        };
+    }
+
+    async fn handle_timer(&mut self) {
+        let now = Instant::now();
+        match &self.connection {
+            Connection::Idle {
+                retry_at: Some(retry_at),
+            } if *retry_at <= now => {
+                tracing::debug!("Asking the peer to reconnect");
+                self.connection = Connection::Idle { retry_at: None };
+                self.their_hints = None;
+                self.awaiting_reconnecting = true;
+                self.send_message(ManagerMessage::Reconnect);
+            },
+            Connection::Connected { .. }
+                if self.stalled.is_none() && self.last_received + CONNECTION_TIMEOUT <= now =>
+            {
+                tracing::debug!("The dilated connection timed out");
+                self.connection_lost();
+            },
+            Connection::Connected { .. } if self.next_ping <= now => {
+                self.next_ping = now + PING_INTERVAL;
+                self.send_record(Record::Ping(rand::random())).await;
+            },
+            _ => {},
+        }
+    }
+
+    async fn handle_message(&mut self, message: ManagerMessage) -> Result<(), DilationError> {
+        match (message, &self.role) {
+            (ManagerMessage::ConnectionHints { .. }, TransitRole::Leader)
+                if self.awaiting_reconnecting =>
+            {
+                tracing::debug!("Ignoring stale connection hints");
+            },
+            (ManagerMessage::ConnectionHints { hints }, _) => {
+                tracing::debug!("Received connection hints: {:?}", hints);
+                match &mut self.their_hints {
+                    Some(their_hints) => {
+                        their_hints.direct_tcp.extend(hints.direct_tcp);
+                        for relay in hints.relay {
+                            relay.merge_into(&mut their_hints.relay);
+                        }
+                    },
+                    None => self.their_hints = Some(hints),
+                }
+                self.maybe_connect();
+            },
+            (ManagerMessage::Reconnect, TransitRole::Follower) => {
+                tracing::debug!("The peer asked us to reconnect");
+                self.their_hints = None;
+                self.send_message(ManagerMessage::Reconnecting);
+                self.start_generation().await?;
+            },
+            (ManagerMessage::Reconnecting, TransitRole::Leader) if self.awaiting_reconnecting => {
+                self.awaiting_reconnecting = false;
+                self.start_generation().await?;
+            },
+            (message, _) => {
+                tracing::warn!("Ignoring unexpected dilation message {:?}", message);
+            },
+        }
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Open { scid, inbound } => {
+                self.subchannels.insert(
+                    scid,
+                    SubchannelState {
+                        inbound: Some(inbound),
+                        sent_close: false,
+                    },
+                );
+                self.queue_record(|seqnum| Record::Open { scid, seqnum })
+                    .await;
+            },
+            Command::Data { scid, data } => {
+                if self
+                    .subchannels
+                    .get(&scid)
+                    .is_some_and(|state| !state.sent_close)
+                {
+                    self.queue_record(|seqnum| Record::Data { scid, seqnum, data })
+                        .await;
+                }
+            },
+            Command::Close { scid } => {
+                if let Some(state) = self.subchannels.get_mut(&scid)
+                    && !state.sent_close
+                {
+                    state.sent_close = true;
+                    self.queue_record(|seqnum| Record::Close { scid, seqnum })
+                        .await;
+                }
+            },
+        }
+    }
+
+    async fn handle_record(&mut self, record: Record) {
+        if let Some(seqnum) = record.seqnum() {
+            self.send_record(Record::Ack {
+                resp_seqnum: seqnum,
+            })
+            .await;
+            /* We might receive records again after reconnecting */
+            if self
+                .last_inbound_seqnum
+                .is_some_and(|last| seqnum_not_after(seqnum, last))
+            {
+                return;
+            }
+            self.last_inbound_seqnum = Some(seqnum);
+        }
+
+        match record {
+            Record::KeyConfirmation => {
+                tracing::debug!("Ignoring superfluous key confirmation");
+            },
+            Record::Ping(ping_id) => {
+                self.send_record(Record::Pong(ping_id)).await;
+            },
+            Record::Pong(_) => {},
+            Record::Ack { resp_seqnum } => {
+                while self
+                    .outbound
+                    .front()
+                    .and_then(Record::seqnum)
+                    .is_some_and(|seqnum| seqnum_not_after(seqnum, resp_seqnum))
+                {
+                    let record = self.outbound.pop_front().unwrap();
+                    self.outbound_bytes -= record_size(&record);
+                }
+            },
+            Record::Open { scid, .. } => {
+                if self.subchannels.contains_key(&scid) {
+                    tracing::warn!("The peer opened subchannel {scid} twice");
+                    return;
+                }
+                let (inbound_tx, inbound_rx) = mpsc::channel(SUBCHANNEL_QUEUE_SIZE);
+                self.subchannels.insert(
+                    scid,
+                    SubchannelState {
+                        inbound: Some(inbound_tx),
+                        sent_close: false,
+                    },
+                );
+                /* If nobody accepts it, it gets dropped and thus closed */
+                let subchannel = Subchannel::new(scid, self.commands_tx.clone(), inbound_rx);
+                if let Some(incoming) = &self.incoming {
+                    let _ = incoming.unbounded_send(subchannel);
+                }
+            },
+            Record::Data { scid, data, .. } => self.deliver(scid, data),
+            Record::Close { scid, .. } => {
+                if let Some(mut state) = self.subchannels.remove(&scid) {
+                    state.inbound = None;
+                    if !state.sent_close {
+                        self.queue_record(|seqnum| Record::Close { scid, seqnum })
+                            .await;
+                    }
+                }
+            },
+        }
+    }
+
+    /* Hand received data to its subchannel, or hold it back until the subchannel has room for it */
+    fn deliver(&mut self, scid: u32, data: Vec<u8>) {
+        let Some(inbound) = self
+            .subchannels
+            .get_mut(&scid)
+            .and_then(|state| state.inbound.as_mut())
+        else {
+            tracing::debug!("Dropping data for closed subchannel {scid}");
+            return;
+        };
+        match inbound.try_send(data) {
+            Ok(()) => {},
+            Err(error) if error.is_full() => {
+                tracing::trace!("Subchannel {scid} is full, pausing the dilated connection");
+                self.stalled = Some((scid, error.into_inner()));
+            },
+            /* The subchannel may have been dropped already, its close is on the way */
+            Err(_) => {},
+        }
+    }
+
+    /* Send a record that needs to be acknowledged */
+    async fn queue_record(&mut self, record: impl FnOnce(u32) -> Record) {
+        let record = record(self.next_seqnum);
+        self.next_seqnum = self.next_seqnum.wrapping_add(1);
+        self.outbound_bytes += record_size(&record);
+        self.outbound.push_back(record.clone());
+        self.send_record(record).await;
+    }
+
+    /* Send a record if we are connected. Returns false if the connection failed. */
+    async fn send_record(&mut self, record: Record) -> bool {
+        let Connection::Connected { tx, .. } = &mut self.connection else {
+            return true;
+        };
+        match tx.send(record.encode()).await {
+            Ok(()) => true,
+            Err(error) => {
+                tracing::debug!("Dilated connection failed: {error}");
+                self.connection_lost();
+                false
+            },
+        }
+    }
+}
+
+/* Whether `seqnum` is `other` or comes before it. Sequence numbers wrap around,
+ * so this uses serial number arithmetic (RFC 1982).
+ */
+fn seqnum_not_after(seqnum: u32, other: u32) -> bool {
+    other.wrapping_sub(seqnum) < 1 << 31
+}
+
+fn record_size(record: &Record) -> usize {
+    match record {
+        Record::Data { data, .. } => data.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /* Python's dilation manager lets the leader open odd subchannels starting at 1,
+     * and the follower even ones starting at 2. The control channel is 0.
+     */
+    #[test]
+    fn test_subchannel_ids() {
+        let (leader, follower) = ("f3c0ffee", "0badc0de");
+
+        let (role, first_scid) = choose_role(leader, follower);
+        assert!(matches!(role, TransitRole::Leader));
+        assert_eq!(first_scid, 1);
+
+        let (role, first_scid) = choose_role(follower, leader);
+        assert!(matches!(role, TransitRole::Follower));
+        assert_eq!(first_scid, 2);
+    }
+
+    #[test]
+    fn test_seqnum_wraparound() {
+        assert!(seqnum_not_after(3, 3));
+        assert!(seqnum_not_after(3, 4));
+        assert!(!seqnum_not_after(4, 3));
+        /* After wrapping around, the new records come after the old ones */
+        assert!(seqnum_not_after(u32::MAX, 0));
+        assert!(seqnum_not_after(u32::MAX - 1, 5));
+        assert!(!seqnum_not_after(0, u32::MAX));
+        assert!(!seqnum_not_after(5, u32::MAX - 1));
+    }
+}
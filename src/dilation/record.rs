@@ -0,0 +1,173 @@
+//! The records exchanged over a dilated connection (L4)
+//!
+//! Every record is encrypted into one Noise message. The first byte denotes the type,
+//! all numbers are encoded as four bytes in big endian.
+
+use super::DilationError;
+
+/// A (decrypted) record on the dilated connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Record {
+    /// The key confirmation message which selects a connection. It is part of the handshake.
+    KeyConfirmation,
+    /// Check whether the connection is still alive
+    Ping([u8; 4]),
+    /// Reply to a [`Record::Ping`] with the same ID
+    Pong([u8; 4]),
+    /// Open a new subchannel
+    Open { scid: u32, seqnum: u32 },
+    /// Payload for a subchannel
+    Data {
+        scid: u32,
+        seqnum: u32,
+        data: Vec<u8>,
+    },
+    /// Close a subchannel
+    Close { scid: u32, seqnum: u32 },
+    /// Acknowledge the reception of the record with that sequence number
+    Ack { resp_seqnum: u32 },
+}
+
+impl Record {
+    /// The sequence number, for the records that need to be acknowledged
+    pub fn seqnum(&self) -> Option<u32> {
+        match self {
+            Self::Open { seqnum, .. } | Self::Data { seqnum, .. } | Self::Close { seqnum, .. } => {
+                Some(*seqnum)
+            },
+            _ => None,
+        }
+    }
+
+    pub fn encode(&self) -> Box<[u8]> {
+        let mut buffer = Vec::with_capacity(9);
+        match self {
+            Self::KeyConfirmation => buffer.push(0x00),
+            Self::Ping(ping_id) => {
+                buffer.push(0x01);
+                buffer.extend_from_slice(ping_id);
+            },
+            Self::Pong(ping_id) => {
+                buffer.push(0x02);
+                buffer.extend_from_slice(ping_id);
+            },
+            Self::Open { scid, seqnum } => {
+                buffer.push(0x03);
+                buffer.extend_from_slice(&scid.to_be_bytes());
+                buffer.extend_from_slice(&seqnum.to_be_bytes());
+            },
+            Self::Data { scid, seqnum, data } => {
+                buffer.reserve(data.len());
+                buffer.push(0x04);
+                buffer.extend_from_slice(&scid.to_be_bytes());
+                buffer.extend_from_slice(&seqnum.to_be_bytes());
+                buffer.extend_from_slice(data);
+            },
+            Self::Close { scid, seqnum } => {
+                buffer.push(0x05);
+                buffer.extend_from_slice(&scid.to_be_bytes());
+                buffer.extend_from_slice(&seqnum.to_be_bytes());
+            },
+            Self::Ack { resp_seqnum } => {
+                buffer.push(0x06);
+                buffer.extend_from_slice(&resp_seqnum.to_be_bytes());
+            },
+        }
+        buffer.into_boxed_slice()
+    }
+
+    pub fn decode(plaintext: &[u8]) -> Result<Self, DilationError> {
+        fn be4(bytes: &[u8], offset: usize) -> Result<[u8; 4], DilationError> {
+            bytes
+                .get(offset..offset + 4)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| DilationError::protocol("Record is too short"))
+        }
+        fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, DilationError> {
+            be4(bytes, offset).map(u32::from_be_bytes)
+        }
+
+        let (&kind, body) = plaintext
+            .split_first()
+            .ok_or_else(|| DilationError::protocol("Received an empty record"))?;
+        Ok(match kind {
+            0x00 => Self::KeyConfirmation,
+            0x01 => Self::Ping(be4(body, 0)?),
+            0x02 => Self::Pong(be4(body, 0)?),
+            /* Newer versions may append a subprotocol name to the `Open`, which we don't support yet */
+            0x03 => Self::Open {
+                scid: u32_at(body, 0)?,
+                seqnum: u32_at(body, 4)?,
+            },
+            0x04 => Self::Data {
+                scid: u32_at(body, 0)?,
+                seqnum: u32_at(body, 4)?,
+                data: body[8..].to_vec(),
+            },
+            0x05 => Self::Close {
+                scid: u32_at(body, 0)?,
+                seqnum: u32_at(body, 4)?,
+            },
+            0x06 => Self::Ack {
+                resp_seqnum: u32_at(body, 0)?,
+            },
+            other => {
+                return Err(DilationError::protocol(format!(
+                    "Unknown record type {other:#04x}"
+                )));
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_encoding() {
+        let records = [
+            (Record::KeyConfirmation, &b"\x00"[..]),
+            (Record::Ping(*b"ping"), b"\x01ping"),
+            (Record::Pong(*b"pong"), b"\x02pong"),
+            (
+                Record::Open { scid: 1, seqnum: 2 },
+                b"\x03\x00\x00\x00\x01\x00\x00\x00\x02",
+            ),
+            (
+                Record::Data {
+                    scid: 258,
+                    seqnum: 3,
+                    data: b"hello".to_vec(),
+                },
+                b"\x04\x00\x00\x01\x02\x00\x00\x00\x03hello",
+            ),
+            (
+                Record::Close { scid: 4, seqnum: 5 },
+                b"\x05\x00\x00\x00\x04\x00\x00\x00\x05",
+            ),
+            (Record::Ack { resp_seqnum: 6 }, b"\x06\x00\x00\x00\x06"),
+        ];
+
+        for (record, encoded) in records {
+            assert_eq!(&*record.encode(), encoded);
+            assert_eq!(Record::decode(encoded).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_record_decoding_errors() {
+        assert!(Record::decode(b"").is_err());
+        assert!(Record::decode(b"\x07").is_err());
+        assert!(Record::decode(b"\x03\x00\x00\x00\x01\x00").is_err());
+        assert!(Record::decode(b"\x06\x00").is_err());
+        assert_eq!(
+            Record::decode(b"\x04\x00\x00\x00\x01\x00\x00\x00\x02").unwrap(),
+            Record::Data {
+                scid: 1,
+                seqnum: 2,
+                data: Vec::new()
+            },
+        );
+    }
+}
@@ -0,0 +1,150 @@
+//! Subchannels multiplexed over the dilated connection (L5)
+
+use super::Command;
+use futures::{
+    channel::mpsc,
+    io::{AsyncRead, AsyncWrite},
+    stream::StreamExt,
+};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+/// The maximum size of a single data record. Larger writes are split up.
+const MAX_DATA_LEN: usize = 32 * 1024;
+
+/**
+ * A bidirectional byte stream within a dilated wormhole
+ *
+ * Subchannels survive reconnections of the dilated connection: data that was written
+ * but not yet acknowledged by the peer is sent again once a new connection has been
+ * established. Close a subchannel (or drop it) once you are done.
+ */
+pub struct Subchannel {
+    scid: u32,
+    commands: mpsc::Sender<Command>,
+    inbound: mpsc::Receiver<Vec<u8>>,
+    /** Received data that did not fit into the reader's buffer yet */
+    buffer: Vec<u8>,
+    offset: usize,
+    closed: bool,
+}
+
+impl Subchannel {
+    pub(super) fn new(
+        scid: u32,
+        commands: mpsc::Sender<Command>,
+        inbound: mpsc::Receiver<Vec<u8>>,
+    ) -> Self {
+        Self {
+            scid,
+            commands,
+            inbound,
+            buffer: Vec::new(),
+            offset: 0,
+            closed: false,
+        }
+    }
+
+    /// The subchannel ID, which is unique within the dilated wormhole
+    pub fn id(&self) -> u32 {
+        self.scid
+    }
+}
+
+impl std::fmt::Debug for Subchannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Subchannel")
+            .field("scid", &self.scid)
+            .field("closed", &self.closed)
+            .finish_non_exhaustive()
+    }
+}
+
+fn connection_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "The dilated connection has been shut down",
+    )
+}
+
+impl AsyncRead for Subchannel {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.offset >= this.buffer.len() {
+            match ready!(this.inbound.poll_next_unpin(cx)) {
+                Some(data) => {
+                    this.buffer = data;
+                    this.offset = 0;
+                },
+                /* The peer closed the subchannel */
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+
+        let len = buf.len().min(this.buffer.len() - this.offset);
+        buf[..len].copy_from_slice(&this.buffer[this.offset..][..len]);
+        this.offset += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for Subchannel {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.closed {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "The subchannel has been closed",
+            )));
+        }
+        ready!(self.commands.poll_ready(cx)).map_err(|_| connection_closed())?;
+
+        let len = buf.len().min(MAX_DATA_LEN);
+        let command = Command::Data {
+            scid: self.scid,
+            data: buf[..len].to_vec(),
+        };
+        self.commands
+            .start_send(command)
+            .map_err(|_| connection_closed())?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        /* Everything is handed over to the connection immediately */
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.closed {
+            if ready!(self.commands.poll_ready(cx)).is_ok() {
+                let scid = self.scid;
+                let _ = self.commands.start_send(Command::Close { scid });
+            }
+            self.closed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Subchannel {
+    fn drop(&mut self) {
+        if !self.closed {
+            /* A fresh sender always has room for one message */
+            let _ = self
+                .commands
+                .clone()
+                .try_send(Command::Close { scid: self.scid });
+        }
+    }
+}
@@ -1,7 +1,7 @@
-//! Client-to-Client protocol to forward TCP connections
+//! Client-to-Client protocol to forward TCP connections and UDP traffic
 //!
-//! This is a new (and still slightly experimental feature) that allows you to forward TCP connections over a wormhole
-//! `transit` connection.
+//! This is a new (and still slightly experimental feature) that allows you to forward TCP connections and UDP traffic
+//! over a wormhole `transit` connection.
 //!
 //! It is bound to an [`APPID`], which is distinct to the one used for file transfer. Therefore, the codes used
 //! for port forwarding are in an independent namespace than those for sending files.
@@ -10,8 +10,22 @@
 //! They are used to set up a [`transit`] portal that will be used instead of the wormhole connection, which will be closed.
 //! Connections are tracked via an identifier, and multiplexed over the transit channel. The forwarding is
 //! "logical" and not "raw"; because "TCP in TCP" tunneling is known to be problematic. Packages are sent
-//! and received as they come in, no additional buffering is applied. (Under the assumption that those applications
-//! that need buffering already do it on their side, and those who don't, don't.)
+//! and received as they come in, no additional buffering beyond the flow control window is applied. (Under the
+//! assumption that those applications that need buffering already do it on their side, and those who don't, don't.)
+//!
+//! On unix, local unix sockets can be forwarded as well. Their addresses in the offer carry a `unix:` prefix.
+//! The connecting side may expose them as unix sockets or as TCP ports.
+//!
+//! Every stream connection has a window of bytes that its sender may have in flight. The receiver hands out
+//! more as it writes the data, so that a slow connection holds up neither the transit nor the other connections.
+//! This flow control is one of the optional [`Features`] as well.
+//!
+//! Instead of fixed targets, the serving side may offer to be the exit of a SOCKS5 proxy, see [`Target::Socks`].
+//! The connecting side then runs the proxy, and every connection to it goes to the destination that the client asked for.
+//!
+//! UDP is forwarded datagram by datagram. On the connecting side, every source address is tracked as a
+//! pseudo-connection, which expires after some time without traffic. UDP forwarding is one of the optional [`Features`]
+//! that both sides need to support.
 
 use crate::transit::TransitRole;
 
@@ -22,11 +36,16 @@ use serde_derive::{Deserialize, Serialize};
 use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
+    net::SocketAddr,
     rc::Rc,
     sync::Arc,
+    time::{Duration, Instant},
 };
 use transit::{TransitConnectError, TransitError};
 
+mod socks;
+pub use socks::{ParseSocksRuleError, SocksPolicy, SocksRule};
+
 const APPID_RAW: &str = "piegames.de/wormhole/port-forwarding";
 
 /// The App ID associated with this protocol.
@@ -41,10 +60,36 @@ pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersi
     rendezvous_url: Cow::Borrowed(crate::rendezvous::DEFAULT_RENDEZVOUS_SERVER),
     app_version: AppVersion {
         transit_abilities: transit::Abilities::ALL,
+        features: Features::ALL,
         other: serde_json::Value::Null,
     },
 };
 
+/* UDP pseudo-connections without traffic in either direction are closed after this time */
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const UDP_EXPIRY_INTERVAL: Duration = Duration::from_secs(10);
+/* Large enough for any UDP datagram */
+const MAX_DATAGRAM_SIZE: usize = 65535;
+/* How many datagrams may wait for a UDP target to be connected or to catch up, further ones are dropped */
+const UDP_QUEUE_SIZE: usize = 64;
+/* Connection IDs are chosen by the side that opens the connection. The serving side,
+ * which only opens connections for reverse forwarding, uses the upper half of the range.
+ */
+const SERVE_CONNECTION_IDS: u64 = 1 << 63;
+/* How many bytes of a stream connection may be in flight until the peer hands out more */
+const FLOW_CONTROL_WINDOW: usize = 256 * 1024;
+/* Hand out more bytes in batches, so that not every write needs its own window update */
+const WINDOW_UPDATE_THRESHOLD: usize = FLOW_CONTROL_WINDOW / 4;
+/* How long to wait for the peer to hang up after closing the session */
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/* The address of a SOCKS proxy in the offer. Its connections go to `socks:HOST:PORT` */
+const SOCKS_ADDRESS: &str = "socks";
+/* SOCKS clients that take longer than this for the handshake are dropped */
+const SOCKS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/* How many SOCKS handshakes may happen at the same time */
+const SOCKS_CONCURRENT_HANDSHAKES: usize = 16;
+const UNSPECIFIED_ADDRESS: std::net::IpAddr = std::net::IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED);
+
 /**
  * The application specific version information for this protocol.
  */
@@ -52,10 +97,119 @@ pub const APP_CONFIG: crate::AppConfig<AppVersion> = crate::AppConfig::<AppVersi
 pub struct AppVersion {
     /// Our transit abilities
     pub transit_abilities: transit::Abilities,
+    /// Our optional protocol features
+    #[serde(default)]
+    pub features: Features,
     #[serde(flatten)]
     other: serde_json::Value,
 }
 
+/**
+ * Optional features of the forwarding protocol
+ *
+ * A feature is only used if both sides support it. Peers that don't know about features support none of them.
+ */
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+#[non_exhaustive]
+pub struct Features {
+    /** Forward UDP traffic, see [`Target::Udp`] */
+    pub udp: bool,
+    /** Per-connection flow control for stream connections, with window updates */
+    pub flow_control: bool,
+    /** Act as the exit of a SOCKS5 proxy, see [`Target::Socks`] */
+    pub socks: bool,
+}
+
+impl Features {
+    /// All features that we support
+    pub const ALL: Self = Self {
+        udp: true,
+        flow_control: true,
+        socks: true,
+    };
+
+    /// None of the features, only TCP forwarding
+    pub const NONE: Self = Self {
+        udp: false,
+        flow_control: false,
+        socks: false,
+    };
+
+    /// Keep only features that both sides support
+    pub fn intersect(mut self, other: &Self) -> Self {
+        self.udp &= other.udp;
+        self.flow_control &= other.flow_control;
+        self.socks &= other.socks;
+        self
+    }
+}
+
+/**
+ * A service that can be forwarded to the peer
+ *
+ * If no host is provided, then a local port will be forwarded (`localhost`).
+ * Tuples of host and port convert into TCP targets.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Target {
+    /// A TCP service
+    Tcp(Option<url::Host>, u16),
+    /// A UDP service, like DNS or WireGuard
+    Udp(Option<url::Host>, u16),
+    /// A local unix socket, like the one of Docker or of an ssh-agent
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+    /// The exit of a SOCKS5 proxy, which the peer runs on its side. Its clients may connect to every TCP
+    /// service that we can reach and that the policy allows.
+    Socks(SocksPolicy),
+}
+
+impl Target {
+    /* The address of the target in the offer, like `example.com:80`, `8080` or `udp:53` */
+    fn address(&self) -> String {
+        match self {
+            Target::Tcp(Some(host), port) => format!("{host}:{port}"),
+            Target::Tcp(None, port) => port.to_string(),
+            Target::Udp(Some(host), port) => format!("udp:{host}:{port}"),
+            Target::Udp(None, port) => format!("udp:{port}"),
+            #[cfg(unix)]
+            Target::Unix(path) => format!("unix:{}", path.display()),
+            Target::Socks(_) => SOCKS_ADDRESS.to_owned(),
+        }
+    }
+
+    /* Where to actually connect to */
+    fn socket_address(&self) -> String {
+        match self {
+            Target::Tcp(Some(host), port) | Target::Udp(Some(host), port) => {
+                format!("{host}:{port}")
+            },
+            Target::Tcp(None, port) | Target::Udp(None, port) => format!("[::1]:{port}"),
+            #[cfg(unix)]
+            Target::Unix(path) => path.display().to_string(),
+            Target::Socks(_) => SOCKS_ADDRESS.to_owned(),
+        }
+    }
+}
+
+impl From<(Option<url::Host>, u16)> for Target {
+    fn from((host, port): (Option<url::Host>, u16)) -> Self {
+        Target::Tcp(host, port)
+    }
+}
+
+/* Offered addresses of UDP targets carry a prefix */
+fn is_udp_address(address: &str) -> bool {
+    address.starts_with("udp:")
+}
+
+/* The path of an offered unix socket target */
+fn unix_socket_path(address: &str) -> Option<&std::path::Path> {
+    address.strip_prefix("unix:").map(std::path::Path::new)
+}
+
 #[derive(Debug, thiserror::Error)]
 #[non_exhaustive]
 /// An error occurred when establishing a port forwarding session
@@ -136,19 +290,77 @@ impl ForwardingError {
 
 /// Offer to forward some ports
 ///
-/// `targets` is a mapping of (host, port) pairs. If no target host is provided, then
+/// `targets` is a list of [`Target`]s, or of (host, port) pairs for TCP. If no target host is provided, then
 /// a local port will be forwarded (`localhost`). Forwarding remote ports only works well
 /// when the protocol being forwarded is not host-aware. HTTP, for example, is host aware.
+/// UDP targets are only offered if the peer supports [`Features::udp`], and a [`Target::Socks`] proxy only
+/// if it supports [`Features::socks`].
+///
+/// `rate_limit` limits the bandwidth of the transit connection to that many bytes per second, in each direction.
 ///
 /// The port forwarding will run until an error occurs, the peer terminates the connection
 /// or `cancel` resolves. The last one can be used to provide timeouts or to inject CTRL-C
 /// handling. If you want the forward to never (successfully) stop, pass [`futures::future::pending()`]
 /// as the value.
 pub async fn serve(
+    wormhole: Wormhole,
+    transit_handler: impl FnOnce(transit::TransitInfo),
+    relay_hints: Vec<transit::RelayHint>,
+    rate_limit: Option<u64>,
+    targets: impl IntoIterator<Item = impl Into<Target>>,
+    cancel: impl Future<Output = ()>,
+) -> Result<(), ForwardingError> {
+    serve_inner(
+        wormhole,
+        transit_handler,
+        relay_hints,
+        rate_limit,
+        targets.into_iter().map(Into::into).collect(),
+        None,
+        cancel,
+    )
+    .await
+}
+
+/// Offer to forward some ports, and accept the services the peer offers in return
+///
+/// This works like [`serve`], but the peer may also offer some of its own services, see
+/// [`ConnectOffer::with_reverse_targets`]. Like `ssh -R`, we then listen on some arbitrary
+/// ports on `bind_address` and forward every connection to the peer. The resulting mapping
+/// from our local ports to the peer's targets is passed to `reverse_handler`.
+pub async fn serve_with_reverse(
+    wormhole: Wormhole,
+    transit_handler: impl FnOnce(transit::TransitInfo),
+    relay_hints: Vec<transit::RelayHint>,
+    rate_limit: Option<u64>,
+    targets: impl IntoIterator<Item = impl Into<Target>>,
+    bind_address: Option<std::net::IpAddr>,
+    reverse_handler: impl FnOnce(&[(u16, String)]) + Send + 'static,
+    cancel: impl Future<Output = ()>,
+) -> Result<(), ForwardingError> {
+    let reverse = Reverse {
+        bind_address: bind_address.unwrap_or(UNSPECIFIED_ADDRESS),
+        handler: Box::new(reverse_handler),
+    };
+    serve_inner(
+        wormhole,
+        transit_handler,
+        relay_hints,
+        rate_limit,
+        targets.into_iter().map(Into::into).collect(),
+        Some(reverse),
+        cancel,
+    )
+    .await
+}
+
+async fn serve_inner(
     mut wormhole: Wormhole,
     transit_handler: impl FnOnce(transit::TransitInfo),
     relay_hints: Vec<transit::RelayHint>,
-    targets: Vec<(Option<url::Host>, u16)>,
+    rate_limit: Option<u64>,
+    targets: Vec<Target>,
+    reverse: Option<Reverse>,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), ForwardingError> {
     assert!(
@@ -161,12 +373,14 @@ pub async fn serve(
         .downcast_ref()
         .expect("You may only use a Wormhole instance with the correct AppVersion type!");
     let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
+    let features = our_version.features.intersect(&peer_version.features);
     let connector = transit::init(
         our_version.transit_abilities,
         Some(peer_version.transit_abilities),
         relay_hints,
     )
-    .await?;
+    .await?
+    .with_rate_limit(rate_limit);
 
     /* Send our transit hints */
     wormhole
@@ -175,18 +389,14 @@ pub async fn serve(
         })
         .await?;
 
-    let targets: HashMap<String, (Option<url::Host>, u16)> = targets
-        .into_iter()
-        .map(|(host, port)| match host {
-            Some(host) => {
-                if port == 80 || port == 443 || port == 8000 || port == 8080 {
-                    tracing::warn!("It seems like you are trying to forward a remote HTTP target ('{}'). Due to HTTP being host-aware this will very likely fail!", host);
-                }
-                (format!("{host}:{port}"), (Some(host), port))
-            },
-            None => (port.to_string(), (host, port)),
-        })
-        .collect();
+    let targets = offer_targets(targets, features);
+    if targets.is_empty() {
+        let error = ForwardingError::protocol("None of the targets can be offered to the peer");
+        let _ = wormhole
+            .send_json(&PeerMessage::Error(format!("{error}")))
+            .await;
+        bail!(error);
+    }
 
     /* Receive their transit hints */
     let their_hints: transit::Hints = match wormhole.receive_json().await?? {
@@ -233,14 +443,12 @@ pub async fn serve(
         .send_record(
             &PeerMessage::Offer {
                 addresses: targets.keys().cloned().collect(),
+                accept_reverse: reverse.is_some(),
             }
             .ser_msgpack(),
         )
         .await?;
 
-    let (backchannel_tx, backchannel_rx) =
-        futures::channel::mpsc::channel::<(u64, Option<Vec<u8>>)>(20);
-
     let (transit_tx, transit_rx) = transit.split();
     let transit_rx = transit_rx.fuse();
     use futures::future::FutureExt;
@@ -250,19 +458,14 @@ pub async fn serve(
     futures::pin_mut!(cancel);
 
     /* Main processing loop. Catch errors */
-    let result = ForwardingServe {
-        targets,
-        connections: HashMap::new(),
-        historic_connections: HashSet::new(),
-        backchannel_tx,
-        backchannel_rx,
-    }
-    .run(&mut transit_tx, &mut transit_rx, &mut cancel)
-    .await;
+    let result = Forwarding::new(targets, features, SERVE_CONNECTION_IDS, reverse)
+        .run(&mut transit_tx, &mut transit_rx, &mut cancel)
+        .await;
     /* If the error is not a PeerError (i.e. coming from the other side), try notifying the other side before quitting. */
     match result {
         Ok(()) => Ok(()),
-        Err(error @ ForwardingError::PeerError(_)) => Err(error),
+        /* Don't bother the peer with its own errors, and don't use a broken transit */
+        Err(error @ (ForwardingError::PeerError(_) | ForwardingError::Transit(_))) => Err(error),
         Err(error) => {
             let _ = transit_tx
                 .send(
@@ -276,254 +479,191 @@ pub async fn serve(
     }
 }
 
-struct ForwardingServe {
-    targets: HashMap<String, (Option<url::Host>, u16)>,
-    /* self => remote */
-    connections: HashMap<
-        u64,
-        (
-            async_task::Task<()>,
-            futures_lite::io::WriteHalf<async_net::TcpStream>,
-        ),
-    >,
-    /* Track old connection IDs that won't be reused again. This is to distinguish race hazards where
-     * one side closes a connection while the other one accesses it simultaneously. Despite the name, the
-     * set also includes connections that are currently live.
-     */
-    historic_connections: HashSet<u64>,
-    /* remote => self. (connection_id, Some=payload or None=close) */
-    backchannel_tx: futures::channel::mpsc::Sender<(u64, Option<Vec<u8>>)>,
-    backchannel_rx: futures::channel::mpsc::Receiver<(u64, Option<Vec<u8>>)>,
-}
-
-//futures::pin_mut!(backchannel_rx);
-impl ForwardingServe {
-    async fn forward(
-        &mut self,
-        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
-        connection_id: u64,
-        payload: &[u8],
-    ) -> Result<(), ForwardingError> {
-        tracing::debug!("Forwarding {} bytes from #{}", payload.len(), connection_id);
-        match self.connections.get_mut(&connection_id) {
-            Some((_worker, connection)) => {
-                /* On an error, log for the user and then terminate that connection */
-                if let Err(e) = connection.write_all(payload).await {
-                    tracing::warn!("Forwarding to #{} failed: {}", connection_id, e);
-                    self.remove_connection(transit_tx, connection_id, true)
-                        .await?;
-                }
+/* Index the targets by their address in the offer, and drop those that the peer doesn't support */
+fn offer_targets(targets: Vec<Target>, features: Features) -> HashMap<String, Target> {
+    targets
+        .into_iter()
+        .filter(|target| match target {
+            Target::Udp(..) if !features.udp => {
+                tracing::warn!(
+                    "Not offering '{}', because UDP forwarding is not supported by both sides",
+                    target.address()
+                );
+                false
             },
-            None if !self.historic_connections.contains(&connection_id) => {
-                bail!(ForwardingError::protocol(format!(
-                    "Connection '{connection_id}' not found"
-                )));
+            Target::Socks(_) if !features.socks => {
+                tracing::warn!("Not offering a SOCKS proxy, because it is not supported by both sides");
+                false
             },
-            None => { /* Race hazard. Do nothing. */ },
-        }
-        Ok(())
-    }
+            _ => true,
+        })
+        .inspect(|target| {
+            if let Target::Tcp(Some(host), 80 | 443 | 8000 | 8080) = target {
+                tracing::warn!("It seems like you are trying to forward a remote HTTP target ('{}'). Due to HTTP being host-aware this will very likely fail!", host);
+            }
+        })
+        .map(|target| (target.address(), target))
+        .collect()
+}
 
-    async fn remove_connection(
-        &mut self,
-        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
-        connection_id: u64,
-        tell_peer: bool,
-    ) -> Result<(), ForwardingError> {
-        tracing::debug!("Removing connection: #{}", connection_id);
-        if tell_peer {
-            transit_tx
-                .send(
-                    PeerMessage::Disconnect { connection_id }
-                        .ser_msgpack()
-                        .into_boxed_slice(),
-                )
-                .await?;
-        }
-        match self.connections.remove(&connection_id) {
-            Some((worker, _connection)) => {
-                worker.cancel().await;
-            },
-            None if !self.historic_connections.contains(&connection_id) => {
-                bail!(ForwardingError::protocol(format!(
-                    "Connection '{connection_id}' not found"
-                )));
-            },
-            None => { /* Race hazard. Do nothing. */ },
-        }
-        Ok(())
-    }
+/* The local end of a forwarded connection, to write what comes from the peer */
+enum Connection {
+    Stream(Box<dyn futures::AsyncWrite + Send + Unpin>),
+    /* A shared socket together with the source address of the pseudo-connection */
+    Udp(async_net::UdpSocket, SocketAddr),
+}
 
-    async fn spawn_connection(
-        &mut self,
-        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
-        mut target: String,
-        connection_id: u64,
-    ) -> Result<(), ForwardingError> {
-        tracing::debug!("Creating new connection: #{} -> {}", connection_id, target);
+/* The local end of a forwarded connection, to read what goes to the peer */
+enum Reader {
+    Stream(Box<dyn futures::AsyncRead + Send + Unpin>),
+    Udp(async_net::UdpSocket),
+}
 
-        use std::collections::hash_map::Entry;
-        let entry = match self.connections.entry(connection_id) {
-            Entry::Vacant(entry) => entry,
-            Entry::Occupied(_) => {
-                bail!(ForwardingError::protocol(format!(
-                    "Connection '{connection_id}' already exists"
-                )));
-            },
-        };
+/* Both ends of a TCP or unix socket connection */
+fn split_stream(
+    stream: impl futures::AsyncRead + futures::AsyncWrite + Send + Unpin + 'static,
+) -> (Reader, Connection) {
+    let (reader, writer) = futures_lite::io::split(stream);
+    (
+        Reader::Stream(Box::new(reader)),
+        Connection::Stream(Box::new(writer)),
+    )
+}
 
-        let (host, port) = self.targets.get(&target).unwrap();
-        if host.is_none() {
-            target = format!("[::1]:{port}");
-        }
-        let stream = match async_net::TcpStream::connect(&target).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                tracing::warn!(
-                    "Cannot open connection to {}: {}. The forwarded service might be down.",
-                    target,
-                    err
-                );
-                transit_tx
-                    .send(
-                        PeerMessage::Disconnect { connection_id }
-                            .ser_msgpack()
-                            .into_boxed_slice(),
-                    )
-                    .await?;
-                return Ok(());
-            },
+/* Pass everything that is read from a connection on to the backchannel, until it closes.
+ * With flow control, only read as much as the peer allows, and wait for the credit it hands out.
+ */
+fn spawn_worker(
+    connection_id: u64,
+    mut reader: Reader,
+    mut credit: Option<futures::channel::mpsc::UnboundedReceiver<usize>>,
+    mut backchannel_tx: futures::channel::mpsc::Sender<(u64, Option<Vec<u8>>)>,
+) -> async_task::Task<()> {
+    crate::util::spawn(async move {
+        let mut buffer = match reader {
+            Reader::Stream(_) => vec![0; 4096],
+            /* Every read is one whole datagram */
+            Reader::Udp(_) => vec![0; MAX_DATAGRAM_SIZE],
         };
-        let (mut connection_rd, connection_wr) = futures_lite::io::split(stream);
-        let mut backchannel_tx = self.backchannel_tx.clone();
-        let worker = crate::util::spawn(async move {
-            let mut buffer = vec![0; 4096];
-            /* Ignore errors */
-            macro_rules! break_on_err {
-                ($expr:expr_2021) => {
-                    match $expr {
-                        Ok(val) => val,
-                        Err(_) => break,
-                    }
-                };
-            }
-            #[expect(clippy::while_let_loop)]
-            loop {
-                let read = break_on_err!(connection_rd.read(&mut buffer).await);
-                if read == 0 {
-                    break;
+        let mut window = FLOW_CONTROL_WINDOW;
+        /* Ignore errors */
+        macro_rules! break_on_err {
+            ($expr:expr_2021) => {
+                match $expr {
+                    Ok(val) => val,
+                    Err(_) => break,
                 }
-                let buffer = &buffer[..read];
-                break_on_err!(
-                    backchannel_tx
-                        .send((connection_id, Some(buffer.to_vec())))
-                        .await
-                );
-            }
-            /* Close connection (maybe or not because of error) */
-            let _ = backchannel_tx.send((connection_id, None)).await;
-            backchannel_tx.disconnect();
-        });
-        entry.insert((worker, connection_wr));
-        Ok(())
-    }
-
-    async fn shutdown(self) {
-        tracing::debug!("Shutting down everything");
-        for (worker, _connection) in self.connections.into_values() {
-            worker.cancel().await;
+            };
         }
-    }
-
-    async fn run(
-        mut self,
-        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
-        transit_rx: &mut (
-                 impl futures::stream::FusedStream<Item = Result<Box<[u8]>, TransitError>> + Unpin
-             ),
-        cancel: &mut (impl futures::future::FusedFuture<Output = ()> + Unpin),
-    ) -> Result<(), ForwardingError> {
-        /* Event processing loop */
-        tracing::debug!("Entered processing loop");
-        let ret = loop {
-            futures::select! {
-                message = transit_rx.next() => {
-                    match PeerMessage::de_msgpack(&message.unwrap()?)? {
-                        PeerMessage::Forward { connection_id, payload } => {
-                            self.forward(transit_tx, connection_id, &payload).await?
-                        },
-                        PeerMessage::Connect { target, connection_id } => {
-                            /* No matter what happens, as soon as we receive the "connect" command that ID is burned. */
-                            self.historic_connections.insert(connection_id);
-                            ensure!(
-                                self.targets.contains_key(&target),
-                                ForwardingError::protocol(format!("We don't know forwarding target '{target}'")),
-                            );
-
-                            self.spawn_connection(transit_tx, target, connection_id).await?;
-                        },
-                        PeerMessage::Disconnect { connection_id } => {
-                            self.remove_connection(transit_tx, connection_id, false).await?;
-                        },
-                        PeerMessage::Close => {
-                            tracing::info!("Peer gracefully closed connection");
-                            self.shutdown().await;
-                            break Ok(());
-                        },
-                        PeerMessage::Error(err) => {
-                            self.shutdown().await;
-                            bail!(ForwardingError::PeerError(err));
-                        },
-                        other => {
-                            self.shutdown().await;
-                            bail!(ForwardingError::unexpected_message("connect' or 'disconnect' or 'forward' or 'close", other));
-                        },
+        loop {
+            let mut limit = buffer.len();
+            if let Some(credit) = &mut credit {
+                while let Ok(bytes) = credit.try_recv() {
+                    window = window.saturating_add(bytes);
+                }
+                if window == 0 {
+                    match credit.next().await {
+                        Some(bytes) => window = window.saturating_add(bytes),
+                        None => break,
                     }
-                },
-                message = self.backchannel_rx.next() => {
-                    /* This channel will never run dry, since we always have at least one sender active */
-                    match message.unwrap() {
-                        (connection_id, Some(payload)) => {
-                            transit_tx.send(
-                                PeerMessage::Forward {
-                                    connection_id,
-                                    payload
-                                }
-                                .ser_msgpack()
-                                .into_boxed_slice()
-                            ).await?;
-                        },
-                        (connection_id, None) => {
-                            self.remove_connection(transit_tx, connection_id, true).await?;
-                        },
+                }
+                limit = limit.min(window);
+            }
+            let read = match &mut reader {
+                Reader::Stream(connection) => {
+                    let read = break_on_err!(connection.read(&mut buffer[..limit]).await);
+                    if read == 0 {
+                        break;
                     }
+                    read
                 },
-                /* We are done */
-                () = &mut *cancel => {
-                    tracing::info!("Closing connection");
-                    transit_tx.send(
-                        PeerMessage::Close.ser_msgpack()
-                        .into_boxed_slice()
-                    )
-                    .await?;
-                    transit_tx.close().await?;
-                    self.shutdown().await;
-                    break Ok(());
-                },
+                Reader::Udp(socket) => break_on_err!(socket.recv(&mut buffer).await),
+            };
+            if credit.is_some() {
+                window -= read;
             }
-        };
-        tracing::debug!("Exited processing loop");
-        ret
+            let buffer = &buffer[..read];
+            break_on_err!(
+                backchannel_tx
+                    .send((connection_id, Some(buffer.to_vec())))
+                    .await
+            );
+        }
+        /* Close connection (maybe or not because of error) */
+        let _ = backchannel_tx.send((connection_id, None)).await;
+        backchannel_tx.disconnect();
+    })
+}
+
+/* Write everything that the peer sends on a stream connection, and report how many bytes were written,
+ * or None on an error. Once the queue is closed, the remaining data is still written.
+ */
+fn spawn_writer(
+    connection_id: u64,
+    mut writer: Box<dyn futures::AsyncWrite + Send + Unpin>,
+    mut queue: futures::channel::mpsc::UnboundedReceiver<Vec<u8>>,
+    written_tx: futures::channel::mpsc::UnboundedSender<(u64, Option<usize>)>,
+) -> async_task::Task<()> {
+    crate::util::spawn(async move {
+        while let Some(payload) = queue.next().await {
+            /* On an error, log for the user and then terminate that connection */
+            if let Err(e) = writer.write_all(&payload).await {
+                tracing::warn!("Forwarding to #{} failed: {}", connection_id, e);
+                let _ = written_tx.unbounded_send((connection_id, None));
+                return;
+            }
+            let _ = written_tx.unbounded_send((connection_id, Some(payload.len())));
+        }
+        let _ = writer.close().await;
+    })
+}
+
+fn warn_connection_failed(address: &str, err: &std::io::Error) {
+    if err.kind() == std::io::ErrorKind::PermissionDenied {
+        tracing::warn!("Cannot open connection to {}: {}", address, err);
+    } else {
+        tracing::warn!(
+            "Cannot open connection to {}: {}. The forwarded service might be down.",
+            address,
+            err
+        );
     }
 }
 
+/* Open a local UDP socket that only talks to the target */
+async fn connect_udp(target: &str) -> std::io::Result<async_net::UdpSocket> {
+    let address = async_net::resolve(target)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "No address found"))?;
+    let unspecified: std::net::IpAddr = match address {
+        SocketAddr::V4(_) => std::net::Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => std::net::Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = async_net::UdpSocket::bind((unspecified, 0)).await?;
+    socket.connect(address).await?;
+    Ok(socket)
+}
+
 /// Request a port forwarding offer from the other side
 ///
 /// You can optionally specify a `bind_address` where the port forwarding
 /// will be made available. You can also specify a list of `custom_ports` that
 /// will be used for the forwarding. The mapping between custom ports and forwarded
 /// targets is 1:1 and order preserving. If more ports are forwarded than custom
-/// ports were specified, then the remaining ports will be arbitrary.
+/// ports were specified, then the remaining ports will be arbitrary. UDP targets,
+/// which are prefixed with `udp:` in the mapping, get a UDP port.
+///
+/// Unix socket targets, which are prefixed with `unix:`, get a TCP port as well unless
+/// a `unix_socket_dir` is given. Then they are made available as unix sockets with the same
+/// file name in that directory, which don't take any of the custom ports. The sockets are
+/// removed again once the forwarding ends. This is only supported on unix.
+///
+/// If the peer offers to be the exit of a SOCKS proxy, which is `socks` in the mapping, then a SOCKS5
+/// proxy without authentication runs on its port. The peer decides which destinations may be reached.
+///
+/// `rate_limit` limits the bandwidth of the transit connection to that many bytes per second, in each direction.
 ///
 /// The method returns a [`ConnectOffer`] from which the resulting port mapping can
 /// be queried. That struct also has an `accept` and `reject` method, of which one
@@ -535,21 +675,25 @@ pub async fn connect(
     mut wormhole: Wormhole,
     transit_handler: impl FnOnce(transit::TransitInfo),
     relay_hints: Vec<transit::RelayHint>,
+    rate_limit: Option<u64>,
     bind_address: Option<std::net::IpAddr>,
     custom_ports: &[u16],
+    unix_socket_dir: Option<&std::path::Path>,
 ) -> Result<ConnectOffer, ForwardingError> {
     let our_version: &AppVersion = wormhole
         .our_version()
         .downcast_ref()
         .expect("You may only use a Wormhole instance with the correct AppVersion type!");
     let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
+    let features = our_version.features.intersect(&peer_version.features);
     let connector = transit::init(
         our_version.transit_abilities,
         Some(peer_version.transit_abilities),
         relay_hints,
     )
-    .await?;
-    let bind_address = bind_address.unwrap_or_else(|| std::net::IpAddr::V6("::".parse().unwrap()));
+    .await?
+    .with_rate_limit(rate_limit);
+    let bind_address = bind_address.unwrap_or(UNSPECIFIED_ADDRESS);
 
     /* Send our transit hints */
     wormhole
@@ -602,50 +746,63 @@ pub async fn connect(
     let run = async {
         /* Receive offer and ask user */
 
-        let addresses = match PeerMessage::de_msgpack(&transit.receive_record().await?)? {
-            PeerMessage::Offer { addresses } => addresses,
-            PeerMessage::Error(err) => {
-                bail!(ForwardingError::PeerError(err));
-            },
-            other => {
-                bail!(ForwardingError::unexpected_message("offer", other))
-            },
-        };
+        let (addresses, accepts_reverse) =
+            match PeerMessage::de_msgpack(&transit.receive_record().await?)? {
+                PeerMessage::Offer {
+                    addresses,
+                    accept_reverse,
+                } => (addresses, accept_reverse),
+                PeerMessage::Error(err) => {
+                    bail!(ForwardingError::PeerError(err));
+                },
+                other => {
+                    bail!(ForwardingError::unexpected_message("offer", other))
+                },
+            };
 
         /* Sanity check on untrusted input */
-        if addresses.len() > 1024 {
-            return Err(ForwardingError::protocol("Too many forwarded ports"));
+        check_offered_addresses(&addresses, features)?;
+
+        /* self => remote */
+        let mut custom_ports = custom_ports.iter().copied();
+        let mut mapping = Vec::new();
+        let mut unix_mapping = Vec::new();
+        let mut listeners = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let listener = match unix_socket_path(&address).zip(unix_socket_dir) {
+                #[cfg(unix)]
+                Some((path, directory)) => {
+                    let Some(file_name) = path.file_name() else {
+                        bail!(ForwardingError::protocol(format!(
+                            "Invalid unix socket target '{address}'"
+                        )));
+                    };
+                    let path = directory.join(file_name);
+                    let listener = async_net::unix::UnixListener::bind(&path)?;
+                    unix_mapping.push((path.clone(), Rc::new(address.clone())));
+                    Listener::Unix(listener, SocketFile(path))
+                },
+                _ => {
+                    let port = custom_ports.next().unwrap_or(0);
+                    let (listener, port) = bind(&address, bind_address, port).await?;
+                    mapping.push((port, Rc::new(address.clone())));
+                    listener
+                },
+            };
+            listeners.push((listener, Arc::new(address)));
         }
-
-        /* self => remote
-         *                  (address, connection)
-         * Vec<Stream<Item = (String, TcpStream)>>
-         */
-        let listeners: Vec<(
-            async_net::TcpListener,
-            u16,
-            std::rc::Rc<std::string::String>,
-        )> = futures::stream::iter(
-            addresses
-                .into_iter()
-                .map(Rc::new)
-                .zip(custom_ports.iter().copied().chain(std::iter::repeat(0))),
-        )
-        .then(|(address, port)| async move {
-            let connection = TcpListener::bind((bind_address, port)).await?;
-            let port = connection.local_addr()?.port();
-            Result::<_, std::io::Error>::Ok((connection, port, address))
-        })
-        .try_collect()
-        .await?;
-        Ok(listeners)
-    };
+        Ok((mapping, unix_mapping, listeners, accepts_reverse))
+    };
 
     match run.await {
-        Ok(listeners) => Ok(ConnectOffer {
+        Ok((mapping, unix_mapping, listeners, accepts_reverse)) => Ok(ConnectOffer {
+            mapping,
+            unix_mapping,
             transit,
-            mapping: listeners.iter().map(|(_, b, c)| (*b, c.clone())).collect(),
             listeners,
+            features,
+            accepts_reverse,
+            reverse_targets: Vec::new(),
         }),
         Err(error @ ForwardingError::PeerError(_)) => Err(error),
         Err(error) => {
@@ -657,6 +814,48 @@ pub async fn connect(
     }
 }
 
+/* Don't trust the addresses in an offer */
+fn check_offered_addresses(
+    addresses: &[String],
+    features: Features,
+) -> Result<(), ForwardingError> {
+    if addresses.len() > 1024 {
+        return Err(ForwardingError::protocol("Too many forwarded ports"));
+    }
+    if !features.udp && addresses.iter().any(|address| is_udp_address(address)) {
+        return Err(ForwardingError::protocol(
+            "Got offered UDP targets, but UDP forwarding is disabled",
+        ));
+    }
+    if !features.socks && addresses.iter().any(|address| address == SOCKS_ADDRESS) {
+        return Err(ForwardingError::protocol(
+            "Got offered a SOCKS proxy, but SOCKS is disabled",
+        ));
+    }
+    Ok(())
+}
+
+/* Bind a TCP or UDP port for an offered address, or one for a SOCKS proxy */
+async fn bind(
+    address: &str,
+    bind_address: std::net::IpAddr,
+    port: u16,
+) -> std::io::Result<(Listener, u16)> {
+    if is_udp_address(address) {
+        let socket = async_net::UdpSocket::bind((bind_address, port)).await?;
+        let port = socket.local_addr()?.port();
+        Ok((Listener::Udp(socket), port))
+    } else if address == SOCKS_ADDRESS {
+        let listener = TcpListener::bind((bind_address, port)).await?;
+        let port = listener.local_addr()?.port();
+        Ok((Listener::Socks(listener), port))
+    } else {
+        let listener = TcpListener::bind((bind_address, port)).await?;
+        let port = listener.local_addr()?.port();
+        Ok((Listener::Tcp(listener), port))
+    }
+}
+
 /// A pending forwarding offer from the other side
 ///
 /// You *should* consume this object, either by calling [`accept`](ConnectOffer::accept) or [`reject`](ConnectOffer::reject).
@@ -664,15 +863,129 @@ pub async fn connect(
 pub struct ConnectOffer {
     /// The offered port mapping
     pub mapping: Vec<(u16, Rc<String>)>,
+    /// The offered unix sockets that are made available as unix sockets, see [`connect`]
+    pub unix_mapping: Vec<(std::path::PathBuf, Rc<String>)>,
     transit: transit::Transit,
-    listeners: Vec<(
-        async_net::TcpListener,
-        u16,
-        std::rc::Rc<std::string::String>,
-    )>,
+    listeners: Vec<(Listener, Arc<String>)>,
+    features: Features,
+    accepts_reverse: bool,
+    reverse_targets: Vec<Target>,
+}
+
+enum Listener {
+    Tcp(async_net::TcpListener),
+    Udp(async_net::UdpSocket),
+    #[cfg(unix)]
+    Unix(async_net::unix::UnixListener, SocketFile),
+    Socks(async_net::TcpListener),
+}
+
+/* Removes the file of a unix socket we created, once we don't listen anymore */
+#[cfg(unix)]
+struct SocketFile(std::path::PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/* What arrives on a listener */
+enum Incoming {
+    Stream(Reader, Connection),
+    /* A datagram, the socket it arrived on and where it came from */
+    Udp(async_net::UdpSocket, SocketAddr, Vec<u8>),
+}
+
+type IncomingStream =
+    futures::stream::BoxStream<'static, Result<(Arc<String>, Incoming), std::io::Error>>;
+
+/* Everything that arrives on a listener for the target at `address` */
+fn listen(listener: Listener, address: Arc<String>) -> IncomingStream {
+    match listener {
+        Listener::Tcp(listener) => futures_lite::stream::unfold(listener, |listener| async move {
+            let res = listener.accept().await.map(|(stream, _)| {
+                let (reader, connection) = split_stream(stream);
+                Incoming::Stream(reader, connection)
+            });
+            Some((res, listener))
+        })
+        .map_ok(move |incoming| (address.clone(), incoming))
+        .boxed(),
+        #[cfg(unix)]
+        Listener::Unix(listener, file) => {
+            futures_lite::stream::unfold((listener, file), |(listener, file)| async move {
+                let res = listener.accept().await.map(|(stream, _)| {
+                    let (reader, connection) = split_stream(stream);
+                    Incoming::Stream(reader, connection)
+                });
+                Some((res, (listener, file)))
+            })
+            .map_ok(move |incoming| (address.clone(), incoming))
+            .boxed()
+        },
+        /* Every connection goes to the destination that the client asks for in the handshake */
+        Listener::Socks(listener) => futures_lite::stream::unfold(listener, |listener| async move {
+            Some((listener.accept().await, listener))
+        })
+        .map_ok(|(mut stream, _)| async move {
+            /* A client that doesn't get the handshake right is not worth an error */
+            match crate::util::timeout(SOCKS_HANDSHAKE_TIMEOUT, socks::handshake(&mut stream))
+                .await
+            {
+                Ok(Ok((host, port))) => {
+                    let (reader, connection) = split_stream(stream);
+                    Ok(Some((
+                        Arc::new(format!("{SOCKS_ADDRESS}:{host}:{port}")),
+                        Incoming::Stream(reader, connection),
+                    )))
+                },
+                Ok(Err(err)) => {
+                    tracing::warn!("SOCKS handshake failed: {}", err);
+                    Ok(None)
+                },
+                Err(_) => {
+                    tracing::warn!("SOCKS handshake timed out");
+                    Ok(None)
+                },
+            }
+        })
+        .try_buffer_unordered(SOCKS_CONCURRENT_HANDSHAKES)
+        .try_filter_map(|incoming| async { Ok(incoming) })
+        .boxed(),
+        Listener::Udp(socket) => futures_lite::stream::unfold(socket, |socket| async move {
+            let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+            /* Some platforms report ICMP errors of earlier datagrams here. They only concern one source. */
+            let (read, source) = loop {
+                match socket.recv_from(&mut buffer).await {
+                    Ok(received) => break received,
+                    Err(err) => tracing::debug!("Receiving a datagram failed: {}", err),
+                }
+            };
+            buffer.truncate(read);
+            Some((Ok(Incoming::Udp(socket.clone(), source, buffer)), socket))
+        })
+        .map_ok(move |incoming| (address.clone(), incoming))
+        .boxed(),
+    }
 }
 
 impl ConnectOffer {
+    /// Offer some of our own services to the peer in return, like `ssh -R`
+    ///
+    /// The peer then listens for connections to them and forwards them to us. This only works if
+    /// the peer uses [`serve_with_reverse`], otherwise [`accept`](ConnectOffer::accept) fails.
+    /// The targets work like the ones of [`serve`].
+    pub fn with_reverse_targets(
+        mut self,
+        targets: impl IntoIterator<Item = impl Into<Target>>,
+    ) -> Self {
+        self.reverse_targets
+            .extend(targets.into_iter().map(Into::into));
+        self
+    }
+
     /// Accept the offer and start the forwarding
     ///
     /// The method will run until an error occurs, the peer terminates the connection
@@ -690,33 +1003,37 @@ impl ConnectOffer {
 
         /* Error handling catcher (see below) */
         let run = async {
-            let (backchannel_tx, backchannel_rx) =
-                futures::channel::mpsc::channel::<(u64, Option<Vec<u8>>)>(20);
-
-            let incoming_listeners = self.listeners.into_iter().map(|(connection, _, address)| {
-                Box::pin(
-                    futures_lite::stream::unfold(connection, |listener| async move {
-                        let res = listener.accept().await.map(|(stream, _)| stream);
-                        Some((res, listener))
-                    })
-                    .map_ok(move |stream| (address.clone(), stream)),
-                )
-            });
+            let targets = offer_targets(self.reverse_targets, self.features);
+            if !targets.is_empty() {
+                ensure!(
+                    self.accepts_reverse,
+                    ForwardingError::protocol("The peer does not accept reverse forwarding")
+                );
+                transit_tx
+                    .send(
+                        PeerMessage::ReverseOffer {
+                            addresses: targets.keys().cloned().collect(),
+                        }
+                        .ser_msgpack()
+                        .into_boxed_slice(),
+                    )
+                    .await?;
+            }
 
-            ForwardConnect {
-                incoming: futures::stream::select_all(incoming_listeners),
-                connection_counter: 0,
-                connections: HashMap::new(),
-                backchannel_tx,
-                backchannel_rx,
+            let mut forwarding = Forwarding::new(targets, self.features, 0, None);
+            for (listener, address) in self.listeners {
+                forwarding.incoming.push(listen(listener, address));
             }
-            .run(&mut transit_tx, &mut transit_rx, &mut cancel)
-            .await
+            forwarding
+                .run(&mut transit_tx, &mut transit_rx, &mut cancel)
+                .await
         };
 
         match run.await {
             Ok(()) => Ok(()),
-            Err(error @ ForwardingError::PeerError(_)) => Err(error),
+            Err(error @ (ForwardingError::PeerError(_) | ForwardingError::Transit(_))) => {
+                Err(error)
+            },
             Err(error) => {
                 let _ = transit_tx
                     .send(
@@ -742,57 +1059,393 @@ impl ConnectOffer {
     }
 }
 
-struct ForwardConnect<I> {
-    //transit: &'a mut transit::Transit,
-    /* when can I finally store an `impl Trait` in a struct? */
-    incoming: I,
+type ReverseHandler = dyn FnOnce(&[(u16, String)]) + Send;
+
+/* Where to listen for the services of a reverse offer, and whom to tell about it */
+struct Reverse {
+    bind_address: std::net::IpAddr,
+    handler: Box<ReverseHandler>,
+}
+
+/* A connection of a forwarding session */
+struct ForwardedConnection {
+    /* Reads from the local end. UDP pseudo-connections of our listeners share the listening socket instead */
+    worker: Option<async_task::Task<()>>,
+    writer: Writer,
+}
+
+/* Where to put what the peer sends on a connection */
+enum Writer {
+    /* Streams are written by a task of their own, so that a slow one doesn't hold up the others */
+    Stream {
+        queue: futures::channel::mpsc::UnboundedSender<Vec<u8>>,
+        /* Hands out more bytes to the worker, with flow control */
+        credit: Option<futures::channel::mpsc::UnboundedSender<usize>>,
+        /* Bytes that are queued, but not written yet */
+        buffered: usize,
+        /* Bytes that are written, but not handed out to the peer again yet */
+        unannounced: usize,
+    },
+    /* Datagrams to a local client are sent right away */
+    Udp(async_net::UdpSocket, SocketAddr),
+    /* Datagrams to a target are sent by a task of their own, which first has to connect to it */
+    UdpQueue(futures::channel::mpsc::Sender<Vec<u8>>),
+}
+
+/* The state of a forwarding session. Both sides may offer targets, and listen for the targets that the other side offered. */
+struct Forwarding {
+    /* What we offered, the peer connects to these */
+    targets: HashMap<String, Target>,
+    /* Connections to the targets that the peer offered */
+    incoming: futures::stream::SelectAll<IncomingStream>,
+    features: Features,
+    /* Only on the serving side, until the peer sent its reverse offer */
+    reverse: Option<Reverse>,
     /* Our next unique connection_id */
     connection_counter: u64,
-    connections: HashMap<
-        u64,
-        (
-            async_task::Task<()>,
-            futures_lite::io::WriteHalf<async_net::TcpStream>,
-        ),
-    >,
+    connections: HashMap<u64, ForwardedConnection>,
+    /* Track old connection IDs of the peer that won't be reused again. This is to distinguish race hazards where
+     * one side closes a connection while the other one accesses it simultaneously. Despite the name, the
+     * set also includes connections that are currently live.
+     */
+    historic_connections: HashSet<u64>,
+    /* The UDP pseudo-connection for each target and source address */
+    udp_sessions: HashMap<(Arc<String>, SocketAddr), u64>,
+    /* When the UDP pseudo-connections had traffic the last time */
+    udp_activity: HashMap<u64, Instant>,
     /* application => self. (connection_id, Some=payload or None=close) */
     backchannel_tx: futures::channel::mpsc::Sender<(u64, Option<Vec<u8>>)>,
     backchannel_rx: futures::channel::mpsc::Receiver<(u64, Option<Vec<u8>>)>,
+    /* writers => self. (connection_id, Some=bytes written or None=failed) */
+    written_tx: futures::channel::mpsc::UnboundedSender<(u64, Option<usize>)>,
+    written_rx: futures::channel::mpsc::UnboundedReceiver<(u64, Option<usize>)>,
 }
 
-impl<I> ForwardConnect<I>
-where
-    I: Unpin
-        + futures::stream::FusedStream<
-            Item = Result<(Rc<String>, async_net::TcpStream), std::io::Error>,
-        >,
-{
+impl Forwarding {
+    fn new(
+        targets: HashMap<String, Target>,
+        features: Features,
+        first_connection_id: u64,
+        reverse: Option<Reverse>,
+    ) -> Self {
+        let (backchannel_tx, backchannel_rx) =
+            futures::channel::mpsc::channel::<(u64, Option<Vec<u8>>)>(20);
+        let (written_tx, written_rx) = futures::channel::mpsc::unbounded();
+        Self {
+            targets,
+            incoming: futures::stream::SelectAll::new(),
+            features,
+            reverse,
+            connection_counter: first_connection_id,
+            connections: HashMap::new(),
+            historic_connections: HashSet::new(),
+            udp_sessions: HashMap::new(),
+            udp_activity: HashMap::new(),
+            backchannel_tx,
+            backchannel_rx,
+            written_tx,
+            written_rx,
+        }
+    }
+
+    /* Whether we opened that connection, see SERVE_CONNECTION_IDS */
+    fn is_ours(&self, connection_id: u64) -> bool {
+        (connection_id ^ self.connection_counter) & SERVE_CONNECTION_IDS == 0
+    }
+
+    /* Whether the connection exists or existed once */
+    fn is_known(&self, connection_id: u64) -> bool {
+        if self.is_ours(connection_id) {
+            connection_id < self.connection_counter
+        } else {
+            self.historic_connections.contains(&connection_id)
+        }
+    }
+
     async fn forward(
         &mut self,
         transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
         connection_id: u64,
-        payload: &[u8],
+        payload: Vec<u8>,
     ) -> Result<(), ForwardingError> {
         tracing::debug!("Forwarding {} bytes from #{}", payload.len(), connection_id);
+        let known = self.is_known(connection_id);
+        let flow_control = self.features.flow_control;
         match self.connections.get_mut(&connection_id) {
-            Some((_worker, connection)) => {
+            Some(ForwardedConnection {
+                writer:
+                    Writer::Stream {
+                        queue,
+                        buffered,
+                        unannounced,
+                        ..
+                    },
+                ..
+            }) => {
+                ensure!(
+                    !flow_control
+                        || *buffered + *unannounced + payload.len() <= FLOW_CONTROL_WINDOW,
+                    ForwardingError::protocol(format!(
+                        "Connection '{connection_id}' exceeded its flow control window"
+                    )),
+                );
+                *buffered += payload.len();
+                /* If the writer failed, the connection gets removed soon anyways */
+                let _ = queue.unbounded_send(payload);
+            },
+            Some(ForwardedConnection {
+                writer: Writer::Udp(socket, source),
+                ..
+            }) => {
+                if let Some(activity) = self.udp_activity.get_mut(&connection_id) {
+                    *activity = Instant::now();
+                }
                 /* On an error, log for the user and then terminate that connection */
-                if let Err(e) = connection.write_all(payload).await {
+                if let Err(e) = socket.send_to(&payload, *source).await {
                     tracing::warn!("Forwarding to #{} failed: {}", connection_id, e);
                     self.remove_connection(transit_tx, connection_id, true)
                         .await?;
                 }
             },
-            None if self.connection_counter <= connection_id => {
+            Some(ForwardedConnection {
+                writer: Writer::UdpQueue(queue),
+                ..
+            }) => {
+                /* Like UDP itself, drop datagrams if the target can't keep up */
+                let _ = queue.try_send(payload);
+            },
+            None if !known => {
                 bail!(ForwardingError::protocol(format!(
                     "Connection '{connection_id}' not found"
                 )));
             },
             None => { /* Race hazard. Do nothing. */ },
         }
+        /* Without flow control the peer doesn't wait for us, so stop reading from the transit until the connection caught up */
+        while !flow_control && self.buffered(connection_id) > FLOW_CONTROL_WINDOW {
+            /* This channel will never run dry, since we always have a sender */
+            let (connection_id, written) = self.written_rx.next().await.unwrap();
+            self.written(transit_tx, connection_id, written).await?;
+        }
         Ok(())
     }
 
+    /* Bytes that the peer sent on a connection, which are not written yet */
+    fn buffered(&self, connection_id: u64) -> usize {
+        match self.connections.get(&connection_id) {
+            Some(ForwardedConnection {
+                writer: Writer::Stream { buffered, .. },
+                ..
+            }) => *buffered,
+            _ => 0,
+        }
+    }
+
+    /* A writer made progress. With flow control, hand the bytes out to the peer again */
+    async fn written(
+        &mut self,
+        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
+        connection_id: u64,
+        written: Option<usize>,
+    ) -> Result<(), ForwardingError> {
+        let Some(written) = written else {
+            /* The writer failed, unless the connection is gone already */
+            if self.connections.contains_key(&connection_id) {
+                self.remove_connection(transit_tx, connection_id, true)
+                    .await?;
+            }
+            return Ok(());
+        };
+        let Some(ForwardedConnection {
+            writer:
+                Writer::Stream {
+                    buffered,
+                    unannounced,
+                    ..
+                },
+            ..
+        }) = self.connections.get_mut(&connection_id)
+        else {
+            /* The connection is gone already */
+            return Ok(());
+        };
+        *buffered -= written;
+        if self.features.flow_control {
+            *unannounced += written;
+            if *unannounced >= WINDOW_UPDATE_THRESHOLD {
+                let bytes = std::mem::take(unannounced) as u64;
+                transit_tx
+                    .send(
+                        PeerMessage::WindowUpdate {
+                            connection_id,
+                            bytes,
+                        }
+                        .ser_msgpack()
+                        .into_boxed_slice(),
+                    )
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /* The peer allows us to send more bytes on a connection */
+    fn grant(&mut self, connection_id: u64, bytes: u64) -> Result<(), ForwardingError> {
+        match self.connections.get(&connection_id) {
+            Some(ForwardedConnection {
+                writer:
+                    Writer::Stream {
+                        credit: Some(credit),
+                        ..
+                    },
+                ..
+            }) => {
+                /* If the worker is done, it doesn't need it anymore */
+                let _ = credit.unbounded_send(usize::try_from(bytes).unwrap_or(usize::MAX));
+            },
+            Some(_) => {
+                bail!(ForwardingError::protocol(format!(
+                    "Connection '{connection_id}' is not flow controlled"
+                )));
+            },
+            None if !self.is_known(connection_id) => {
+                bail!(ForwardingError::protocol(format!(
+                    "Connection '{connection_id}' not found"
+                )));
+            },
+            None => { /* Race hazard. Do nothing. */ },
+        }
+        Ok(())
+    }
+
+    /* Start forwarding a connection in both directions */
+    fn insert_connection(
+        &mut self,
+        connection_id: u64,
+        reader: Option<Reader>,
+        connection: Connection,
+    ) {
+        let flow_control =
+            self.features.flow_control && matches!(connection, Connection::Stream(_));
+        let (credit, credit_rx) = flow_control.then(futures::channel::mpsc::unbounded).unzip();
+        let worker = reader.map(|reader| {
+            spawn_worker(
+                connection_id,
+                reader,
+                credit_rx,
+                self.backchannel_tx.clone(),
+            )
+        });
+        let writer = match connection {
+            Connection::Stream(stream) => {
+                let (queue, queue_rx) = futures::channel::mpsc::unbounded();
+                /* Once the connection is removed, the writer finishes what is queued and closes it */
+                spawn_writer(connection_id, stream, queue_rx, self.written_tx.clone()).detach();
+                Writer::Stream {
+                    queue,
+                    credit,
+                    buffered: 0,
+                    unannounced: 0,
+                }
+            },
+            Connection::Udp(socket, source) => Writer::Udp(socket, source),
+        };
+        self.connections
+            .insert(connection_id, ForwardedConnection { worker, writer });
+    }
+
+    /* Like `insert_connection`, but for a stream that is still being connected. Until then, the peer's
+     * data is queued up. If connecting fails, the worker closes the connection like any other.
+     */
+    fn insert_pending_connection<S>(
+        &mut self,
+        connection_id: u64,
+        connect: impl Future<Output = std::io::Result<S>> + Send + 'static,
+        address: String,
+    ) where
+        S: futures::AsyncRead + futures::AsyncWrite + Send + Unpin + 'static,
+    {
+        let (credit, credit_rx) = self
+            .features
+            .flow_control
+            .then(futures::channel::mpsc::unbounded)
+            .unzip();
+        let (queue, queue_rx) = futures::channel::mpsc::unbounded();
+        let mut backchannel_tx = self.backchannel_tx.clone();
+        let written_tx = self.written_tx.clone();
+        let worker = crate::util::spawn(async move {
+            match connect.await {
+                Ok(stream) => {
+                    let (reader, writer) = futures_lite::io::split(stream);
+                    spawn_writer(connection_id, Box::new(writer), queue_rx, written_tx).detach();
+                    spawn_worker(
+                        connection_id,
+                        Reader::Stream(Box::new(reader)),
+                        credit_rx,
+                        backchannel_tx,
+                    )
+                    .await;
+                },
+                Err(err) => {
+                    warn_connection_failed(&address, &err);
+                    let _ = backchannel_tx.send((connection_id, None)).await;
+                },
+            }
+        });
+        self.connections.insert(
+            connection_id,
+            ForwardedConnection {
+                worker: Some(worker),
+                writer: Writer::Stream {
+                    queue,
+                    credit,
+                    buffered: 0,
+                    unannounced: 0,
+                },
+            },
+        );
+    }
+
+    /* Like `insert_pending_connection`, but for a UDP target. Datagrams from the peer are queued until
+     * the socket is connected.
+     */
+    fn insert_pending_udp_connection(&mut self, connection_id: u64, address: String) {
+        let (queue, mut queue_rx) = futures::channel::mpsc::channel::<Vec<u8>>(UDP_QUEUE_SIZE);
+        let mut backchannel_tx = self.backchannel_tx.clone();
+        let worker = crate::util::spawn(async move {
+            let socket = match connect_udp(&address).await {
+                Ok(socket) => socket,
+                Err(err) => {
+                    warn_connection_failed(&address, &err);
+                    let _ = backchannel_tx.send((connection_id, None)).await;
+                    return;
+                },
+            };
+            let reader = spawn_worker(
+                connection_id,
+                Reader::Udp(socket.clone()),
+                None,
+                backchannel_tx.clone(),
+            );
+            while let Some(datagram) = queue_rx.next().await {
+                /* On an error, log for the user and then terminate that connection */
+                if let Err(e) = socket.send(&datagram).await {
+                    tracing::warn!("Forwarding to #{} failed: {}", connection_id, e);
+                    break;
+                }
+            }
+            reader.cancel().await;
+            let _ = backchannel_tx.send((connection_id, None)).await;
+        });
+        self.connections.insert(
+            connection_id,
+            ForwardedConnection {
+                worker: Some(worker),
+                writer: Writer::UdpQueue(queue),
+            },
+        );
+    }
+
     async fn remove_connection(
         &mut self,
         transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
@@ -809,11 +1462,18 @@ where
                 )
                 .await?;
         }
+        if self.udp_activity.remove(&connection_id).is_some() {
+            self.udp_sessions.retain(|_, id| *id != connection_id);
+        }
         match self.connections.remove(&connection_id) {
-            Some((worker, _connection)) => {
+            Some(ForwardedConnection {
+                worker: Some(worker),
+                ..
+            }) => {
                 worker.cancel().await;
             },
-            None if connection_id >= self.connection_counter => {
+            Some(ForwardedConnection { worker: None, .. }) => {},
+            None if !self.is_known(connection_id) => {
                 bail!(ForwardingError::protocol(format!(
                     "Connection '{connection_id}' not found"
                 )));
@@ -823,67 +1483,194 @@ where
         Ok(())
     }
 
-    async fn spawn_connection(
+    /* The peer opened a connection to one of our targets */
+    fn connect_target(
         &mut self,
-        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
-        target: Rc<String>,
-        connection: async_net::TcpStream,
+        target: String,
+        connection_id: u64,
     ) -> Result<(), ForwardingError> {
+        tracing::debug!("Creating new connection: #{} -> {}", connection_id, target);
+
+        ensure!(
+            !self.is_ours(connection_id),
+            ForwardingError::protocol(format!(
+                "Connection '{connection_id}' is not in the peer's range"
+            )),
+        );
+        /* No matter what happens, as soon as we receive the "connect" command that ID is burned. */
+        if !self.historic_connections.insert(connection_id) {
+            bail!(ForwardingError::protocol(format!(
+                "Connection '{connection_id}' already exists"
+            )));
+        }
+        let (address, target) = match self.targets.get(&target) {
+            Some(target) => (target.socket_address(), target.clone()),
+            /* Connections through the SOCKS proxy carry their destination */
+            None => match (
+                target.strip_prefix(&format!("{SOCKS_ADDRESS}:")),
+                self.targets.get(SOCKS_ADDRESS),
+            ) {
+                (Some(destination), Some(target @ Target::Socks(_))) => {
+                    (destination.to_owned(), target.clone())
+                },
+                _ => {
+                    bail!(ForwardingError::protocol(format!(
+                        "We don't know forwarding target '{target}'"
+                    )));
+                },
+            },
+        };
+        /* Targets are connected in the background, since resolving and connecting may take a while */
+        match target {
+            Target::Tcp(..) => self.insert_pending_connection(
+                connection_id,
+                {
+                    let address = address.clone();
+                    async move { async_net::TcpStream::connect(&address).await }
+                },
+                address,
+            ),
+            Target::Udp(..) => self.insert_pending_udp_connection(connection_id, address),
+            #[cfg(unix)]
+            Target::Unix(path) => self.insert_pending_connection(
+                connection_id,
+                async move { async_net::unix::UnixStream::connect(path).await },
+                address,
+            ),
+            Target::Socks(policy) => {
+                if address == SOCKS_ADDRESS {
+                    bail!(ForwardingError::protocol(
+                        "Connections through the SOCKS proxy need a destination"
+                    ));
+                }
+                self.insert_pending_connection(
+                    connection_id,
+                    {
+                        let address = address.clone();
+                        async move { socks::connect(&policy, &address).await }
+                    },
+                    address,
+                )
+            },
+        }
+        Ok(())
+    }
+
+    /* Ask the peer to open a connection to one of its targets */
+    async fn open_connection(
+        &mut self,
+        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
+        target: &str,
+    ) -> Result<u64, ForwardingError> {
         let connection_id = self.connection_counter;
         self.connection_counter += 1;
-        let (mut connection_rd, connection_wr) = futures_lite::io::split(connection);
-        let mut backchannel_tx = self.backchannel_tx.clone();
         tracing::debug!("Creating new connection: #{} -> {}", connection_id, target);
 
         transit_tx
             .send(
                 PeerMessage::Connect {
-                    target: (*target).clone(),
+                    target: target.to_owned(),
                     connection_id,
                 }
                 .ser_msgpack()
                 .into_boxed_slice(),
             )
             .await?;
+        Ok(connection_id)
+    }
 
-        let worker = crate::util::spawn(async move {
-            let mut buffer = vec![0; 4096];
-            /* Ignore errors */
-            macro_rules! break_on_err {
-                ($expr:expr_2021) => {
-                    match $expr {
-                        Ok(val) => val,
-                        Err(_) => break,
-                    }
-                };
-            }
-            #[expect(clippy::while_let_loop)]
-            loop {
-                let read = break_on_err!(connection_rd.read(&mut buffer).await);
-                if read == 0 {
-                    break;
+    async fn spawn_connection(
+        &mut self,
+        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
+        target: Arc<String>,
+        reader: Reader,
+        connection: Connection,
+    ) -> Result<(), ForwardingError> {
+        let connection_id = self.open_connection(transit_tx, &target).await?;
+        self.insert_connection(connection_id, Some(reader), connection);
+        Ok(())
+    }
+
+    /* Forward a datagram, in a new pseudo-connection if we haven't heard from its source recently */
+    async fn forward_datagram(
+        &mut self,
+        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
+        target: Arc<String>,
+        socket: async_net::UdpSocket,
+        source: SocketAddr,
+        payload: Vec<u8>,
+    ) -> Result<(), ForwardingError> {
+        let connection_id = match self.udp_sessions.get(&(target.clone(), source)) {
+            Some(connection_id) => *connection_id,
+            None => {
+                let connection_id = self.open_connection(transit_tx, &target).await?;
+                self.insert_connection(connection_id, None, Connection::Udp(socket, source));
+                self.udp_sessions.insert((target, source), connection_id);
+                connection_id
+            },
+        };
+        self.udp_activity.insert(connection_id, Instant::now());
+
+        transit_tx
+            .send(
+                PeerMessage::Forward {
+                    connection_id,
+                    payload,
                 }
-                let buffer = &buffer[..read];
-                break_on_err!(
-                    backchannel_tx
-                        .send((connection_id, Some(buffer.to_vec())))
-                        .await
-                );
-            }
-            /* Close connection (maybe or not because of error) */
-            let _ = backchannel_tx.send((connection_id, None)).await;
-            backchannel_tx.disconnect();
-        });
+                .ser_msgpack()
+                .into_boxed_slice(),
+            )
+            .await?;
+        Ok(())
+    }
 
-        self.connections
-            .insert(connection_id, (worker, connection_wr));
+    async fn expire_udp_sessions(
+        &mut self,
+        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
+    ) -> Result<(), ForwardingError> {
+        let expired: Vec<u64> = self
+            .udp_activity
+            .iter()
+            .filter(|(_, activity)| activity.elapsed() >= UDP_IDLE_TIMEOUT)
+            .map(|(connection_id, _)| *connection_id)
+            .collect();
+        for connection_id in expired {
+            tracing::debug!("UDP connection #{} expired", connection_id);
+            self.remove_connection(transit_tx, connection_id, true)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /* Listen for the services that the peer offered in return */
+    async fn accept_reverse_offer(
+        &mut self,
+        addresses: Vec<String>,
+    ) -> Result<(), ForwardingError> {
+        let Some(reverse) = self.reverse.take() else {
+            bail!(ForwardingError::protocol(
+                "Got a reverse offer, but we don't accept one"
+            ));
+        };
+        check_offered_addresses(&addresses, self.features)?;
+
+        let mut mapping = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let (listener, port) = bind(&address, reverse.bind_address, 0).await?;
+            self.incoming
+                .push(listen(listener, Arc::new(address.clone())));
+            mapping.push((port, address));
+        }
+        (reverse.handler)(&mapping);
         Ok(())
     }
 
     async fn shutdown(self) {
         tracing::debug!("Shutting down everything");
-        for (worker, _connection) in self.connections.into_values() {
-            worker.cancel().await;
+        for connection in self.connections.into_values() {
+            if let Some(worker) = connection.worker {
+                worker.cancel().await;
+            }
         }
     }
 
@@ -895,31 +1682,69 @@ where
              ),
         cancel: &mut (impl futures::future::FusedFuture<Output = ()> + Unpin),
     ) -> Result<(), ForwardingError> {
-        /* Event processing loop */
         tracing::debug!("Entered processing loop");
-        let ret = loop {
+        let mut ret = self.process(transit_tx, transit_rx, cancel).await;
+        /* The peer may have closed the transit while we were still sending to it */
+        if matches!(ret, Err(ForwardingError::Transit(_))) && Self::peer_closed(transit_rx).await {
+            tracing::info!("Peer gracefully closed connection");
+            ret = Ok(());
+        }
+        tracing::debug!("Exited processing loop");
+        self.shutdown().await;
+        ret
+    }
+
+    /** Look through the messages we have yet to process for a [`PeerMessage::Close`] */
+    async fn peer_closed(
+        transit_rx: &mut (
+                 impl futures::stream::FusedStream<Item = Result<Box<[u8]>, TransitError>> + Unpin
+             ),
+    ) -> bool {
+        while let Some(Ok(message)) = transit_rx.next().await {
+            if let Ok(PeerMessage::Close) = PeerMessage::de_msgpack(&message) {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn process(
+        &mut self,
+        transit_tx: &mut (impl futures::sink::Sink<Box<[u8]>, Error = TransitError> + Unpin),
+        transit_rx: &mut (
+                 impl futures::stream::FusedStream<Item = Result<Box<[u8]>, TransitError>> + Unpin
+             ),
+        cancel: &mut (impl futures::future::FusedFuture<Output = ()> + Unpin),
+    ) -> Result<(), ForwardingError> {
+        let mut expiry = async_io::Timer::interval(UDP_EXPIRY_INTERVAL).fuse();
+        /* Event processing loop */
+        loop {
             futures::select! {
                 message = transit_rx.next() => {
                     match PeerMessage::de_msgpack(&message.unwrap()?)? {
                         PeerMessage::Forward { connection_id, payload } => {
-                            self.forward(transit_tx, connection_id, &payload).await?;
+                            self.forward(transit_tx, connection_id, payload).await?
+                        },
+                        PeerMessage::WindowUpdate { connection_id, bytes } => {
+                            self.grant(connection_id, bytes)?;
+                        },
+                        PeerMessage::Connect { target, connection_id } => {
+                            self.connect_target(target, connection_id)?;
                         },
                         PeerMessage::Disconnect { connection_id } => {
                             self.remove_connection(transit_tx, connection_id, false).await?;
                         },
+                        PeerMessage::ReverseOffer { addresses } => {
+                            self.accept_reverse_offer(addresses).await?;
+                        },
                         PeerMessage::Close => {
                             tracing::info!("Peer gracefully closed connection");
-                            self.shutdown().await;
-                            break Ok(())
+                            break Ok(());
                         },
                         PeerMessage::Error(err) => {
-                            for (worker, _connection) in self.connections.into_values() {
-                                worker.cancel().await;
-                            }
                             bail!(ForwardingError::PeerError(err));
                         },
                         other => {
-                            self.shutdown().await;
                             bail!(ForwardingError::unexpected_message("connect' or 'disconnect' or 'forward' or 'close", other));
                         },
                     }
@@ -932,19 +1757,35 @@ where
                                 PeerMessage::Forward {
                                     connection_id,
                                     payload
-                                }.ser_msgpack()
+                                }
+                                .ser_msgpack()
                                 .into_boxed_slice()
-                            )
-                            .await?;
+                            ).await?;
                         },
                         (connection_id, None) => {
                             self.remove_connection(transit_tx, connection_id, true).await?;
                         },
                     }
                 },
+                message = self.written_rx.next() => {
+                    /* This channel will never run dry, since we always have a sender */
+                    let (connection_id, written) = message.unwrap();
+                    self.written(transit_tx, connection_id, written).await?;
+                },
+                /* This is `None` as long as we don't listen at all */
                 connection = self.incoming.next() => {
-                    let (target, connection): (Rc<String>, async_net::TcpStream) = connection.unwrap()?;
-                    self.spawn_connection(transit_tx, target, connection).await?;
+                    match connection.transpose()? {
+                        Some((target, Incoming::Stream(reader, connection))) => {
+                            self.spawn_connection(transit_tx, target, reader, connection).await?;
+                        },
+                        Some((target, Incoming::Udp(socket, source, payload))) => {
+                            self.forward_datagram(transit_tx, target, socket, source, payload).await?;
+                        },
+                        None => {},
+                    }
+                },
+                _ = expiry.next() => {
+                    self.expire_udp_sessions(transit_tx).await?;
                 },
                 /* We are done */
                 () = &mut *cancel => {
@@ -955,13 +1796,15 @@ where
                     )
                     .await?;
                     transit_tx.close().await?;
-                    self.shutdown().await;
+                    /* Wait for the peer to hang up. Closing the transit with data from the peer
+                     * that we didn't read would reset it, and the peer might never see our message.
+                     */
+                    let hangup = async { while let Some(Ok(_)) = transit_rx.next().await {} };
+                    let _ = crate::util::timeout(CLOSE_TIMEOUT, hangup).await;
                     break Ok(());
                 },
             }
-        };
-        tracing::debug!("Exited processing loop");
-        ret
+        }
     }
 }
 
@@ -973,21 +1816,36 @@ enum PeerMessage {
     /** Offer some destinations to be forwarded to.
      * forwarder -> forwardee only
      */
-    Offer { addresses: Vec<String> },
-    /** Forward a new connection.
+    Offer {
+        addresses: Vec<String>,
+        /** Whether the forwardee may offer destinations in return */
+        #[serde(default)]
+        accept_reverse: bool,
+    },
+    /** Offer some destinations in return, if the forwarder accepts them.
      * forwardee -> forwarder only
      */
+    ReverseOffer { addresses: Vec<String> },
+    /** Forward a new connection to one of the destinations the other side offered.
+     * Any direction, for reverse forwarding.
+     */
     Connect { target: String, connection_id: u64 },
     /** End a forwarded connection.
      * Any direction. Errors or the reason why the connection is closed
      * are not forwarded.
      */
     Disconnect { connection_id: u64 },
-    /** Forward some bytes for a connection. */
+    /** Forward some bytes for a connection.
+     * For UDP targets, this is exactly one datagram.
+     */
     Forward {
         connection_id: u64,
         payload: Vec<u8>,
     },
+    /** Allow the other side to send this many more bytes on a connection.
+     * Any direction, only with the flow control feature.
+     */
+    WindowUpdate { connection_id: u64, bytes: u64 },
     /** Close the whole session */
     Close,
     /** Tell the other side you got an error */
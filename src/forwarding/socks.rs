@@ -0,0 +1,461 @@
+//! A minimal SOCKS5 proxy, whose exit is on the serving side
+//!
+//! The connecting side does the SOCKS5 handshake with local clients (only `CONNECT` without authentication, see
+//! [RFC 1928](https://www.rfc-editor.org/rfc/rfc1928)), and the serving side connects to the destinations that its
+//! [`SocksPolicy`] allows.
+
+use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+const SUCCEEDED: u8 = 0;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/* Loopback, unspecified and link-local networks. The latter is where cloud providers put their metadata services */
+const LOCAL_NETWORKS: [(IpAddr, u8); 6] = [
+    (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+    (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    /* Connecting to the unspecified address reaches local services, like loopback */
+    (IpAddr::V4(Ipv4Addr::UNSPECIFIED), 8),
+    (IpAddr::V6(Ipv6Addr::UNSPECIFIED), 128),
+    (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+    (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+];
+
+/**
+ * Which destinations the peer may reach through our SOCKS proxy, see [`Target::Socks`](super::Target::Socks)
+ *
+ * Destinations that match a deny rule are never allowed. If there are any allow rules, a destination also has
+ * to match one of them, otherwise everything else is allowed. Rules apply to the host name that the peer asks for
+ * as well as to the addresses that it resolves to, so a network can't be reached through a domain name if it is denied.
+ *
+ * Loopback (`127.0.0.0/8`, `::1`), unspecified (`0.0.0.0/8`, `::`) and link-local (`169.254.0.0/16`, `fe80::/10`)
+ * addresses are denied by default. They need an allow rule that matches them by name, address or network; the wildcard `*` is not enough.
+ */
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SocksPolicy {
+    allow: Vec<SocksRule>,
+    deny: Vec<SocksRule>,
+}
+
+impl SocksPolicy {
+    /// Only allow destinations that match this or another allow rule
+    pub fn allow(mut self, rule: SocksRule) -> Self {
+        self.allow.push(rule);
+        self
+    }
+
+    /// Never allow destinations that match this rule
+    pub fn deny(mut self, rule: SocksRule) -> Self {
+        self.deny.push(rule);
+        self
+    }
+
+    /// Whether there are no allow rules, so that everything that is not denied is allowed, except for local addresses
+    pub fn allows_any(&self) -> bool {
+        self.allow.is_empty()
+    }
+
+    /// Whether the peer may connect to `address`, when it asked for `host`
+    pub fn allows(&self, host: &url::Host, address: &SocketAddr) -> bool {
+        let matches = |rule: &SocksRule| rule.matches(host, address);
+        let is_local = LOCAL_NETWORKS
+            .iter()
+            .any(|(network, prefix)| contains(*network, *prefix, address.ip().to_canonical()));
+        if self.deny.iter().any(matches) {
+            false
+        } else if is_local {
+            self.allow
+                .iter()
+                .any(|rule| rule.hosts != Hosts::Any && matches(rule))
+        } else {
+            self.allow.is_empty() || self.allow.iter().any(matches)
+        }
+    }
+}
+
+/**
+ * A rule of a [`SocksPolicy`] for some hosts, and optionally only one of their ports
+ *
+ * Rules are parsed from `HOST[:PORT]`. `HOST` is either `*` for any host, a domain, `*.` and a domain for all of its
+ * subdomains, an IP address or a network like `10.0.0.0/8`. IPv6 addresses and networks need brackets if a port
+ * follows, like `[fd00::/8]:443`.
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SocksRule {
+    hosts: Hosts,
+    port: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Hosts {
+    Any,
+    Domain(String),
+    /* With a leading dot */
+    Subdomains(String),
+    Network(IpAddr, u8),
+}
+
+impl SocksRule {
+    fn matches(&self, host: &url::Host, address: &SocketAddr) -> bool {
+        self.port.is_none_or(|port| port == address.port())
+            && match &self.hosts {
+                Hosts::Any => true,
+                Hosts::Domain(domain) => matches!(host, url::Host::Domain(name) if name == domain),
+                Hosts::Subdomains(suffix) => {
+                    matches!(host, url::Host::Domain(name) if name.ends_with(suffix.as_str()))
+                },
+                Hosts::Network(network, prefix) => {
+                    contains(*network, *prefix, address.ip().to_canonical())
+                },
+            }
+    }
+}
+
+/* Whether the address is within the network */
+fn contains(network: IpAddr, prefix: u8, address: IpAddr) -> bool {
+    match (network, address) {
+        (IpAddr::V4(network), IpAddr::V4(address)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+            u32::from(network) & mask == u32::from(address) & mask
+        },
+        (IpAddr::V6(network), IpAddr::V6(address)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+            u128::from(network) & mask == u128::from(address) & mask
+        },
+        _ => false,
+    }
+}
+
+/// An error occurred when parsing a [`SocksRule`]
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParseSocksRuleError {
+    /// The host is neither `*`, a domain, an IP address nor a network
+    #[error("Invalid host '{0}'")]
+    Host(String),
+    /// The port is not a number
+    #[error("Invalid port '{0}'")]
+    Port(String),
+}
+
+impl FromStr for SocksRule {
+    type Err = ParseSocksRuleError;
+
+    fn from_str(rule: &str) -> Result<Self, Self::Err> {
+        let (hosts, port) = match rule.strip_prefix('[') {
+            Some(rule) => {
+                let (hosts, port) = rule
+                    .split_once(']')
+                    .ok_or_else(|| ParseSocksRuleError::Host(rule.into()))?;
+                match port {
+                    "" => (hosts, None),
+                    port => (
+                        hosts,
+                        Some(
+                            port.strip_prefix(':')
+                                .ok_or_else(|| ParseSocksRuleError::Port(port.into()))?,
+                        ),
+                    ),
+                }
+            },
+            /* IPv6 addresses without brackets have more than one colon */
+            None => match rule.split_once(':') {
+                Some((hosts, port)) if !port.contains(':') => (hosts, Some(port)),
+                _ => (rule, None),
+            },
+        };
+        let port = port
+            .map(|port| {
+                port.parse()
+                    .map_err(|_| ParseSocksRuleError::Port(port.into()))
+            })
+            .transpose()?;
+        let invalid_host = || ParseSocksRuleError::Host(hosts.into());
+
+        let hosts = if hosts == "*" {
+            Hosts::Any
+        } else if let Some((network, prefix)) = hosts.split_once('/') {
+            let network: IpAddr = network.parse().map_err(|_| invalid_host())?;
+            let prefix: u8 = prefix.parse().map_err(|_| invalid_host())?;
+            let max = if network.is_ipv4() { 32 } else { 128 };
+            if prefix > max {
+                return Err(invalid_host());
+            }
+            Hosts::Network(network, prefix)
+        } else if let Ok(address) = hosts.parse::<IpAddr>() {
+            Hosts::Network(address, if address.is_ipv4() { 32 } else { 128 })
+        } else {
+            let (subdomains, domain) = match hosts.strip_prefix("*.") {
+                Some(domain) => (true, domain),
+                None => (false, hosts),
+            };
+            /* Normalize the domain like the ones that the peer asks for */
+            let Ok(url::Host::Domain(domain)) = url::Host::parse(domain) else {
+                return Err(invalid_host());
+            };
+            if subdomains {
+                Hosts::Subdomains(format!(".{domain}"))
+            } else {
+                Hosts::Domain(domain)
+            }
+        };
+        Ok(Self { hosts, port })
+    }
+}
+
+/* Do the server side of a SOCKS5 handshake, and return the destination the client wants to connect to.
+ * We tell the client right away that the connection succeeded, since the peer doesn't tell whether it could connect.
+ * If it couldn't, the connection gets closed instead.
+ */
+pub(super) async fn handshake(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+) -> std::io::Result<(url::Host, u16)> {
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    let [version, methods] = header;
+    if version != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a SOCKS5 client"));
+    }
+    let mut methods = vec![0; methods.into()];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "The SOCKS client requires authentication",
+        ));
+    }
+    stream.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, command, _reserved, address_type] = request;
+    if version != VERSION {
+        return Err(Error::new(ErrorKind::InvalidData, "Not a SOCKS5 request"));
+    }
+    let host = match address_type {
+        IPV4 => {
+            let mut address = [0; 4];
+            stream.read_exact(&mut address).await?;
+            url::Host::Ipv4(address.into())
+        },
+        DOMAIN => {
+            let mut length = [0; 1];
+            stream.read_exact(&mut length).await?;
+            let mut domain = vec![0; length[0].into()];
+            stream.read_exact(&mut domain).await?;
+            std::str::from_utf8(&domain)
+                .ok()
+                .and_then(|domain| url::Host::parse(domain).ok())
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid domain"))?
+        },
+        IPV6 => {
+            let mut address = [0; 16];
+            stream.read_exact(&mut address).await?;
+            url::Host::Ipv6(address.into())
+        },
+        _ => {
+            reply(stream, ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Unsupported SOCKS address type",
+            ));
+        },
+    };
+    let mut port = [0; 2];
+    stream.read_exact(&mut port).await?;
+    if command != CONNECT {
+        reply(stream, COMMAND_NOT_SUPPORTED).await?;
+        return Err(Error::new(
+            ErrorKind::Unsupported,
+            "Only the SOCKS CONNECT command is supported",
+        ));
+    }
+    reply(stream, SUCCEEDED).await?;
+    Ok((host, u16::from_be_bytes(port)))
+}
+
+async fn reply(stream: &mut (impl AsyncWrite + Unpin), code: u8) -> std::io::Result<()> {
+    /* There is no bound address on our side to tell about */
+    stream
+        .write_all(&[VERSION, code, 0, IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+/* Connect to a destination of the SOCKS proxy like `example.com:443`, if the policy allows it */
+pub(super) async fn connect(
+    policy: &SocksPolicy,
+    destination: &str,
+) -> std::io::Result<async_net::TcpStream> {
+    let (host, port) = destination
+        .rsplit_once(':')
+        .and_then(|(host, port)| Some((url::Host::parse(host).ok()?, port.parse::<u16>().ok()?)))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid SOCKS destination"))?;
+    let addresses: Vec<SocketAddr> = async_net::resolve(format!("{host}:{port}"))
+        .await?
+        .into_iter()
+        .filter(|address| policy.allows(&host, address))
+        .collect();
+    if addresses.is_empty() {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "Not allowed by the SOCKS policy",
+        ));
+    }
+    async_net::TcpStream::connect(&*addresses).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rule(rule: &str) -> SocksRule {
+        rule.parse().unwrap()
+    }
+
+    fn allows(policy: &SocksPolicy, host: &str, address: &str) -> bool {
+        policy.allows(&url::Host::parse(host).unwrap(), &address.parse().unwrap())
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        assert_eq!(rule("*").hosts, Hosts::Any);
+        assert_eq!(rule("*:443").port, Some(443));
+        assert_eq!(
+            rule("Example.com:22"),
+            SocksRule {
+                hosts: Hosts::Domain("example.com".into()),
+                port: Some(22)
+            }
+        );
+        assert_eq!(
+            rule("*.example.com").hosts,
+            Hosts::Subdomains(".example.com".into())
+        );
+        assert_eq!(
+            rule("10.0.0.0/8").hosts,
+            Hosts::Network("10.0.0.0".parse().unwrap(), 8)
+        );
+        assert_eq!(
+            rule("192.168.1.1:80"),
+            SocksRule {
+                hosts: Hosts::Network("192.168.1.1".parse().unwrap(), 32),
+                port: Some(80)
+            }
+        );
+        assert_eq!(
+            rule("fd00::/8").hosts,
+            Hosts::Network("fd00::".parse().unwrap(), 8)
+        );
+        assert_eq!(
+            rule("[::1]:22"),
+            SocksRule {
+                hosts: Hosts::Network("::1".parse().unwrap(), 128),
+                port: Some(22)
+            }
+        );
+
+        for invalid in [
+            "",
+            "10.0.0.0/33",
+            "[::1",
+            "[::1]22",
+            "example.com:http",
+            "a b",
+        ] {
+            assert!(invalid.parse::<SocksRule>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_policy() {
+        let anything = SocksPolicy::default();
+        assert!(allows(&anything, "example.com", "93.184.215.14:443"));
+
+        let policy = SocksPolicy::default()
+            .allow(rule("*.example.com"))
+            .allow(rule("10.0.0.0/8:80"))
+            .deny(rule("10.0.0.1"));
+        assert!(allows(&policy, "www.example.com", "93.184.215.14:443"));
+        assert!(!allows(&policy, "example.com", "93.184.215.14:443"));
+        assert!(!allows(&policy, "notexample.com", "93.184.215.14:443"));
+        assert!(allows(&policy, "10.1.2.3", "10.1.2.3:80"));
+        assert!(!allows(&policy, "10.1.2.3", "10.1.2.3:81"));
+        assert!(allows(&policy, "intranet", "10.1.2.3:80"));
+        assert!(allows(&policy, "10.1.2.3", "[::ffff:10.1.2.3]:80"));
+        /* Deny rules win, also when the peer asks for a name */
+        assert!(!allows(&policy, "10.0.0.1", "10.0.0.1:80"));
+        assert!(!allows(&policy, "www.example.com", "10.0.0.1:443"));
+    }
+
+    #[test]
+    fn test_policy_local() {
+        for policy in [
+            SocksPolicy::default(),
+            SocksPolicy::default().allow(rule("*")),
+        ] {
+            assert!(!allows(&policy, "localhost", "127.0.0.1:80"));
+            assert!(!allows(&policy, "127.1.2.3", "127.1.2.3:80"));
+            assert!(!allows(&policy, "[::1]", "[::1]:80"));
+            assert!(!allows(&policy, "127.0.0.1", "[::ffff:127.0.0.1]:80"));
+            assert!(!allows(&policy, "169.254.169.254", "169.254.169.254:80"));
+            assert!(!allows(&policy, "[fe80::1]", "[fe80::1]:80"));
+            assert!(!allows(&policy, "0.0.0.0", "0.0.0.0:22"));
+            assert!(!allows(&policy, "0.1.2.3", "0.1.2.3:22"));
+            assert!(!allows(&policy, "[::]", "[::]:22"));
+            assert!(allows(&policy, "example.com", "93.184.215.14:443"));
+        }
+
+        let policy = SocksPolicy::default()
+            .allow(rule("localhost"))
+            .allow(rule("169.254.0.0/16:80"))
+            .allow(rule("*"));
+        assert!(allows(&policy, "localhost", "127.0.0.1:22"));
+        assert!(!allows(&policy, "127.0.0.1", "127.0.0.1:22"));
+        assert!(allows(&policy, "169.254.169.254", "169.254.169.254:80"));
+        assert!(!allows(&policy, "169.254.169.254", "169.254.169.254:81"));
+        assert!(allows(&policy, "example.com", "93.184.215.14:443"));
+    }
+
+    #[test]
+    fn test_handshake() {
+        async_io::block_on(async {
+            let listener = async_net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let client = async {
+                let mut stream = async_net::TcpStream::connect(address).await.unwrap();
+                stream.write_all(&[5, 2, 2, 0]).await.unwrap();
+                let mut method = [0; 2];
+                stream.read_exact(&mut method).await.unwrap();
+                assert_eq!(method, [5, 0]);
+                stream
+                    .write_all(&[5, 1, 0, 3, 11, b'e', b'x', b'a', b'm', b'p', b'l', b'e'])
+                    .await
+                    .unwrap();
+                stream.write_all(b".com\x01\xbb").await.unwrap();
+                let mut reply = [0; 10];
+                stream.read_exact(&mut reply).await.unwrap();
+                assert_eq!(reply[..2], [5, 0]);
+            };
+            let server = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                handshake(&mut stream).await.unwrap()
+            };
+            let ((), (host, port)) = futures::join!(client, server);
+            assert_eq!(host, url::Host::Domain("example.com".to_owned()));
+            assert_eq!(port, 443);
+        });
+    }
+}
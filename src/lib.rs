@@ -12,7 +12,10 @@
 //! Magic Wormhole is known for its ability to transfer files. This is implemented in the [`transfer`] module, which builds upon the wormhole
 //! protocol and thus requires a [`Wormhole`].
 //!
-//! As an alternative to file transfer, there is the [`forwarding`] module, which allows to forward arbitrary TCP connections over the Wormhole/Transit tunnel.
+//! As an alternative to file transfer, there is the [`forwarding`] module, which allows to forward arbitrary TCP connections and UDP traffic over the Wormhole/Transit tunnel, or to reach any host through a SOCKS proxy.
+//!
+//! Applications that need a durable connection to their peer can [`dilation::dilate`] their wormhole into a multiplexed,
+//! automatically reconnecting connection which is compatible with the Python implementation.
 //!
 //! Transferring large amounts of data should not be done over the rendezvous server. Instead, you have to set up a [`transit`]
 //! connection. A transit is little more than an encrypted TcpConnection. If a direct connection between both clients is not possible,
@@ -28,6 +31,8 @@
 #[macro_use]
 mod util;
 mod core;
+#[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+pub mod dilation;
 #[cfg(feature = "forwarding")]
 pub mod forwarding;
 #[cfg(feature = "transfer")]
@@ -39,10 +44,14 @@ pub mod uri;
 
 pub use crate::core::{
     AppConfig, AppID, Code, MailboxConnection, Mood, Nameplate, ParseCodeError,
-    ParseNameplateError, ParsePasswordError, Password, Wormhole, WormholeError,
+    ParseNameplateError, ParsePasswordError, Password, VerifierFormat, Wormhole, WormholeDriver,
+    WormholeError, WormholeReceiver, WormholeSender,
     key::{Key, KeyPurpose, WormholeKey},
     rendezvous,
 };
 
+#[cfg(not(target_family = "wasm"))]
+pub use crate::core::mailbox_server;
+
 #[doc(hidden)]
 pub use core::wordlist::Wordlist;
@@ -8,7 +8,7 @@
 //! At its core, "peer messages" are exchanged over an established wormhole connection with the other side.
 //! They are used to set up a [transit] portal and to exchange a file offer/accept. Then, the file is transmitted over the transit relay.
 
-use futures::{AsyncRead, AsyncWrite};
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use serde_derive::{Deserialize, Serialize};
 #[cfg(test)]
 use serde_json::json;
@@ -26,6 +26,7 @@ use transit::{
 };
 
 mod cancel;
+mod event;
 #[doc(hidden)]
 pub mod offer;
 mod v1;
@@ -33,11 +34,12 @@ mod v1;
 #[allow(missing_docs)]
 mod v2;
 
-#[doc(hidden)]
+use event::EventReporter;
+pub use event::{Progress, TransferEvent};
 pub use v1::ReceiveRequest as ReceiveRequestV1;
 
-#[cfg(not(feature = "experimental-transfer-v2"))]
-pub use v1::ReceiveRequest;
+#[cfg(not(target_family = "wasm"))]
+pub use v1::ReceiveDirectoryRequest;
 
 #[cfg(feature = "experimental-transfer-v2")]
 pub use v2::ReceiveRequest as ReceiveRequestV2;
@@ -71,6 +73,18 @@ pub enum TransferError {
     #[error("Receive checksum error")]
     Checksum,
 
+    /// The offer contains streams of unknown size, but the peer doesn't support them and they couldn't be buffered
+    #[error("The peer does not support receiving streams of unknown size")]
+    UnknownSizeUnsupported,
+
+    /// The checksum of a received file does not match the sender's. Unlike [`Checksum`](Self::Checksum),
+    /// which the sender gets in transfer v1, this names the file.
+    #[error(
+        "The received file '{}' is corrupt: its checksum does not match the sender's",
+        _0
+    )]
+    ChecksumMismatch(Box<str>),
+
     /// The file contained a different amount of bytes than advertized
     #[error(
         "The file contained a different amount of bytes than advertized! Sent {} bytes, but should have been {}",
@@ -88,6 +102,14 @@ pub enum TransferError {
     #[error("The file(s) to send got modified during the transfer, and thus corrupted")]
     FilesystemSkew,
 
+    /// The files to accept do not match the offer
+    #[error("Invalid answer to the offer: {}", _0)]
+    InvalidAnswer(Box<str>),
+
+    /// A received archive is corrupt, unsafe to extract or does not match the offer
+    #[error("Invalid archive: {}", _0)]
+    InvalidArchive(Box<str>),
+
     // TODO be more specific
     /// Unsupported offer type
     #[error("Unsupported offer type")]
@@ -198,6 +220,23 @@ impl AppVersion {
     fn supports_v2(&self) -> bool {
         self.abilities.contains(&"transfer-v2".into())
     }
+
+    /* Only transfer v2 peers which announce it can receive files of unknown size */
+    #[cfg_attr(target_family = "wasm", allow(dead_code))]
+    fn supports_unknown_size(&self) -> bool {
+        #[cfg(feature = "experimental-transfer-v2")]
+        {
+            self.supports_v2()
+                && self
+                    .transfer_v2
+                    .as_ref()
+                    .is_some_and(|hint| hint.unknown_size)
+        }
+        #[cfg(not(feature = "experimental-transfer-v2"))]
+        {
+            false
+        }
+    }
 }
 
 impl Default for AppVersion {
@@ -213,6 +252,12 @@ impl Default for AppVersion {
 pub struct AppVersionTransferV2Hint {
     supported_formats: Cow<'static, [Cow<'static, str>]>,
     transit_abilities: transit::Abilities,
+    /// The supported compression algorithms for file contents, see [`v2::Compression`]
+    #[serde(default)]
+    compression: Cow<'static, [Cow<'static, str>]>,
+    /// Whether files of unknown size, which end with a `FileEnd` message, are supported
+    #[serde(default)]
+    unknown_size: bool,
 }
 
 #[cfg(feature = "experimental-transfer-v2")]
@@ -221,6 +266,8 @@ impl AppVersionTransferV2Hint {
         Self {
             supported_formats: Cow::Borrowed(&[Cow::Borrowed("plain"), Cow::Borrowed("tar")]),
             transit_abilities: transit::Abilities::ALL,
+            compression: Cow::Borrowed(v2::compression::SUPPORTED),
+            unknown_size: true,
         }
     }
 }
@@ -268,7 +315,6 @@ pub(crate) enum PeerMessage {
 }
 
 impl PeerMessage {
-    #[allow(dead_code)]
     fn offer_message_v1(msg: impl Into<String>) -> Self {
         PeerMessage::Offer(v1::OfferMessage::Message(msg.into()))
     }
@@ -297,7 +343,6 @@ impl PeerMessage {
         })
     }
 
-    #[allow(dead_code)]
     fn message_ack_v1(msg: impl Into<String>) -> Self {
         PeerMessage::Answer(v1::AnswerMessage::MessageAck(msg.into()))
     }
@@ -337,6 +382,9 @@ impl PeerMessage {
 
 /// Send a previously constructed offer.
 ///
+/// The progress handler gets the number of bytes sent and the total, which is `None` for offers
+/// of unknown size (see [`offer::Offer::new_file_stream`]).
+///
 /// Part of the experimental and unstable transfer-v2 API.
 /// Expect some amount of API breakage in the future to adapt to protocol changes and API ergonomics.
 #[cfg_attr(not(feature = "experimental-transfer-v2"), doc(hidden))]
@@ -344,79 +392,232 @@ pub async fn send(
     wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
     offer: offer::OfferSend,
     transit_handler: impl FnOnce(transit::TransitInfo),
-    progress_handler: impl FnMut(u64, u64) + 'static,
+    progress_handler: impl FnMut(u64, Option<u64>) + 'static,
+    cancel: impl Future<Output = ()>,
+) -> Result<(), TransferError> {
+    let mut events = EventReporter::new(event::legacy_handler(transit_handler, progress_handler));
+    send_reporting(
+        wormhole,
+        relay_hints,
+        transit_abilities,
+        rate_limit,
+        offer,
+        &mut events,
+        cancel,
+    )
+    .await
+}
+
+/// Send a previously constructed offer, and report what happens as [`TransferEvent`]s.
+///
+/// The last event is either [`TransferEvent::Finished`] or [`TransferEvent::Failed`].
+///
+/// Part of the experimental and unstable transfer-v2 API.
+/// Expect some amount of API breakage in the future to adapt to protocol changes and API ergonomics.
+#[cfg_attr(not(feature = "experimental-transfer-v2"), doc(hidden))]
+pub async fn send_with_events(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
+    offer: offer::OfferSend,
+    event_handler: impl FnMut(TransferEvent),
+    cancel: impl Future<Output = ()>,
+) -> Result<(), TransferError> {
+    let mut events = EventReporter::new(event_handler);
+    let result = send_reporting(
+        wormhole,
+        relay_hints,
+        transit_abilities,
+        rate_limit,
+        offer,
+        &mut events,
+        cancel,
+    )
+    .await;
+    events.finish(result)
+}
+
+async fn send_reporting(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
+    offer: offer::OfferSend,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError> {
     let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
 
+    /* Peers that can't receive streams of unknown size get them with their size, after buffering */
+    #[cfg(not(target_family = "wasm"))]
+    let offer = if offer.is_size_known() || peer_version.supports_unknown_size() {
+        offer
+    } else {
+        offer.buffer_unknown_sizes().await?
+    };
+
     #[cfg(feature = "experimental-transfer-v2")]
     {
         if peer_version.supports_v2() {
-            return v2::send(
+            return Box::pin(v2::send(
                 wormhole,
                 relay_hints,
                 transit_abilities,
+                rate_limit,
                 offer,
-                progress_handler,
+                events,
                 peer_version,
                 cancel,
-            )
+            ))
             .await;
         }
     }
 
-    v1::send(
+    /* Boxed, because the transfer futures get large */
+    Box::pin(v1::send(
         wormhole,
         relay_hints,
         transit_abilities,
+        rate_limit,
         offer,
-        progress_handler,
-        transit_handler,
+        events,
         peer_version,
         cancel,
-    )
+    ))
     .await
 }
 
 /**
- * Wait for a file offer from the other side
+ * Wait for a file or text offer from the other side
  *
  * This method waits for an offer message and builds up a [`ReceiveRequest`].
- * It will also start building a TCP connection to the other side using the transit protocol.
+ * For file offers, it will also start building a TCP connection to the other side using the transit protocol.
+ * Text messages are acknowledged right away, after which the wormhole is closed.
  *
  * Returns `None` if the task got cancelled.
- *
- * Part of the experimental and unstable transfer-v2 API.
- * Expect some amount of API breakage in the future to adapt to protocol changes and API ergonomics.
  */
-#[cfg(feature = "experimental-transfer-v2")]
 pub async fn request(
     wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
+    cancel: impl Future<Output = ()>,
+) -> Result<Option<ReceiveRequest>, TransferError> {
+    request_reporting(
+        wormhole,
+        relay_hints,
+        transit_abilities,
+        rate_limit,
+        &mut EventReporter::new(|_| ()),
+        cancel,
+    )
+    .await
+}
+
+/**
+ * Wait for a file or text offer from the other side, and report what happens as [`TransferEvent`]s
+ *
+ * This works like [`request`]. Transfer v2 establishes the transit connection before receiving the offer,
+ * so the attempts to connect get reported here, followed by [`TransferEvent::OfferReceived`].
+ * Accept the request with [`ReceiveRequest::accept_with_events`] to get the events of the transfer itself.
+ */
+pub async fn request_with_events(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
+    event_handler: impl FnMut(TransferEvent),
+    cancel: impl Future<Output = ()>,
+) -> Result<Option<ReceiveRequest>, TransferError> {
+    let mut events = EventReporter::new(event_handler);
+    let result = request_reporting(
+        wormhole,
+        relay_hints,
+        transit_abilities,
+        rate_limit,
+        &mut events,
+        cancel,
+    )
+    .await;
+    match &result {
+        Ok(request) => {
+            if let Some(offer) = request.as_ref().and_then(ReceiveRequest::offer) {
+                events.report(TransferEvent::OfferReceived(offer));
+            }
+            result
+        },
+        Err(_) => events.finish(result),
+    }
+}
+
+async fn request_reporting(
+    wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
+    #[cfg_attr(
+        not(feature = "experimental-transfer-v2"),
+        expect(
+            unused_variables,
+            reason = "Only transfer v2 connects before the offer"
+        )
+    )]
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     cancel: impl Future<Output = ()>,
 ) -> Result<Option<ReceiveRequest>, TransferError> {
     #[cfg(feature = "experimental-transfer-v2")]
     {
         let peer_version: AppVersion = serde_json::from_value(wormhole.peer_version().clone())?;
         if peer_version.supports_v2() {
-            v2::request(
+            return v2::request(
                 wormhole,
                 relay_hints,
                 peer_version,
                 transit_abilities,
+                rate_limit,
+                events,
                 cancel,
             )
             .await
-            .map(|req| req.map(ReceiveRequest::V2))
-        } else {
-            v1::request(wormhole, relay_hints, transit_abilities, cancel)
-                .await
-                .map(|req| req.map(ReceiveRequest::V1))
+            .map(|req| req.map(ReceiveRequest::V2));
         }
     }
+
+    v1::request_any(
+        wormhole,
+        relay_hints,
+        transit_abilities,
+        rate_limit,
+        true,
+        cancel,
+    )
+    .await
+    .map(|req| {
+        req.map(|req| match req {
+            v1::Request::File(req) => ReceiveRequest::V1(req),
+            #[cfg(not(target_family = "wasm"))]
+            v1::Request::Directory(req) => ReceiveRequest::Directory(req),
+            v1::Request::Text(text) => ReceiveRequest::Text(text),
+        })
+    })
+}
+
+/**
+ * Send a text message to the other side
+ *
+ * This is compatible with `wormhole send --text` of the Python implementation. The message is sent
+ * over the mailbox server instead of a transit connection, so it should be kept short.
+ */
+pub async fn send_text(
+    wormhole: Wormhole,
+    text: impl Into<String>,
+    cancel: impl Future<Output = ()>,
+) -> Result<(), TransferError> {
+    v1::send_text(wormhole, text.into(), cancel).await
 }
 
 /// Wait for a file offer from the other side
@@ -459,7 +660,7 @@ pub async fn send_file<F, N, G, H>(
     file_size: u64,
     transit_abilities: transit::Abilities,
     transit_handler: G,
-    progress_handler: H,
+    mut progress_handler: H,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError>
 where
@@ -468,6 +669,10 @@ where
     G: FnOnce(transit::TransitInfo),
     H: FnMut(u64, u64) + 'static,
 {
+    let mut events = EventReporter::new(event::legacy_handler(
+        transit_handler,
+        move |sent, total: Option<u64>| progress_handler(sent, total.unwrap_or(0)),
+    ));
     v1::send_file(
         wormhole,
         relay_hints,
@@ -475,8 +680,8 @@ where
         file_name,
         file_size,
         transit_abilities,
-        transit_handler,
-        progress_handler,
+        None,
+        &mut events,
         cancel,
     )
     .await
@@ -562,7 +767,7 @@ pub async fn send_folder<N, M, G, H>(
     folder_name: M,
     transit_abilities: transit::Abilities,
     transit_handler: G,
-    progress_handler: H,
+    mut progress_handler: H,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError>
 where
@@ -573,48 +778,93 @@ where
 {
     let offer = offer::OfferSendEntry::new(folder_path.into()).await?;
 
+    let mut events = EventReporter::new(event::legacy_handler(
+        transit_handler,
+        move |sent, total: Option<u64>| progress_handler(sent, total.unwrap_or(0)),
+    ));
     v1::send_folder(
         wormhole,
         relay_hints,
         folder_name.into(),
         offer,
         transit_abilities,
-        transit_handler,
-        progress_handler,
+        None,
+        &mut events,
         cancel,
     )
     .await
 }
 
 /**
- * A pending files send offer from the other side
+ * A pending send offer from the other side
  *
  * You *should* consume this object, by matching on the protocol version and then calling either `accept` or `reject`.
+ * Text messages have already been received and acknowledged, there is nothing left to do for them.
  */
 #[must_use]
-#[cfg(feature = "experimental-transfer-v2")]
+#[non_exhaustive]
+#[allow(clippy::large_enum_variant)] /* Only one of them exists at a time anyways */
 pub enum ReceiveRequest {
     /// A protocol version 1 receive request
     V1(ReceiveRequestV1),
+    /// A directory sent as zip file by a protocol version 1 peer, which gets extracted on the fly
+    #[cfg(not(target_family = "wasm"))]
+    Directory(ReceiveDirectoryRequest),
+    /// A text message
+    Text(String),
     /// A protocol version 2 receive request
+    #[cfg(feature = "experimental-transfer-v2")]
     V2(ReceiveRequestV2),
 }
 
-#[cfg(feature = "experimental-transfer-v2")]
 impl ReceiveRequest {
-    /// Accept this receive request
+    /**
+     * Accept this receive request
+     *
+     * [`ReceiveRequest::Directory`] needs a target directory instead of an answer, accept it with
+     * [`ReceiveDirectoryRequest::accept`]. Passing it here fails with [`TransferError::InvalidAnswer`],
+     * and so does [`ReceiveRequest::Text`], which has no files to answer for.
+     */
     pub async fn accept<F, G, W>(
         self,
         transit_handler: G,
         progress_handler: F,
-        mut answer: offer::OfferAccept,
+        answer: offer::OfferAccept,
         cancel: impl Future<Output = ()>,
     ) -> Result<(), TransferError>
     where
-        F: FnMut(u64, u64) + 'static,
+        F: FnMut(u64, Option<u64>) + 'static,
         G: FnOnce(transit::TransitInfo),
         W: AsyncWrite + Unpin,
     {
+        let mut events =
+            EventReporter::new(event::legacy_handler(transit_handler, progress_handler));
+        self.accept_reporting(answer, &mut events, cancel).await
+    }
+
+    /**
+     * Accept this receive request, and report what happens as [`TransferEvent`]s
+     *
+     * This works like [`accept`](ReceiveRequest::accept). The last event is either
+     * [`TransferEvent::Finished`] or [`TransferEvent::Failed`].
+     */
+    pub async fn accept_with_events(
+        self,
+        answer: offer::OfferAccept,
+        event_handler: impl FnMut(TransferEvent),
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError> {
+        let mut events = EventReporter::new(event_handler);
+        let result = self.accept_reporting(answer, &mut events, cancel).await;
+        events.finish(result)
+    }
+
+    async fn accept_reporting(
+        self,
+        mut answer: offer::OfferAccept,
+        events: &mut EventReporter<impl FnMut(TransferEvent)>,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError> {
         match self {
             ReceiveRequest::V1(request) => {
                 // Desynthesize the previously synthesized offer to make transfer v1 more similar to transfer v2
@@ -631,11 +881,87 @@ impl ReceiveRequest {
                     ),
                 };
 
+                Box::pin(request.accept_with_events(events, &mut acceptor, cancel)).await
+            },
+            #[cfg(not(target_family = "wasm"))]
+            ReceiveRequest::Directory(_) => Err(TransferError::InvalidAnswer(
+                "directory offers must be accepted with `ReceiveDirectoryRequest::accept`".into(),
+            )),
+            ReceiveRequest::Text(_) => Err(TransferError::InvalidAnswer(
+                "text messages have no files to accept, use `ReceiveRequest::accept_into` to write them out".into(),
+            )),
+            #[cfg(feature = "experimental-transfer-v2")]
+            ReceiveRequest::V2(request) => {
+                Box::pin(request.accept_with_events(answer, events, cancel)).await
+            },
+        }
+    }
+
+    /**
+     * Accept an offer of a single file, and write its content into `writer` instead of a file on disk
+     *
+     * This allows to stream a file into stdout, a pipe or a socket. Offers with more than one file or
+     * with directories get rejected, and fail with [`TransferError::InvalidAnswer`]. Text messages
+     * are written into `writer` as they are.
+     */
+    pub async fn accept_into<F, G, W>(
+        self,
+        transit_handler: G,
+        mut progress_handler: F,
+        mut writer: W,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError>
+    where
+        F: FnMut(u64, Option<u64>) + 'static,
+        G: FnOnce(transit::TransitInfo),
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        match self {
+            ReceiveRequest::V1(request) => {
                 request
-                    .accept(transit_handler, progress_handler, &mut acceptor, cancel)
+                    .accept(
+                        transit_handler,
+                        move |received, total| progress_handler(received, Some(total)),
+                        &mut writer,
+                        cancel,
+                    )
                     .await
             },
+            #[cfg(not(target_family = "wasm"))]
+            ReceiveRequest::Directory(request) => {
+                request.reject().await?;
+                Err(TransferError::InvalidAnswer(
+                    "directories can't be written into a single stream".into(),
+                ))
+            },
+            ReceiveRequest::Text(text) => {
+                writer.write_all(text.as_bytes()).await?;
+                writer.flush().await?;
+                Ok(())
+            },
+            #[cfg(feature = "experimental-transfer-v2")]
             ReceiveRequest::V2(request) => {
+                let offer = request.offer();
+                if offer.is_multiple() || offer.is_directory() {
+                    request.reject().await?;
+                    return Err(TransferError::InvalidAnswer(
+                        "only single files can be written into a stream".into(),
+                    ));
+                }
+
+                let mut writer = Some(writer);
+                let answer = offer.set_content(|_path| {
+                    let writer = writer.take().expect("The offer has only one file");
+                    offer::AcceptInner {
+                        offset: 0,
+                        sha256: None,
+                        content: Box::new(move |_append| {
+                            Box::pin(futures::future::ready(Ok(
+                                Box::new(writer) as Box<dyn AsyncWrite + Unpin + Send>
+                            ))) as _
+                        }),
+                    }
+                });
                 request
                     .accept(transit_handler, answer, progress_handler, cancel)
                     .await
@@ -651,15 +977,23 @@ impl ReceiveRequest {
     pub async fn reject(self) -> Result<(), TransferError> {
         match self {
             ReceiveRequest::V1(request) => request.reject().await,
+            #[cfg(not(target_family = "wasm"))]
+            ReceiveRequest::Directory(request) => request.reject().await,
+            ReceiveRequest::Text(_) => Ok(()),
+            #[cfg(feature = "experimental-transfer-v2")]
             ReceiveRequest::V2(request) => request.reject().await,
         }
     }
 
-    /// The file offer for this receive request
-    pub fn offer(&self) -> Arc<offer::Offer> {
+    /// The file offer for this receive request, or `None` for text messages and directories, whose content is not known in advance
+    pub fn offer(&self) -> Option<Arc<offer::Offer>> {
         match self {
-            ReceiveRequest::V1(req) => req.offer(),
-            ReceiveRequest::V2(req) => req.offer(),
+            ReceiveRequest::V1(req) => Some(req.offer()),
+            #[cfg(not(target_family = "wasm"))]
+            ReceiveRequest::Directory(_) => None,
+            ReceiveRequest::Text(_) => None,
+            #[cfg(feature = "experimental-transfer-v2")]
+            ReceiveRequest::V2(req) => Some(req.offer()),
         }
     }
 }
@@ -750,4 +1084,24 @@ mod test {
             "{\"answer\":{\"file_ack\":\"ok\"}}"
         );
     }
+
+    #[test]
+    #[cfg(not(target_family = "wasm"))]
+    fn test_text_accept_into() {
+        async_io::block_on(async {
+            let target =
+                std::env::temp_dir().join(format!("wormhole-test-text-{}", rand::random::<u32>()));
+            ReceiveRequest::Text("hello".into())
+                .accept_into(
+                    |_| (),
+                    |_, _| (),
+                    async_fs::File::create(&target).await.unwrap(),
+                    futures::future::pending(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(async_fs::read_to_string(&target).await.unwrap(), "hello");
+            async_fs::remove_file(&target).await.unwrap();
+        })
+    }
 }
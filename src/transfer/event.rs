@@ -0,0 +1,188 @@
+//! Structured reporting of what happens during a transfer
+//!
+//! This is a more detailed alternative to the progress and transit handlers, see
+//! [`send_with_events`](super::send_with_events) and [`request_with_events`](super::request_with_events).
+
+use super::{TransferError, offer::Offer};
+use crate::transit::{
+    self, Hints, Transit, TransitConnectError, TransitConnector, TransitInfo, TransitRole,
+};
+use futures::StreamExt;
+use futures_lite::FutureExt;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// Something that happened during a transfer
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum TransferEvent {
+    /// We received the peer's offer
+    OfferReceived(Arc<Offer>),
+    /// A step of establishing the transit connection, like an attempt to reach one of the peer's hints
+    Transit(transit::TransitEvent),
+    /// The transit connection has been established
+    Connected(TransitInfo),
+    /// A file started. Transfer v1 sends everything as one file, which is an archive for directories.
+    FileStarted {
+        /// The path of the file within the offer
+        path: Vec<String>,
+        /// Its size in bytes, if known
+        size: Option<u64>,
+    },
+    /// Bytes have been sent or received
+    Progress(Progress),
+    /// A file has been sent or received completely
+    FileFinished {
+        /// The path of the file within the offer
+        path: Vec<String>,
+    },
+    /// The transfer completed successfully
+    Finished,
+    /// The transfer failed. This is the last event, and the error is returned as well.
+    Failed(Box<str>),
+}
+
+/// How far a transfer got, and how long it will still take
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct Progress {
+    /// The number of bytes transferred so far, including those of resumed files
+    pub bytes: u64,
+    /// The total number of bytes, if the size of all files is known
+    pub total: Option<u64>,
+    /// The average rate since the transfer started, in bytes per second
+    pub rate: f64,
+    /// The estimated remaining time, if the total is known and data is flowing
+    pub eta: Option<Duration>,
+}
+
+/* Adapt the separate transit and progress handlers to events */
+pub(super) fn legacy_handler(
+    transit_handler: impl FnOnce(TransitInfo),
+    mut progress_handler: impl FnMut(u64, Option<u64>),
+) -> impl FnMut(TransferEvent) {
+    let mut transit_handler = Some(transit_handler);
+    move |event| match event {
+        TransferEvent::Connected(info) => {
+            if let Some(transit_handler) = transit_handler.take() {
+                transit_handler(info);
+            }
+        },
+        TransferEvent::Progress(progress) => progress_handler(progress.bytes, progress.total),
+        _ => (),
+    }
+}
+
+/** Wraps an event handler, and keeps track of the transfer rate */
+pub(crate) struct EventReporter<F> {
+    handler: F,
+    /* When the first progress was reported, and how many bytes had been transferred then */
+    start: Option<(Instant, u64)>,
+}
+
+impl<F: FnMut(TransferEvent)> EventReporter<F> {
+    pub fn new(handler: F) -> Self {
+        Self {
+            handler,
+            start: None,
+        }
+    }
+
+    pub fn report(&mut self, event: TransferEvent) {
+        (self.handler)(event)
+    }
+
+    pub fn progress(&mut self, bytes: u64, total: Option<u64>) {
+        let (start, start_bytes) = *self.start.get_or_insert_with(|| (Instant::now(), bytes));
+        let elapsed = start.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            bytes.saturating_sub(start_bytes) as f64 / elapsed
+        } else {
+            0.0
+        };
+        let eta = total
+            .filter(|_| rate > 0.0)
+            .map(|total| Duration::from_secs_f64(total.saturating_sub(bytes) as f64 / rate));
+        self.report(TransferEvent::Progress(Progress {
+            bytes,
+            total,
+            rate,
+            eta,
+        }));
+    }
+
+    /** Report the final result of a transfer */
+    pub fn finish<T>(&mut self, result: Result<T, TransferError>) -> Result<T, TransferError> {
+        match &result {
+            Ok(_) => self.report(TransferEvent::Finished),
+            Err(err) => self.report(TransferEvent::Failed(err.to_string().into())),
+        }
+        result
+    }
+
+    /** Connect while reporting the attempts. The established connection is reported by the caller. */
+    pub async fn connect(
+        &mut self,
+        connector: TransitConnector,
+        role: TransitRole,
+        transit_key: crate::Key<transit::TransitKey>,
+        their_abilities: transit::Abilities,
+        their_hints: Arc<Hints>,
+    ) -> Result<(Transit, TransitInfo), TransitConnectError> {
+        /* The connection attempts run concurrently, so they send their events to us through a channel */
+        let (events_tx, mut events_rx) = futures::channel::mpsc::unbounded();
+        /* Boxed, because connecting is a large future */
+        let connect = Box::pin(
+            connector
+                .with_event_handler(move |event| {
+                    let _ = events_tx.unbounded_send(event);
+                })
+                .connect(role, transit_key, their_abilities, their_hints),
+        );
+        let forward = async {
+            while let Some(event) = events_rx.next().await {
+                self.report(TransferEvent::Transit(event));
+            }
+            futures::future::pending().await
+        };
+        let result = connect.or(forward).await;
+        while let Ok(event) = events_rx.try_recv() {
+            self.report(TransferEvent::Transit(event));
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_progress_rate() {
+        let mut events = Vec::new();
+        let mut reporter = EventReporter::new(|event| events.push(event));
+        reporter.progress(1000, Some(3000));
+        reporter.start = reporter
+            .start
+            .map(|(start, bytes)| (start - Duration::from_secs(2), bytes));
+        reporter.progress(2000, Some(3000));
+        reporter.progress(2500, None);
+
+        let progress = events
+            .into_iter()
+            .map(|event| match event {
+                TransferEvent::Progress(progress) => progress,
+                other => panic!("Expected progress, got {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(progress[0].eta, None);
+        /* Resumed bytes don't count towards the rate */
+        assert!((progress[1].rate - 500.0).abs() < 10.0);
+        let eta = progress[1].eta.unwrap().as_secs_f64();
+        assert!((eta - 2.0).abs() < 0.1);
+        assert_eq!(progress[2].eta, None);
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 #[cfg(not(target_family = "wasm"))]
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use futures::{AsyncRead, AsyncSeek, AsyncWrite, Future};
 use serde_derive::{Deserialize, Serialize};
@@ -59,9 +59,52 @@ impl OfferSend {
     /// as advertized in file_size.
     pub fn new_file_custom(offer_name: String, size: u64, content: OfferContent) -> Self {
         let mut content_ = BTreeMap::new();
-        content_.insert(offer_name, OfferSendEntry::RegularFile { size, content });
+        content_.insert(
+            offer_name,
+            OfferSendEntry::RegularFile {
+                size,
+                content,
+                metadata: Metadata::default(),
+                unknown_size: false,
+            },
+        );
         Self { content: content_ }
     }
+
+    /// Offer a single file with custom content of unknown length, like the output of another program
+    ///
+    /// Only transfer v2 peers which announce support receive it as a stream. For all other peers,
+    /// the content is first buffered into a temporary file, so that it can be offered with its size.
+    /// Where that isn't possible, sending fails with
+    /// [`TransferError::UnknownSizeUnsupported`](super::TransferError::UnknownSizeUnsupported).
+    pub fn new_file_stream(offer_name: String, content: OfferContent) -> Self {
+        let mut content_ = BTreeMap::new();
+        content_.insert(
+            offer_name,
+            OfferSendEntry::RegularFile {
+                size: 0,
+                content,
+                metadata: Metadata::default(),
+                unknown_size: true,
+            },
+        );
+        Self { content: content_ }
+    }
+
+    /**
+     * Buffer all files of unknown size into temporary files, so that they can be offered with their size
+     *
+     * This is for peers which don't support streams of unknown size. The temporary files are
+     * removed once the offer is dropped.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub(super) async fn buffer_unknown_sizes(self) -> std::io::Result<Self> {
+        let mut content = BTreeMap::new();
+        for (name, entry) in self.content {
+            content.insert(name, entry.buffer_unknown_size().await?);
+        }
+        Ok(Self { content })
+    }
 }
 
 impl<T> Offer<T> {
@@ -106,8 +149,23 @@ impl<T> Offer<T> {
         self.iter_files().map(|v| v.2).sum()
     }
 
+    /** Whether the size of all files is known in advance, see [`new_file_stream`](Offer::new_file_stream) */
+    pub fn is_size_known(&self) -> bool {
+        self.content.values().all(OfferEntry::is_size_known)
+    }
+
     #[cfg(not(target_family = "wasm"))]
     pub fn accept_all(&self, target_dir: &Path) -> OfferAccept {
+        self.accept_all_with(target_dir, |_path| None)
+    }
+
+    /* `resume` returns the offset and hash of already received content */
+    #[cfg(not(target_family = "wasm"))]
+    fn accept_all_with(
+        &self,
+        target_dir: &Path,
+        mut resume: impl FnMut(&[String]) -> Option<(u64, [u8; 32])>,
+    ) -> OfferAccept {
         self.set_content(|path| {
             let full_path: PathBuf = target_dir.join(path.join("/"));
             let content = new_accept_content(move |append| {
@@ -119,14 +177,47 @@ impl<T> Offer<T> {
                     .truncate(!append)
                     .open(full_path)
             });
+            let (offset, sha256) = match resume(path) {
+                Some((offset, sha256)) => (offset, Some(sha256)),
+                None => (0, None),
+            };
             AcceptInner {
                 content: Box::new(content) as _,
-                offset: 0,
-                sha256: None,
+                offset,
+                sha256,
             }
         })
     }
 
+    /**
+     * Like [`accept_all`](Self::accept_all), but continue where a previous attempt left off
+     *
+     * Files that already exist in `target_dir` and are smaller than offered are assumed to be
+     * partially received. Their content is hashed, so that the sender can verify it and only send
+     * the remainder. If the sender's file does not match, it will be received from scratch instead.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn accept_all_resume(&self, target_dir: &Path) -> std::io::Result<OfferAccept> {
+        let mut partial = std::collections::HashMap::new();
+        for (path, _, size) in self.iter_files() {
+            let full_path = target_dir.join(path.join("/"));
+            let len = match async_fs::metadata(&full_path).await {
+                Ok(metadata) if metadata.is_file() => metadata.len(),
+                Ok(_) => continue,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(error) => return Err(error),
+            };
+            if len == 0 || len > size {
+                continue;
+            }
+            let sha256 = sha256_prefix(async_fs::File::open(&full_path).await?, len).await?;
+            tracing::debug!("Resuming {} at {len} of {size} bytes", full_path.display());
+            partial.insert(path, (len, sha256));
+        }
+
+        Ok(self.accept_all_with(target_dir, |path| partial.remove(path)))
+    }
+
     #[cfg(not(target_family = "wasm"))]
     pub async fn create_directories(&self, target_path: &Path) -> std::io::Result<()> {
         // TODO this could be made more efficient by passing around just one buffer
@@ -136,14 +227,121 @@ impl<T> Offer<T> {
         Ok(())
     }
 
-    // #[cfg(not(target_family = "wasm"))]
-    // pub async fn create_symlinks(&self, target_path: &Path) -> std::io::Result<()> {
-    //     // TODO this could be made more efficient by passing around just one buffer
-    //     for (name, file) in &self.content {
-    //         file.create_symlinks(&target_path.join(name)).await?;
-    //     }
-    //     Ok(())
-    // }
+    /** Recursively list all symlinks, together with their target. */
+    pub fn iter_symlinks(&self) -> impl Iterator<Item = (Vec<String>, &str)> + '_ {
+        self.content.iter().flat_map(|(name, offer)| {
+            let name = name.clone();
+            offer.iter_symlinks().map(move |mut val| {
+                val.0.insert(0, name.clone());
+                val
+            })
+        })
+    }
+
+    /**
+     * Create the offered symlinks in `target_path`
+     *
+     * Call this only after all files have been received, so that nothing gets written through a link.
+     * All links are checked against the `policy` before the first one is created.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn create_symlinks(
+        &self,
+        target_path: &Path,
+        policy: SymlinkPolicy,
+    ) -> std::io::Result<()> {
+        if policy == SymlinkPolicy::Skip {
+            return Ok(());
+        }
+
+        let links = self.iter_symlinks().collect::<Vec<_>>();
+        for (path, target) in &links {
+            if !path.iter().all(|name| is_valid_file_name(name)) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("The offered path '{}' is not allowed", path.join("/")),
+                ));
+            }
+            if policy == SymlinkPolicy::WithinTarget && !self.symlink_stays_within(path, target) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    format!(
+                        "The symlink '{}' points outside of the target directory: {target}",
+                        path.join("/")
+                    ),
+                ));
+            }
+        }
+
+        for (path, target) in links {
+            let link = target_path.join(path.join("/"));
+            /* Replace links from a previous attempt, but nothing else */
+            if async_fs::symlink_metadata(&link)
+                .await
+                .is_ok_and(|metadata| metadata.is_symlink())
+            {
+                async_fs::remove_file(&link).await?;
+            }
+            create_symlink(target, &link).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Apply the offered file metadata (permissions, modification time) in `target_path`
+     *
+     * Call this on the accepted answer only after all of its files have been received and the
+     * symlinks have been created, as it may remove write permissions. Entries that don't exist
+     * are skipped. All names are checked before any metadata is applied.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn apply_metadata(&self, target_path: &Path) -> std::io::Result<()> {
+        if let Some((name, _)) = self
+            .content
+            .iter()
+            .find(|(name, file)| !is_valid_file_name(name) || !file.has_valid_names())
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("The offered path '{name}' contains names that are not allowed"),
+            ));
+        }
+        for (name, file) in &self.content {
+            file.apply_metadata(&target_path.join(name)).await?;
+        }
+        Ok(())
+    }
+
+    /* Resolve the link target lexically. Going through other links is not allowed, as we can't know where they point to */
+    #[cfg(not(target_family = "wasm"))]
+    fn symlink_stays_within(&self, link: &[String], target: &str) -> bool {
+        let is_relative = Path::new(target)
+            .components()
+            .all(|component| !matches!(component, Component::Prefix(_) | Component::RootDir));
+        if target.is_empty() || !is_relative || target.contains(['\\', '\0']) {
+            return false;
+        }
+
+        let mut resolved = link[..link.len() - 1].to_vec();
+        let components = target
+            .split('/')
+            .filter(|component| !matches!(*component, "" | "."))
+            .collect::<Vec<_>>();
+        for (i, component) in components.iter().enumerate() {
+            if *component == ".." {
+                if resolved.pop().is_none() {
+                    return false;
+                }
+            } else {
+                resolved.push(component.to_string());
+            }
+            let is_last = i + 1 == components.len();
+            if !is_last && matches!(self.get(&resolved), Some(OfferEntry::Symlink { .. })) {
+                return false;
+            }
+        }
+        true
+    }
 
     pub fn offer_name(&self) -> String {
         let (name, entry) = self.content.iter().next().unwrap();
@@ -181,6 +379,20 @@ impl<T> Offer<T> {
                 .collect(),
         }
     }
+
+    /* Like `set_content`, but leave out the files where `f` returns `None`, symlinks, and directories that end up empty */
+    fn filter_content<U>(&self, mut f: impl FnMut(&[String]) -> Option<U>) -> Offer<U> {
+        Offer {
+            content: self
+                .content
+                .iter()
+                .filter_map(|(k, v)| {
+                    v.filter_content(&mut vec![k.clone()], &mut f)
+                        .map(|v| (k.clone(), v))
+                })
+                .collect(),
+        }
+    }
 }
 
 impl<T: 'static + Send> Offer<T> {
@@ -233,6 +445,138 @@ where
     Box::new(wrap_fun) as _
 }
 
+/**
+ * Offer content from a stream that can only be read once, like stdin or a pipe
+ *
+ * The stream is handed out on the first invocation only, and seeking is limited to where it
+ * currently is. This means that it can't be sent multiple times, and that resuming a transfer
+ * might fail.
+ */
+pub fn new_offer_content_stream<R>(reader: R) -> OfferContent
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let reader = std::sync::Mutex::new(Some(reader));
+    new_offer_content(move || {
+        let reader = reader.lock().unwrap().take();
+        async move {
+            reader
+                .map(|reader| ReadOnce {
+                    reader,
+                    position: 0,
+                })
+                .ok_or_else(|| std::io::Error::other("The stream has already been read"))
+        }
+    })
+}
+
+/* Adapter to pretend that a stream is seekable, as long as nobody actually tries to move */
+struct ReadOnce<R> {
+    reader: R,
+    position: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ReadOnce<R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let result = std::pin::Pin::new(&mut self.reader).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(n)) = result {
+            self.position += n as u64;
+        }
+        result
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncSeek for ReadOnce<R> {
+    fn poll_seek(
+        self: std::pin::Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+        pos: std::io::SeekFrom,
+    ) -> std::task::Poll<std::io::Result<u64>> {
+        let target = match pos {
+            std::io::SeekFrom::Start(offset) => Some(offset),
+            std::io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            std::io::SeekFrom::End(_) => None,
+        };
+        std::task::Poll::Ready(match target {
+            Some(target) if target == self.position => Ok(target),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Streams can't be seeked",
+            )),
+        })
+    }
+}
+
+/* Copy some content into a temporary file, and offer that file instead */
+#[cfg(not(target_family = "wasm"))]
+async fn buffer_content(content: OfferContent) -> std::io::Result<(u64, OfferContent)> {
+    use futures::AsyncWriteExt;
+
+    /* Removes the file once the last handle to it is dropped */
+    struct TempFile(PathBuf);
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    let path = std::env::temp_dir().join(format!(
+        "wormhole-buffer-{}",
+        hex::encode(rand::random::<[u8; 8]>())
+    ));
+    let mut file = create_private_file(&path).await?;
+    let temp_file = std::sync::Arc::new(TempFile(path));
+    let size = futures::io::copy(content().await?, &mut file).await?;
+    file.flush().await?;
+    tracing::debug!("Buffered {size} bytes into {}", temp_file.0.display());
+
+    Ok((
+        size,
+        new_offer_content(move || {
+            let temp_file = temp_file.clone();
+            async move { async_fs::File::open(&temp_file.0).await }
+        }),
+    ))
+}
+
+/* Create a new file that other users can't read */
+#[cfg(not(target_family = "wasm"))]
+async fn create_private_file(path: &Path) -> std::io::Result<async_fs::File> {
+    let mut options = async_fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use async_fs::unix::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path).await
+}
+
+/// Hash the first `len` bytes of some content, to check whether a transfer can be resumed
+pub(super) async fn sha256_prefix(
+    mut content: impl AsyncRead + Unpin,
+    len: u64,
+) -> std::io::Result<[u8; 32]> {
+    use futures::AsyncReadExt;
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::default();
+    let mut buffer = vec![0u8; 16 * 1024];
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = &mut buffer[..remaining.min(16 * 1024) as usize];
+        content.read_exact(chunk).await?;
+        hasher.update(&chunk);
+        remaining -= chunk.len() as u64;
+    }
+    Ok(hasher.finalize().into())
+}
+
 pub type OfferSendEntry = OfferEntry<OfferContent>;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
@@ -244,33 +588,178 @@ pub enum OfferEntry<T = ()> {
         size: u64,
         #[serde(skip)]
         content: T,
+        #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+        metadata: Metadata,
+        /// The content is a stream whose length is not known in advance. The `size` is zero then.
+        #[serde(
+            default,
+            rename = "unknown-size",
+            skip_serializing_if = "std::ops::Not::not"
+        )]
+        unknown_size: bool,
     },
     Directory {
         content: BTreeMap<String, Self>,
+        #[serde(default, skip_serializing_if = "Metadata::is_empty")]
+        metadata: Metadata,
+    },
+    Symlink {
+        target: String,
     },
-    // Symlink {
-    //     target: String,
-    // },
+}
+
+/// Optional file system metadata of an offered file or directory
+///
+/// Senders fill in what they know about, receivers only apply it when asked to via
+/// [`Offer::apply_metadata`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub struct Metadata {
+    /// Unix permission bits (e.g. `0o755`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    /// Last modification time, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+}
+
+impl Metadata {
+    pub fn new(mode: Option<u32>, mtime: Option<u64>) -> Self {
+        Self { mode, mtime }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.mtime.is_none()
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn from_fs(metadata: &std::fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_secs());
+        Self { mode, mtime }
+    }
+
+    /** Apply the metadata to an existing file or directory */
+    #[cfg(not(target_family = "wasm"))]
+    async fn apply(self, path: &Path) -> std::io::Result<()> {
+        let path = path.to_owned();
+        /* There is no async version of `set_modified`, so do it all on the blocking thread pool */
+        blocking::unblock(move || {
+            if let Some(mtime) = self.mtime {
+                std::fs::File::open(&path)?
+                    .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(mtime))?;
+            }
+            #[cfg(unix)]
+            if let Some(mode) = self.mode {
+                use std::os::unix::fs::PermissionsExt;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o777))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Which of the offered symlinks to create
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SymlinkPolicy {
+    /// Only create relative links that stay within the target directory, and refuse the offer otherwise
+    #[default]
+    WithinTarget,
+    /// Create all links as they were offered, no matter where they point to
+    Any,
+    /// Don't create any links
+    Skip,
+}
+
+#[cfg(unix)]
+async fn create_symlink(target: &str, link: &Path) -> std::io::Result<()> {
+    async_fs::unix::symlink(target, link).await
+}
+
+#[cfg(all(not(unix), not(target_family = "wasm")))]
+async fn create_symlink(_target: &str, link: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        format!(
+            "Creating symlinks is not supported on this platform: {}",
+            link.display()
+        ),
+    ))
 }
 
 impl OfferSendEntry {
     #[cfg(not(target_family = "wasm"))]
     pub(super) async fn new(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        Self::new_inner(path.as_ref(), true).await
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    async fn buffer_unknown_size(self) -> std::io::Result<Self> {
         // Workaround for https://github.com/rust-lang/rust/issues/78649
         #[inline(always)]
-        fn new_recurse<'a>(
-            path: impl AsRef<Path> + 'a + Send,
-        ) -> futures::future::BoxFuture<'a, std::io::Result<OfferSendEntry>> {
-            Box::pin(OfferSendEntry::new(path))
+        fn recurse(
+            entry: OfferSendEntry,
+        ) -> futures::future::BoxFuture<'static, std::io::Result<OfferSendEntry>> {
+            Box::pin(entry.buffer_unknown_size())
         }
 
-        let path = path.as_ref();
-        // let metadata = async_fs::symlink_metadata(path).await?;
-        let metadata = async_fs::metadata(path).await?;
-        // let mtime = metadata.modified()?
-        //     .duration_since(std::time::SystemTime::UNIX_EPOCH)
-        //     .unwrap_or_default()
-        //     .as_secs();
+        match self {
+            Self::RegularFile {
+                content,
+                metadata,
+                unknown_size: true,
+                ..
+            } => {
+                let (size, content) = buffer_content(content).await?;
+                Ok(Self::RegularFile {
+                    size,
+                    content,
+                    metadata,
+                    unknown_size: false,
+                })
+            },
+            Self::Directory { content, metadata } => {
+                let mut buffered = BTreeMap::new();
+                for (name, entry) in content {
+                    buffered.insert(name, recurse(entry).await?);
+                }
+                Ok(Self::Directory {
+                    content: buffered,
+                    metadata,
+                })
+            },
+            entry => Ok(entry),
+        }
+    }
+
+    /* Only explicitly passed symlinks are followed, the ones within directories are sent as symlinks */
+    #[cfg(not(target_family = "wasm"))]
+    async fn new_inner(path: &Path, follow_symlinks: bool) -> std::io::Result<Self> {
+        // Workaround for https://github.com/rust-lang/rust/issues/78649
+        #[inline(always)]
+        fn new_recurse(
+            path: PathBuf,
+        ) -> futures::future::BoxFuture<'static, std::io::Result<OfferSendEntry>> {
+            Box::pin(async move { OfferSendEntry::new_inner(&path, false).await })
+        }
+
+        let metadata = match follow_symlinks {
+            true => async_fs::metadata(path).await?,
+            false => async_fs::symlink_metadata(path).await?,
+        };
         if metadata.is_file() {
             tracing::trace!("OfferSendEntry::new {path:?} is file");
             let path = path.to_owned();
@@ -280,20 +769,20 @@ impl OfferSendEntry {
                     let path = path.clone();
                     async_fs::File::open(path)
                 }),
+                metadata: Metadata::from_fs(&metadata),
+                unknown_size: false,
+            })
+        } else if metadata.is_symlink() {
+            tracing::trace!("OfferSendEntry::new {path:?} is symlink");
+            let target = async_fs::read_link(path).await?;
+            Ok(Self::Symlink {
+                target: target
+                    .to_str()
+                    .ok_or_else(|| {
+                        std::io::Error::other(format!("{} is not UTF-8 encoded", target.display()))
+                    })?
+                    .to_string(),
             })
-        // } else if metadata.is_symlink() {
-        //     tracing::trace!("OfferSendEntry::new {path:?} is symlink");
-        //     let target = async_fs::read_link(path).await?;
-        //     Ok(Self::Symlink {
-        //         target: target
-        //             .to_str()
-        //             .ok_or_else(|| {
-        //                 std::io::Error::other(
-        //                     format!("{} is not UTF-8 encoded", target.display()),
-        //                 )
-        //             })?
-        //             .to_string(),
-        //     })
         } else if metadata.is_dir() {
             use futures::TryStreamExt;
             tracing::trace!("OfferSendEntry::new {path:?} is directory");
@@ -318,7 +807,10 @@ impl OfferSendEntry {
                 })
                 .try_collect()
                 .await?;
-            Ok(Self::Directory { content })
+            Ok(Self::Directory {
+                content,
+                metadata: Metadata::from_fs(&metadata),
+            })
         } else {
             unreachable!()
         }
@@ -340,10 +832,39 @@ impl<T> OfferEntry<T> {
                 });
                 Box::new(iter) as Box<dyn Iterator<Item = _>>
             },
-            Self::RegularFile { content, size } => {
+            Self::RegularFile { content, size, .. } => {
                 Box::new(std::iter::once((vec![], content, *size))) as Box<dyn Iterator<Item = _>>
             },
-            // Self::Symlink { .. } => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _>>,
+            Self::Symlink { .. } => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _>>,
+        }
+    }
+
+    /** Recursively list all symlinks, together with their target. */
+    fn iter_symlinks(&self) -> impl Iterator<Item = (Vec<String>, &str)> + '_ {
+        match self {
+            Self::Directory { content, .. } => {
+                let iter = content.iter().flat_map(|(name, offer)| {
+                    let name = name.clone();
+                    offer.iter_symlinks().map(move |mut val| {
+                        val.0.insert(0, name.clone());
+                        val
+                    })
+                });
+                Box::new(iter) as Box<dyn Iterator<Item = _>>
+            },
+            Self::Symlink { target } => {
+                Box::new(std::iter::once((vec![], target.as_str()))) as Box<dyn Iterator<Item = _>>
+            },
+            Self::RegularFile { .. } => Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _>>,
+        }
+    }
+
+    /** Whether the size of this file, or of all files in this directory, is known in advance */
+    pub fn is_size_known(&self) -> bool {
+        match self {
+            Self::RegularFile { unknown_size, .. } => !unknown_size,
+            Self::Directory { content, .. } => content.values().all(Self::is_size_known),
+            Self::Symlink { .. } => true,
         }
     }
 
@@ -362,7 +883,7 @@ impl<T> OfferEntry<T> {
     fn get_file(&self, path: &[String]) -> Option<(&T, u64)> {
         match path {
             [] => match self {
-                Self::RegularFile { content, size } => Some((content, *size)),
+                Self::RegularFile { content, size, .. } => Some((content, *size)),
                 _ => None,
             },
             [start, rest @ ..] => match self {
@@ -385,7 +906,8 @@ impl<T> OfferEntry<T> {
         }
         match self {
             Self::Directory { content, .. } => {
-                async_fs::create_dir(target_path).await?;
+                /* The directories may already exist when resuming */
+                async_fs::create_dir_all(target_path).await?;
                 for (name, file) in content {
                     recurse(file, &target_path.join(name)).await?;
                 }
@@ -395,28 +917,41 @@ impl<T> OfferEntry<T> {
         }
     }
 
-    // #[cfg(not(target_family = "wasm"))]
-    // async fn create_symlinks(&self, target_path: &Path) -> std::io::Result<()> {
-    //     #[inline(always)]
-    //     fn recurse<'a, T>(
-    //         this: &'a OfferEntry<T>,
-    //         path: &'a Path,
-    //     ) -> futures::future::LocalBoxFuture<'a, std::io::Result<()>> {
-    //         Box::pin(OfferEntry::create_symlinks(this, path))
-    //     }
-    //     match self {
-    //         Self::Symlink { target } => {
-    //             todo!()
-    //         },
-    //         Self::Directory { content, .. } => {
-    //             for (name, file) in content {
-    //                 recurse(file, &target_path.join(name)).await?;
-    //             }
-    //             Ok(())
-    //         },
-    //         _ => Ok(()),
-    //     }
-    // }
+    #[cfg(not(target_family = "wasm"))]
+    fn has_valid_names(&self) -> bool {
+        match self {
+            Self::Directory { content, .. } => content
+                .iter()
+                .all(|(name, file)| is_valid_file_name(name) && file.has_valid_names()),
+            _ => true,
+        }
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    async fn apply_metadata(&self, target_path: &Path) -> std::io::Result<()> {
+        #[inline(always)]
+        fn recurse<'a, T>(
+            this: &'a OfferEntry<T>,
+            path: &'a Path,
+        ) -> futures::future::LocalBoxFuture<'a, std::io::Result<()>> {
+            Box::pin(OfferEntry::apply_metadata(this, path))
+        }
+        let metadata = match self {
+            Self::RegularFile { metadata, .. } => metadata,
+            Self::Directory { content, metadata } => {
+                /* Children first, the directory's mode might not allow writing to it anymore */
+                for (name, file) in content {
+                    recurse(file, &target_path.join(name)).await?;
+                }
+                metadata
+            },
+            Self::Symlink { .. } => return Ok(()),
+        };
+        match metadata.apply(target_path).await {
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
 
     fn set_content<U>(
         &self,
@@ -424,11 +959,19 @@ impl<T> OfferEntry<T> {
         f: &mut impl FnMut(&[String]) -> U,
     ) -> OfferEntry<U> {
         match self {
-            OfferEntry::RegularFile { size, .. } => OfferEntry::RegularFile {
+            OfferEntry::RegularFile {
+                size,
+                metadata,
+                unknown_size,
+                ..
+            } => OfferEntry::RegularFile {
                 size: *size,
                 content: f(base_path),
+                metadata: *metadata,
+                unknown_size: *unknown_size,
             },
-            OfferEntry::Directory { content } => OfferEntry::Directory {
+            OfferEntry::Directory { content, metadata } => OfferEntry::Directory {
+                metadata: *metadata,
                 content: content
                     .iter()
                     .map(|(k, v)| {
@@ -439,9 +982,45 @@ impl<T> OfferEntry<T> {
                     })
                     .collect(),
             },
-            // OfferEntry::Symlink { target } => OfferEntry::Symlink {
-            //     target: target.clone(),
-            // },
+            OfferEntry::Symlink { target } => OfferEntry::Symlink {
+                target: target.clone(),
+            },
+        }
+    }
+
+    fn filter_content<U>(
+        &self,
+        base_path: &mut Vec<String>,
+        f: &mut impl FnMut(&[String]) -> Option<U>,
+    ) -> Option<OfferEntry<U>> {
+        match self {
+            OfferEntry::RegularFile {
+                size,
+                metadata,
+                unknown_size,
+                ..
+            } => f(base_path).map(|content| OfferEntry::RegularFile {
+                size: *size,
+                content,
+                metadata: *metadata,
+                unknown_size: *unknown_size,
+            }),
+            OfferEntry::Directory { content, metadata } => {
+                let content: BTreeMap<_, _> = content
+                    .iter()
+                    .filter_map(|(k, v)| {
+                        base_path.push(k.clone());
+                        let v = v.filter_content(base_path, f);
+                        base_path.pop();
+                        v.map(|v| (k.clone(), v))
+                    })
+                    .collect();
+                (!content.is_empty()).then_some(OfferEntry::Directory {
+                    content,
+                    metadata: *metadata,
+                })
+            },
+            OfferEntry::Symlink { .. } => None,
         }
     }
 }
@@ -460,13 +1039,13 @@ impl<T: 'static + Send> OfferEntry<T> {
                 });
                 Box::new(iter) as Box<dyn Iterator<Item = _> + Send>
             },
-            Self::RegularFile { content, size } => {
+            Self::RegularFile { content, size, .. } => {
                 Box::new(std::iter::once((vec![], content, size)))
                     as Box<dyn Iterator<Item = _> + Send>
             },
-            // Self::Symlink { .. } => {
-            //     Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _> + Send>
-            // },
+            Self::Symlink { .. } => {
+                Box::new(std::iter::empty()) as Box<dyn Iterator<Item = _> + Send>
+            },
         }
     }
 }
@@ -515,3 +1094,723 @@ pub struct AcceptInner {
     pub sha256: Option<[u8; 32]>,
     pub content: AcceptContent,
 }
+
+impl Offer {
+    /**
+     * Choose which of the offered files to receive, and where to
+     *
+     * By default, all files are accepted into `target_dir`, keeping their relative paths, and existing
+     * files are overwritten.
+     */
+    #[cfg(not(target_family = "wasm"))]
+    pub fn accept_builder(&self, target_dir: impl Into<PathBuf>) -> OfferAcceptBuilder<'_> {
+        OfferAcceptBuilder {
+            offer: self,
+            target_dir: target_dir.into(),
+            selection: Vec::new(),
+            renames: Vec::new(),
+            destinations: Vec::new(),
+            existing: ExistingFile::default(),
+            existing_at: Vec::new(),
+        }
+    }
+}
+
+/// What to do when a file already exists at the destination
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ExistingFile {
+    /// Receive the file again, replacing the existing one
+    #[default]
+    Overwrite,
+    /// Don't receive the file
+    Skip,
+    /// Assume that the file has been received partially, and only receive the remainder.
+    /// Falls back to overwriting if the content does not match.
+    Resume,
+}
+
+#[cfg(not(target_family = "wasm"))]
+#[derive(Clone, Debug)]
+enum Selector {
+    /** An entry in the offer, and everything below it */
+    Path(String),
+    Glob(String),
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl Selector {
+    fn matches(&self, file: &[String]) -> bool {
+        /* Check the file and all directories containing it */
+        (1..=file.len()).any(|len| {
+            let path = file[..len].join("/");
+            match self {
+                Selector::Path(selected) => path == *selected,
+                Selector::Glob(pattern) => glob_matches(
+                    &pattern.chars().collect::<Vec<_>>(),
+                    &path.chars().collect::<Vec<_>>(),
+                ),
+            }
+        })
+    }
+}
+
+/* `*` and `?` match within a path component, `**` matches across components */
+#[cfg(not(target_family = "wasm"))]
+fn glob_matches(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', '/', rest @ ..] => (0..=path.len())
+            .filter(|&i| i == 0 || path[i - 1] == '/')
+            .any(|i| glob_matches(rest, &path[i..])),
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+        ['*', rest @ ..] => (0..=path.len())
+            .take_while(|&i| i == 0 || path[i - 1] != '/')
+            .any(|i| glob_matches(rest, &path[i..])),
+        ['?', rest @ ..] => matches!(path, [c, tail @ ..] if *c != '/' && glob_matches(rest, tail)),
+        [p, rest @ ..] => matches!(path, [c, tail @ ..] if c == p && glob_matches(rest, tail)),
+    }
+}
+
+/**
+ * Build an [`OfferAccept`] that only receives some of the offered files
+ *
+ * Paths are relative to the offer and separated by `/`, like `folder/file.txt`. Selecting a
+ * directory selects everything inside of it. When multiple rules apply to a file, the last one wins.
+ *
+ * ```no_run
+ * # async fn example(offer: &magic_wormhole::transfer::offer::Offer) -> Result<(), magic_wormhole::transfer::TransferError> {
+ * use magic_wormhole::transfer::offer::ExistingFile;
+ *
+ * let answer = offer
+ *     .accept_builder("Downloads")
+ *     .exclude_glob("**.tmp")
+ *     .rename("holiday", "Holiday 2024")
+ *     .on_existing(ExistingFile::Resume)
+ *     .build()
+ *     .await?;
+ * # Ok(()) }
+ * ```
+ */
+#[cfg(not(target_family = "wasm"))]
+#[must_use]
+pub struct OfferAcceptBuilder<'a> {
+    offer: &'a Offer,
+    target_dir: PathBuf,
+    /* `true` for include */
+    selection: Vec<(bool, Selector)>,
+    renames: Vec<(String, String)>,
+    destinations: Vec<(String, PathBuf)>,
+    existing: ExistingFile,
+    existing_at: Vec<(String, ExistingFile)>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl OfferAcceptBuilder<'_> {
+    /// Receive this entry of the offer
+    pub fn include(mut self, path: impl Into<String>) -> Self {
+        self.selection.push((true, Selector::Path(path.into())));
+        self
+    }
+
+    /// Don't receive this entry of the offer
+    pub fn exclude(mut self, path: impl Into<String>) -> Self {
+        self.selection.push((false, Selector::Path(path.into())));
+        self
+    }
+
+    /// Receive all entries matching the glob pattern
+    pub fn include_glob(mut self, pattern: impl Into<String>) -> Self {
+        self.selection.push((true, Selector::Glob(pattern.into())));
+        self
+    }
+
+    /// Don't receive any entries matching the glob pattern
+    pub fn exclude_glob(mut self, pattern: impl Into<String>) -> Self {
+        self.selection.push((false, Selector::Glob(pattern.into())));
+        self
+    }
+
+    /// Save an entry under a different name, within the same directory
+    pub fn rename(mut self, path: impl Into<String>, name: impl Into<String>) -> Self {
+        self.renames.push((path.into(), name.into()));
+        self
+    }
+
+    /// Save a single file at an arbitrary location
+    pub fn destination(mut self, path: impl Into<String>, destination: impl Into<PathBuf>) -> Self {
+        self.destinations.push((path.into(), destination.into()));
+        self
+    }
+
+    /// What to do with files that already exist at their destination
+    pub fn on_existing(mut self, existing: ExistingFile) -> Self {
+        self.existing = existing;
+        self
+    }
+
+    /// What to do with the files of this entry that already exist at their destination
+    pub fn on_existing_at(mut self, path: impl Into<String>, existing: ExistingFile) -> Self {
+        self.existing_at.push((path.into(), existing));
+        self
+    }
+
+    fn is_included(&self, file: &[String]) -> bool {
+        self.selection
+            .iter()
+            .rev()
+            .find(|(_, selector)| selector.matches(file))
+            .is_none_or(|(include, _)| *include)
+    }
+
+    fn destination_of(&self, file: &[String]) -> PathBuf {
+        let joined = file.join("/");
+        if let Some((_, destination)) = self
+            .destinations
+            .iter()
+            .rev()
+            .find(|(path, _)| *path == joined)
+        {
+            return destination.clone();
+        }
+
+        let mut destination = self.target_dir.clone();
+        for len in 1..=file.len() {
+            let path = file[..len].join("/");
+            match self
+                .renames
+                .iter()
+                .rev()
+                .find(|(renamed, _)| *renamed == path)
+            {
+                Some((_, name)) => destination.push(name),
+                None => destination.push(&file[len - 1]),
+            }
+        }
+        destination
+    }
+
+    fn existing_of(&self, file: &[String]) -> ExistingFile {
+        self.existing_at
+            .iter()
+            .rev()
+            .find(|(path, _)| Selector::Path(path.clone()).matches(file))
+            .map_or(self.existing, |(_, existing)| *existing)
+    }
+
+    /**
+     * Check the selection against the offer and build the answer
+     *
+     * This fails if any of the given paths are not part of the offer, if the offer contains
+     * file names that would escape their destination directory, or if multiple files
+     * would be saved at the same location.
+     */
+    pub async fn build(self) -> Result<OfferAccept, super::TransferError> {
+        use super::TransferError;
+
+        let paths = self
+            .selection
+            .iter()
+            .filter_map(|(_, selector)| match selector {
+                Selector::Path(path) => Some(path),
+                Selector::Glob(_) => None,
+            })
+            .chain(self.renames.iter().map(|(path, _)| path))
+            .chain(self.destinations.iter().map(|(path, _)| path))
+            .chain(self.existing_at.iter().map(|(path, _)| path));
+        for path in paths {
+            let split = path.split('/').map(str::to_owned).collect::<Vec<_>>();
+            ensure!(
+                self.offer.get(&split).is_some(),
+                TransferError::InvalidAnswer(format!("'{path}' is not part of the offer").into())
+            );
+        }
+        for (_, name) in &self.renames {
+            ensure!(
+                is_valid_file_name(name),
+                TransferError::InvalidAnswer(format!("'{name}' is not a valid file name").into())
+            );
+        }
+
+        let mut files = std::collections::HashMap::new();
+        let mut destinations = std::collections::HashSet::new();
+        for (file, _, size) in self.offer.iter_files() {
+            ensure!(
+                file.iter().all(|name| is_valid_file_name(name)),
+                TransferError::InvalidAnswer(
+                    format!("The offered path '{}' is not allowed", file.join("/")).into()
+                )
+            );
+            if !self.is_included(&file) {
+                continue;
+            }
+
+            let destination = self.destination_of(&file);
+            ensure!(
+                destinations.insert(destination.clone()),
+                TransferError::InvalidAnswer(
+                    format!("Multiple files would be saved as {}", destination.display()).into()
+                )
+            );
+
+            let metadata = match async_fs::metadata(&destination).await {
+                Ok(metadata) => Some(metadata),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => None,
+                Err(error) => return Err(error.into()),
+            };
+            let (offset, sha256) = match (self.existing_of(&file), metadata) {
+                (ExistingFile::Skip, Some(_)) => continue,
+                (ExistingFile::Resume, Some(metadata))
+                    if metadata.is_file() && metadata.len() > 0 && metadata.len() <= size =>
+                {
+                    let len = metadata.len();
+                    let sha256 =
+                        sha256_prefix(async_fs::File::open(&destination).await?, len).await?;
+                    (len, Some(sha256))
+                },
+                _ => (0, None),
+            };
+
+            let content = new_accept_content(move |append| {
+                let destination = destination.clone();
+                async move {
+                    if let Some(parent) = destination.parent() {
+                        async_fs::create_dir_all(parent).await?;
+                    }
+                    async_fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .append(append)
+                        .truncate(!append)
+                        .open(destination)
+                        .await
+                }
+            });
+            files.insert(
+                file,
+                AcceptInner {
+                    offset,
+                    sha256,
+                    content,
+                },
+            );
+        }
+
+        Ok(self.offer.filter_content(|path| files.remove(path)))
+    }
+}
+
+/* Offered names are untrusted, they must not take us out of the target directory */
+#[cfg(not(target_family = "wasm"))]
+pub(super) fn is_valid_file_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\\', '\0'])
+}
+
+#[cfg(all(test, not(target_family = "wasm")))]
+mod test {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_accept_all_resume() {
+        async_io::block_on(async {
+            let data = async_fs::read("tests/example-file.bin").await.unwrap();
+            let offer: Offer = (&OfferSend::new_file_or_folder(
+                "example-file.bin".into(),
+                "tests/example-file.bin",
+            )
+            .await
+            .unwrap())
+                .into();
+
+            let target_dir = std::env::temp_dir()
+                .join(format!("wormhole-test-resume-{}", rand::random::<u32>()));
+            async_fs::create_dir_all(&target_dir).await.unwrap();
+
+            /* Nothing to resume */
+            let accept = offer.accept_all_resume(&target_dir).await.unwrap();
+            let (_, inner, _) = accept.iter_files().next().unwrap();
+            assert_eq!((inner.offset, inner.sha256), (0, None));
+
+            /* A partially received file */
+            async_fs::write(target_dir.join("example-file.bin"), &data[..1000])
+                .await
+                .unwrap();
+            let accept = offer.accept_all_resume(&target_dir).await.unwrap();
+            let (_, inner, _) = accept.iter_files().next().unwrap();
+            assert_eq!(inner.offset, 1000);
+            assert_eq!(inner.sha256, Some(Sha256::digest(&data[..1000]).into()));
+
+            async_fs::remove_dir_all(&target_dir).await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        let glob = |pattern: &str, path: &str| {
+            glob_matches(
+                &pattern.chars().collect::<Vec<_>>(),
+                &path.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(glob("*.txt", "a.txt"));
+        assert!(!glob("*.txt", "dir/a.txt"));
+        assert!(glob("dir/?.txt", "dir/a.txt"));
+        assert!(glob("**/a.txt", "a.txt"));
+        assert!(glob("**/a.txt", "dir/sub/a.txt"));
+        assert!(!glob("**/a.txt", "dir/ba.txt"));
+        assert!(glob("**.txt", "dir/sub/b.txt"));
+        assert!(!glob("dir/*", "other/a"));
+    }
+
+    #[test]
+    fn test_accept_builder() {
+        async_io::block_on(async {
+            let offer: Offer = (&OfferSend::new_file_or_folder("tests".into(), "tests")
+                .await
+                .unwrap())
+                .into();
+            let target_dir = std::env::temp_dir()
+                .join(format!("wormhole-test-accept-{}", rand::random::<u32>()));
+
+            let accept = offer
+                .accept_builder(&target_dir)
+                .exclude_glob("**.bin")
+                .include("tests/example-file.bin")
+                .rename("tests", "renamed")
+                .build()
+                .await
+                .unwrap();
+            let mut files = accept.iter_file_paths().collect::<Vec<_>>();
+            files.sort();
+            assert_eq!(
+                files,
+                [
+                    vec!["tests".to_owned(), "example-file-empty".into()],
+                    vec!["tests".to_owned(), "example-file.bin".into()],
+                ]
+            );
+
+            /* Renaming applies when opening the file */
+            let (_, inner) = accept
+                .into_iter_files()
+                .map(|(path, inner, _)| (path, inner))
+                .find(|(path, _)| path[1] == "example-file-empty")
+                .unwrap();
+            drop((inner.content)(false).await.unwrap());
+            assert!(target_dir.join("renamed/example-file-empty").exists());
+
+            /* Skip the file that exists now */
+            let accept = offer
+                .accept_builder(&target_dir)
+                .rename("tests", "renamed")
+                .on_existing(ExistingFile::Skip)
+                .build()
+                .await
+                .unwrap();
+            assert_eq!(accept.iter_files().count(), 2);
+
+            /* Invalid selections */
+            assert!(
+                offer
+                    .accept_builder(&target_dir)
+                    .include("tests/nonexistent")
+                    .build()
+                    .await
+                    .is_err()
+            );
+            assert!(
+                offer
+                    .accept_builder(&target_dir)
+                    .rename("tests", "../escape")
+                    .build()
+                    .await
+                    .is_err()
+            );
+            assert!(
+                offer
+                    .accept_builder(&target_dir)
+                    .destination("tests/example-file.bin", "/tmp/a")
+                    .destination("tests/example-file-empty", "/tmp/a")
+                    .build()
+                    .await
+                    .is_err()
+            );
+
+            async_fs::remove_dir_all(&target_dir).await.unwrap();
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_symlinks() {
+        async_io::block_on(async {
+            let source_dir = std::env::temp_dir()
+                .join(format!("wormhole-test-symlinks-{}", rand::random::<u32>()));
+            async_fs::create_dir_all(source_dir.join("dir/sub"))
+                .await
+                .unwrap();
+            async_fs::write(source_dir.join("dir/sub/file.txt"), b"hello")
+                .await
+                .unwrap();
+            async_fs::unix::symlink("sub/file.txt", source_dir.join("dir/link"))
+                .await
+                .unwrap();
+            async_fs::unix::symlink("..", source_dir.join("dir/sub/up"))
+                .await
+                .unwrap();
+
+            /* Links within directories are sent as links, the explicitly passed path is followed */
+            let offer: Offer =
+                (&OfferSend::new_file_or_folder("dir".into(), source_dir.join("dir/sub/up"))
+                    .await
+                    .unwrap())
+                    .into();
+            let mut links = offer.iter_symlinks().collect::<Vec<_>>();
+            links.sort();
+            assert_eq!(
+                links,
+                [
+                    (vec!["dir".into(), "link".into()], "sub/file.txt"),
+                    (vec!["dir".into(), "sub".into(), "up".into()], ".."),
+                ]
+            );
+            assert_eq!(offer.total_size(), 5);
+
+            let target_dir = source_dir.join("target");
+            async_fs::create_dir_all(target_dir.join("dir/sub"))
+                .await
+                .unwrap();
+            offer
+                .create_symlinks(&target_dir, SymlinkPolicy::WithinTarget)
+                .await
+                .unwrap();
+            assert_eq!(
+                async_fs::read_link(target_dir.join("dir/link"))
+                    .await
+                    .unwrap(),
+                Path::new("sub/file.txt")
+            );
+            /* Creating them again replaces the previous links */
+            offer
+                .create_symlinks(&target_dir, SymlinkPolicy::WithinTarget)
+                .await
+                .unwrap();
+
+            let within = |link: &str, target| {
+                offer.symlink_stays_within(
+                    &link.split('/').map(str::to_owned).collect::<Vec<_>>(),
+                    target,
+                )
+            };
+            assert!(within("dir/link", "sub/file.txt"));
+            assert!(within("dir/link", "./sub/../../dir"));
+            assert!(within("dir/sub/up", "../link"));
+            assert!(!within("dir/link", "../.."));
+            assert!(!within("dir/link", "/etc/passwd"));
+            assert!(!within("dir/link", ""));
+            /* `up` is a link itself, so we don't know where this ends up */
+            assert!(!within("dir/link", "sub/up/../file.txt"));
+
+            let escaping: Offer = Offer {
+                content: [(
+                    "escape".to_owned(),
+                    OfferEntry::Symlink {
+                        target: "../outside".into(),
+                    },
+                )]
+                .into(),
+            };
+            let error = escaping
+                .create_symlinks(&target_dir, SymlinkPolicy::WithinTarget)
+                .await
+                .unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::PermissionDenied);
+            escaping
+                .create_symlinks(&target_dir, SymlinkPolicy::Skip)
+                .await
+                .unwrap();
+            assert!(!target_dir.join("escape").exists());
+            escaping
+                .create_symlinks(&target_dir, SymlinkPolicy::Any)
+                .await
+                .unwrap();
+            assert!(
+                async_fs::symlink_metadata(target_dir.join("escape"))
+                    .await
+                    .is_ok()
+            );
+
+            async_fs::remove_dir_all(&source_dir).await.unwrap();
+        })
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        async_io::block_on(async {
+            let source_dir = std::env::temp_dir()
+                .join(format!("wormhole-test-metadata-{}", rand::random::<u32>()));
+            async_fs::create_dir_all(source_dir.join("dir"))
+                .await
+                .unwrap();
+            async_fs::write(source_dir.join("dir/script.sh"), b"#!/bin/sh")
+                .await
+                .unwrap();
+            async_fs::set_permissions(
+                source_dir.join("dir/script.sh"),
+                std::fs::Permissions::from_mode(0o750),
+            )
+            .await
+            .unwrap();
+            std::fs::File::open(source_dir.join("dir/script.sh"))
+                .unwrap()
+                .set_modified(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000))
+                .unwrap();
+
+            let offer: Offer =
+                (&OfferSend::new_file_or_folder("dir".into(), source_dir.join("dir"))
+                    .await
+                    .unwrap())
+                    .into();
+            let Some(OfferEntry::RegularFile { metadata, .. }) =
+                offer.get(&["dir".into(), "script.sh".into()])
+            else {
+                panic!("Expected a file");
+            };
+            assert_eq!(*metadata, Metadata::new(Some(0o750), Some(1_600_000_000)));
+
+            /* Metadata survives serialization, and is omitted when empty */
+            let json = serde_json::to_value(&offer).unwrap();
+            assert_eq!(
+                json["content"]["dir"]["content"]["script.sh"]["metadata"],
+                serde_json::json!({"mode": 0o750, "mtime": 1_600_000_000})
+            );
+            assert_eq!(serde_json::from_value::<Offer>(json).unwrap(), offer);
+            let custom: Offer = (&OfferSend::new_file_custom(
+                "file".into(),
+                0,
+                new_offer_content(|| async { Ok(futures::io::Cursor::new(Vec::<u8>::new())) }),
+            ))
+                .into();
+            assert!(
+                serde_json::to_value(&custom).unwrap()["content"]["file"]
+                    .get("metadata")
+                    .is_none()
+            );
+
+            let target_dir = source_dir.join("target");
+            async_fs::create_dir_all(target_dir.join("dir"))
+                .await
+                .unwrap();
+            async_fs::write(target_dir.join("dir/script.sh"), b"#!/bin/sh")
+                .await
+                .unwrap();
+            /* Files that weren't accepted are left alone */
+            let answer = offer
+                .accept_builder(&target_dir)
+                .exclude("dir/script.sh")
+                .build()
+                .await
+                .unwrap();
+            Offer::from(&answer)
+                .apply_metadata(&target_dir)
+                .await
+                .unwrap();
+            let received = async_fs::metadata(target_dir.join("dir/script.sh"))
+                .await
+                .unwrap();
+            assert_ne!(received.permissions().mode() & 0o777, 0o750);
+
+            offer.apply_metadata(&target_dir).await.unwrap();
+            let received = async_fs::metadata(target_dir.join("dir/script.sh"))
+                .await
+                .unwrap();
+            assert_eq!(received.permissions().mode() & 0o777, 0o750);
+            assert_eq!(
+                received.modified().unwrap(),
+                std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000)
+            );
+
+            /* Offered names must not take us out of the target directory */
+            let escape = |name: &str| -> Offer {
+                serde_json::from_value(serde_json::json!({"content": {"dir": {
+                    "type": "directory",
+                    "content": {name: {"type": "regular-file", "size": 0, "metadata": {"mode": 0o777}}},
+                }}}))
+                .unwrap()
+            };
+            for name in ["..", "../script.sh"] {
+                let err = escape(name).apply_metadata(&target_dir).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+            }
+            let received = async_fs::metadata(&target_dir).await.unwrap();
+            assert_ne!(received.permissions().mode() & 0o777, 0o777);
+
+            async_fs::remove_dir_all(&source_dir).await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_unknown_size() {
+        let empty =
+            || new_offer_content(|| async { Ok(futures::io::Cursor::new(Vec::<u8>::new())) });
+        let stream: Offer = (&OfferSend::new_file_stream("stdin".into(), empty())).into();
+        assert!(!stream.is_size_known());
+        let json = serde_json::to_value(&stream).unwrap();
+        assert_eq!(json["content"]["stdin"]["unknown-size"], true);
+        assert_eq!(serde_json::from_value::<Offer>(json).unwrap(), stream);
+
+        /* The flag is omitted for files of known size, which keeps the offer readable by older peers */
+        let custom: Offer = (&OfferSend::new_file_custom("file".into(), 0, empty())).into();
+        assert!(custom.is_size_known());
+        let json = serde_json::to_value(&custom).unwrap();
+        assert!(json["content"]["file"].get("unknown-size").is_none());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_create_private_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        async_io::block_on(async {
+            let path = std::env::temp_dir()
+                .join(format!("wormhole-test-private-{}", rand::random::<u32>()));
+            create_private_file(&path).await.unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(mode & 0o777, 0o600);
+        })
+    }
+
+    #[test]
+    fn test_buffer_unknown_sizes() {
+        use futures::AsyncReadExt;
+
+        async_io::block_on(async {
+            let data = b"some stream".to_vec();
+            let stream = OfferSend::new_file_stream(
+                "stdin".into(),
+                new_offer_content_stream(futures::io::Cursor::new(data.clone())),
+            );
+            let buffered = stream.buffer_unknown_sizes().await.unwrap();
+            assert!(buffered.is_size_known());
+
+            /* The buffered content can be read as often as needed, like for resuming */
+            let (content, size) = buffered.get_file(&["stdin".into()]).unwrap();
+            assert_eq!(size, data.len() as u64);
+            for _ in 0..2 {
+                let mut read = Vec::new();
+                content()
+                    .await
+                    .unwrap()
+                    .read_to_end(&mut read)
+                    .await
+                    .unwrap();
+                assert_eq!(read, data);
+            }
+        })
+    }
+}
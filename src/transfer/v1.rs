@@ -8,6 +8,9 @@ use crate::transit::TransitRole;
 
 use super::{offer::*, *};
 
+#[cfg(not(target_family = "wasm"))]
+mod unzip;
+
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub enum OfferMessage {
@@ -73,15 +76,19 @@ pub(crate) async fn send(
     wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
     offer: OfferSend,
-    progress_handler: impl FnMut(u64, u64) + 'static,
-    transit_handler: impl FnOnce(transit::TransitInfo),
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     _peer_version: AppVersion,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError> {
+    /* The file size is part of the v1 offer message */
+    ensure!(offer.is_size_known(), TransferError::UnknownSizeUnsupported);
+
     if offer.is_multiple() {
         let folder = OfferSendEntry::Directory {
             content: offer.content,
+            metadata: Default::default(),
         };
         send_folder(
             wormhole,
@@ -89,8 +96,8 @@ pub(crate) async fn send(
             "<unnamed folder>".into(),
             folder,
             transit_abilities,
-            transit_handler,
-            progress_handler,
+            rate_limit,
+            events,
             cancel,
         )
         .await
@@ -102,15 +109,15 @@ pub(crate) async fn send(
             folder_name,
             folder,
             transit_abilities,
-            transit_handler,
-            progress_handler,
+            rate_limit,
+            events,
             cancel,
         )
         .await
     } else {
         let (file_name, file) = offer.content.into_iter().next().unwrap();
         let (mut file, file_size) = match file {
-            OfferSendEntry::RegularFile { content, size } => {
+            OfferSendEntry::RegularFile { content, size, .. } => {
                 /* This must be split into two statements to appease the borrow checker (unfortunate side effect of borrow-through) */
                 let content = content();
                 let content = content.await?;
@@ -125,32 +132,33 @@ pub(crate) async fn send(
             file_name,
             file_size,
             transit_abilities,
-            transit_handler,
-            progress_handler,
+            rate_limit,
+            events,
             cancel,
         )
         .await
     }
 }
 
-pub(crate) async fn send_file<F, G, H>(
+pub(crate) async fn send_file<F>(
     mut wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     file: &mut F,
     file_name: impl Into<String>,
     file_size: u64,
     transit_abilities: transit::Abilities,
-    transit_handler: G,
-    progress_handler: H,
+    rate_limit: Option<u64>,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError>
 where
     F: AsyncRead + Unpin + Send,
-    G: FnOnce(transit::TransitInfo),
-    H: FnMut(u64, u64) + 'static,
 {
+    let file_name = file_name.into();
     let run = Box::pin(async {
-        let connector = transit::init(transit_abilities, None, relay_hints).await?;
+        let connector = transit::init(transit_abilities, None, relay_hints)
+            .await?
+            .with_rate_limit(rate_limit);
 
         // We want to do some transit
         tracing::debug!("Sending transit message '{:?}", connector.our_hints());
@@ -164,7 +172,7 @@ where
         // Send file offer message.
         tracing::debug!("Sending file offer");
         wormhole
-            .send_json(&PeerMessage::offer_file_v1(file_name, file_size))
+            .send_json(&PeerMessage::offer_file_v1(file_name.clone(), file_size))
             .await?;
 
         // Wait for their transit response
@@ -197,23 +205,28 @@ where
             }
         }
 
-        let (mut transit, info) = connector
+        let (mut transit, info) = events
             .connect(
+                connector,
                 TransitRole::Leader,
                 wormhole.key().derive_transit_key(wormhole.appid()),
                 their_abilities,
                 Arc::new(their_hints),
             )
             .await?;
-        transit_handler(info);
+        events.report(TransferEvent::Connected(info));
 
         tracing::debug!("Beginning file transfer");
+        events.report(TransferEvent::FileStarted {
+            path: vec![file_name.clone()],
+            size: Some(file_size),
+        });
 
         // 11. send the file as encrypted records.
         let file = futures::stream::once(futures::future::ready(std::io::Result::Ok(
             Box::new(file) as Box<dyn AsyncRead + Unpin + Send>,
         )));
-        let checksum = v1::send_records(&mut transit, file, file_size, progress_handler).await?;
+        let checksum = v1::send_records(&mut transit, file, file_size, events).await?;
 
         // 13. wait for the transit ack with sha256 sum from the peer.
         tracing::debug!("sent file. Waiting for ack");
@@ -224,6 +237,9 @@ where
             TransferError::Checksum
         );
         tracing::debug!("Transfer complete!");
+        events.report(TransferEvent::FileFinished {
+            path: vec![file_name],
+        });
 
         Ok(())
     });
@@ -239,12 +255,14 @@ pub(crate) async fn send_folder(
     mut folder_name: String,
     folder: OfferSendEntry,
     transit_abilities: transit::Abilities,
-    transit_handler: impl FnOnce(transit::TransitInfo),
-    progress_handler: impl FnMut(u64, u64) + 'static,
+    rate_limit: Option<u64>,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError> {
     let run = Box::pin(async {
-        let connector = transit::init(transit_abilities, None, relay_hints).await?;
+        let connector = transit::init(transit_abilities, None, relay_hints)
+            .await?
+            .with_rate_limit(rate_limit);
 
         // We want to do some transit
         tracing::debug!("Sending transit message '{:?}", connector.our_hints());
@@ -285,9 +303,9 @@ pub(crate) async fn send_folder(
             path: &mut Vec<String>,
         ) -> IoResult<Vec<WrappedDataFut>> {
             match offer {
-                OfferSendEntry::Directory { content } => {
+                OfferSendEntry::Directory { content, metadata } => {
                     tracing::debug!("Adding directory {path:?}");
-                    let header = tar_helper::create_header_directory(path)?;
+                    let header = tar_helper::create_header_directory(path, metadata)?;
                     *total_size += header.len() as u64;
                     total_content.push(wrap(header));
 
@@ -297,9 +315,14 @@ pub(crate) async fn send_folder(
                         path.pop();
                     }
                 },
-                OfferSendEntry::RegularFile { size, content } => {
+                OfferSendEntry::RegularFile {
+                    size,
+                    content,
+                    metadata,
+                    ..
+                } => {
                     tracing::debug!("Adding file {path:?}; {size} bytes");
-                    let header = tar_helper::create_header_file(path, size)?;
+                    let header = tar_helper::create_header_file(path, size, metadata)?;
                     let padding = tar_helper::padding(size);
                     *total_size += header.len() as u64;
                     *total_size += padding.len() as u64;
@@ -313,7 +336,12 @@ pub(crate) async fn send_folder(
                     total_content.push(Box::pin(content) as _);
                     total_content.push(wrap(padding));
                 },
-                // OfferSendEntry::Symlink { .. } => todo!(),
+                OfferSendEntry::Symlink { target } => {
+                    tracing::debug!("Adding symlink {path:?} -> {target}");
+                    let header = tar_helper::create_header_symlink(path, &target)?;
+                    *total_size += header.len() as u64;
+                    total_content.push(wrap(header));
+                },
             }
             Ok(total_content)
         }
@@ -338,7 +366,7 @@ pub(crate) async fn send_folder(
         tracing::debug!("Sending file offer ({total_size} bytes)");
         folder_name.push_str(".tar");
         wormhole
-            .send_json(&PeerMessage::offer_file_v1(folder_name, total_size))
+            .send_json(&PeerMessage::offer_file_v1(folder_name.clone(), total_size))
             .await?;
 
         // Wait for their transit response
@@ -363,21 +391,26 @@ pub(crate) async fn send_folder(
             },
         }
 
-        let (mut transit, info) = connector
+        let (mut transit, info) = events
             .connect(
+                connector,
                 TransitRole::Leader,
                 wormhole.key().derive_transit_key(wormhole.appid()),
                 their_abilities,
                 Arc::new(their_hints),
             )
             .await?;
-        transit_handler(info);
+        events.report(TransferEvent::Connected(info));
 
         tracing::debug!("Beginning file transfer");
+        /* The folder is sent as one tar file */
+        events.report(TransferEvent::FileStarted {
+            path: vec![folder_name.clone()],
+            size: Some(total_size),
+        });
 
         // 11. send the file as encrypted records.
-        let checksum =
-            v1::send_records(&mut transit, content, total_size, progress_handler).await?;
+        let checksum = v1::send_records(&mut transit, content, total_size, events).await?;
 
         // 13. wait for the transit ack with sha256 sum from the peer.
         tracing::debug!("sent file. Waiting for ack");
@@ -388,6 +421,9 @@ pub(crate) async fn send_folder(
             TransferError::Checksum
         );
         tracing::debug!("Transfer complete!");
+        events.report(TransferEvent::FileFinished {
+            path: vec![folder_name],
+        });
 
         Ok(())
     });
@@ -406,14 +442,62 @@ pub(crate) async fn send_folder(
  * Returns `None` if the task got cancelled.
  */
 pub async fn request(
-    mut wormhole: Wormhole,
+    wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
     cancel: impl Future<Output = ()>,
 ) -> Result<Option<ReceiveRequest>, TransferError> {
+    match request_any(
+        wormhole,
+        relay_hints,
+        transit_abilities,
+        None,
+        false,
+        cancel,
+    )
+    .await?
+    {
+        Some(Request::File(request)) => Ok(Some(request)),
+        Some(_) => unreachable!("only file offers are supported with `all_offers = false`"),
+        None => Ok(None),
+    }
+}
+
+/// The kinds of offers transfer v1 knows about
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum Request {
+    File(ReceiveRequest),
+    #[cfg(not(target_family = "wasm"))]
+    Directory(ReceiveDirectoryRequest),
+    Text(String),
+}
+
+/**
+ * Wait for a file, directory or text offer from the other side
+ *
+ * Text messages are acknowledged immediately and the wormhole gets closed. If `all_offers`
+ * is `false`, they are treated as an unsupported offer instead, and directories are received
+ * as a zip file without extracting them.
+ */
+pub(crate) async fn request_any(
+    mut wormhole: Wormhole,
+    relay_hints: Vec<transit::RelayHint>,
+    transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
+    all_offers: bool,
+    cancel: impl Future<Output = ()>,
+) -> Result<Option<Request>, TransferError> {
+    #[allow(clippy::large_enum_variant)]
+    enum Offered {
+        Transit(OfferMessage, TransitConnector, TransitV1),
+        Text(String),
+    }
+
     // Error handling
     let run = Box::pin(async {
-        let connector = transit::init(transit_abilities, None, relay_hints).await?;
+        let connector = transit::init(transit_abilities, None, relay_hints)
+            .await?
+            .with_rate_limit(rate_limit);
 
         // send the transit message
         tracing::debug!("Sending transit message '{:?}", connector.our_hints());
@@ -424,59 +508,149 @@ pub async fn request(
             ))
             .await?;
 
-        // receive transit message
-        let (their_abilities, their_hints): (transit::Abilities, transit::Hints) =
+        /* Text senders don't send a transit message, so the offer may come first */
+        let mut their_transit = None;
+        let mut offer = None;
+        while their_transit.is_none() || offer.is_none() {
             match wormhole.receive_json::<PeerMessage>().await??.check_err()? {
-                PeerMessage::Transit(transit) => {
+                PeerMessage::Transit(transit) if their_transit.is_none() => {
                     tracing::debug!("received transit message: {:?}", transit);
-                    (transit.abilities_v1, transit.hints_v1)
+                    their_transit = Some(transit);
+                },
+                // 3. receive file offer message from peer
+                PeerMessage::Offer(offer_type) if offer.is_none() => match offer_type {
+                    v1::OfferMessage::File { .. } => offer = Some(offer_type),
+                    v1::OfferMessage::Directory { ref mode, .. } => {
+                        /* We can only extract zip files, but we can always save them as-is */
+                        ensure!(
+                            !all_offers || mode.starts_with("zipfile/"),
+                            TransferError::UnsupportedOffer
+                        );
+                        offer = Some(offer_type);
+                    },
+                    v1::OfferMessage::Message(text) if all_offers => {
+                        tracing::debug!("Received text message, sending ack");
+                        wormhole
+                            .send_json(&PeerMessage::message_ack_v1("ok"))
+                            .await?;
+                        return Ok(Offered::Text(text));
+                    },
+                    _ => bail!(TransferError::UnsupportedOffer),
                 },
                 other => {
-                    bail!(TransferError::unexpected_message("transit", other));
+                    let expected = if their_transit.is_none() {
+                        "transit"
+                    } else {
+                        "offer"
+                    };
+                    bail!(TransferError::unexpected_message(expected, other));
                 },
-            };
+            }
+        }
+
+        Ok(Offered::Transit(
+            offer.unwrap(),
+            connector,
+            their_transit.unwrap(),
+        ))
+    });
+
+    futures::pin_mut!(cancel);
+    let result = cancel::cancellable_2(run, cancel).await;
+    let Some((offered, wormhole, _)) = cancel::handle_run_result_noclose(wormhole, result).await?
+    else {
+        return Ok(None);
+    };
+    Ok(Some(match offered {
+        Offered::Transit(offer, connector, transit) => match offer {
+            #[cfg(not(target_family = "wasm"))]
+            OfferMessage::Directory {
+                dirname,
+                zipsize,
+                numbytes,
+                numfiles,
+                ..
+            } if all_offers => Request::Directory(ReceiveDirectoryRequest {
+                wormhole,
+                connector,
+                dir_name: dirname,
+                zipsize,
+                numbytes,
+                numfiles,
+                their_abilities: transit.abilities_v1,
+                their_hints: Arc::new(transit.hints_v1),
+            }),
+            OfferMessage::Directory {
+                mut dirname,
+                zipsize,
+                ..
+            } => {
+                dirname.push_str(".zip");
+                Request::File(ReceiveRequest::new(
+                    dirname,
+                    zipsize,
+                    connector,
+                    transit.abilities_v1,
+                    transit.hints_v1,
+                    wormhole,
+                ))
+            },
+            OfferMessage::File { filename, filesize } => Request::File(ReceiveRequest::new(
+                filename,
+                filesize,
+                connector,
+                transit.abilities_v1,
+                transit.hints_v1,
+                wormhole,
+            )),
+            OfferMessage::Message(_) | OfferMessage::Unknown => unreachable!(),
+        },
+        Offered::Text(text) => {
+            wormhole.close().await?;
+            Request::Text(text)
+        },
+    }))
+}
 
-        // 3. receive file offer message from peer
-        let (filename, filesize) =
+/**
+ * Send a text message to the other side
+ *
+ * The text goes over the encrypted mailbox connection, no transit connection is established.
+ */
+pub(crate) async fn send_text(
+    mut wormhole: Wormhole,
+    text: String,
+    cancel: impl Future<Output = ()>,
+) -> Result<(), TransferError> {
+    let run = Box::pin(async {
+        tracing::debug!("Sending text offer");
+        wormhole
+            .send_json(&PeerMessage::offer_message_v1(text))
+            .await?;
+
+        loop {
             match wormhole.receive_json::<PeerMessage>().await??.check_err()? {
-                PeerMessage::Offer(offer_type) => match offer_type {
-                    v1::OfferMessage::File { filename, filesize } => (filename, filesize),
-                    v1::OfferMessage::Directory {
-                        mut dirname,
-                        zipsize,
-                        ..
-                    } => {
-                        dirname.push_str(".zip");
-                        (dirname, zipsize)
-                    },
-                    _ => bail!(TransferError::UnsupportedOffer),
+                /* The receiver sends its transit hints before it knows what we offer */
+                PeerMessage::Transit(_) => continue,
+                PeerMessage::Answer(AnswerMessage::MessageAck(msg)) => {
+                    ensure!(msg == "ok", TransferError::AckError);
+                    break;
                 },
                 other => {
-                    bail!(TransferError::unexpected_message("offer", other));
+                    bail!(TransferError::unexpected_message(
+                        "answer/message_ack",
+                        other
+                    ));
                 },
-            };
+            }
+        }
 
-        Ok((filename, filesize, connector, their_abilities, their_hints))
+        Ok(())
     });
 
     futures::pin_mut!(cancel);
     let result = cancel::cancellable_2(run, cancel).await;
-    cancel::handle_run_result_noclose(wormhole, result)
-        .await
-        .map(|inner: Option<_>| {
-            inner.map(
-                |((filename, filesize, connector, their_abilities, their_hints), wormhole, _)| {
-                    ReceiveRequest::new(
-                        filename,
-                        filesize,
-                        connector,
-                        their_abilities,
-                        their_hints,
-                        wormhole,
-                    )
-                },
-            )
-        })
+    cancel::handle_run_result(wormhole, result).await
 }
 
 /**
@@ -494,7 +668,6 @@ pub struct ReceiveRequest {
     /// The expected size of the file
     filesize: u64,
 
-    #[allow(dead_code)]
     offer: Arc<Offer>,
     their_abilities: transit::Abilities,
     their_hints: Arc<transit::Hints>,
@@ -518,6 +691,8 @@ impl ReceiveRequest {
             OfferEntry::RegularFile {
                 size: filesize,
                 content: (),
+                metadata: Default::default(),
+                unknown_size: false,
             },
         );
 
@@ -540,9 +715,9 @@ impl ReceiveRequest {
      * This will transfer the file and save it on disk.
      */
     pub async fn accept<F, G, W>(
-        mut self,
+        self,
         transit_handler: G,
-        progress_handler: F,
+        mut progress_handler: F,
         content_handler: &mut W,
         cancel: impl Future<Output = ()>,
     ) -> Result<(), TransferError>
@@ -551,6 +726,20 @@ impl ReceiveRequest {
         G: FnOnce(transit::TransitInfo),
         W: AsyncWrite + Unpin,
     {
+        let mut events = EventReporter::new(event::legacy_handler(
+            transit_handler,
+            move |received, total: Option<u64>| progress_handler(received, total.unwrap_or(0)),
+        ));
+        self.accept_with_events(&mut events, content_handler, cancel)
+            .await
+    }
+
+    pub(crate) async fn accept_with_events<W: AsyncWrite + Unpin>(
+        mut self,
+        events: &mut EventReporter<impl FnMut(TransferEvent)>,
+        content_handler: &mut W,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError> {
         let run = Box::pin(async {
             // send file ack.
             tracing::debug!("Sending ack");
@@ -558,9 +747,9 @@ impl ReceiveRequest {
                 .send_json(&PeerMessage::file_ack_v1("ok"))
                 .await?;
 
-            let (mut transit, info) = self
-                .connector
+            let (mut transit, info) = events
                 .connect(
+                    self.connector,
                     TransitRole::Follower,
                     self.wormhole
                         .key()
@@ -569,16 +758,16 @@ impl ReceiveRequest {
                     self.their_hints.clone(),
                 )
                 .await?;
-            transit_handler(info);
+            events.report(TransferEvent::Connected(info));
 
             tracing::debug!("Beginning file transfer");
-            tcp_file_receive(
-                &mut transit,
-                self.filesize,
-                progress_handler,
-                content_handler,
-            )
-            .await?;
+            let path = vec![self.file_name.clone()];
+            events.report(TransferEvent::FileStarted {
+                path: path.clone(),
+                size: Some(self.filesize),
+            });
+            tcp_file_receive(&mut transit, self.filesize, events, content_handler).await?;
+            events.report(TransferEvent::FileFinished { path });
             Ok(())
         });
 
@@ -601,8 +790,7 @@ impl ReceiveRequest {
         Ok(())
     }
 
-    #[cfg(feature = "experimental-transfer-v2")]
-    #[allow(missing_docs)]
+    /// The file offer, synthesized to look like a transfer v2 offer
     pub fn offer(&self) -> Arc<Offer> {
         self.offer.clone()
     }
@@ -620,13 +808,152 @@ impl ReceiveRequest {
     }
 }
 
+/**
+ * A pending directory send offer from the other side
+ *
+ * The Python implementation sends directories as a zip file, which gets extracted while it is received.
+ * You *should* consume this object, either by calling [`accept`](ReceiveDirectoryRequest::accept) or [`reject`](ReceiveDirectoryRequest::reject).
+ */
+#[must_use]
+#[cfg(not(target_family = "wasm"))]
+pub struct ReceiveDirectoryRequest {
+    wormhole: Wormhole,
+    connector: TransitConnector,
+
+    dir_name: String,
+    /// The size of the zip file that gets transferred
+    zipsize: u64,
+    /// The total size of all files
+    numbytes: u64,
+    numfiles: u64,
+
+    their_abilities: transit::Abilities,
+    their_hints: Arc<transit::Hints>,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl ReceiveDirectoryRequest {
+    /**
+     * Accept the directory offer
+     *
+     * The content of the directory is extracted into `target_path`, which gets created if it
+     * does not exist yet. Existing files in it will be overwritten.
+     * The progress handler reports the transferred bytes of the (compressed) zip file.
+     */
+    pub async fn accept<F, G>(
+        self,
+        transit_handler: G,
+        mut progress_handler: F,
+        target_path: &Path,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError>
+    where
+        F: FnMut(u64, u64) + 'static,
+        G: FnOnce(transit::TransitInfo),
+    {
+        let mut events = EventReporter::new(event::legacy_handler(
+            transit_handler,
+            move |received, total: Option<u64>| progress_handler(received, total.unwrap_or(0)),
+        ));
+        self.accept_with_events(&mut events, target_path, cancel)
+            .await
+    }
+
+    pub(crate) async fn accept_with_events(
+        mut self,
+        events: &mut EventReporter<impl FnMut(TransferEvent)>,
+        target_path: &Path,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError> {
+        use futures_concurrency::future::TryJoin;
+
+        let run = Box::pin(async {
+            // send file ack.
+            tracing::debug!("Sending ack");
+            self.wormhole
+                .send_json(&PeerMessage::file_ack_v1("ok"))
+                .await?;
+
+            let (mut transit, info) = events
+                .connect(
+                    self.connector,
+                    TransitRole::Follower,
+                    self.wormhole
+                        .key()
+                        .derive_transit_key(self.wormhole.appid()),
+                    self.their_abilities,
+                    self.their_hints.clone(),
+                )
+                .await?;
+            events.report(TransferEvent::Connected(info));
+
+            tracing::debug!("Beginning directory transfer");
+            /* The directory is received as one zip file */
+            let path = vec![self.dir_name.clone()];
+            events.report(TransferEvent::FileStarted {
+                path: path.clone(),
+                size: Some(self.zipsize),
+            });
+            let (mut writer, reader) = unzip::pipe();
+            (
+                tcp_file_receive(&mut transit, self.zipsize, events, &mut writer),
+                unzip::extract(reader, target_path, self.numfiles, self.numbytes),
+            )
+                .try_join()
+                .await?;
+            events.report(TransferEvent::FileFinished { path });
+            Ok(())
+        });
+
+        futures::pin_mut!(cancel);
+        let result = cancel::cancellable_2(run, cancel).await;
+        cancel::handle_run_result(self.wormhole, result).await
+    }
+
+    /**
+     * Reject the directory offer
+     *
+     * This will send an error message to the other side so that it knows the transfer failed.
+     */
+    pub async fn reject(mut self) -> Result<(), TransferError> {
+        self.wormhole
+            .send_json(&PeerMessage::error_message("transfer rejected"))
+            .await?;
+        self.wormhole.close().await?;
+
+        Ok(())
+    }
+
+    /// The name of the offered directory.
+    ///
+    /// This is untrusted and unverified input.
+    pub fn dir_name(&self) -> String {
+        self.dir_name.clone()
+    }
+
+    /// The number of files in the directory, as announced by the sender
+    pub fn num_files(&self) -> u64 {
+        self.numfiles
+    }
+
+    /// The total size of all files in the directory, as announced by the sender
+    pub fn num_bytes(&self) -> u64 {
+        self.numbytes
+    }
+
+    /// The size of the zip file that will be transferred
+    pub fn transfer_size(&self) -> u64 {
+        self.zipsize
+    }
+}
+
 // encrypt and send the file to tcp stream and return the sha256 sum
 // of the file before encryption.
 pub(crate) async fn send_records<'a>(
     transit: &mut Transit,
     files: impl futures::Stream<Item = std::io::Result<Box<dyn AsyncRead + Unpin + Send + 'a>>>,
     file_size: u64,
-    mut progress_handler: impl FnMut(u64, u64) + 'static,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
 ) -> Result<Vec<u8>, TransferError> {
     // rough plan:
     // 1. Open the file
@@ -638,7 +965,7 @@ pub(crate) async fn send_records<'a>(
     // 7. if eof, return sha256 sum.
 
     // Report at 0 to allow clients to configure as necessary.
-    progress_handler(0, file_size);
+    events.progress(0, Some(file_size));
 
     let mut hasher = Sha256::default();
 
@@ -658,7 +985,7 @@ pub(crate) async fn send_records<'a>(
             // send the encrypted record
             transit.send_record(&plaintext[0..n]).await?;
             sent_size += n as u64;
-            progress_handler(sent_size, file_size);
+            events.progress(sent_size, Some(file_size));
 
             // sha256 of the input
             hasher.update(&plaintext[..n]);
@@ -682,14 +1009,13 @@ pub(crate) async fn send_records<'a>(
     Ok(hasher.finalize_fixed().to_vec())
 }
 
-pub(crate) async fn receive_records<F, W>(
+pub(crate) async fn receive_records<W>(
     filesize: u64,
     transit: &mut Transit,
-    mut progress_handler: F,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     mut content_handler: W,
 ) -> Result<Vec<u8>, TransferError>
 where
-    F: FnMut(u64, u64) + 'static,
     W: AsyncWrite + Unpin,
 {
     let mut hasher = Sha256::default();
@@ -699,7 +1025,7 @@ where
 
     // Might not need to do this here, since `accept()` is where they'd know the filesize
     // already...
-    progress_handler(0, total);
+    events.progress(0, Some(total));
 
     while remaining_size > 0 {
         // 3. decrypt the vector 'enc_packet' with the key.
@@ -713,7 +1039,7 @@ where
         remaining_size -= plaintext.len();
 
         let remaining = remaining_size as u64;
-        progress_handler(total - remaining, total);
+        events.progress(total - remaining, Some(total));
     }
     content_handler.close().await?;
 
@@ -722,20 +1048,19 @@ where
     Ok(hasher.finalize_fixed().to_vec())
 }
 
-pub(crate) async fn tcp_file_receive<F, W>(
+pub(crate) async fn tcp_file_receive<W>(
     transit: &mut Transit,
     filesize: u64,
-    progress_handler: F,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     content_handler: &mut W,
 ) -> Result<(), TransferError>
 where
-    F: FnMut(u64, u64) + 'static,
     W: AsyncWrite + Unpin,
 {
     // 5. receive encrypted records
     // now skey and rkey can be used. skey is used by the tx side, rkey is used
     // by the rx side for symmetric encryption.
-    let checksum = receive_records(filesize, transit, progress_handler, content_handler).await?;
+    let checksum = receive_records(filesize, transit, events, content_handler).await?;
 
     let sha256sum = hex::encode(checksum.as_slice());
     tracing::debug!("sha256 sum: {:?}", sha256sum);
@@ -760,29 +1085,54 @@ mod tar_helper {
         str,
     };
 
-    pub(crate) fn create_header_file(path: &[String], size: u64) -> std::io::Result<Vec<u8>> {
+    use super::Metadata;
+
+    pub(crate) fn create_header_file(
+        path: &[String],
+        size: u64,
+        metadata: Metadata,
+    ) -> std::io::Result<Vec<u8>> {
         let mut header = tar::Header::new_gnu();
         header.set_size(size);
         let mut data = Vec::with_capacity(1024);
         prepare_header_path(&mut data, &mut header, path.join("/").as_ref())?;
-        header.set_mode(0o644);
+        header.set_mode(metadata.mode.unwrap_or(0o644));
+        header.set_mtime(metadata.mtime.unwrap_or(0));
         header.set_cksum();
         data.write_all(header.as_bytes())?;
         Ok(data)
     }
 
-    pub(crate) fn create_header_directory(path: &[String]) -> std::io::Result<Vec<u8>> {
+    pub(crate) fn create_header_directory(
+        path: &[String],
+        metadata: Metadata,
+    ) -> std::io::Result<Vec<u8>> {
         let mut header = tar::Header::new_gnu();
         header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
         let mut data = Vec::with_capacity(1024);
         prepare_header_path(&mut data, &mut header, path.join("/").as_ref())?;
-        header.set_mode(0o755);
+        header.set_mode(metadata.mode.unwrap_or(0o755));
+        header.set_mtime(metadata.mtime.unwrap_or(0));
         header.set_cksum();
         data.write_all(header.as_bytes())?;
         // append(&mut data, header, data)?;
         Ok(data)
     }
 
+    pub(crate) fn create_header_symlink(path: &[String], target: &str) -> std::io::Result<Vec<u8>> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        let mut data = Vec::with_capacity(1024);
+        prepare_header_path(&mut data, &mut header, path.join("/").as_ref())?;
+        prepare_header_link(&mut data, &mut header, target)?;
+        header.set_mode(0o777);
+        header.set_cksum();
+        data.write_all(header.as_bytes())?;
+        Ok(data)
+    }
+
     pub(crate) fn padding(size: u64) -> &'static [u8] {
         const BLOCK: [u8; 512] = [0; 512];
         if !size.is_multiple_of(512) {
@@ -854,6 +1204,24 @@ mod tar_helper {
         Ok(())
     }
 
+    fn prepare_header_link(
+        dst: &mut dyn std::io::Write,
+        header: &mut tar::Header,
+        link_name: &str,
+    ) -> std::io::Result<()> {
+        // Same as with the path, but using the GNU-specific long link name extension
+        if let Err(e) = header.set_link_name(link_name) {
+            let data = path2bytes(link_name);
+            if data.len() < header.as_old().linkname.len() {
+                return Err(e);
+            }
+            let header2 = prepare_header(data.len() as u64, b'K');
+            let mut data2 = data.chain(io::repeat(0).take(1));
+            append(dst, &header2, &mut data2)?;
+        }
+        Ok(())
+    }
+
     #[cfg(any(windows, target_arch = "wasm32"))]
     pub(crate) fn path2bytes(p: &str) -> Cow<'_, [u8]> {
         let bytes = p.as_bytes();
@@ -887,4 +1255,72 @@ mod test {
         let f1 = TransitAck::new("ok", "deadbeaf");
         assert_eq!(f1.serialize(), "{\"ack\":\"ok\",\"sha256\":\"deadbeaf\"}");
     }
+
+    #[test]
+    fn test_tar_symlink() {
+        let long_target = format!("../{}", "a".repeat(200));
+        let mut archive = Vec::new();
+        archive.extend(
+            tar_helper::create_header_symlink(&["dir".into(), "link".into()], "file").unwrap(),
+        );
+        archive.extend(
+            tar_helper::create_header_symlink(&["dir".into(), "long".into()], &long_target)
+                .unwrap(),
+        );
+        archive.extend([0; 1024]);
+
+        let mut archive = tar::Archive::new(&archive[..]);
+        let links = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                assert_eq!(entry.header().entry_type(), tar::EntryType::Symlink);
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.link_name().unwrap().unwrap().into_owned(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            links,
+            [
+                ("dir/link".into(), "file".into()),
+                ("dir/long".into(), long_target.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tar_metadata() {
+        let mut archive = Vec::new();
+        archive.extend(
+            tar_helper::create_header_directory(&["dir".into()], Metadata::new(Some(0o700), None))
+                .unwrap(),
+        );
+        archive.extend(
+            tar_helper::create_header_file(
+                &["dir".into(), "script".into()],
+                0,
+                Metadata::new(Some(0o755), Some(1_600_000_000)),
+            )
+            .unwrap(),
+        );
+        archive.extend(
+            tar_helper::create_header_file(&["dir".into(), "file".into()], 0, Metadata::default())
+                .unwrap(),
+        );
+        archive.extend([0; 1024]);
+
+        let mut archive = tar::Archive::new(&archive[..]);
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let header = entry.unwrap().header().clone();
+                (header.mode().unwrap(), header.mtime().unwrap())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(entries, [(0o700, 0), (0o755, 1_600_000_000), (0o644, 0)]);
+    }
 }
@@ -0,0 +1,516 @@
+//! Streaming extraction of the zip files that the Python implementation sends for directories
+//!
+//! The archive gets extracted while it is still being received, so we can't seek to the central
+//! directory at its end like most zip readers do. Instead, we walk the local file headers in order
+//! and only use the central directory afterwards, to restore the file permissions.
+
+use super::TransferError;
+use flate2::{Crc, Decompress, FlushDecompress, Status};
+use futures::{
+    channel::mpsc,
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    stream::TryStreamExt,
+};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const DATA_DESCRIPTOR: u32 = 0x08074b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x06064b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR: u32 = 0x07064b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+
+/// The sizes and checksum follow the data instead of being in the local header
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+/// The "version made by" of archives created on Unix, which store the mode in the external attributes
+const HOST_UNIX: u16 = 3;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+
+fn invalid(message: impl Into<Box<str>>) -> TransferError {
+    TransferError::InvalidArchive(message.into())
+}
+
+fn le16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+async fn read_bytes(
+    reader: &mut (impl AsyncRead + Unpin),
+    len: usize,
+) -> Result<Vec<u8>, TransferError> {
+    let mut buffer = vec![0; len];
+    reader.read_exact(&mut buffer).await?;
+    Ok(buffer)
+}
+
+async fn skip(reader: &mut (impl AsyncRead + Unpin), len: u64) -> Result<(), TransferError> {
+    let skipped = futures::io::copy(reader.take(len), &mut futures::io::sink()).await?;
+    ensure!(skipped == len, invalid("Unexpected end of archive"));
+    Ok(())
+}
+
+/// A local file header, which precedes the data of every entry
+struct LocalHeader {
+    flags: u16,
+    method: u16,
+    crc32: u32,
+    /// `None` if it is stored in the data descriptor instead
+    compressed_size: Option<u64>,
+    uncompressed_size: Option<u64>,
+    zip64: bool,
+    name: String,
+}
+
+impl LocalHeader {
+    async fn read(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self, TransferError> {
+        let header = read_bytes(reader, 26).await?;
+        let flags = le16(&header, 2);
+        let method = le16(&header, 4);
+        let crc32 = le32(&header, 10);
+        let mut compressed_size = le32(&header, 14) as u64;
+        let mut uncompressed_size = le32(&header, 18) as u64;
+        let name = read_bytes(reader, le16(&header, 22) as usize).await?;
+        let extra = read_bytes(reader, le16(&header, 24) as usize).await?;
+
+        let name = String::from_utf8(name).map_err(|_| invalid("File name is not UTF-8"))?;
+
+        /* The Zip64 extra field only contains the sizes that didn't fit into the header */
+        let mut zip64 = false;
+        let mut fields = &extra[..];
+        while fields.len() >= 4 {
+            let (id, len) = (le16(fields, 0), le16(fields, 2) as usize);
+            let data = fields
+                .get(4..4 + len)
+                .ok_or_else(|| invalid("Truncated extra field"))?;
+            if id == ZIP64_EXTRA_FIELD {
+                zip64 = true;
+                let mut values = data.chunks_exact(8).map(|value| le64(value, 0));
+                if uncompressed_size == u32::MAX as u64 {
+                    uncompressed_size = values
+                        .next()
+                        .ok_or_else(|| invalid("Truncated Zip64 extra field"))?;
+                }
+                if compressed_size == u32::MAX as u64 {
+                    compressed_size = values
+                        .next()
+                        .ok_or_else(|| invalid("Truncated Zip64 extra field"))?;
+                }
+            }
+            fields = &fields[4 + len..];
+        }
+
+        let has_sizes = flags & FLAG_DATA_DESCRIPTOR == 0;
+        Ok(Self {
+            flags,
+            method,
+            crc32,
+            compressed_size: has_sizes.then_some(compressed_size),
+            uncompressed_size: has_sizes.then_some(uncompressed_size),
+            zip64,
+            name,
+        })
+    }
+
+    fn is_directory(&self) -> bool {
+        self.name.ends_with('/')
+    }
+}
+
+/**
+ * Turn the name of an entry into a relative path
+ *
+ * Entry names are untrusted, so anything that could take us out of the target directory is rejected.
+ */
+fn sanitize_path(name: &str) -> Result<PathBuf, TransferError> {
+    ensure!(
+        !name.starts_with('/'),
+        invalid(format!("Absolute path '{name}' in archive"))
+    );
+    let path: PathBuf = name
+        .split('/')
+        .filter(|component| !component.is_empty())
+        .map(|component| {
+            /* Also catches Windows drive letters and similar */
+            let valid = super::is_valid_file_name(component)
+                && matches!(
+                    Path::new(component).components().collect::<Vec<_>>()[..],
+                    [std::path::Component::Normal(_)]
+                );
+            ensure!(valid, invalid(format!("Invalid path '{name}' in archive")));
+            Ok(component)
+        })
+        .collect::<Result<_, TransferError>>()?;
+    ensure!(
+        path.components().next().is_some(),
+        invalid("Empty path in archive")
+    );
+    Ok(path)
+}
+
+/**
+ * Extract a zip archive into `target`, which will be created if necessary
+ *
+ * The number of files and the total size are checked against the values from the offer.
+ * Existing files get overwritten.
+ */
+pub(super) async fn extract(
+    reader: impl AsyncRead + Unpin,
+    target: &Path,
+    num_files: u64,
+    num_bytes: u64,
+) -> Result<(), TransferError> {
+    let mut reader = futures::io::BufReader::new(reader);
+    let mut files = 0;
+    let mut bytes = 0;
+    /* Remember what we extracted, to restore the permissions from the central directory */
+    let mut extracted = HashMap::new();
+
+    async_fs::create_dir_all(target).await?;
+
+    loop {
+        let mut signature = [0; 4];
+        reader.read_exact(&mut signature).await?;
+        match u32::from_le_bytes(signature) {
+            LOCAL_FILE_HEADER => {
+                let header = LocalHeader::read(&mut reader).await?;
+                let path = target.join(sanitize_path(&header.name)?);
+                tracing::debug!("Extracting {}", path.display());
+
+                if header.is_directory() {
+                    async_fs::create_dir_all(&path).await?;
+                    /* Directories have no content, but skip it properly anyways */
+                    extract_entry(&mut reader, &header, &mut futures::io::sink(), 0).await?;
+                } else {
+                    files += 1;
+                    ensure!(
+                        files <= num_files,
+                        invalid("The archive contains more files than offered")
+                    );
+                    if let Some(parent) = path.parent() {
+                        async_fs::create_dir_all(parent).await?;
+                    }
+                    let mut file = async_fs::OpenOptions::new()
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(&path)
+                        .await?;
+                    bytes +=
+                        extract_entry(&mut reader, &header, &mut file, num_bytes - bytes).await?;
+                    file.close().await?;
+                }
+                extracted.insert(header.name, path);
+            },
+            CENTRAL_DIRECTORY_HEADER => {
+                let header = read_bytes(&mut reader, 42).await?;
+                let host = le16(&header, 0) >> 8;
+                let external_attributes = le32(&header, 34);
+                let name = read_bytes(&mut reader, le16(&header, 24) as usize).await?;
+                skip(
+                    &mut reader,
+                    le16(&header, 26) as u64 + le16(&header, 28) as u64,
+                )
+                .await?;
+
+                let path = String::from_utf8(name)
+                    .ok()
+                    .and_then(|name| extracted.get(&name));
+                if let Some(path) = path
+                    && host == HOST_UNIX
+                {
+                    set_mode(path, external_attributes >> 16).await?;
+                }
+            },
+            ZIP64_END_OF_CENTRAL_DIRECTORY => {
+                let size = read_bytes(&mut reader, 8).await?;
+                skip(&mut reader, le64(&size, 0)).await?;
+            },
+            ZIP64_END_OF_CENTRAL_DIRECTORY_LOCATOR => skip(&mut reader, 16).await?,
+            END_OF_CENTRAL_DIRECTORY => {
+                let record = read_bytes(&mut reader, 18).await?;
+                skip(&mut reader, le16(&record, 16) as u64).await?;
+                break;
+            },
+            other => bail!(invalid(format!("Unknown signature {other:#010x}"))),
+        }
+    }
+
+    ensure!(
+        files == num_files,
+        invalid(format!(
+            "The archive contains {files} files, but {num_files} were offered"
+        ))
+    );
+    ensure!(
+        bytes == num_bytes,
+        invalid(format!(
+            "The archive contains {bytes} bytes, but {num_bytes} were offered"
+        ))
+    );
+    Ok(())
+}
+
+/// Decompress the data of an entry, and return its size
+async fn extract_entry(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    header: &LocalHeader,
+    writer: &mut (impl AsyncWrite + Unpin),
+    limit: u64,
+) -> Result<u64, TransferError> {
+    let mut crc = Crc::new();
+    let mut written = 0;
+    let mut output = vec![0; 64 * 1024];
+    let mut write = async |data: &[u8]| {
+        written += data.len() as u64;
+        ensure!(
+            written <= limit,
+            invalid("The archive contains more data than offered")
+        );
+        crc.update(data);
+        writer.write_all(data).await?;
+        Ok::<_, TransferError>(())
+    };
+
+    /* Don't read into the next entry if we know where this one ends */
+    let mut input = (&mut *reader).take(header.compressed_size.unwrap_or(u64::MAX));
+    match header.method {
+        METHOD_STORED => {
+            ensure!(
+                header.compressed_size.is_some(),
+                invalid("Uncompressed entries must have a known size")
+            );
+            loop {
+                let len = input.read(&mut output).await?;
+                if len == 0 {
+                    break;
+                }
+                write(&output[..len]).await?;
+            }
+        },
+        METHOD_DEFLATED => {
+            let mut decompress = Decompress::new(false);
+            loop {
+                let buffer = input.fill_buf().await?;
+                let eof = buffer.is_empty();
+                let (total_in, total_out) = (decompress.total_in(), decompress.total_out());
+                let flush = match eof {
+                    true => FlushDecompress::Finish,
+                    false => FlushDecompress::None,
+                };
+                let status = decompress
+                    .decompress(buffer, &mut output, flush)
+                    .map_err(|error| invalid(format!("Corrupt compressed data: {error}")))?;
+                let consumed = (decompress.total_in() - total_in) as usize;
+                let produced = (decompress.total_out() - total_out) as usize;
+                input.consume_unpin(consumed);
+                write(&output[..produced]).await?;
+
+                match status {
+                    Status::StreamEnd => break,
+                    _ if eof && consumed == 0 && produced == 0 => {
+                        bail!(invalid("Unexpected end of compressed data"))
+                    },
+                    _ => {},
+                }
+            }
+        },
+        other => bail!(invalid(format!("Unsupported compression method {other}"))),
+    }
+    ensure!(
+        header.compressed_size.is_none() || input.limit() == 0,
+        invalid("Compressed size does not match the data")
+    );
+
+    let (crc32, size) = match header.uncompressed_size {
+        Some(size) => (header.crc32, size),
+        None => {
+            debug_assert!(header.flags & FLAG_DATA_DESCRIPTOR != 0);
+            /* The signature of the data descriptor is optional */
+            let mut crc32 = le32(&read_bytes(reader, 4).await?, 0);
+            if crc32 == DATA_DESCRIPTOR {
+                crc32 = le32(&read_bytes(reader, 4).await?, 0);
+            }
+            let size = match header.zip64 {
+                true => le64(&read_bytes(reader, 16).await?, 8),
+                false => le32(&read_bytes(reader, 8).await?, 4) as u64,
+            };
+            (crc32, size)
+        },
+    };
+    ensure!(
+        written == size,
+        invalid(format!("Entry '{}' has the wrong size", header.name))
+    );
+    ensure!(
+        crc.sum() == crc32,
+        invalid(format!("Entry '{}' is corrupt", header.name))
+    );
+    Ok(written)
+}
+
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: u32) -> Result<(), TransferError> {
+    use std::os::unix::fs::PermissionsExt;
+
+    /* Only restore the permission bits, no setuid and the like */
+    let permissions = std::fs::Permissions::from_mode(mode & 0o777);
+    async_fs::set_permissions(path, permissions).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_path: &Path, _mode: u32) -> Result<(), TransferError> {
+    Ok(())
+}
+
+/**
+ * An in-memory pipe, to extract the archive while it is being received
+ *
+ * Closing the writer signals the end of the data to the reader.
+ */
+pub(super) fn pipe() -> (PipeWriter, impl AsyncRead + Unpin) {
+    let (sender, receiver) = mpsc::channel::<io::Result<Vec<u8>>>(16);
+    (PipeWriter(sender), receiver.into_async_read())
+}
+
+pub(super) struct PipeWriter(mpsc::Sender<io::Result<Vec<u8>>>);
+
+fn pipe_closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "The archive extraction stopped early",
+    )
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        ready!(self.0.poll_ready(cx)).map_err(|_| pipe_closed())?;
+        self.0
+            .start_send(Ok(buf.to_vec()))
+            .map_err(|_| pipe_closed())?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.0.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /**
+     * A zip file as created by Python's `zipfile` with `ZIP_DEFLATED`, like `wormhole send` does
+     *
+     * It contains `hello.txt`, the executable `sub/script.sh` and `sub/repeated.txt`.
+     */
+    #[rustfmt::skip]
+    const PYTHON_ZIP: &[u8] = &[
+    0x50, 0x4b, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x4c, 0x0e, 0x51, 0x5d, 0x18, 0xa7, 0x55, 0x7b, 0x10, 0x00, 0x00, 0x00, 0x0e, 0x00,
+    0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0xd7, 0x51, 0x28, 0xcf,
+    0x2f, 0xca, 0x49, 0x51, 0xe4, 0x02, 0x00, 0x50, 0x4b, 0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x4c, 0x0e, 0x51, 0x5d, 0x2f, 0x3a, 0xda,
+    0xe9, 0x14, 0x00, 0x00, 0x00, 0x12, 0x00, 0x00, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x73, 0x75, 0x62, 0x2f, 0x73, 0x63, 0x72, 0x69, 0x70, 0x74, 0x2e,
+    0x73, 0x68, 0x53, 0x56, 0xd4, 0x4f, 0xca, 0xcc, 0xd3, 0x2f, 0xce, 0xe0, 0x4a, 0x4d, 0xce, 0xc8, 0x57, 0xc8, 0xc8, 0xe4, 0x02, 0x00, 0x50, 0x4b,
+    0x03, 0x04, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x4c, 0x0e, 0x51, 0x5d, 0x19, 0x9b, 0xe2, 0x90, 0x13, 0x00, 0x00, 0x00, 0xec, 0x07, 0x00, 0x00,
+    0x10, 0x00, 0x00, 0x00, 0x73, 0x75, 0x62, 0x2f, 0x72, 0x65, 0x70, 0x65, 0x61, 0x74, 0x65, 0x64, 0x2e, 0x74, 0x78, 0x74, 0x4b, 0x4c, 0x4a, 0x4e,
+    0x1c, 0x45, 0xa3, 0x68, 0x14, 0x8d, 0xa2, 0x51, 0x34, 0x8a, 0x46, 0xd1, 0xf0, 0x45, 0x00, 0x50, 0x4b, 0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00,
+    0x00, 0x08, 0x00, 0x4c, 0x0e, 0x51, 0x5d, 0x18, 0xa7, 0x55, 0x7b, 0x10, 0x00, 0x00, 0x00, 0x0e, 0x00, 0x00, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa4, 0x81, 0x00, 0x00, 0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2e, 0x74, 0x78, 0x74, 0x50, 0x4b,
+    0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x4c, 0x0e, 0x51, 0x5d, 0x2f, 0x3a, 0xda, 0xe9, 0x14, 0x00, 0x00, 0x00, 0x12, 0x00,
+    0x00, 0x00, 0x0d, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xed, 0x81, 0x37, 0x00, 0x00, 0x00, 0x73, 0x75, 0x62, 0x2f,
+    0x73, 0x63, 0x72, 0x69, 0x70, 0x74, 0x2e, 0x73, 0x68, 0x50, 0x4b, 0x01, 0x02, 0x14, 0x03, 0x14, 0x00, 0x00, 0x00, 0x08, 0x00, 0x4c, 0x0e, 0x51,
+    0x5d, 0x19, 0x9b, 0xe2, 0x90, 0x13, 0x00, 0x00, 0x00, 0xec, 0x07, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0xa4, 0x81, 0x76, 0x00, 0x00, 0x00, 0x73, 0x75, 0x62, 0x2f, 0x72, 0x65, 0x70, 0x65, 0x61, 0x74, 0x65, 0x64, 0x2e, 0x74, 0x78, 0x74, 0x50,
+    0x4b, 0x05, 0x06, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x03, 0x00, 0xb0, 0x00, 0x00, 0x00, 0xb7, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_sanitize_path() {
+        assert_eq!(
+            sanitize_path("a/b.txt").unwrap(),
+            Path::new("a").join("b.txt")
+        );
+        assert_eq!(sanitize_path("a/").unwrap(), Path::new("a"));
+        for name in [
+            "",
+            "/",
+            "/etc/passwd",
+            "../a",
+            "a/../../b",
+            "a/./b",
+            "a\\..\\b",
+        ] {
+            assert!(sanitize_path(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_extract() {
+        async_io::block_on(async {
+            let target = std::env::temp_dir().join(format!(
+                "magic-wormhole-unzip-test-{}",
+                rand::random::<u64>()
+            ));
+            extract(PYTHON_ZIP, &target, 3, 2060).await.unwrap();
+
+            assert_eq!(
+                async_fs::read_to_string(target.join("hello.txt"))
+                    .await
+                    .unwrap(),
+                "Hello, world!\n"
+            );
+            let script = target.join("sub").join("script.sh");
+            assert_eq!(
+                async_fs::read(&script).await.unwrap(),
+                b"#!/bin/sh\necho hi\n"
+            );
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = async_fs::metadata(&script)
+                    .await
+                    .unwrap()
+                    .permissions()
+                    .mode();
+                assert_eq!(mode & 0o777, 0o755);
+            }
+            let repeated = async_fs::read(target.join("sub").join("repeated.txt"))
+                .await
+                .unwrap();
+            assert_eq!(repeated, "abc".repeat(676).into_bytes());
+
+            /* The offer must match the archive */
+            assert!(extract(PYTHON_ZIP, &target, 2, 2060).await.is_err());
+            assert!(extract(PYTHON_ZIP, &target, 3, 2000).await.is_err());
+            assert!(extract(&PYTHON_ZIP[..100], &target, 3, 2060).await.is_err());
+
+            async_fs::remove_dir_all(&target).await.unwrap();
+        })
+    }
+}
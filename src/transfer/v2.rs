@@ -1,11 +1,14 @@
 use futures::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use serde_derive::{Deserialize, Serialize};
-use sha2::{Digest, Sha256, digest::FixedOutput};
+use sha2::{Digest, Sha256};
 
 use crate::transit::TransitRole;
 
 use super::{offer::*, *};
 
+pub(super) mod compression;
+pub use compression::Compression;
+
 /**
  * A set of hints for both sides to find each other
  */
@@ -82,6 +85,9 @@ struct AnswerMessageInner {
 pub struct FileStart {
     pub file: Vec<String>,
     pub start_at_offset: bool,
+    /// The compression of the following payloads, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<Compression>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -90,9 +96,16 @@ pub struct Payload {
     payload: Vec<u8>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "kebab-case")]
-pub struct FileEnd {}
+pub struct FileEnd {
+    /// The sha256 of the (uncompressed) content sent since the `FileStart`
+    ///
+    /// When resuming, this only covers the part after the offset. The part before it has already
+    /// been checked by the sender, using the hash in the answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<[u8; 32]>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
@@ -104,9 +117,13 @@ async fn make_transit(
     role: TransitRole,
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
     peer_abilities: transit::Abilities,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
 ) -> Result<(transit::Transit, transit::TransitInfo), TransferError> {
-    let connector = transit::init(transit_abilities, Some(peer_abilities), relay_hints).await?;
+    let connector = transit::init(transit_abilities, Some(peer_abilities), relay_hints)
+        .await?
+        .with_rate_limit(rate_limit);
 
     /* Send our transit hints */
     wormhole
@@ -130,8 +147,9 @@ async fn make_transit(
         };
 
     /* Get a transit connection */
-    let (transit, info) = match connector
+    let (transit, info) = match events
         .connect(
+            connector,
             role,
             wormhole.key().derive_transit_key(wormhole.appid()),
             peer_abilities,
@@ -156,27 +174,36 @@ pub async fn send(
     mut wormhole: Wormhole,
     relay_hints: Vec<transit::RelayHint>,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
     offer: OfferSend,
-    progress_handler: impl FnMut(u64, u64) + 'static,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     peer_version: AppVersion,
     cancel: impl Future<Output = ()>,
 ) -> Result<(), TransferError> {
     let peer_abilities = peer_version.transfer_v2.unwrap();
+    let compression = Compression::negotiate(&peer_abilities.compression);
+    ensure!(
+        offer.is_size_known() || peer_abilities.unknown_size,
+        TransferError::UnknownSizeUnsupported
+    );
     futures::pin_mut!(cancel);
 
     /* Establish transit connection, close the Wormhole and switch to using the transit connection (msgpack instead of json) */
     let (mut transit, wormhole, cancel) = cancel::with_cancel_wormhole!(
         wormhole,
         run = async {
-            Ok(make_transit(
+            let (transit, info) = make_transit(
                 &mut wormhole,
                 TransitRole::Leader,
                 relay_hints,
                 transit_abilities,
+                rate_limit,
                 peer_abilities.transit_abilities,
+                events,
             )
-            .await?
-            .0)
+            .await?;
+            events.report(TransferEvent::Connected(info));
+            Ok(transit)
         },
         cancel,
         ret_cancel = (),
@@ -188,7 +215,7 @@ pub async fn send(
             /* Close the wormhole only here so that the operation may be cancelled */
             wormhole.close().await?;
 
-            send_inner(&mut transit, offer, progress_handler).await
+            send_inner(&mut transit, offer, compression, events).await
         },
         cancel,
         |err| PeerMessageV2::Error(err.to_string()).ser_msgpack(),
@@ -206,7 +233,8 @@ pub async fn send(
 async fn send_inner(
     transit: &mut transit::Transit,
     offer: OfferSend,
-    mut progress_handler: impl FnMut(u64, u64) + 'static,
+    compression: Option<Compression>,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
 ) -> Result<(), TransferError> {
     transit.send_record(&{
         /* This must be split into two statements to appease the borrow checker (unfortunate side effect of borrow-through) */
@@ -220,10 +248,12 @@ async fn send_inner(
         },
     };
 
-    let mut total_size = 0;
+    let mut total_size = Some(0);
     for file in &files {
         if let Some((_, size)) = offer.get_file(&file.file) {
-            total_size += size;
+            total_size = total_size
+                .filter(|_| offer.get(&file.file).is_some_and(OfferEntry::is_size_known))
+                .map(|total| total + size);
         } else {
             bail!(TransferError::Protocol(
                 format!("Invalid file request: {}", file.file.join("/")).into()
@@ -243,57 +273,64 @@ async fn send_inner(
     {
         let offset = *offset;
         /* This must be split into two statements to appease the borrow checker (unfortunate side effect of borrow-through) */
-        let content = (offer.get_file(file).unwrap().0)();
-        let mut content = content.await?;
+        let (content, size) = offer.get_file(file).unwrap();
+        let mut content = content().await?;
         let file = file.clone();
+        ensure!(
+            offset <= size,
+            TransferError::Protocol(
+                format!(
+                    "Invalid offset for {}: {offset} is beyond the file size of {size}",
+                    file.join("/")
+                )
+                .into()
+            )
+        );
 
-        /* If they specified a hash, check our local file's contents */
-        if let Some(sha256) = sha256 {
-            content.seek(std::io::SeekFrom::Start(offset)).await?;
-            let mut hasher = Sha256::default();
-            let mut take = (&mut content).take(offset);
-            take.read_exact(&mut buffer).await?;
-            hasher.update(&buffer);
-            let our_hash = hasher.finalize_fixed();
-
-            /* If it doesn't match, start at 0 instead of the originally requested offset */
-            if *our_hash == sha256[..] {
-                transit
-                    .send_record(
-                        &PeerMessageV2::FileStart(FileStart {
-                            file,
-                            start_at_offset: true,
-                        })
-                        .ser_msgpack(),
-                    )
-                    .await?;
-            } else {
-                transit
-                    .send_record(
-                        &PeerMessageV2::FileStart(FileStart {
-                            file,
-                            start_at_offset: false,
-                        })
-                        .ser_msgpack(),
-                    )
-                    .await?;
+        /* If they specified a hash, check that their partial file matches the beginning of ours */
+        let start_at_offset = match sha256 {
+            Some(sha256) => {
                 content.seek(std::io::SeekFrom::Start(0)).await?;
-                // offset = 0; TODO
-            }
-        } else {
+                sha256_prefix(&mut content, offset).await? == *sha256
+            },
+            None => true,
+        };
+        /* If it doesn't match, start at 0 instead of the originally requested offset */
+        if start_at_offset {
             content.seek(std::io::SeekFrom::Start(offset)).await?;
-            transit
-                .send_record(
-                    &PeerMessageV2::FileStart(FileStart {
-                        file,
-                        start_at_offset: true,
-                    })
-                    .ser_msgpack(),
-                )
-                .await?;
+            total_sent += offset;
+        } else {
+            tracing::debug!(
+                "The partial file {} does not match, sending it from scratch",
+                file.join("/")
+            );
+            content.seek(std::io::SeekFrom::Start(0)).await?;
         }
+        let remaining = if start_at_offset { size - offset } else { size };
+        let size_known = offer.get(&file).is_some_and(OfferEntry::is_size_known);
+        let remaining = Some(remaining).filter(|_| size_known);
+        let mut encoder = compression
+            .filter(|_| Compression::should_compress(&file, remaining))
+            .map(compression::Encoder::new)
+            .transpose()?;
+        transit
+            .send_record(
+                &PeerMessageV2::FileStart(FileStart {
+                    file: file.clone(),
+                    start_at_offset,
+                    compression: encoder.as_ref().map(compression::Encoder::compression),
+                })
+                .ser_msgpack(),
+            )
+            .await?;
+
+        events.report(TransferEvent::FileStarted {
+            path: file.clone(),
+            size: Some(size).filter(|_| size_known),
+        });
 
-        progress_handler(total_sent, total_size);
+        let mut hasher = Sha256::default();
+        events.progress(total_sent, total_size);
         loop {
             let n = content.read(&mut buffer[..]).await?;
             let buffer = &buffer[..n];
@@ -302,26 +339,38 @@ async fn send_inner(
                 // EOF
                 break;
             }
-
+            hasher.update(buffer);
+
+            let payload = match &mut encoder {
+                Some(encoder) => encoder.compress(buffer)?,
+                None => buffer.into(),
+            };
+            /* The encoder may buffer the data for a while */
+            if !payload.is_empty() {
+                transit
+                    .send_record(&PeerMessageV2::Payload(Payload { payload }).ser_msgpack())
+                    .await?;
+            }
+            /* Progress is always reported in uncompressed bytes */
+            total_sent += n as u64;
+            events.progress(total_sent, total_size);
+        }
+        if let Some(encoder) = encoder {
+            let payload = encoder.finish()?;
             transit
-                .send_record(
-                    &PeerMessageV2::Payload(Payload {
-                        payload: buffer.into(),
-                    })
-                    .ser_msgpack(),
-                )
+                .send_record(&PeerMessageV2::Payload(Payload { payload }).ser_msgpack())
                 .await?;
-            total_sent += n as u64;
-            progress_handler(total_sent, total_size);
-
-            if n < BUFFER_LEN {
-                break;
-            }
         }
 
         transit
-            .send_record(&PeerMessageV2::FileEnd(FileEnd {}).ser_msgpack())
+            .send_record(
+                &PeerMessageV2::FileEnd(FileEnd {
+                    sha256: Some(hasher.finalize().into()),
+                })
+                .ser_msgpack(),
+            )
             .await?;
+        events.report(TransferEvent::FileFinished { path: file });
     }
     transit
         .send_record(&PeerMessageV2::TransferAck(TransferAck {}).ser_msgpack())
@@ -335,6 +384,8 @@ pub async fn request(
     relay_hints: Vec<transit::RelayHint>,
     peer_version: AppVersion,
     transit_abilities: transit::Abilities,
+    rate_limit: Option<u64>,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
     cancel: impl Future<Output = ()>,
 ) -> Result<Option<ReceiveRequest>, TransferError> {
     let peer_abilities = peer_version.transfer_v2.unwrap();
@@ -349,7 +400,9 @@ pub async fn request(
                 TransitRole::Follower,
                 relay_hints,
                 transit_abilities,
+                rate_limit,
                 peer_abilities.transit_abilities,
+                events,
             )
             .await
         },
@@ -420,16 +473,38 @@ impl ReceiveRequest {
         self,
         transit_handler: impl FnOnce(transit::TransitInfo),
         answer: OfferAccept,
-        progress_handler: impl FnMut(u64, u64) + 'static,
+        progress_handler: impl FnMut(u64, Option<u64>) + 'static,
+        cancel: impl Future<Output = ()>,
+    ) -> Result<(), TransferError> {
+        let mut events =
+            EventReporter::new(event::legacy_handler(transit_handler, progress_handler));
+        self.accept_with_events(answer, &mut events, cancel).await
+    }
+
+    /* The connection has been established while requesting, but it only gets reported here like with transfer v1 */
+    pub(crate) async fn accept_with_events(
+        self,
+        answer: OfferAccept,
+        events: &mut EventReporter<impl FnMut(TransferEvent)>,
         cancel: impl Future<Output = ()>,
     ) -> Result<(), TransferError> {
-        transit_handler(self.info);
+        events.report(TransferEvent::Connected(self.info));
         futures::pin_mut!(cancel);
 
         let mut transit = self.transit;
         cancel::with_cancel_transit!(
             transit,
             run = async {
+                /* The answer must be a subset of the offer */
+                for (path, _, size) in answer.iter_files() {
+                    ensure!(
+                        self.offer.get_file(&path).map(|(_, offered)| offered) == Some(size),
+                        TransferError::InvalidAnswer(
+                            format!("'{}' is not part of the offer", path.join("/")).into()
+                        )
+                    );
+                }
+
                 transit.send_record(&{
                     /* This must be split into two statements to appease the borrow checker (unfortunate side effect of borrow-through) */
 
@@ -444,7 +519,7 @@ impl ReceiveRequest {
                     }).ser_msgpack()
                 }).await?;
 
-                receive_inner(&mut transit, &self.offer, answer, progress_handler).await
+                receive_inner(&mut transit, answer, events).await
             },
             cancel,
             |err| PeerMessageV2::Error(err.to_string()).ser_msgpack(),
@@ -475,28 +550,26 @@ impl ReceiveRequest {
 /** We've established the transit connection and closed the Wormhole */
 async fn receive_inner(
     transit: &mut transit::Transit,
-    offer: &Arc<Offer>,
     our_answer: OfferAccept,
-    mut progress_handler: impl FnMut(u64, u64) + 'static,
+    events: &mut EventReporter<impl FnMut(TransferEvent)>,
 ) -> Result<(), TransferError> {
-    /* This does not check for file sizes, but should be good enough
-     * (failures will eventually lead to protocol errors later on anyways)
-     */
-    assert!(
-        our_answer
-            .iter_file_paths()
-            .all(|path| offer.get_file(&path).is_some()),
-        "Mismatch between offer and accept: accept must be a true subset of offer"
-    );
     let n_accepted = our_answer.iter_file_paths().count();
-    let total_size = our_answer
-        .iter_files()
-        .map(|(_path, _inner, size)| size)
-        .sum::<u64>();
+    let size_known = our_answer
+        .iter_file_paths()
+        .map(|path| our_answer.get(&path).is_some_and(OfferEntry::is_size_known))
+        .collect::<Vec<_>>();
+    let total_size = size_known.iter().all(|known| *known).then(|| {
+        our_answer
+            .iter_files()
+            .map(|(_path, _inner, size)| size)
+            .sum::<u64>()
+    });
     let mut total_received = 0;
 
     /* The receive loop */
-    for (i, (file, answer, size)) in our_answer.into_iter_files().enumerate() {
+    for (i, ((file, answer, size), size_known)) in
+        our_answer.into_iter_files().zip(size_known).enumerate()
+    {
         let file_start = match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?
             .check_err()?
         {
@@ -524,17 +597,35 @@ async fn receive_inner(
         let mut received_size = 0;
         if file_start.start_at_offset {
             content = (answer.content)(true).await?;
-            let offset = answer.offset;
-            received_size = offset;
+            received_size = answer.offset;
+            total_received += answer.offset;
         } else {
             content = (answer.content)(false).await?;
         }
 
-        progress_handler(total_received, total_size);
-        loop {
+        let mut decoder = file_start
+            .compression
+            .map(compression::Decoder::new)
+            .transpose()?;
+        let mut end = None;
+        let mut hasher = Sha256::default();
+        /* Compressed payloads and streams of unknown size are only terminated by the 'file-end' */
+        let open_ended = decoder.is_some() || !size_known;
+
+        events.report(TransferEvent::FileStarted {
+            path: file.clone(),
+            size: Some(size).filter(|_| size_known),
+        });
+        events.progress(total_received, total_size);
+        /* When resuming, we might already have everything */
+        while end.is_none() && (received_size < size || open_ended) {
             let payload =
                 match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?.check_err()? {
                     PeerMessageV2::Payload(payload) => payload.payload,
+                    PeerMessageV2::FileEnd(file_end) if open_ended => {
+                        end = Some(file_end);
+                        continue;
+                    },
                     PeerMessageV2::FileEnd(_) => {
                         bail!(TransferError::Protocol(
                             format!(
@@ -549,35 +640,83 @@ async fn receive_inner(
                     },
                 };
 
-            content.write_all(&payload).await?;
-            received_size += payload.len() as u64;
-            total_received += payload.len() as u64;
-            progress_handler(total_received, total_size);
+            /* Compressed payloads are taken apart into chunks of bounded size, so that a tiny one
+             * can't expand to gigabytes before we notice
+             */
+            let mut payload = Some(payload);
+            let mut uncompressed;
+            loop {
+                let data: &[u8] = match &mut decoder {
+                    Some(decoder) => {
+                        if let Some(payload) = payload.take() {
+                            decoder.feed(payload);
+                        }
+                        match decoder.read()? {
+                            Some(data) => data,
+                            None => break,
+                        }
+                    },
+                    None => match payload.take() {
+                        Some(payload) => {
+                            uncompressed = payload;
+                            &uncompressed
+                        },
+                        None => break,
+                    },
+                };
 
-            if received_size == size {
-                break;
-            } else if received_size >= size {
                 /* `received_size` must never become greater than `size` or we might panic on an integer underflow in the next iteration
                  * (only on an unhappy path, but still). Also, the progress bar might not appreciate.
                  */
-                bail!(TransferError::Protocol(
-                    format!(
-                        "File too large: expected only {size} bytes, got at least {} more",
-                        size - received_size
-                    )
-                    .into_boxed_str()
-                ))
+                let new_size = received_size + data.len() as u64;
+                if size_known && new_size > size {
+                    bail!(TransferError::Protocol(
+                        format!(
+                            "File too large: expected only {size} bytes, got at least {} more",
+                            new_size - size
+                        )
+                        .into_boxed_str()
+                    ))
+                }
+
+                content.write_all(data).await?;
+                hasher.update(data);
+                received_size = new_size;
+                total_received += data.len() as u64;
+                events.progress(total_received, total_size);
             }
         }
+        ensure!(
+            !size_known || received_size == size,
+            TransferError::Protocol(
+                format!(
+                    "File too small: expected {size} bytes, but the data ended after {received_size}"
+                )
+                .into_boxed_str()
+            )
+        );
 
-        content.close().await?;
-
-        let _end = match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?.check_err()? {
-            PeerMessageV2::FileEnd(end) => end,
-            other => {
-                bail!(TransferError::unexpected_message("file-end", other))
+        let end = match end {
+            Some(end) => end,
+            None => {
+                match PeerMessageV2::de_msgpack(&transit.receive_record().await?)?.check_err()? {
+                    PeerMessageV2::FileEnd(end) => end,
+                    other => {
+                        bail!(TransferError::unexpected_message("file-end", other))
+                    },
+                }
             },
         };
+        /* Older peers don't send a hash */
+        if let Some(sha256) = end.sha256 {
+            ensure!(
+                <[u8; 32]>::from(hasher.finalize()) == sha256,
+                TransferError::ChecksumMismatch(file.join("/").into())
+            );
+        }
+
+        content.close().await?;
+        events.report(TransferEvent::FileFinished { path: file });
     }
 
     let _transfer_ack =
@@ -597,3 +736,30 @@ async fn receive_inner(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_file_end_sha256() {
+        let end = PeerMessageV2::FileEnd(FileEnd {
+            sha256: Some(Sha256::digest(b"hello").into()),
+        });
+        let PeerMessageV2::FileEnd(decoded) =
+            PeerMessageV2::de_msgpack(&end.ser_msgpack()).unwrap()
+        else {
+            panic!("Expected a file-end message");
+        };
+        assert_eq!(decoded.sha256, Some(Sha256::digest(b"hello").into()));
+
+        /* Peers that don't know about hashes yet */
+        let PeerMessageV2::FileEnd(decoded) = PeerMessageV2::de_msgpack(
+            &PeerMessageV2::FileEnd(FileEnd { sha256: None }).ser_msgpack(),
+        )
+        .unwrap() else {
+            panic!("Expected a file-end message");
+        };
+        assert_eq!(decoded, FileEnd { sha256: None });
+    }
+}
@@ -0,0 +1,265 @@
+//! Per-file compression of the payload stream
+//!
+//! Both sides advertise the algorithms they support in their [`AppVersionTransferV2Hint`](super::super::AppVersionTransferV2Hint),
+//! and the sender then chooses for each file whether and how to compress it. The chosen algorithm
+//! is announced in the [`FileStart`](super::FileStart) message, and applies to all following payloads
+//! up to the [`FileEnd`](super::FileEnd).
+
+use serde_derive::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// A compression algorithm for the content of a file
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum Compression {
+    #[cfg(not(target_family = "wasm"))]
+    Zstd,
+}
+
+/// The names of the algorithms we support, in order of preference
+#[cfg(not(target_family = "wasm"))]
+pub(in crate::transfer) const SUPPORTED: &[Cow<'static, str>] = &[Cow::Borrowed("zstd")];
+#[cfg(target_family = "wasm")]
+pub(in crate::transfer) const SUPPORTED: &[Cow<'static, str>] = &[];
+
+/// File extensions of formats that already are compressed, so compressing them again is a waste of time
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz",
+    "heic", "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt",
+    "ogg", "opus", "pdf", "png", "rar", "rpm", "tgz", "txz", "webm", "webp", "whl", "xlsx", "xz",
+    "zip", "zst",
+];
+
+/* Below this size, the compression overhead is likely larger than what we save */
+const MIN_SIZE: u64 = 1024;
+
+impl Compression {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            #[cfg(not(target_family = "wasm"))]
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /** Choose the first of our supported algorithms that the peer supports as well */
+    pub(super) fn negotiate(peer: &[Cow<'_, str>]) -> Option<Self> {
+        SUPPORTED
+            .iter()
+            .filter(|name| peer.contains(name))
+            .find_map(|name| Self::from_name(name))
+    }
+
+    /**
+     * Whether a file is worth compressing, judging from its name and the number of bytes to send
+     *
+     * Files that already are compressed won't get any smaller, but still cost CPU time on both sides.
+     * Streams of unknown size are judged by their name only.
+     */
+    pub(super) fn should_compress(path: &[String], size: Option<u64>) -> bool {
+        let extension = path
+            .last()
+            .and_then(|name| name.rsplit_once('.'))
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        size.is_none_or(|size| size >= MIN_SIZE)
+            && !extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&&*extension))
+    }
+}
+
+/** Compresses the content of one file, chunk by chunk */
+pub(super) enum Encoder {
+    #[cfg(not(target_family = "wasm"))]
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl Encoder {
+    pub fn new(compression: Compression) -> std::io::Result<Self> {
+        match compression {
+            #[cfg(not(target_family = "wasm"))]
+            Compression::Zstd => Ok(Self::Zstd(zstd::stream::write::Encoder::new(
+                Vec::new(),
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?)),
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self {
+            #[cfg(not(target_family = "wasm"))]
+            Self::Zstd(_) => Compression::Zstd,
+        }
+    }
+
+    /** Feed some data into the encoder, and take the compressed data that is ready to be sent. This may be empty. */
+    pub fn compress(&mut self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        use std::io::Write;
+
+        match self {
+            #[cfg(not(target_family = "wasm"))]
+            Self::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            },
+        }
+    }
+
+    /** Take the remaining compressed data */
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            #[cfg(not(target_family = "wasm"))]
+            Self::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/* Decompressed data is handed out in chunks of at most this size */
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/**
+ * Decompresses the content of one file, payload by payload
+ *
+ * The output comes in chunks of bounded size, so that the caller can stop a payload that expands
+ * to more than it should, without holding all of it in memory first.
+ */
+pub(super) struct Decoder {
+    algorithm: DecoderAlgorithm,
+    input: Vec<u8>,
+    consumed: usize,
+    chunk: Box<[u8]>,
+}
+
+enum DecoderAlgorithm {
+    #[cfg(not(target_family = "wasm"))]
+    Zstd(zstd::stream::raw::Decoder<'static>),
+}
+
+impl Decoder {
+    pub fn new(compression: Compression) -> std::io::Result<Self> {
+        let algorithm = match compression {
+            #[cfg(not(target_family = "wasm"))]
+            Compression::Zstd => DecoderAlgorithm::Zstd(zstd::stream::raw::Decoder::new()?),
+        };
+        Ok(Self {
+            algorithm,
+            input: Vec::new(),
+            consumed: 0,
+            chunk: vec![0; CHUNK_SIZE].into_boxed_slice(),
+        })
+    }
+
+    /** Feed a payload into the decoder. All of the previous one must have been [read](Self::read) by now. */
+    pub fn feed(&mut self, payload: Vec<u8>) {
+        debug_assert_eq!(self.consumed, self.input.len());
+        self.input = payload;
+        self.consumed = 0;
+    }
+
+    /** Take the next chunk of decompressed data, or `None` once everything that was fed has been taken */
+    pub fn read(&mut self) -> std::io::Result<Option<&[u8]>> {
+        loop {
+            let (consumed, produced) = match &mut self.algorithm {
+                #[cfg(not(target_family = "wasm"))]
+                DecoderAlgorithm::Zstd(decoder) => {
+                    use zstd::stream::raw::{InBuffer, Operation, OutBuffer};
+
+                    let mut input = InBuffer::around(&self.input[self.consumed..]);
+                    let mut output = OutBuffer::around(&mut self.chunk[..]);
+                    decoder.run(&mut input, &mut output)?;
+                    (input.pos(), output.pos())
+                },
+            };
+            self.consumed += consumed;
+            if produced > 0 {
+                return Ok(Some(&self.chunk[..produced]));
+            }
+            if self.consumed == self.input.len() {
+                return Ok(None);
+            }
+            if consumed == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Decompression does not make any progress",
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            Compression::negotiate(&["brotli".into(), "zstd".into()]),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::negotiate(&["brotli".into()]), None);
+        assert_eq!(Compression::negotiate(&[]), None);
+    }
+
+    #[test]
+    fn test_should_compress() {
+        let path = |name: &str| vec!["dir".to_owned(), name.to_owned()];
+        assert!(Compression::should_compress(
+            &path("server.log"),
+            Some(1 << 20)
+        ));
+        assert!(Compression::should_compress(
+            &path("Makefile"),
+            Some(1 << 20)
+        ));
+        assert!(!Compression::should_compress(
+            &path("server.log"),
+            Some(100)
+        ));
+        assert!(!Compression::should_compress(
+            &path("holiday.JPG"),
+            Some(1 << 20)
+        ));
+        assert!(!Compression::should_compress(
+            &path("logs.tar.gz"),
+            Some(1 << 20)
+        ));
+        assert!(Compression::should_compress(&path("stdin"), None));
+        assert!(!Compression::should_compress(&path("backup.zst"), None));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let data = b"All work and no play makes Jack a dull boy. ".repeat(2000);
+
+        let mut encoder = Encoder::new(Compression::Zstd).unwrap();
+        let mut payloads = Vec::new();
+        for chunk in data.chunks(16 * 1024) {
+            payloads.push(encoder.compress(chunk).unwrap());
+        }
+        payloads.push(encoder.finish().unwrap());
+        assert!(payloads.iter().map(Vec::len).sum::<usize>() < data.len() / 10);
+
+        let mut decoder = Decoder::new(Compression::Zstd).unwrap();
+        let mut received = Vec::<u8>::new();
+        for payload in payloads {
+            decoder.feed(payload);
+            while let Some(chunk) = decoder.read().unwrap() {
+                assert!(chunk.len() <= CHUNK_SIZE);
+                received.extend(chunk);
+            }
+        }
+        assert_eq!(received, data);
+    }
+
+    /* A tiny payload that expands to a lot is handed out chunk by chunk */
+    #[test]
+    fn test_bomb() {
+        let bomb = zstd::encode_all(&vec![0u8; 64 << 20][..], 19).unwrap();
+        assert!(bomb.len() < 64 * 1024);
+
+        let mut decoder = Decoder::new(Compression::Zstd).unwrap();
+        decoder.feed(bomb);
+        let chunk = decoder.read().unwrap().unwrap();
+        assert_eq!(chunk.len(), CHUNK_SIZE);
+    }
+}
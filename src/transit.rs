@@ -33,6 +33,8 @@ use std::{
 };
 
 mod crypto;
+#[cfg(all(feature = "relay-server", not(target_family = "wasm")))]
+pub mod relay_server;
 mod transport;
 use crypto::TransitHandshakeError;
 use transport::{TransitTransport, TransitTransportRx, TransitTransportTx};
@@ -652,6 +654,66 @@ pub struct TransitInfo {
 
 type TransitConnection = (Box<dyn TransitTransport>, TransitInfo);
 
+/// A step while establishing a transit connection
+///
+/// Set a handler with [`TransitConnector::with_event_handler`] to get notified about them.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum TransitEvent {
+    /// The hints have been exchanged with the peer, and we start connecting
+    Hints {
+        /// The hints we have sent
+        ours: Arc<Hints>,
+        /// The hints we have received
+        theirs: Arc<Hints>,
+    },
+    /// Trying to connect to one of the peer's direct hints, or to a relay server
+    Connecting {
+        /// Whether this is a direct or a relay connection
+        conn_type: ConnectionType,
+        /// The address we are connecting to
+        address: String,
+    },
+    /// Connecting to the address failed
+    ConnectFailed {
+        /// Whether this is a direct or a relay connection
+        conn_type: ConnectionType,
+        /// The address we tried to connect to
+        address: String,
+        /// Why it failed
+        error: String,
+    },
+    /// We are connected, but the transit handshake over that connection failed
+    HandshakeFailed {
+        /// The connection on which the handshake failed
+        info: TransitInfo,
+        /// Why it failed
+        error: String,
+    },
+}
+
+type EventHandler = Arc<dyn Fn(TransitEvent) + Send + Sync>;
+
+/* Report the start and failure of a connection attempt */
+async fn report_attempt(
+    events: EventHandler,
+    conn_type: ConnectionType,
+    address: String,
+    attempt: impl Future<Output = Result<TransitConnection, TransitHandshakeError>>,
+) -> Result<TransitConnection, TransitHandshakeError> {
+    events(TransitEvent::Connecting {
+        conn_type: conn_type.clone(),
+        address: address.clone(),
+    });
+    attempt.await.inspect_err(|err| {
+        events(TransitEvent::ConnectFailed {
+            conn_type,
+            address,
+            error: err.to_string(),
+        })
+    })
+}
+
 #[cfg(not(target_family = "wasm"))]
 #[derive(Debug, thiserror::Error)]
 enum StunError {
@@ -839,6 +901,8 @@ pub async fn init(
         sockets,
         our_abilities: abilities,
         our_hints: Arc::new(our_hints),
+        events: Arc::new(|_| {}),
+        rate_limit: None,
     })
 }
 
@@ -889,9 +953,28 @@ pub struct TransitConnector {
     sockets: Option<(MaybeConnectedSocket, TcpListener)>,
     our_abilities: Abilities,
     our_hints: Arc<Hints>,
+    events: EventHandler,
+    rate_limit: Option<u64>,
 }
 
 impl TransitConnector {
+    /// Get notified about each step of connecting, like the attempts to reach the peer's hints
+    pub fn with_event_handler(
+        mut self,
+        handler: impl Fn(TransitEvent) + Send + Sync + 'static,
+    ) -> Self {
+        self.events = Arc::new(handler);
+        self
+    }
+
+    /// Limit the bandwidth of the established connection to this many bytes per second, in each direction
+    ///
+    /// This is a local setting that the peer doesn't know about. `None`, the default, means no limit.
+    pub fn with_rate_limit(mut self, bytes_per_second: Option<u64>) -> Self {
+        self.rate_limit = bytes_per_second;
+        self
+    }
+
     /// The abilities that we've sent to the other side
     pub fn our_abilities(&self) -> &Abilities {
         &self.our_abilities
@@ -912,14 +995,79 @@ impl TransitConnector {
         transit_key: Key<TransitKey>,
         their_abilities: Abilities,
         their_hints: Arc<Hints>,
+    ) -> Result<(Transit, TransitInfo), TransitConnectError> {
+        let transit_key = Arc::new(transit_key);
+        let cryptor = if self.our_abilities.can_noise_crypto() && their_abilities.can_noise_crypto()
+        {
+            tracing::debug!("Using noise protocol for encryption");
+            Arc::new(crypto::NoiseInit {
+                key: transit_key.clone(),
+                confirmation: b"",
+            }) as Arc<dyn crypto::TransitCryptoInit>
+        } else {
+            tracing::debug!("Using secretbox for encryption");
+            Arc::new(crypto::SecretboxInit {
+                key: transit_key.clone(),
+            }) as Arc<dyn crypto::TransitCryptoInit>
+        };
+        let tside = Arc::new(hex::encode(rand::random::<[u8; 8]>()));
+
+        self.connect_with(
+            role,
+            transit_key,
+            cryptor,
+            tside,
+            their_abilities,
+            their_hints,
+        )
+        .await
+    }
+
+    /// Connect to the other side for a [dilated](crate::dilation) wormhole.
+    ///
+    /// This uses the Noise handshake with dilation's key confirmation record, and our
+    /// `side` as the relay handshake side.
+    #[cfg(all(feature = "dilation", not(target_family = "wasm")))]
+    pub(crate) async fn dilation_connect(
+        self,
+        role: TransitRole,
+        dilation_key: Arc<Key<TransitKey>>,
+        side: Arc<String>,
+        their_abilities: Abilities,
+        their_hints: Arc<Hints>,
+    ) -> Result<(Transit, TransitInfo), TransitConnectError> {
+        let cryptor = Arc::new(crypto::NoiseInit {
+            key: dilation_key.clone(),
+            confirmation: crate::dilation::KEY_CONFIRMATION,
+        });
+
+        self.connect_with(
+            role,
+            dilation_key,
+            cryptor,
+            side,
+            their_abilities,
+            their_hints,
+        )
+        .await
+    }
+
+    async fn connect_with(
+        self,
+        role: TransitRole,
+        transit_key: Arc<Key<TransitKey>>,
+        cryptor: Arc<dyn crypto::TransitCryptoInit>,
+        tside: Arc<String>,
+        their_abilities: Abilities,
+        their_hints: Arc<Hints>,
     ) -> Result<(Transit, TransitInfo), TransitConnectError> {
         match role {
             TransitRole::Leader => {
-                self.leader_connect(transit_key, their_abilities, their_hints)
+                self.leader_connect(transit_key, cryptor, tside, their_abilities, their_hints)
                     .await
             },
             TransitRole::Follower => {
-                self.follower_connect(transit_key, their_abilities, their_hints)
+                self.follower_connect(transit_key, cryptor, tside, their_abilities, their_hints)
                     .await
             },
         }
@@ -930,7 +1078,9 @@ impl TransitConnector {
      */
     async fn leader_connect(
         self,
-        transit_key: Key<TransitKey>,
+        transit_key: Arc<Key<TransitKey>>,
+        cryptor: Arc<dyn crypto::TransitCryptoInit>,
+        tside: Arc<String>,
         their_abilities: Abilities,
         their_hints: Arc<Hints>,
     ) -> Result<(Transit, TransitInfo), TransitConnectError> {
@@ -939,18 +1089,26 @@ impl TransitConnector {
             sockets,
             our_abilities,
             our_hints,
+            events,
+            rate_limit,
         } = self;
-        let transit_key = Arc::new(transit_key);
+        events(TransitEvent::Hints {
+            ours: our_hints.clone(),
+            theirs: their_hints.clone(),
+        });
 
         let start = Instant::now();
         let mut connection_stream = Box::pin(
             Self::connect_inner(
                 true,
                 transit_key,
+                cryptor,
+                tside,
                 our_abilities,
                 our_hints,
                 their_abilities,
                 their_hints,
+                events,
                 #[cfg(not(target_family = "wasm"))]
                 sockets,
             )
@@ -1021,14 +1179,7 @@ impl TransitConnector {
                 TransitConnectError::Handshake
             })?;
 
-        Ok((
-            Transit {
-                socket: transit,
-                tx,
-                rx,
-            },
-            conn_info,
-        ))
+        Ok((Transit::new(transit, tx, rx, rate_limit), conn_info))
     }
 
     /**
@@ -1036,7 +1187,9 @@ impl TransitConnector {
      */
     async fn follower_connect(
         self,
-        transit_key: Key<TransitKey>,
+        transit_key: Arc<Key<TransitKey>>,
+        cryptor: Arc<dyn crypto::TransitCryptoInit>,
+        tside: Arc<String>,
         their_abilities: Abilities,
         their_hints: Arc<Hints>,
     ) -> Result<(Transit, TransitInfo), TransitConnectError> {
@@ -1045,17 +1198,25 @@ impl TransitConnector {
             sockets,
             our_abilities,
             our_hints,
+            events,
+            rate_limit,
         } = self;
-        let transit_key = Arc::new(transit_key);
+        events(TransitEvent::Hints {
+            ours: our_hints.clone(),
+            theirs: their_hints.clone(),
+        });
 
         let mut connection_stream = Box::pin(
             Self::connect_inner(
                 false,
                 transit_key,
+                cryptor,
+                tside,
                 our_abilities,
                 our_hints,
                 their_abilities,
                 their_hints,
+                events,
                 #[cfg(not(target_family = "wasm"))]
                 sockets,
             )
@@ -1085,7 +1246,7 @@ impl TransitConnector {
                         TransitConnectError::Handshake
                     })?;
 
-                Ok((Transit { socket, tx, rx }, conn_info))
+                Ok((Transit::new(socket, tx, rx, rate_limit), conn_info))
             },
             Ok(None) | Err(_) => {
                 tracing::debug!("`follower_connect` timed out");
@@ -1113,31 +1274,20 @@ impl TransitConnector {
     fn connect_inner(
         is_leader: bool,
         transit_key: Arc<Key<TransitKey>>,
+        cryptor: Arc<dyn crypto::TransitCryptoInit>,
+        tside: Arc<String>,
         our_abilities: Abilities,
         our_hints: Arc<Hints>,
         their_abilities: Abilities,
         their_hints: Arc<Hints>,
+        events: EventHandler,
         #[cfg(not(target_family = "wasm"))] sockets: Option<(MaybeConnectedSocket, TcpListener)>,
     ) -> impl Stream<Item = Result<HandshakeResult, TransitHandshakeError>> + 'static {
         /* Have Some(sockets) → Can direct */
         #[cfg(not(target_family = "wasm"))]
         assert!(sockets.is_none() || our_abilities.can_direct());
 
-        let cryptor = if our_abilities.can_noise_crypto() && their_abilities.can_noise_crypto() {
-            tracing::debug!("Using noise protocol for encryption");
-            Arc::new(crypto::NoiseInit {
-                key: transit_key.clone(),
-            }) as Arc<dyn crypto::TransitCryptoInit>
-        } else {
-            tracing::debug!("Using secretbox for encryption");
-            Arc::new(crypto::SecretboxInit {
-                key: transit_key.clone(),
-            }) as Arc<dyn crypto::TransitCryptoInit>
-        };
-
         // 8. listen for connections on the port and simultaneously try connecting to the peer port.
-        let tside = Arc::new(hex::encode(rand::random::<[u8; 8]>()));
-
         /* Iterator of futures yielding a connection. They'll be then mapped with the handshake, collected into
          * a Vec and polled concurrently.
          */
@@ -1160,6 +1310,7 @@ impl TransitConnector {
                         .expect("This is guaranteed to be an IP socket"),
                 )
             });
+            let events = events.clone();
             /* Connect to each hint of the peer */
             connectors = Box::new(
                 connectors.chain(
@@ -1169,7 +1320,14 @@ impl TransitConnector {
                         .into_iter()
                         /* Nobody should have that many IP addresses, even with NATing */
                         .take(50)
-                        .map(move |hint| transport::connect_tcp_direct(local_addr.clone(), hint))
+                        .map(move |hint| {
+                            report_attempt(
+                                events.clone(),
+                                ConnectionType::Direct,
+                                hint.to_string(),
+                                transport::connect_tcp_direct(local_addr.clone(), hint),
+                            )
+                        })
                         .map(|fut| Box::pin(fut) as ConnectorFuture),
                 ),
             ) as BoxIterator<ConnectorFuture>;
@@ -1183,6 +1341,7 @@ impl TransitConnector {
             for hint in their_hints.relay.iter().take(2).cloned() {
                 hint.merge_into(&mut relay_hints);
             }
+            let events = events.clone();
 
             #[cfg(not(target_family = "wasm"))]
             {
@@ -1216,12 +1375,21 @@ impl TransitConnector {
                                 .enumerate()
                                 .map(move |(i, h)| (i, h, name.clone()))
                             })
-                            .map(|(index, host, name)| async move {
-                                async_io::Timer::after(std::time::Duration::from_secs(
-                                    index as u64 * 5,
-                                ))
-                                .await;
-                                transport::connect_tcp_relay(host, name).await
+                            .map(move |(index, host, name)| {
+                                let events = events.clone();
+                                async move {
+                                    async_io::Timer::after(std::time::Duration::from_secs(
+                                        index as u64 * 5,
+                                    ))
+                                    .await;
+                                    report_attempt(
+                                        events,
+                                        ConnectionType::Relay { name: name.clone() },
+                                        host.to_string(),
+                                        transport::connect_tcp_relay(host, name),
+                                    )
+                                    .await
+                                }
                             })
                             .map(|fut| Box::pin(fut) as ConnectorFuture),
                     ),
@@ -1260,12 +1428,21 @@ impl TransitConnector {
                                     .enumerate()
                                     .map(move |(i, u)| (i, u, name.clone()))
                             })
-                            .map(|(index, url, name)| async move {
-                                crate::util::sleep(std::time::Duration::from_secs(
-                                    index as u64 * 5,
-                                ))
-                                .await;
-                                transport::connect_ws_relay(url, name).await
+                            .map(move |(index, url, name)| {
+                                let events = events.clone();
+                                async move {
+                                    crate::util::sleep(std::time::Duration::from_secs(
+                                        index as u64 * 5,
+                                    ))
+                                    .await;
+                                    report_attempt(
+                                        events,
+                                        ConnectionType::Relay { name: name.clone() },
+                                        url.to_string(),
+                                        transport::connect_ws_relay(url, name),
+                                    )
+                                    .await
+                                }
                             })
                             .map(|fut| Box::pin(fut) as ConnectorFuture),
                     ),
@@ -1277,6 +1454,7 @@ impl TransitConnector {
         let transit_key2 = transit_key.clone();
         let tside2 = tside.clone();
         let cryptor2 = cryptor.clone();
+        let events2 = events.clone();
         #[allow(unused_mut)] // For WASM targets
         let mut connectors = Box::new(
             connectors
@@ -1284,6 +1462,7 @@ impl TransitConnector {
                     let transit_key = transit_key2.clone();
                     let tside = tside2.clone();
                     let cryptor = cryptor2.clone();
+                    let events = events2.clone();
                     async move {
                         let (socket, conn_info) = fut.await?;
                         let (transit, finalizer) = handshake_exchange(
@@ -1294,7 +1473,13 @@ impl TransitConnector {
                             &*cryptor,
                             transit_key,
                         )
-                        .await?;
+                        .await
+                        .inspect_err(|err| {
+                            events(TransitEvent::HandshakeFailed {
+                                info: conn_info.clone(),
+                                error: err.to_string(),
+                            })
+                        })?;
                         Ok((transit, finalizer, conn_info))
                     }
                 })
@@ -1326,7 +1511,13 @@ impl TransitConnector {
                                 &*cryptor,
                                 transit_key.clone(),
                             )
-                            .await?;
+                            .await
+                            .inspect_err(|err| {
+                                events(TransitEvent::HandshakeFailed {
+                                    info: info.clone(),
+                                    error: err.to_string(),
+                                })
+                            })?;
                             Result::<_, TransitHandshakeError>::Ok((transit, finalizer, info))
                         };
                         loop {
@@ -1364,17 +1555,39 @@ pub struct Transit {
     socket: Box<dyn TransitTransport>,
     tx: Box<dyn crypto::TransitCryptoEncrypt>,
     rx: Box<dyn crypto::TransitCryptoDecrypt>,
+    /* Both directions are limited independently, see `TransitConnector::with_rate_limit` */
+    tx_limiter: crate::util::RateLimiter,
+    rx_limiter: crate::util::RateLimiter,
 }
 
 impl Transit {
+    fn new(
+        socket: Box<dyn TransitTransport>,
+        tx: Box<dyn crypto::TransitCryptoEncrypt>,
+        rx: Box<dyn crypto::TransitCryptoDecrypt>,
+        rate_limit: Option<u64>,
+    ) -> Self {
+        Self {
+            socket,
+            tx,
+            rx,
+            tx_limiter: crate::util::RateLimiter::new(rate_limit),
+            rx_limiter: crate::util::RateLimiter::new(rate_limit),
+        }
+    }
+
     /** Receive and decrypt one message from the other side. */
     pub async fn receive_record(&mut self) -> Result<Box<[u8]>, TransitError> {
-        self.rx.decrypt(&mut self.socket).await
+        let record = self.rx.decrypt(&mut self.socket).await?;
+        /* Not reading from the socket for a while makes the peer slow down as well */
+        self.rx_limiter.throttle(record.len()).await;
+        Ok(record)
     }
 
     /** Send an encrypted message to the other side */
     pub async fn send_record(&mut self, plaintext: &[u8]) -> Result<(), TransitError> {
         assert!(!plaintext.is_empty());
+        self.tx_limiter.throttle(plaintext.len()).await;
         self.tx.encrypt(&mut self.socket, plaintext).await
     }
 
@@ -1396,18 +1609,22 @@ impl Transit {
         let (reader, writer) = self.socket.split();
         (
             futures::sink::unfold(
-                (writer, self.tx),
-                |(mut writer, mut tx), plaintext: Box<[u8]>| async move {
+                (writer, self.tx, self.tx_limiter),
+                |(mut writer, mut tx, mut limiter), plaintext: Box<[u8]>| async move {
+                    limiter.throttle(plaintext.len()).await;
                     tx.encrypt(&mut writer, &plaintext)
                         .await
-                        .map(|()| (writer, tx))
+                        .map(|()| (writer, tx, limiter))
+                },
+            ),
+            futures::stream::try_unfold(
+                (reader, self.rx, self.rx_limiter),
+                |(mut reader, mut rx, mut limiter)| async move {
+                    let record = rx.decrypt(&mut reader).await?;
+                    limiter.throttle(record.len()).await;
+                    Ok(Some((record, (reader, rx, limiter))))
                 },
             ),
-            futures::stream::try_unfold((reader, self.rx), |(mut reader, mut rx)| async move {
-                rx.decrypt(&mut reader)
-                    .await
-                    .map(|record| Some((record, (reader, rx))))
-            }),
         )
     }
 }
@@ -235,12 +235,16 @@ type NoiseCipherState = noise_protocol::CipherState<noise_rust_crypto::ChaCha20P
 /// ← "Magic-Wormhole Dilation Handshake v1 Follower\n\n"
 /// → psk, e // Handshake
 /// ← e, ee
-/// ← "" // First real message
-/// → "" // Not in this method, to confirm the connection
+/// ← confirmation // First real message
+/// → confirmation // Not in this method, to confirm the connection
 ///
 /// The noise protocol pattern used is "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s"
+///
+/// The confirmation message is empty for transit. Dilation uses the same handshake,
+/// but confirms with its "key confirmation" record, a single zero byte.
 pub struct NoiseInit {
     pub key: Arc<Key<TransitKey>>,
+    pub confirmation: &'static [u8],
 }
 
 #[async_trait]
@@ -276,16 +280,17 @@ impl TransitCryptoInit for NoiseInit {
         assert!(handshake.completed());
         let (tx, mut rx) = handshake.get_ciphers();
 
-        // ← ""
+        // ← confirmation
         let peer_confirmation_message = rx.decrypt_vec(&socket.read_transit_message().await?)?;
         ensure!(
-            peer_confirmation_message.is_empty(),
+            peer_confirmation_message == self.confirmation,
             TransitHandshakeError::HandshakeFailed
         );
 
         struct Finalizer {
             tx: NoiseCipherState,
             rx: NoiseCipherState,
+            confirmation: &'static [u8],
         }
 
         impl TransitCryptoInitFinalizer for Finalizer {
@@ -294,9 +299,9 @@ impl TransitCryptoInit for NoiseInit {
                 socket: &mut dyn TransitTransport,
             ) -> BoxFuture<Result<DynTransitCrypto, TransitHandshakeError>> {
                 Box::pin(async move {
-                    // → ""
+                    // → confirmation
                     socket
-                        .write_transit_message(&self.tx.encrypt_vec(&[]))
+                        .write_transit_message(&self.tx.encrypt_vec(self.confirmation))
                         .await?;
 
                     Ok::<_, TransitHandshakeError>((
@@ -309,7 +314,11 @@ impl TransitCryptoInit for NoiseInit {
             }
         }
 
-        Ok(Box::new(Finalizer { tx, rx }))
+        Ok(Box::new(Finalizer {
+            tx,
+            rx,
+            confirmation: self.confirmation,
+        }))
     }
 
     async fn handshake_follower(
@@ -344,13 +353,15 @@ impl TransitCryptoInit for NoiseInit {
         // Warning: rx and tx are swapped here (read the `get_ciphers` doc carefully)
         let (mut rx, mut tx) = handshake.get_ciphers();
 
-        // → ""
-        socket.write_transit_message(&tx.encrypt_vec(&[])).await?;
+        // → confirmation
+        socket
+            .write_transit_message(&tx.encrypt_vec(self.confirmation))
+            .await?;
 
-        // ← ""
+        // ← confirmation
         let peer_confirmation_message = rx.decrypt_vec(&socket.read_transit_message().await?)?;
         ensure!(
-            peer_confirmation_message.is_empty(),
+            peer_confirmation_message == self.confirmation,
             TransitHandshakeError::HandshakeFailed
         );
 
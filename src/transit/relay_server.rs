@@ -0,0 +1,709 @@
+//! An embeddable transit relay server
+//!
+//! This is the server side of the relay handshake that transit clients do when connecting via a [`RelayHint`].
+//! Clients connect via TCP or WebSocket and send `please relay <token> for side <side>\n`. As soon as a
+//! second client with the same token (but a different side) shows up, both get an `ok\n` and all further
+//! data is passed through unchanged. The server never sees any plaintext, since the transit encryption
+//! is end to end.
+//!
+//! ```no_run
+//! # fn main() -> eyre::Result<()> { async_io::block_on(async {
+//! use magic_wormhole::transit::{self, relay_server::RelayServer};
+//!
+//! let server = RelayServer::bind("127.0.0.1:0")
+//!     .await?
+//!     .listen_websocket("127.0.0.1:0")
+//!     .await?
+//!     .bandwidth_limit(1024 * 1024)
+//!     .idle_timeout(std::time::Duration::from_secs(60));
+//! let relay_hints = vec![server.hint()?];
+//! std::thread::spawn(|| async_io::block_on(server.run()));
+//!
+//! let connector = transit::init(transit::Abilities::FORCE_RELAY, None, relay_hints).await?;
+//! # Ok(()) })}
+//! ```
+
+use super::{DirectHint, RelayHint};
+use async_net::{AsyncToSocketAddrs, TcpListener, TcpStream};
+use async_tungstenite::tungstenite as ws2;
+use futures::{
+    AsyncReadExt, AsyncWriteExt, Sink, SinkExt, StreamExt, TryStreamExt,
+    channel::oneshot,
+    future::{self, BoxFuture},
+    stream::BoxStream,
+};
+use futures_lite::FutureExt;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Clients must finish their handshake within this time
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// The longest handshake line we accept, including the newline
+const MAX_HANDSHAKE_LENGTH: usize = 256;
+
+/**
+ * A transit relay server
+ *
+ * Create one with [`RelayServer::bind`], hand out [`RelayServer::hint`] to your clients and then
+ * drive [`RelayServer::run`] on an executor of your choice.
+ */
+#[derive(Debug)]
+pub struct RelayServer {
+    tcp: TcpListener,
+    websocket: Option<TcpListener>,
+    bandwidth_limit: Option<u64>,
+    idle_timeout: Option<Duration>,
+}
+
+impl RelayServer {
+    /**
+     * Listen for TCP clients on the given address
+     *
+     * Use port `0` to let the operating system pick a free port, e.g. `"127.0.0.1:0"`.
+     */
+    pub async fn bind(addr: impl AsyncToSocketAddrs) -> std::io::Result<Self> {
+        Ok(Self {
+            tcp: TcpListener::bind(addr).await?,
+            websocket: None,
+            bandwidth_limit: None,
+            idle_timeout: None,
+        })
+    }
+
+    /// Additionally listen for WebSocket clients on the given address
+    pub async fn listen_websocket(
+        mut self,
+        addr: impl AsyncToSocketAddrs,
+    ) -> std::io::Result<Self> {
+        self.websocket = Some(TcpListener::bind(addr).await?);
+        Ok(self)
+    }
+
+    /// Limit the throughput of each relayed connection to that many bytes per second and direction
+    pub fn bandwidth_limit(mut self, bytes_per_second: u64) -> Self {
+        assert!(bytes_per_second > 0, "Bandwidth limit must be positive");
+        self.bandwidth_limit = Some(bytes_per_second);
+        self
+    }
+
+    /**
+     * Disconnect clients after that much time without any traffic
+     *
+     * This applies to clients waiting for their peer as well as to relayed connections.
+     */
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// The address the server is listening on for TCP clients
+    pub fn tcp_addr(&self) -> std::io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+
+    /// The address the server is listening on for WebSocket clients, if enabled
+    pub fn websocket_addr(&self) -> Option<std::io::Result<SocketAddr>> {
+        self.websocket.as_ref().map(TcpListener::local_addr)
+    }
+
+    /**
+     * A relay hint pointing to this server
+     *
+     * The hint is built from the local addresses of the listening sockets. When listening on an
+     * unspecified address like `0.0.0.0`, you will have to construct the hint yourself.
+     */
+    pub fn hint(&self) -> std::io::Result<RelayHint> {
+        let tcp = self.tcp_addr()?;
+        let websocket = self
+            .websocket_addr()
+            .transpose()?
+            .map(|addr| format!("ws://{addr}/").parse().unwrap());
+        Ok(RelayHint::new(
+            None,
+            [DirectHint::new(tcp.ip().to_string(), tcp.port())],
+            websocket,
+        ))
+    }
+
+    /**
+     * Relay clients
+     *
+     * This never returns. Connections that can't be accepted are logged and skipped.
+     * All state lives inside of this future, dropping it will disconnect all clients.
+     */
+    pub async fn run(self) -> std::io::Result<()> {
+        let state = Arc::new(Mutex::new(RelayState::default()));
+        let executor = async_executor::Executor::new();
+        let limits = Limits {
+            bandwidth: self.bandwidth_limit,
+            idle_timeout: self.idle_timeout,
+        };
+
+        let accept_tcp = async {
+            loop {
+                let (stream, peer) = accept(&self.tcp, "TCP").await;
+                tracing::debug!("Accepted TCP relay connection from {}", peer);
+                executor
+                    .spawn(handle_connection(
+                        state.clone(),
+                        limits,
+                        async move { Ok(tcp_connection(stream)) }.boxed(),
+                    ))
+                    .detach();
+            }
+        };
+
+        let accept_websocket = async {
+            let Some(websocket) = &self.websocket else {
+                return future::pending().await;
+            };
+            loop {
+                let (stream, peer) = accept(websocket, "WebSocket").await;
+                tracing::debug!("Accepted WebSocket relay connection from {}", peer);
+                executor
+                    .spawn(handle_connection(
+                        state.clone(),
+                        limits,
+                        websocket_connection(stream).boxed(),
+                    ))
+                    .detach();
+            }
+        };
+
+        executor.run(accept_tcp.or(accept_websocket)).await
+    }
+}
+
+/* A failed accept only affects that one connection, so log it and keep going */
+async fn accept(
+    listener: &async_net::TcpListener,
+    kind: &str,
+) -> (async_net::TcpStream, SocketAddr) {
+    loop {
+        match listener.accept().await {
+            Ok(connection) => return connection,
+            Err(error) => {
+                tracing::warn!("Failed to accept a {} relay connection: {}", kind, error);
+                /* Don't spin if the error persists, e.g. when running out of file descriptors */
+                crate::util::sleep(Duration::from_millis(100)).await;
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    bandwidth: Option<u64>,
+    idle_timeout: Option<Duration>,
+}
+
+/// A client connection, regardless of the transport. WebSocket messages are mapped to chunks of bytes.
+struct Connection {
+    stream: BoxStream<'static, std::io::Result<Vec<u8>>>,
+    sink: Pin<Box<dyn Sink<Vec<u8>, Error = std::io::Error> + Send>>,
+}
+
+fn tcp_connection(stream: TcpStream) -> Connection {
+    let (reader, writer) = stream.split();
+    Connection {
+        stream: futures::stream::try_unfold(reader, |mut reader| async move {
+            let mut buffer = vec![0; 16 * 1024];
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+            buffer.truncate(read);
+            Ok(Some((buffer, reader)))
+        })
+        .boxed(),
+        sink: Box::pin(writer.into_sink()),
+    }
+}
+
+async fn websocket_connection(stream: TcpStream) -> std::io::Result<Connection> {
+    let (sink, stream) = async_tungstenite::accept_async(stream)
+        .await
+        .map_err(std::io::Error::other)?
+        .split();
+    Ok(Connection {
+        stream: stream
+            .map_err(std::io::Error::other)
+            .try_take_while(|message| future::ok(!message.is_close()))
+            .try_filter_map(|message| {
+                future::ready(match message {
+                    ws2::Message::Binary(data) => Ok(Some(data.to_vec())),
+                    ws2::Message::Text(_) => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "relay messages must be binary",
+                    )),
+                    _ => Ok(None),
+                })
+            })
+            .boxed(),
+        sink: Box::pin(
+            sink.sink_map_err(std::io::Error::other)
+                .with(|data: Vec<u8>| future::ok(ws2::Message::binary(data))),
+        ),
+    })
+}
+
+#[derive(Default)]
+struct RelayState {
+    /// Clients waiting for their peer, by token
+    pending: HashMap<String, Vec<PendingClient>>,
+    next_client_id: u64,
+}
+
+struct PendingClient {
+    id: u64,
+    side: Option<String>,
+    peer: oneshot::Sender<Connection>,
+}
+
+/// Parse the handshake line without the trailing newline
+///
+/// Old clients don't send their side, so it is optional.
+fn parse_handshake(line: &[u8]) -> Option<(String, Option<String>)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let rest = line.strip_prefix("please relay ")?;
+    let (token, side) = match rest.split_once(" for side ") {
+        Some((token, side)) => (token, Some(side)),
+        None => (rest, None),
+    };
+    let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+    if !is_hex(token) || !side.is_none_or(is_hex) {
+        return None;
+    }
+    Some((token.to_owned(), side.map(str::to_owned)))
+}
+
+/// Read the handshake line, returning it together with any data that came after it
+async fn read_handshake(connection: &mut Connection) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buffer = Vec::new();
+    loop {
+        let chunk = connection.stream.try_next().await?.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed during handshake",
+            )
+        })?;
+        buffer.extend_from_slice(&chunk);
+        if let Some(position) = buffer.iter().position(|&byte| byte == b'\n') {
+            let rest = buffer.split_off(position + 1);
+            buffer.pop();
+            return Ok((buffer, rest));
+        }
+        if buffer.len() >= MAX_HANDSHAKE_LENGTH {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "handshake too long",
+            ));
+        }
+    }
+}
+
+async fn handle_connection(
+    state: Arc<Mutex<RelayState>>,
+    limits: Limits,
+    connection: BoxFuture<'static, std::io::Result<Connection>>,
+) {
+    if let Err(error) = handle_connection_inner(state, limits, connection).await {
+        tracing::debug!("Relay connection failed: {}", error);
+    }
+}
+
+async fn handle_connection_inner(
+    state: Arc<Mutex<RelayState>>,
+    limits: Limits,
+    connection: BoxFuture<'static, std::io::Result<Connection>>,
+) -> std::io::Result<()> {
+    let (mut connection, line, rest) = crate::util::timeout(HANDSHAKE_TIMEOUT, async {
+        let mut connection = connection.await?;
+        let (line, rest) = read_handshake(&mut connection).await?;
+        std::io::Result::Ok((connection, line, rest))
+    })
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))??;
+
+    let Some((token, side)) = parse_handshake(&line) else {
+        connection.sink.send(b"bad handshake\n".to_vec()).await?;
+        connection.sink.close().await?;
+        return Ok(());
+    };
+    /* Like Python's relay, we drop clients that send anything before getting their `ok` */
+    if !rest.is_empty() {
+        return reject_impatient(connection).await;
+    }
+
+    /* Find a waiting partner, or wait for one ourselves */
+    let (id, receiver) = {
+        let mut state = state.lock().unwrap();
+        state.next_client_id += 1;
+        let id = state.next_client_id;
+        let waiting = state.pending.entry(token.clone()).or_default();
+        loop {
+            waiting.retain(|client| !client.peer.is_canceled());
+            /* Different sides only, otherwise a client might connect to itself */
+            let Some(index) = waiting
+                .iter()
+                .position(|client| side.is_none() || client.side.is_none() || client.side != side)
+            else {
+                let (sender, receiver) = oneshot::channel();
+                waiting.push(PendingClient {
+                    id,
+                    side,
+                    peer: sender,
+                });
+                break (id, receiver);
+            };
+            /* If this fails, the waiting client went away in the meantime */
+            match waiting.remove(index).peer.send(connection) {
+                Ok(()) => {
+                    tracing::debug!("Paired relay clients for token {}", token);
+                    if waiting.is_empty() {
+                        state.pending.remove(&token);
+                    }
+                    return Ok(());
+                },
+                Err(returned) => connection = returned,
+            }
+        }
+    };
+
+    /* While waiting, the client must not send anything. We still need to notice if it goes away */
+    let mut impatient = false;
+    let peer = async { receiver.await.ok() }
+        .or(async {
+            impatient = matches!(connection.stream.next().await, Some(Ok(_)));
+            None
+        })
+        .or(async {
+            match limits.idle_timeout {
+                Some(timeout) => crate::util::sleep(timeout).await,
+                None => future::pending().await,
+            }
+            None
+        })
+        .await;
+
+    let Some(peer) = peer else {
+        {
+            let mut state = state.lock().unwrap();
+            if let Some(waiting) = state.pending.get_mut(&token) {
+                waiting.retain(|client| client.id != id);
+                if waiting.is_empty() {
+                    state.pending.remove(&token);
+                }
+            }
+        }
+        if impatient {
+            return reject_impatient(connection).await;
+        }
+        return Ok(());
+    };
+
+    splice(connection, peer, limits).await
+}
+
+async fn reject_impatient(mut connection: Connection) -> std::io::Result<()> {
+    tracing::debug!("Dropping relay client that sent data before it was paired");
+    connection.sink.send(b"impatient\n".to_vec()).await?;
+    connection.sink.close().await
+}
+
+/// Pass all data between the two clients, until one of them disconnects
+async fn splice(first: Connection, second: Connection, limits: Limits) -> std::io::Result<()> {
+    let Connection {
+        stream: first_stream,
+        sink: mut first_sink,
+    } = first;
+    let Connection {
+        stream: second_stream,
+        sink: mut second_sink,
+    } = second;
+    first_sink.send(b"ok\n".to_vec()).await?;
+    second_sink.send(b"ok\n".to_vec()).await?;
+
+    let last_activity = Mutex::new(Instant::now());
+    let forward =
+        |mut stream: BoxStream<'static, std::io::Result<Vec<u8>>>,
+         mut sink: Pin<Box<dyn Sink<Vec<u8>, Error = std::io::Error> + Send>>| {
+            let last_activity = &last_activity;
+            async move {
+                let mut limiter = crate::util::RateLimiter::new(limits.bandwidth);
+                while let Some(chunk) = stream.try_next().await? {
+                    *last_activity.lock().unwrap() = Instant::now();
+                    limiter.throttle(chunk.len()).await;
+                    sink.send(chunk).await?;
+                }
+                sink.close().await
+            }
+        };
+    let watchdog = async {
+        let Some(timeout) = limits.idle_timeout else {
+            return future::pending().await;
+        };
+        loop {
+            let idle = last_activity.lock().unwrap().elapsed();
+            if idle >= timeout {
+                break Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "relayed connection was idle for too long",
+                ));
+            }
+            crate::util::sleep(timeout - idle).await;
+        }
+    };
+
+    forward(first_stream, second_sink)
+        .or(forward(second_stream, first_sink))
+        .or(watchdog)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        Key,
+        transit::{self, Abilities, ConnectionType, TransitRole},
+    };
+    use futures_concurrency::prelude::*;
+
+    #[test]
+    fn test_transit_via_relay() {
+        async_io::block_on(async {
+            let server = RelayServer::bind("127.0.0.1:0").await.unwrap();
+            let hint = server.hint().unwrap();
+            crate::util::spawn(server.run()).detach();
+
+            let leader = transit::init(Abilities::FORCE_RELAY, None, vec![hint.clone()])
+                .await
+                .unwrap();
+            let follower = transit::init(Abilities::FORCE_RELAY, None, vec![hint])
+                .await
+                .unwrap();
+            let leader_hints = leader.our_hints().clone();
+            let follower_hints = follower.our_hints().clone();
+            let key = || Key::new(Box::new([42; 32].into()));
+
+            let (leader, follower) = (
+                leader.connect(
+                    TransitRole::Leader,
+                    key(),
+                    Abilities::FORCE_RELAY,
+                    follower_hints,
+                ),
+                follower.connect(
+                    TransitRole::Follower,
+                    key(),
+                    Abilities::FORCE_RELAY,
+                    leader_hints,
+                ),
+            )
+                .join()
+                .await;
+            let (mut leader, info) = leader.unwrap();
+            let (mut follower, _) = follower.unwrap();
+            assert!(matches!(info.conn_type, ConnectionType::Relay { .. }));
+
+            leader.send_record(b"hello").await.unwrap();
+            assert_eq!(&*follower.receive_record().await.unwrap(), b"hello");
+            follower.send_record(b"world").await.unwrap();
+            assert_eq!(&*leader.receive_record().await.unwrap(), b"world");
+        })
+    }
+
+    #[test]
+    fn test_transit_rate_limit() {
+        async_io::block_on(async {
+            let server = RelayServer::bind("127.0.0.1:0").await.unwrap();
+            let hint = server.hint().unwrap();
+            crate::util::spawn(server.run()).detach();
+
+            let leader = transit::init(Abilities::FORCE_RELAY, None, vec![hint.clone()])
+                .await
+                .unwrap()
+                .with_rate_limit(Some(128 * 1024));
+            let follower = transit::init(Abilities::FORCE_RELAY, None, vec![hint])
+                .await
+                .unwrap();
+            let leader_hints = leader.our_hints().clone();
+            let follower_hints = follower.our_hints().clone();
+            let key = || Key::new(Box::new([42; 32].into()));
+
+            let (leader, follower) = (
+                leader.connect(
+                    TransitRole::Leader,
+                    key(),
+                    Abilities::FORCE_RELAY,
+                    follower_hints,
+                ),
+                follower.connect(
+                    TransitRole::Follower,
+                    key(),
+                    Abilities::FORCE_RELAY,
+                    leader_hints,
+                ),
+            )
+                .join()
+                .await;
+            let (mut leader, _) = leader.unwrap();
+            let (mut follower, _) = follower.unwrap();
+
+            /* 64 KiB at 128 KiB/s take half a second */
+            let start = Instant::now();
+            let send = async {
+                for _ in 0..4 {
+                    leader.send_record(&[0; 16 * 1024]).await.unwrap();
+                }
+            };
+            let receive = async {
+                for _ in 0..4 {
+                    follower.receive_record().await.unwrap();
+                }
+            };
+            (send, receive).join().await;
+            assert!(start.elapsed() >= Duration::from_millis(450));
+        })
+    }
+
+    #[test]
+    fn test_tcp_to_websocket() {
+        async_io::block_on(async {
+            let server = RelayServer::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .listen_websocket("127.0.0.1:0")
+                .await
+                .unwrap();
+            let tcp_addr = server.tcp_addr().unwrap();
+            let websocket_addr = server.websocket_addr().unwrap().unwrap();
+            crate::util::spawn(server.run()).detach();
+
+            let mut tcp = TcpStream::connect(tcp_addr).await.unwrap();
+            tcp.write_all(b"please relay abcd for side 01\n")
+                .await
+                .unwrap();
+            let (mut websocket, _) =
+                async_tungstenite::smol::connect_async(format!("ws://{websocket_addr}/"))
+                    .await
+                    .unwrap();
+            websocket
+                .send(ws2::Message::binary(
+                    b"please relay abcd for side 02\n".to_vec(),
+                ))
+                .await
+                .unwrap();
+
+            let mut buffer = [0; 3];
+            tcp.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, b"ok\n");
+            assert_eq!(
+                websocket.next().await.unwrap().unwrap(),
+                ws2::Message::binary(b"ok\n".to_vec())
+            );
+
+            tcp.write_all(b"ping").await.unwrap();
+            assert_eq!(
+                websocket.next().await.unwrap().unwrap(),
+                ws2::Message::binary(b"ping".to_vec())
+            );
+            websocket
+                .send(ws2::Message::binary(b"pong".to_vec()))
+                .await
+                .unwrap();
+            let mut buffer = [0; 4];
+            tcp.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(&buffer, b"pong");
+        })
+    }
+
+    #[test]
+    fn test_bad_handshake() {
+        async_io::block_on(async {
+            let server = RelayServer::bind("127.0.0.1:0").await.unwrap();
+            let addr = server.tcp_addr().unwrap();
+            crate::util::spawn(server.run()).detach();
+
+            let mut tcp = TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"please relay nothex\n").await.unwrap();
+            let mut response = Vec::new();
+            tcp.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"bad handshake\n");
+        })
+    }
+
+    #[test]
+    fn test_impatient() {
+        async_io::block_on(async {
+            let server = RelayServer::bind("127.0.0.1:0").await.unwrap();
+            let addr = server.tcp_addr().unwrap();
+            crate::util::spawn(server.run()).detach();
+
+            /* Right after the handshake */
+            let mut tcp = TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"please relay abcd for side 01\nhello")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            tcp.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"impatient\n");
+
+            /* While waiting for the peer */
+            let mut tcp = TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"please relay abcd for side 01\n")
+                .await
+                .unwrap();
+            crate::util::sleep(Duration::from_millis(100)).await;
+            tcp.write_all(b"hello").await.unwrap();
+            let mut response = Vec::new();
+            tcp.read_to_end(&mut response).await.unwrap();
+            assert_eq!(response, b"impatient\n");
+        })
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        async_io::block_on(async {
+            let server = RelayServer::bind("127.0.0.1:0")
+                .await
+                .unwrap()
+                .idle_timeout(Duration::from_millis(100));
+            let addr = server.tcp_addr().unwrap();
+            crate::util::spawn(server.run()).detach();
+
+            /* Nobody else will show up, so we get disconnected */
+            let mut tcp = TcpStream::connect(addr).await.unwrap();
+            tcp.write_all(b"please relay abcd for side 01\n")
+                .await
+                .unwrap();
+            let mut response = Vec::new();
+            crate::util::timeout(Duration::from_secs(10), tcp.read_to_end(&mut response))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(response.is_empty());
+        })
+    }
+
+    #[test]
+    fn test_parse_handshake() {
+        assert_eq!(
+            parse_handshake(b"please relay abcd for side 0123"),
+            Some(("abcd".into(), Some("0123".into())))
+        );
+        assert_eq!(
+            parse_handshake(b"please relay abcd"),
+            Some(("abcd".into(), None))
+        );
+        assert_eq!(parse_handshake(b"please relay abcd for side "), None);
+        assert_eq!(parse_handshake(b"please relay xyz"), None);
+        assert_eq!(parse_handshake(b"hello"), None);
+    }
+}
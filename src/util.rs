@@ -50,29 +50,17 @@ pub fn hashcash(resource: String, bits: u32) -> String {
         );
     }
 
-    /* This is the `[year][month][day]` format, but without activating the parser */
-    use time::format_description::{Component, FormatItem};
-    let format = [
-        FormatItem::Component(Component::Year(
-            time::format_description::modifier::Year::default(),
-        )),
-        FormatItem::Component(Component::Month(
-            time::format_description::modifier::Month::default(),
-        )),
-        FormatItem::Component(Component::Day(
-            time::format_description::modifier::Day::default(),
-        )),
-    ];
-
     let base64_engine = base64::engine::general_purpose::STANDARD;
 
     /* I'm pretty sure HashCash should work with any time zone */
-    let date = base64_engine.encode(
-        time::OffsetDateTime::now_utc()
-            .date()
-            .format(&format[..])
-            .unwrap(),
-    );
+    let date = time::OffsetDateTime::now_utc().date();
+    /* This is the `[year][month][day]` format, but without activating the parser */
+    let date = base64_engine.encode(format!(
+        "{:04}{:02}{:02}",
+        date.year(),
+        u8::from(date.month()),
+        date.day()
+    ));
 
     let rand: String = base64_engine.encode(
         rand::thread_rng()
@@ -144,6 +132,53 @@ pub(crate) fn timeout<'a, R, F: std::future::Future<Output = R> + 'a>(
     futures_lite::future::or(async { Ok(future.await) }, timeout_future)
 }
 
+/// Delay data so that it does not exceed a given rate
+///
+/// This is a token bucket: while idle, credit builds up for a burst of at most one second's worth of data.
+#[cfg(feature = "transit")]
+pub(crate) struct RateLimiter {
+    bytes_per_second: Option<u64>,
+    /* What may pass right away, or how much we are in debt if negative */
+    tokens: f64,
+    last: std::time::Instant,
+}
+
+#[cfg(feature = "transit")]
+impl RateLimiter {
+    /// Without a rate, this never delays anything
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        Self {
+            bytes_per_second,
+            tokens: 0.0,
+            last: std::time::Instant::now(),
+        }
+    }
+
+    /// Wait until `bytes` more bytes may pass
+    pub async fn throttle(&mut self, bytes: usize) {
+        let delay = self.delay(bytes, std::time::Instant::now());
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+    }
+
+    /* Take `bytes` out of the bucket, and return how long to wait for them */
+    fn delay(&mut self, bytes: usize, now: std::time::Instant) -> std::time::Duration {
+        let Some(bytes_per_second) = self.bytes_per_second else {
+            return std::time::Duration::ZERO;
+        };
+        let rate = bytes_per_second as f64;
+        let idle = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + idle * rate).min(rate) - bytes as f64;
+        self.last = now;
+        if self.tokens < 0.0 {
+            std::time::Duration::from_secs_f64(-self.tokens / rate)
+        } else {
+            std::time::Duration::ZERO
+        }
+    }
+}
+
 #[cfg(any(test, feature = "forwarding"))]
 fn executor() -> &'static async_executor::Executor<'static> {
     const NUM_THREADS: usize = 3;
@@ -180,3 +215,31 @@ pub(crate) fn spawn<T: Send + 'static>(
 ) -> async_task::Task<T> {
     executor().spawn(future)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[cfg(feature = "transit")]
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(Some(1000));
+        limiter.last = start;
+
+        assert_eq!(limiter.delay(500, start), Duration::from_millis(500));
+        /* The debt is paid off while waiting */
+        assert_eq!(
+            limiter.delay(1000, start + Duration::from_millis(500)),
+            Duration::from_secs(1)
+        );
+
+        /* A long idle period only allows a burst of one second */
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.delay(1000, later), Duration::ZERO);
+        assert_eq!(limiter.delay(1000, later), Duration::from_secs(1));
+
+        assert_eq!(RateLimiter::new(None).delay(1 << 30, later), Duration::ZERO);
+    }
+}